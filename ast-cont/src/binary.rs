@@ -1,8 +1,8 @@
 use super::*;
 
 pub fn do_binary<'c>(
-    l: &'c Ast<'c>,
-    r: &'c Ast<'c>,
+    l: &'c LoweredAst<'c>,
+    r: &'c LoweredAst<'c>,
     c: WithContinue<'c>,
     op: PrimOpKind,
     idg: &'c IdGet,
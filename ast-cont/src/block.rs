@@ -0,0 +1,114 @@
+use super::*;
+
+type Rest<'c> = Box<FnOnce() -> ContAstPtr<'c> + 'c>;
+
+/// Evaluates each statement for its effects, then continues with `rest`.
+fn do_statements<'c>(
+    statements: &'c [&'c LoweredAst<'c>],
+    rest: Rest<'c>,
+    idg: &'c IdGet,
+    arena: &'c Arena<ContAst<'c>>,
+) -> ContAstPtr<'c> {
+    match statements.split_first() {
+        None => rest(),
+        Some((first, others)) => translate(
+            first,
+            Box::new(move |_| do_statements(others, rest, idg, arena)),
+            idg,
+            arena,
+        ),
+    }
+}
+
+fn is_declaration(ast: &LoweredAst) -> bool {
+    match ast {
+        &LoweredAst::VariableDecl { .. }
+        | &LoweredAst::FunctionDecl { .. }
+        | &LoweredAst::FunctionGroup(..) => true,
+        _ => false,
+    }
+}
+
+pub fn do_variable<'c>(
+    id: Ident<'c>,
+    expression: &'c LoweredAst<'c>,
+    c: WithContinue<'c>,
+    idg: &'c IdGet,
+    arena: &'c Arena<ContAst<'c>>,
+) -> ContAstPtr<'c> {
+    translate(
+        expression,
+        Box::new(move |v| {
+            arena.alloc(ContAst::Primop {
+                op: PrimOpKind::Bind,
+                terminals: vec![v],
+                exports: vec![id],
+                continuations: vec![c(Terminal::Ident(id))],
+            }) as &_
+        }),
+        idg,
+        arena,
+    )
+}
+
+/// Everything after a block is nested inside it, so a block that declares
+/// names returns through a continuation built outside of it, where those
+/// names are not in scope.
+pub fn do_block<'c>(
+    statements: &'c [&'c LoweredAst<'c>],
+    final_expression: &'c LoweredAst<'c>,
+    c: WithContinue<'c>,
+    idg: &'c IdGet,
+    arena: &'c Arena<ContAst<'c>>,
+) -> ContAstPtr<'c> {
+    let body = move |c: WithContinue<'c>| {
+        do_statements(
+            statements,
+            Box::new(move || translate(final_expression, c, idg, arena)),
+            idg,
+            arena,
+        )
+    };
+    if !statements.iter().any(|s| is_declaration(s)) {
+        return body(c);
+    }
+
+    call::build_cont(
+        c,
+        Box::new(move |cont_term| {
+            body(Box::new(move |v| {
+                arena.alloc(ContAst::Call {
+                    target: cont_term,
+                    params: vec![v],
+                    continuation: None,
+                }) as &_
+            }))
+        }),
+        idg,
+        arena,
+    )
+}
+
+/// Runs the statements of a module, which evaluates to an empty map like
+/// it does in the VM.
+pub fn do_module<'c>(
+    statements: &'c [&'c LoweredAst<'c>],
+    c: WithContinue<'c>,
+    idg: &'c IdGet,
+    arena: &'c Arena<ContAst<'c>>,
+) -> ContAstPtr<'c> {
+    do_statements(
+        statements,
+        Box::new(move || {
+            let id = idg.get();
+            arena.alloc(ContAst::Primop {
+                op: PrimOpKind::MapEmpty,
+                terminals: vec![],
+                exports: vec![id],
+                continuations: vec![c(Terminal::Ident(id))],
+            }) as &_
+        }),
+        idg,
+        arena,
+    )
+}
@@ -18,10 +18,10 @@ pub fn build_cont<'c>(
     })
 }
 
-type WithContinueParams<'c> = Box<FnBox(Vec<Terminal<'c>>) -> ContAstPtr<'c> + 'c>;
+type WithContinueParams<'c> = Box<FnOnce(Vec<Terminal<'c>>) -> ContAstPtr<'c> + 'c>;
 
 pub fn eval_params<'c>(
-    args: &'c [&'c Ast<'c>],
+    args: &'c [&'c LoweredAst<'c>],
     mut params: Vec<Terminal<'c>>,
     c: WithContinueParams<'c>,
     idg: &'c IdGet,
@@ -31,7 +31,7 @@ pub fn eval_params<'c>(
         return c(params);
     }
 
-    let (first, rest) = (&args[0], &args[1..]);
+    let (first, rest) = (args[0], &args[1..]);
     translate(
         first,
         Box::new(move |term| {
@@ -44,8 +44,8 @@ pub fn eval_params<'c>(
 }
 
 pub fn do_call<'c>(
-    target: &'c Ast<'c>,
-    args: &'c [&'c Ast<'c>],
+    target: &'c LoweredAst<'c>,
+    args: &'c [&'c LoweredAst<'c>],
     c: WithContinue<'c>,
     idg: &'c IdGet,
     arena: &'c Arena<ContAst<'c>>,
@@ -56,11 +56,19 @@ pub fn do_call<'c>(
             translate(
                 target,
                 Box::new(move |target| {
-                    eval_params(args, vec![], Box::new(move |params| arena.alloc(ContAst::Call {
-                        target: target,
-                        params: params,
-                        continuation: cont_term
-                    }) as &_), idg, arena)
+                    eval_params(
+                        args,
+                        vec![],
+                        Box::new(move |params| {
+                            arena.alloc(ContAst::Call {
+                                target: target,
+                                params: params,
+                                continuation: Some(cont_term),
+                            }) as &_
+                        }),
+                        idg,
+                        arena,
+                    )
                 }),
                 idg,
                 arena,
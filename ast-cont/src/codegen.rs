@@ -0,0 +1,476 @@
+use super::*;
+use shrink::unique_binders;
+use std::collections::{HashMap, HashSet};
+use vm::debug_info::LineTable;
+use vm::value::{new_func, Code, Function as VmFunction, FunctionPtr, Symbol, Value, ValueKind};
+use vm::vm::Instruction;
+
+// Closure conversion from `ContAst` to VM functions.
+//
+// The VM already passes continuations: `Call` attaches a continuation to the
+// callee and `Resume` hands a value to the continuation of the running
+// function.  So a function of the IR that takes its return continuation as
+// a last parameter becomes a VM function without that parameter, and
+// calling the parameter becomes `Resume`.  A function defined as a
+// continuation (one that is only ever passed as the continuation of a call,
+// or called without one) runs with the same VM continuation as the function
+// that defines it, so it can return with `Resume` too.
+//
+// Every body is a straight line of primops ending in a call, so each VM
+// function is a single run of instructions with the usual frame:
+//
+// [self]
+// [args]
+// [upvars]     the free variables of the function, in order of first use
+// [locals]     one per primop export and per function built by a `Fix`
+//
+// A closure captures values when it is built, so a function refers to
+// itself through `self`.  The functions of a `Fix` that refer to each other
+// cannot capture each other that way: they all capture the free variables
+// of the whole `Fix` and then the unbuilt code of every function of it,
+// which is everything needed to build any of them again on use.
+
+#[derive(Debug, PartialEq)]
+pub enum CodegenError<'c> {
+    UnboundIdentifier(Ident<'c>),
+    /// A return continuation was used as a value, or called from a
+    /// function that does not return to it.
+    EscapingContinuation(Ident<'c>),
+}
+
+pub type CodegenResult<'c, T> = Result<T, CodegenError<'c>>;
+
+/// Compiles a whole program into a function that takes no arguments and
+/// terminates the VM with the value given to `Term`.
+pub fn compile_top<'c>(
+    program: ContAstPtr<'c>,
+    arena: &'c Arena<ContAst<'c>>,
+) -> CodegenResult<'c, FunctionPtr> {
+    let program = unique_binders(program, arena);
+    let mut continuations = HashSet::new();
+    find_continuations(program, &mut continuations);
+    let mut return_params = HashSet::new();
+    find_return_params(program, &continuations, &mut return_params);
+
+    let analysis = Analysis {
+        continuations,
+        return_params,
+    };
+    let mut emitter = Emitter::new(&analysis, None, None, &[], &[], &[]);
+    emitter.emit(program)?;
+    Ok(new_func(emitter.finish(None)))
+}
+
+struct Analysis<'c> {
+    /// Functions that are only used as continuations.
+    continuations: HashSet<Ident<'c>>,
+    /// The last parameter of every other function.
+    return_params: HashSet<Ident<'c>>,
+}
+
+fn find_continuations<'c>(node: ContAstPtr<'c>, out: &mut HashSet<Ident<'c>>) {
+    match node {
+        &ContAst::Fix {
+            ref functions,
+            continuation,
+        } => {
+            for function in functions {
+                find_continuations(function.body, out);
+            }
+            find_continuations(continuation, out);
+        }
+        &ContAst::Call {
+            target,
+            continuation,
+            ..
+        } => match (target, continuation) {
+            (Terminal::Ident(target), None) => {
+                out.insert(target);
+            }
+            (_, Some(Terminal::Ident(continuation))) => {
+                out.insert(continuation);
+            }
+            _ => {}
+        },
+        &ContAst::Primop {
+            ref continuations,
+            ..
+        } => for &continuation in continuations {
+            find_continuations(continuation, out);
+        },
+    }
+}
+
+fn find_return_params<'c>(
+    node: ContAstPtr<'c>,
+    continuations: &HashSet<Ident<'c>>,
+    out: &mut HashSet<Ident<'c>>,
+) {
+    match node {
+        &ContAst::Fix {
+            ref functions,
+            continuation,
+        } => {
+            for function in functions {
+                if !continuations.contains(&function.name) {
+                    out.extend(function.params.last());
+                }
+                find_return_params(function.body, continuations, out);
+            }
+            find_return_params(continuation, continuations, out);
+        }
+        &ContAst::Call { .. } => {}
+        &ContAst::Primop {
+            continuations: ref nodes,
+            ..
+        } => for &node in nodes {
+            find_return_params(node, continuations, out);
+        },
+    }
+}
+
+/// Appends the identifiers that `node` uses but does not bind to `out`,
+/// each once, in the order they are first used.
+fn free_variables<'c>(node: ContAstPtr<'c>, bound: &mut Vec<Ident<'c>>, out: &mut Vec<Ident<'c>>) {
+    fn read<'c>(terminal: Terminal<'c>, bound: &[Ident<'c>], out: &mut Vec<Ident<'c>>) {
+        if let Terminal::Ident(ident) = terminal {
+            if !bound.contains(&ident) && !out.contains(&ident) {
+                out.push(ident);
+            }
+        }
+    }
+
+    let mark = bound.len();
+    match node {
+        &ContAst::Fix {
+            ref functions,
+            continuation,
+        } => {
+            bound.extend(functions.iter().map(|function| function.name));
+            for function in functions {
+                let inner = bound.len();
+                bound.extend(function.params.iter().cloned());
+                free_variables(function.body, bound, out);
+                bound.truncate(inner);
+            }
+            free_variables(continuation, bound, out);
+        }
+        &ContAst::Call {
+            target,
+            ref params,
+            continuation,
+        } => {
+            read(target, bound, out);
+            for &param in params {
+                read(param, bound, out);
+            }
+            if let Some(continuation) = continuation {
+                read(continuation, bound, out);
+            }
+        }
+        &ContAst::Primop {
+            ref terminals,
+            ref exports,
+            ref continuations,
+            ..
+        } => {
+            for &terminal in terminals {
+                read(terminal, bound, out);
+            }
+            bound.extend(exports.iter().cloned());
+            for &continuation in continuations {
+                free_variables(continuation, bound, out);
+            }
+        }
+    }
+    bound.truncate(mark);
+}
+
+/// A function that receives a value and resumes its own continuation with
+/// it, for calls whose continuation is the current one.
+fn pass_through() -> Value {
+    Value::Function(new_func(VmFunction::new(Code {
+        name: Some("<pass-through>".into()),
+        instructions: vec![Instruction::GetFromStackPosition(1), Instruction::Resume],
+        constants: vec![],
+        args_count: 1,
+        upvars_count: 0,
+        locals_count: 0,
+        line_table: LineTable::new(),
+    })))
+}
+
+struct Emitter<'c: 'a, 'a> {
+    analysis: &'a Analysis<'c>,
+    /// The return continuation that `Resume` goes to, if it has a name.
+    native: Option<Ident<'c>>,
+    this: Option<Ident<'c>>,
+    /// The functions of the `Fix` that this function is built by, when
+    /// they refer to each other.  Their code follows the other upvars.
+    siblings: Vec<Ident<'c>>,
+    slots: HashMap<Ident<'c>, u32>,
+    args_count: u32,
+    upvars_count: u32,
+    locals_count: u32,
+    instructions: Vec<Instruction>,
+    constants: Vec<Value>,
+}
+
+impl<'c: 'a, 'a> Emitter<'c, 'a> {
+    fn new(
+        analysis: &'a Analysis<'c>,
+        native: Option<Ident<'c>>,
+        this: Option<Ident<'c>>,
+        args: &[Ident<'c>],
+        upvars: &[Ident<'c>],
+        siblings: &[Ident<'c>],
+    ) -> Emitter<'c, 'a> {
+        let slots = args.iter()
+            .chain(upvars)
+            .enumerate()
+            .map(|(i, &ident)| (ident, 1 + i as u32))
+            .collect();
+        Emitter {
+            analysis,
+            native,
+            this,
+            siblings: siblings.to_vec(),
+            slots,
+            args_count: args.len() as u32,
+            upvars_count: (upvars.len() + siblings.len()) as u32,
+            locals_count: 0,
+            instructions: vec![],
+            constants: vec![],
+        }
+    }
+
+    fn finish(self, name: Option<String>) -> VmFunction {
+        VmFunction::new(Code {
+            name,
+            instructions: self.instructions,
+            constants: self.constants,
+            args_count: self.args_count,
+            upvars_count: self.upvars_count,
+            locals_count: self.locals_count,
+            line_table: LineTable::new(),
+        })
+    }
+
+    fn add_const(&mut self, value: Value) -> u32 {
+        let same = |c: &Value| match (c, &value) {
+            // Bitwise, so that 0.0 and -0.0 stay apart.
+            (&Value::Float(a), &Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (c, value) => c == value,
+        };
+        let index = match self.constants.iter().position(same) {
+            Some(index) if value.kind() != ValueKind::Function => index,
+            _ => {
+                self.constants.push(value);
+                self.constants.len() - 1
+            }
+        };
+        index as u32
+    }
+
+    fn push_const(&mut self, value: Value) {
+        let index = self.add_const(value);
+        self.instructions.push(Instruction::PushConst(index));
+    }
+
+    fn push_terminal(&mut self, terminal: Terminal<'c>) -> CodegenResult<'c, ()> {
+        match terminal {
+            Terminal::Integer(i) => self.push_const(Value::Integer(i)),
+            Terminal::Float(f) => self.push_const(Value::Float(f)),
+            Terminal::Symbol(s) => self.push_const(Value::Symbol(Symbol(s.into()))),
+            Terminal::Ident(ident) => {
+                let position = if Some(ident) == self.this {
+                    0
+                } else if let Some(&position) = self.slots.get(&ident) {
+                    position
+                } else if let Some(index) = self.siblings.iter().position(|&s| s == ident) {
+                    self.rebuild_sibling(index as u32);
+                    return Ok(());
+                } else if self.analysis.return_params.contains(&ident) {
+                    return Err(CodegenError::EscapingContinuation(ident));
+                } else {
+                    return Err(CodegenError::UnboundIdentifier(ident));
+                };
+                self.instructions.push(Instruction::GetFromStackPosition(position));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reserves a local for `ident` and stores the value on top of the
+    /// stack in it.
+    fn store(&mut self, ident: Ident<'c>) {
+        let position = 1 + self.args_count + self.upvars_count + self.locals_count;
+        self.locals_count += 1;
+        self.slots.insert(ident, position);
+        self.instructions.push(Instruction::SetToStackPosition(position));
+    }
+
+    fn is_native(&self, terminal: Terminal<'c>) -> bool {
+        match (terminal, self.native) {
+            (Terminal::Ident(ident), Some(native)) => ident == native,
+            _ => false,
+        }
+    }
+
+    /// Builds the sibling with this index again from the upvars, which
+    /// are everything it captures.
+    fn rebuild_sibling(&mut self, index: u32) {
+        let upvars = 1 + self.args_count;
+        for position in upvars..upvars + self.upvars_count {
+            self.instructions.push(Instruction::GetFromStackPosition(position));
+        }
+        let code = upvars + self.upvars_count - self.siblings.len() as u32 + index;
+        self.instructions.push(Instruction::GetFromStackPosition(code));
+        self.instructions.push(Instruction::BuildFunction);
+    }
+
+    /// The variables that `function` captures, including the other
+    /// functions of its `Fix`.
+    fn captures(&self, function: &Function<'c>) -> Vec<Ident<'c>> {
+        let mut bound = function.params.clone();
+        bound.push(function.name);
+        let mut free = vec![];
+        free_variables(function.body, &mut bound, &mut free);
+        free.retain(|ident| !self.analysis.return_params.contains(ident));
+        free
+    }
+
+    /// Compiles `function` into an unbuilt VM function that captures
+    /// `upvars` and then the code of each of `siblings`.
+    fn compile(
+        &self,
+        function: &Function<'c>,
+        upvars: &[Ident<'c>],
+        siblings: &[Ident<'c>],
+    ) -> CodegenResult<'c, Value> {
+        let is_continuation = self.analysis.continuations.contains(&function.name);
+        let (args, native) = match function.params.split_last() {
+            Some((&last, rest)) if !is_continuation => (rest, Some(last)),
+            _ => (&function.params[..], self.native),
+        };
+
+        let mut body = Emitter::new(self.analysis, native, Some(function.name), args, upvars, siblings);
+        body.emit(function.body)?;
+        let code = body.finish(Some(format!("{:?}", function.name)));
+        Ok(Value::Function(new_func(code)))
+    }
+
+    /// Builds the closures of the functions of a `Fix` and stores them.
+    fn emit_fix(&mut self, functions: &[Function<'c>]) -> CodegenResult<'c, ()> {
+        let names = functions.iter().map(|function| function.name).collect::<Vec<_>>();
+        let captures = functions
+            .iter()
+            .map(|function| self.captures(function))
+            .collect::<Vec<_>>();
+        let is_group = captures
+            .iter()
+            .any(|captured| captured.iter().any(|ident| names.contains(ident)));
+
+        let mut shared = vec![];
+        for &ident in captures.iter().flatten() {
+            if !names.contains(&ident) && !shared.contains(&ident) {
+                shared.push(ident);
+            }
+        }
+        let mut codes = vec![];
+        for (function, captured) in functions.iter().zip(&captures) {
+            let code = if is_group {
+                self.compile(function, &shared, &names)?
+            } else {
+                self.compile(function, captured, &[])?
+            };
+            codes.push(self.add_const(code));
+        }
+
+        for ((function, captured), &code) in functions.iter().zip(&captures).zip(&codes) {
+            let upvars = if is_group { &shared } else { captured };
+            for &upvar in upvars {
+                self.push_terminal(Terminal::Ident(upvar))?;
+            }
+            if is_group {
+                for &sibling in &codes {
+                    self.instructions.push(Instruction::PushConst(sibling));
+                }
+            }
+            self.instructions.push(Instruction::PushConst(code));
+            self.instructions.push(Instruction::BuildFunction);
+            self.store(function.name);
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, node: ContAstPtr<'c>) -> CodegenResult<'c, ()> {
+        match node {
+            &ContAst::Fix {
+                ref functions,
+                continuation,
+            } => {
+                self.emit_fix(functions)?;
+                self.emit(continuation)
+            }
+            &ContAst::Call {
+                target,
+                ref params,
+                continuation,
+            } => {
+                if continuation.is_none() && self.is_native(target) {
+                    self.push_terminal(params[0])?;
+                    self.instructions.push(Instruction::Resume);
+                    return Ok(());
+                }
+
+                match continuation.filter(|&c| !self.is_native(c)) {
+                    Some(continuation) => self.push_terminal(continuation)?,
+                    None => {
+                        self.push_const(pass_through());
+                        self.instructions.push(Instruction::BuildFunction);
+                    }
+                }
+                self.push_terminal(target)?;
+                for &param in params {
+                    self.push_terminal(param)?;
+                }
+                self.instructions.push(Instruction::Call(params.len() as u32));
+                Ok(())
+            }
+            &ContAst::Primop {
+                op,
+                ref terminals,
+                ref exports,
+                ref continuations,
+            } => {
+                for &terminal in terminals {
+                    self.push_terminal(terminal)?;
+                }
+                match op {
+                    PrimOpKind::Term => {
+                        self.instructions.push(Instruction::Terminate);
+                        return Ok(());
+                    }
+                    PrimOpKind::Add => self.instructions.push(Instruction::Add),
+                    PrimOpKind::Sub => self.instructions.push(Instruction::Sub),
+                    PrimOpKind::Mul => self.instructions.push(Instruction::Mul),
+                    PrimOpKind::Div => self.instructions.push(Instruction::Div),
+                    PrimOpKind::Bind => {}
+                    PrimOpKind::Debug => {
+                        // `Debug` consumes the value, which is also exported.
+                        self.instructions.push(Instruction::Debug);
+                        self.push_terminal(terminals[0])?;
+                    }
+                    PrimOpKind::MapEmpty => self.instructions.push(Instruction::MapEmpty),
+                    PrimOpKind::MapGet => self.instructions.push(Instruction::MapGet),
+                    PrimOpKind::Check(kind) => self.instructions.push(Instruction::CheckType(kind)),
+                }
+                for &export in exports {
+                    self.store(export);
+                }
+                self.emit(continuations[0])
+            }
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use super::*;
+use graphviz::{EdgeStyle, Graph, NodeId};
+
+// Renders a `ContAst` as a Graphviz DOT graph.
+//
+// The tree itself is drawn with solid edges.  A call whose target or
+// continuation is a function bound by an enclosing `Fix` also gets a
+// dashed edge to that function, so control flow can be followed through
+// the graph.  Names are resolved lexically: parameters and exports hide
+// functions of the same name, except that `Bind` passes a function on to
+// its export.
+
+pub fn to_dot(program: ContAstPtr) -> String {
+    let mut writer = DotWriter {
+        graph: Graph::new("cont"),
+        scope: vec![],
+    };
+    writer.add_node(program);
+    writer.graph.finish()
+}
+
+struct DotWriter<'a> {
+    graph: Graph,
+    /// The names in scope, innermost last, with the node of the function
+    /// each one is bound to, if any.
+    scope: Vec<(Ident<'a>, Option<NodeId>)>,
+}
+
+impl<'a> DotWriter<'a> {
+    fn lookup(&self, terminal: Terminal<'a>) -> Option<NodeId> {
+        match terminal {
+            Terminal::Ident(ident) => self.scope
+                .iter()
+                .rev()
+                .find(|&&(name, _)| name == ident)
+                .and_then(|&(_, node)| node),
+            _ => None,
+        }
+    }
+
+    fn add_node(&mut self, cont: ContAstPtr<'a>) -> NodeId {
+        match cont {
+            &ContAst::Fix {
+                ref functions,
+                continuation,
+            } => {
+                let node = self.graph.node("fix");
+                let depth = self.scope.len();
+                let mut function_nodes = vec![];
+                for function in functions {
+                    let label = format!("fn {:?}({:?})", function.name, function.params);
+                    let function_node = self.graph.node(&label);
+                    self.graph.edge(node, function_node, "");
+                    self.scope.push((function.name, Some(function_node)));
+                    function_nodes.push(function_node);
+                }
+
+                for (function, &function_node) in functions.iter().zip(&function_nodes) {
+                    let params_depth = self.scope.len();
+                    self.scope
+                        .extend(function.params.iter().map(|&param| (param, None)));
+                    let body = self.add_node(function.body);
+                    self.graph.edge(function_node, body, "body");
+                    self.scope.truncate(params_depth);
+                }
+
+                let rest = self.add_node(continuation);
+                self.graph.edge(node, rest, "continue");
+                self.scope.truncate(depth);
+                node
+            }
+            &ContAst::Call {
+                target,
+                ref params,
+                continuation,
+            } => {
+                let mut label = format!("call {:?}({:?})", target, params);
+                if let Some(continuation) = continuation {
+                    label.push_str(&format!(" -> {:?}", continuation));
+                }
+                let node = self.graph.node(&label);
+                if let Some(function) = self.lookup(target) {
+                    self.graph
+                        .styled_edge(node, function, "call", EdgeStyle::Dashed);
+                }
+                if let Some(function) = continuation.and_then(|c| self.lookup(c)) {
+                    self.graph
+                        .styled_edge(node, function, "continuation", EdgeStyle::Dashed);
+                }
+                node
+            }
+            &ContAst::Primop {
+                op,
+                ref terminals,
+                ref exports,
+                ref continuations,
+            } => {
+                let label = format!("{:?}({:?}) -> ({:?})", op, terminals, exports);
+                let node = self.graph.node(&label);
+                let depth = self.scope.len();
+                // A bound name is another name for the same function.
+                let bound = match op {
+                    PrimOpKind::Bind => self.lookup(terminals[0]),
+                    _ => None,
+                };
+                self.scope.extend(exports.iter().map(|&export| (export, bound)));
+                for (i, &continuation) in continuations.iter().enumerate() {
+                    let rest = self.add_node(continuation);
+                    let label = if continuations.len() > 1 {
+                        i.to_string()
+                    } else {
+                        String::new()
+                    };
+                    self.graph.edge(node, rest, &label);
+                }
+                self.scope.truncate(depth);
+                node
+            }
+        }
+    }
+}
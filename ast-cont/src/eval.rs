@@ -0,0 +1,283 @@
+use super::*;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::rc::Rc;
+use vm::value::Value as VmValue;
+use vm::vm::VmError;
+
+// A direct interpreter for `ContAst`, meant as an executable specification
+// of the language rather than a fast way to run it.
+//
+// Every call in continuation-passing style is a tail call, so `run` is a
+// loop that never grows the Rust stack.  Arithmetic is the VM's own
+// `Arithmetic::apply`, so the two cannot disagree about it.
+
+#[derive(Clone)]
+pub enum Value<'a> {
+    Integer(i64),
+    Float(f64),
+    Symbol(&'a str),
+    Function(Closure<'a>),
+    Map(Rc<HashMap<&'a str, Value<'a>>>),
+}
+
+/// One function of a `Fix`, with the environment the `Fix` ran in.  The
+/// other functions of the group are bound again on every call instead of
+/// being captured, so closures never form reference cycles.
+#[derive(Clone)]
+pub struct Closure<'a> {
+    functions: &'a [Function<'a>],
+    index: usize,
+    env: Env<'a>,
+}
+
+struct Binding<'a> {
+    ident: Ident<'a>,
+    value: Value<'a>,
+    parent: Env<'a>,
+}
+
+type Env<'a> = Option<Rc<Binding<'a>>>;
+
+#[derive(Debug)]
+pub enum EvalError<'a> {
+    UnboundIdentifier(Ident<'a>),
+    NotANumber(Value<'a>),
+    NotAFunction(Value<'a>),
+    NotAMap(Value<'a>),
+    NoSuchField(&'a str),
+    UnexpectedType { expected: ValueKind, found: Value<'a> },
+    WrongArgumentCount { expected: usize, found: usize },
+    IntegerOverflow,
+    DivisionByZero,
+}
+
+pub type EvalResult<'a, T> = Result<T, EvalError<'a>>;
+
+pub struct Interpreter<'a> {
+    pub debug_values: Vec<Value<'a>>,
+}
+
+fn bind<'a>(env: Env<'a>, ident: Ident<'a>, value: Value<'a>) -> Env<'a> {
+    Some(Rc::new(Binding {
+        ident,
+        value,
+        parent: env,
+    }))
+}
+
+fn lookup<'a>(env: &Env<'a>, ident: Ident<'a>) -> EvalResult<'a, Value<'a>> {
+    let mut current = env;
+    while let &Some(ref binding) = current {
+        if binding.ident == ident {
+            return Ok(binding.value.clone());
+        }
+        current = &binding.parent;
+    }
+    Err(EvalError::UnboundIdentifier(ident))
+}
+
+fn bind_fix<'a>(functions: &'a [Function<'a>], env: Env<'a>) -> Env<'a> {
+    let mut bound = env.clone();
+    for (index, function) in functions.iter().enumerate() {
+        let closure = Closure {
+            functions,
+            index,
+            env: env.clone(),
+        };
+        bound = bind(bound, function.name, Value::Function(closure));
+    }
+    bound
+}
+
+fn terminal<'a>(env: &Env<'a>, terminal: Terminal<'a>) -> EvalResult<'a, Value<'a>> {
+    match terminal {
+        Terminal::Integer(i) => Ok(Value::Integer(i)),
+        Terminal::Float(f) => Ok(Value::Float(f)),
+        Terminal::Symbol(s) => Ok(Value::Symbol(s)),
+        Terminal::Ident(ident) => lookup(env, ident),
+    }
+}
+
+fn number<'a>(value: Value<'a>) -> EvalResult<'a, VmValue> {
+    match value {
+        Value::Integer(i) => Ok(VmValue::Integer(i)),
+        Value::Float(f) => Ok(VmValue::Float(f)),
+        other => Err(EvalError::NotANumber(other)),
+    }
+}
+
+fn arithmetic<'a>(op: Arithmetic, l: Value<'a>, r: Value<'a>) -> EvalResult<'a, Value<'a>> {
+    match op.apply(&number(l)?, &number(r)?) {
+        Ok(VmValue::Integer(i)) => Ok(Value::Integer(i)),
+        Ok(VmValue::Float(f)) => Ok(Value::Float(f)),
+        Err(VmError::DivisionByZero) => Err(EvalError::DivisionByZero),
+        Err(VmError::IntegerOverflow) => Err(EvalError::IntegerOverflow),
+        other => unreachable!("{:?}", other),
+    }
+}
+
+impl<'a> Value<'a> {
+    /// The kind of VM value this value stands for.
+    fn kind(&self) -> ValueKind {
+        match self {
+            &Value::Integer(_) => ValueKind::Integer,
+            &Value::Float(_) => ValueKind::Float,
+            &Value::Symbol(_) => ValueKind::Symbol,
+            &Value::Function(_) => ValueKind::Function,
+            &Value::Map(_) => ValueKind::Map,
+        }
+    }
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new() -> Interpreter<'a> {
+        Interpreter {
+            debug_values: vec![],
+        }
+    }
+
+    /// Runs `program` until it reaches a `Term` primop, and returns the
+    /// value given to it.
+    pub fn run(&mut self, program: ContAstPtr<'a>) -> EvalResult<'a, Value<'a>> {
+        let mut node = program;
+        let mut env: Env<'a> = None;
+        loop {
+            match node {
+                &ContAst::Fix {
+                    ref functions,
+                    continuation,
+                } => {
+                    env = bind_fix(functions, env);
+                    node = continuation;
+                }
+                &ContAst::Call {
+                    target,
+                    ref params,
+                    continuation,
+                } => {
+                    let target = terminal(&env, target)?;
+                    let mut args = params
+                        .iter()
+                        .map(|&param| terminal(&env, param))
+                        .collect::<EvalResult<Vec<_>>>()?;
+                    if let Some(continuation) = continuation {
+                        args.push(terminal(&env, continuation)?);
+                    }
+
+                    let closure = match target {
+                        Value::Function(closure) => closure,
+                        other => return Err(EvalError::NotAFunction(other)),
+                    };
+                    let function = &closure.functions[closure.index];
+                    if function.params.len() != args.len() {
+                        return Err(EvalError::WrongArgumentCount {
+                            expected: function.params.len(),
+                            found: args.len(),
+                        });
+                    }
+                    env = bind_fix(closure.functions, closure.env);
+                    for (&param, arg) in function.params.iter().zip(args) {
+                        env = bind(env, param, arg);
+                    }
+                    node = function.body;
+                }
+                &ContAst::Primop {
+                    op,
+                    ref terminals,
+                    ref exports,
+                    ref continuations,
+                } => {
+                    let mut values = terminals
+                        .iter()
+                        .map(|&t| terminal(&env, t))
+                        .collect::<EvalResult<Vec<_>>>()?
+                        .into_iter();
+                    let result = match op {
+                        PrimOpKind::Term => return Ok(values.next().unwrap()),
+                        PrimOpKind::Add | PrimOpKind::Sub | PrimOpKind::Mul | PrimOpKind::Div => {
+                            let l = values.next().unwrap();
+                            let r = values.next().unwrap();
+                            arithmetic(op.arithmetic().unwrap(), l, r)?
+                        }
+                        PrimOpKind::Bind => values.next().unwrap(),
+                        PrimOpKind::Debug => {
+                            let value = values.next().unwrap();
+                            self.debug_values.push(value.clone());
+                            value
+                        }
+                        PrimOpKind::MapEmpty => Value::Map(Rc::new(HashMap::new())),
+                        PrimOpKind::MapGet => match (values.next().unwrap(), values.next()) {
+                            (Value::Map(map), Some(Value::Symbol(field))) => map.get(field)
+                                .cloned()
+                                .ok_or(EvalError::NoSuchField(field))?,
+                            (other, _) => return Err(EvalError::NotAMap(other)),
+                        },
+                        PrimOpKind::Check(expected) => {
+                            let value = values.next().unwrap();
+                            if value.kind() != expected {
+                                return Err(EvalError::UnexpectedType {
+                                    expected,
+                                    found: value,
+                                });
+                            }
+                            value
+                        }
+                    };
+
+                    for &export in exports {
+                        env = bind(env, export, result.clone());
+                    }
+                    node = continuations[0];
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Debug for Value<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &Value::Integer(i) => write!(f, "{}", i),
+            &Value::Float(n) => write!(f, "{:?}", n),
+            &Value::Symbol(s) => write!(f, "'{}", s),
+            &Value::Function(ref closure) => {
+                write!(f, "function {:?}", closure.functions[closure.index].name)
+            }
+            &Value::Map(ref map) => write!(f, "map with {} fields", map.len()),
+        }
+    }
+}
+
+impl<'a> Display for EvalError<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &EvalError::UnboundIdentifier(ident) => write!(f, "{:?} is not bound", ident),
+            &EvalError::NotANumber(ref value) => write!(f, "{:?} is not a number", value),
+            &EvalError::NotAFunction(ref value) => write!(f, "{:?} is not a function", value),
+            &EvalError::NotAMap(ref value) => write!(f, "{:?} is not a map", value),
+            &EvalError::NoSuchField(field) => write!(f, "no field named {}", field),
+            &EvalError::UnexpectedType {
+                expected,
+                found: ref found @ Value::Function(_),
+            } => write!(f, "expected {}, found {:?}", expected.name(), found),
+            &EvalError::UnexpectedType {
+                expected,
+                ref found,
+            } => write!(
+                f,
+                "expected {}, found {} {:?}",
+                expected.name(),
+                found.kind().name(),
+                found
+            ),
+            &EvalError::WrongArgumentCount { expected, found } => write!(
+                f,
+                "expected {} arguments (with the continuation), found {}",
+                expected, found
+            ),
+            &EvalError::IntegerOverflow => write!(f, "integer overflow"),
+            &EvalError::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use super::*;
+
+/// Binds `name` to a function with `params` and `body` for the rest of the
+/// program.  The function takes the continuation it returns to as an extra
+/// last parameter, and `name` is in scope inside `body`.
+pub fn do_function<'c>(
+    name: Ident<'c>,
+    params: &'c [Identifier<'c>],
+    body: &'c LoweredAst<'c>,
+    c: WithContinue<'c>,
+    idg: &'c IdGet,
+    arena: &'c Arena<ContAst<'c>>,
+) -> ContAstPtr<'c> {
+    arena.alloc(ContAst::Fix {
+        functions: vec![function(name, params, body, idg, arena)],
+        continuation: c(Terminal::Ident(name)),
+    })
+}
+
+/// Binds the `FunctionDecl`s of a group in one `Fix`, so that each of them
+/// is in scope inside all of their bodies.
+pub fn do_function_group<'c>(
+    declarations: &'c [&'c LoweredAst<'c>],
+    c: WithContinue<'c>,
+    idg: &'c IdGet,
+    arena: &'c Arena<ContAst<'c>>,
+) -> ContAstPtr<'c> {
+    let functions = declarations
+        .iter()
+        .map(|declaration| match declaration {
+            &&LoweredAst::FunctionDecl {
+                name,
+                ref params,
+                body,
+            } => function(Ident::from(name), params, body, idg, arena),
+            other => panic!("expected a function declaration, found {:?}", other),
+        })
+        .collect::<Vec<_>>();
+    let last = functions.last().expect("empty function group").name;
+    arena.alloc(ContAst::Fix {
+        functions,
+        continuation: c(Terminal::Ident(last)),
+    })
+}
+
+fn function<'c>(
+    name: Ident<'c>,
+    params: &'c [Identifier<'c>],
+    body: &'c LoweredAst<'c>,
+    idg: &'c IdGet,
+    arena: &'c Arena<ContAst<'c>>,
+) -> Function<'c> {
+    let return_id = idg.get();
+    let mut param_ids = params.iter().map(|&p| Ident::from(p)).collect::<Vec<_>>();
+    param_ids.push(return_id);
+
+    let body = translate(
+        body,
+        Box::new(move |v| {
+            arena.alloc(ContAst::Call {
+                target: Terminal::Ident(return_id),
+                params: vec![v],
+                continuation: None,
+            }) as &_
+        }),
+        idg,
+        arena,
+    );
+    Function {
+        name: name,
+        params: param_ids,
+        body: body,
+    }
+}
@@ -1,33 +1,47 @@
-#![feature(fnbox)]
-
-extern crate lexer;
-extern crate parser;
+extern crate ast_lower;
+extern crate graphviz;
 extern crate typed_arena;
+extern crate vm;
+#[cfg(test)]
+extern crate copy_arena;
 #[cfg(test)]
 extern crate difference;
+#[cfg(test)]
+extern crate lexer;
+#[cfg(test)]
+extern crate parser;
 
 #[cfg(test)]
 mod test;
 mod binary;
+mod block;
 mod call;
+mod function;
+pub mod eval;
+pub mod shrink;
+pub mod codegen;
+pub mod dot;
 
-use parser::Ast;
-use std::boxed::FnBox;
+use ast_lower::{Identifier, LoweredAst};
 use std::cell::RefCell;
 use std::fmt::{Debug, Formatter, Result as FmtResult, Write};
 use typed_arena::Arena;
+use vm::value::ValueKind;
+use vm::vm::Arithmetic;
 
 pub type ContAstPtr<'parse> = &'parse ContAst<'parse>;
-type WithContinue<'c> = Box<FnBox(Terminal<'c>) -> ContAstPtr<'c> + 'c>;
+type WithContinue<'c> = Box<FnOnce(Terminal<'c>) -> ContAstPtr<'c> + 'c>;
 
 pub struct IdGet {
     id: RefCell<u32>,
 }
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Ident<'parse> {
     Identifier(&'parse str),
     Phantom(u32),
+    /// A temporary that lowering introduced.
+    Temporary(u32),
 }
 
 #[derive(PartialEq, Copy, Clone)]
@@ -35,6 +49,8 @@ pub enum Terminal<'parse> {
     Integer(i64),
     Float(f64),
     Ident(Ident<'parse>),
+    /// The name of a field.
+    Symbol(&'parse str),
 }
 
 #[derive(PartialEq, Debug, Copy, Clone)]
@@ -44,6 +60,29 @@ pub enum PrimOpKind {
     Sub,
     Mul,
     Div,
+    /// Exports its only terminal under a new name.
+    Bind,
+    /// Records its only terminal as a debug value and exports it again.
+    Debug,
+    MapEmpty,
+    /// Exports the field of the map in the first terminal that is named by
+    /// the symbol in the second.
+    MapGet,
+    /// Exports its only terminal again, unless it is not of the kind.
+    Check(ValueKind),
+}
+
+impl PrimOpKind {
+    /// The VM operation behind `Add`, `Sub`, `Mul` and `Div`.
+    pub fn arithmetic(self) -> Option<Arithmetic> {
+        match self {
+            PrimOpKind::Add => Some(Arithmetic::Add),
+            PrimOpKind::Sub => Some(Arithmetic::Sub),
+            PrimOpKind::Mul => Some(Arithmetic::Mul),
+            PrimOpKind::Div => Some(Arithmetic::Div),
+            _ => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -59,10 +98,13 @@ pub enum ContAst<'parse> {
         functions: Vec<Function<'parse>>,
         continuation: ContAstPtr<'parse>,
     },
+    /// Calls a function.  A call to a continuation passes none; a call to
+    /// any other function passes the continuation it returns to as an
+    /// extra last argument.
     Call {
         target: Terminal<'parse>,
         params: Vec<Terminal<'parse>>,
-        continuation: Terminal<'parse>
+        continuation: Option<Terminal<'parse>>,
     },
     Primop {
         op: PrimOpKind,
@@ -73,24 +115,106 @@ pub enum ContAst<'parse> {
 }
 
 pub fn translate<'c>(
-    ast: &'c Ast<'c>,
+    ast: &'c LoweredAst<'c>,
     c: WithContinue<'c>,
     idg: &'c IdGet,
     arena: &'c Arena<ContAst<'c>>,
 ) -> ContAstPtr<'c> {
     match ast {
-        &Ast::Add(l, r) => binary::do_binary(l, r, c, PrimOpKind::Add, idg, arena),
-        &Ast::Mul(l, r) => binary::do_binary(l, r, c, PrimOpKind::Mul, idg, arena),
-        &Ast::Sub(l, r) => binary::do_binary(l, r, c, PrimOpKind::Sub, idg, arena),
-        &Ast::Div(l, r) => binary::do_binary(l, r, c, PrimOpKind::Div, idg, arena),
-        &Ast::Integer(_, i) => c(Terminal::Integer(i)),
-        &Ast::Float(_, f) => c(Terminal::Float(f)),
-        &Ast::Identifier(_, s) => c(Terminal::Ident(Ident::Identifier(s))),
-        &Ast::FunctionCall{ref target, ref args} => call::do_call(target, &*args, c, idg, arena),
-        _ => unimplemented!(),
+        &LoweredAst::Add(l, r) => binary::do_binary(l, r, c, PrimOpKind::Add, idg, arena),
+        &LoweredAst::Mul(l, r) => binary::do_binary(l, r, c, PrimOpKind::Mul, idg, arena),
+        &LoweredAst::Sub(l, r) => binary::do_binary(l, r, c, PrimOpKind::Sub, idg, arena),
+        &LoweredAst::Div(l, r) => binary::do_binary(l, r, c, PrimOpKind::Div, idg, arena),
+        &LoweredAst::Integer(_, i) => c(Terminal::Integer(i)),
+        &LoweredAst::Float(_, f) => c(Terminal::Float(f)),
+        &LoweredAst::Identifier(id) => c(Terminal::Ident(Ident::from(id))),
+        &LoweredAst::FunctionCall { target, ref args } => {
+            call::do_call(target, args, c, idg, arena)
+        }
+        &LoweredAst::DebugCall(arg) => translate(
+            arg,
+            Box::new(move |v| {
+                let id = idg.get();
+                arena.alloc(ContAst::Primop {
+                    op: PrimOpKind::Debug,
+                    terminals: vec![v],
+                    exports: vec![id],
+                    continuations: vec![c(Terminal::Ident(id))],
+                }) as &_
+            }),
+            idg,
+            arena,
+        ),
+        &LoweredAst::FieldAccess { target, field_name } => translate(
+            target,
+            Box::new(move |v| {
+                let id = idg.get();
+                arena.alloc(ContAst::Primop {
+                    op: PrimOpKind::MapGet,
+                    terminals: vec![v, Terminal::Symbol(field_name)],
+                    exports: vec![id],
+                    continuations: vec![c(Terminal::Ident(id))],
+                }) as &_
+            }),
+            idg,
+            arena,
+        ),
+        &LoweredAst::Check(kind, value) => translate(
+            value,
+            Box::new(move |v| {
+                let id = idg.get();
+                arena.alloc(ContAst::Primop {
+                    op: PrimOpKind::Check(kind),
+                    terminals: vec![v],
+                    exports: vec![id],
+                    continuations: vec![c(Terminal::Ident(id))],
+                }) as &_
+            }),
+            idg,
+            arena,
+        ),
+        &LoweredAst::AnonFunc { ref params, body } => {
+            function::do_function(idg.get(), params, body, c, idg, arena)
+        }
+        &LoweredAst::FunctionDecl {
+            name,
+            ref params,
+            body,
+        } => function::do_function(Ident::from(name), params, body, c, idg, arena),
+        &LoweredAst::VariableDecl { name, expression } => {
+            block::do_variable(Ident::from(name), expression, c, idg, arena)
+        }
+        &LoweredAst::FunctionGroup(ref functions) => {
+            function::do_function_group(functions, c, idg, arena)
+        }
+        &LoweredAst::BlockExpr {
+            ref statements,
+            final_expression,
+        } => block::do_block(statements, final_expression, c, idg, arena),
+        &LoweredAst::Module { ref statements, .. } => {
+            block::do_module(statements, c, idg, arena)
+        }
     }
 }
 
+/// Translates a whole program, which ends by handing its value to a `Term`
+/// primop.
+pub fn translate_top<'c>(
+    ast: &'c LoweredAst<'c>,
+    idg: &'c IdGet,
+    arena: &'c Arena<ContAst<'c>>,
+) -> ContAstPtr<'c> {
+    let halt = move |t| {
+        arena.alloc(ContAst::Primop {
+            op: PrimOpKind::Term,
+            terminals: vec![t],
+            exports: vec![],
+            continuations: vec![],
+        }) as &_
+    };
+    translate(ast, Box::new(halt), idg, arena)
+}
+
 impl IdGet {
     pub fn new() -> IdGet {
         IdGet {
@@ -106,6 +230,15 @@ impl IdGet {
     }
 }
 
+impl<'a> From<Identifier<'a>> for Ident<'a> {
+    fn from(identifier: Identifier<'a>) -> Ident<'a> {
+        match identifier {
+            Identifier::Ident(_, name) => Ident::Identifier(name),
+            Identifier::Phantom(n) => Ident::Temporary(n),
+        }
+    }
+}
+
 impl<'a> Debug for ContAst<'a> {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         smart_print(self, f, 0)
@@ -118,6 +251,7 @@ impl<'a> Debug for Terminal<'a> {
             &Terminal::Float(f) => write!(out, "{}", f),
             &Terminal::Integer(i) => write!(out, "{}", i),
             &Terminal::Ident(i) => write!(out, "{:?}", i),
+            &Terminal::Symbol(s) => write!(out, "'{}", s),
         }
     }
 }
@@ -127,6 +261,7 @@ impl<'a> Debug for Ident<'a> {
         match self {
             Ident::Identifier(id) => write!(f, "{}", id),
             Ident::Phantom(n) => write!(f, "id_{}", n),
+            Ident::Temporary(n) => write!(f, "tmp_{}", n),
         }
     }
 }
@@ -174,7 +309,10 @@ pub fn smart_print<'a>(
             ref continuation,
         } => {
             indent(out, indent_count)?;
-            write!(out, "call {:?}({:?}) -> {:?}", target, params, continuation)?;
+            write!(out, "call {:?}({:?})", target, params)?;
+            if let &Some(ref continuation) = continuation {
+                write!(out, " -> {:?}", continuation)?;
+            }
             out.write_char('\n')?;
         }
     }
@@ -0,0 +1,490 @@
+use super::*;
+use std::collections::{HashMap, HashSet};
+use vm::value::Value;
+
+// Shrinking reductions: rewrites that never make a program bigger, repeated
+// until none applies.
+//
+//  * A function called from exactly one place is contracted into that call.
+//  * A function that only forwards its parameters to another one is
+//    replaced by it everywhere.
+//  * A primop whose result is known is replaced by that result: `Bind`
+//    always, arithmetic when both terminals are literals.
+//  * A function nobody refers to, or a pure primop whose exports nobody
+//    reads, is dropped.  Arithmetic on unknown values can still fail, so it
+//    stays even when its result is unused.
+//
+// The translation reuses source names, so the same identifier can be bound
+// in several places.  `shrink` first renames every binder that is not the
+// first of its name to a fresh phantom.  With each name bound once, a
+// substitution can be a single map for the whole tree, and a body moved
+// to a call site cannot see a different binding of any of its names.
+
+/// Applies shrinking reductions to `program` until it stops changing.
+pub fn shrink<'c>(program: ContAstPtr<'c>, arena: &'c Arena<ContAst<'c>>) -> ContAstPtr<'c> {
+    let mut current = unique_binders(program, arena);
+    loop {
+        let mut shrinker = Shrinker {
+            arena,
+            uses: HashMap::new(),
+            substitutions: HashMap::new(),
+            contractions: HashMap::new(),
+            changed: false,
+        };
+        count_uses(current, &mut shrinker.uses);
+        let next = shrinker.rewrite(current);
+        if !shrinker.changed {
+            return next;
+        }
+        current = next;
+    }
+}
+
+fn count_use<'c>(terminal: Terminal<'c>, uses: &mut HashMap<Ident<'c>, usize>) {
+    if let Terminal::Ident(ident) = terminal {
+        *uses.entry(ident).or_insert(0) += 1;
+    }
+}
+
+fn count_uses<'c>(node: ContAstPtr<'c>, uses: &mut HashMap<Ident<'c>, usize>) {
+    match node {
+        &ContAst::Fix {
+            ref functions,
+            continuation,
+        } => {
+            for function in functions {
+                count_uses(function.body, uses);
+            }
+            count_uses(continuation, uses);
+        }
+        &ContAst::Call {
+            target,
+            ref params,
+            continuation,
+        } => {
+            count_use(target, uses);
+            for &param in params {
+                count_use(param, uses);
+            }
+            if let Some(continuation) = continuation {
+                count_use(continuation, uses);
+            }
+        }
+        &ContAst::Primop {
+            ref terminals,
+            ref continuations,
+            ..
+        } => {
+            for &terminal in terminals {
+                count_use(terminal, uses);
+            }
+            for &continuation in continuations {
+                count_uses(continuation, uses);
+            }
+        }
+    }
+}
+
+/// Whether `node` contains a call to `name` that passes `arity` arguments,
+/// counting the continuation.
+fn calls<'c>(node: ContAstPtr<'c>, name: Ident<'c>, arity: usize) -> bool {
+    match node {
+        &ContAst::Fix {
+            ref functions,
+            continuation,
+        } => {
+            functions.iter().any(|function| calls(function.body, name, arity))
+                || calls(continuation, name, arity)
+        }
+        &ContAst::Call {
+            target,
+            ref params,
+            continuation,
+        } => target == Terminal::Ident(name) && params.len() + continuation.iter().count() == arity,
+        &ContAst::Primop {
+            ref continuations,
+            ..
+        } => continuations.iter().any(|&c| calls(c, name, arity)),
+    }
+}
+
+/// Evaluates arithmetic on two literals exactly as the interpreter would,
+/// or gives up when it would fail.
+fn fold<'c>(op: Arithmetic, l: Terminal<'c>, r: Terminal<'c>) -> Option<Terminal<'c>> {
+    let number = |terminal| match terminal {
+        Terminal::Integer(i) => Some(Value::Integer(i)),
+        Terminal::Float(f) => Some(Value::Float(f)),
+        _ => None,
+    };
+
+    match op.apply(&number(l)?, &number(r)?) {
+        Ok(Value::Integer(i)) => Some(Terminal::Integer(i)),
+        Ok(Value::Float(f)) => Some(Terminal::Float(f)),
+        _ => None,
+    }
+}
+
+struct Shrinker<'c> {
+    arena: &'c Arena<ContAst<'c>>,
+    uses: HashMap<Ident<'c>, usize>,
+    /// Identifiers that stand for another terminal from here on.
+    substitutions: HashMap<Ident<'c>, Terminal<'c>>,
+    /// Functions whose only call has not been reached yet.
+    contractions: HashMap<Ident<'c>, &'c Function<'c>>,
+    changed: bool,
+}
+
+impl<'c> Shrinker<'c> {
+    fn resolve(&self, terminal: Terminal<'c>) -> Terminal<'c> {
+        let mut terminal = terminal;
+        while let Terminal::Ident(ident) = terminal {
+            match self.substitutions.get(&ident) {
+                Some(&next) => terminal = next,
+                None => break,
+            }
+        }
+        terminal
+    }
+
+    fn uses(&self, ident: Ident<'c>) -> usize {
+        self.uses.get(&ident).cloned().unwrap_or(0)
+    }
+
+    /// The function that `function` can be replaced by, if its body only
+    /// passes its parameters on, in order, to a function bound outside it.
+    fn eta_target(&self, function: &Function<'c>) -> Option<Terminal<'c>> {
+        let (target, forwarded) = match function.body {
+            &ContAst::Call {
+                target: Terminal::Ident(target),
+                ref params,
+                continuation,
+            } => (
+                target,
+                params.iter().cloned().chain(continuation).collect::<Vec<_>>(),
+            ),
+            _ => return None,
+        };
+        let forwards_params = forwarded.len() == function.params.len()
+            && forwarded
+                .iter()
+                .zip(&function.params)
+                .all(|(&arg, &param)| arg == Terminal::Ident(param));
+        if !forwards_params || function.params.contains(&target) {
+            return None;
+        }
+
+        let resolved = self.resolve(Terminal::Ident(target));
+        match resolved {
+            Terminal::Ident(ident)
+                if ident == function.name || self.contractions.contains_key(&ident) =>
+            {
+                None
+            }
+            _ => Some(resolved),
+        }
+    }
+
+    fn rewrite(&mut self, node: ContAstPtr<'c>) -> ContAstPtr<'c> {
+        match node {
+            &ContAst::Fix {
+                ref functions,
+                continuation,
+            } => {
+                let mut kept = vec![];
+                for function in functions {
+                    let arity = function.params.len();
+                    match self.uses(function.name) {
+                        0 => self.changed = true,
+                        1 if calls(continuation, function.name, arity) => {
+                            self.contractions.insert(function.name, function);
+                            self.changed = true;
+                        }
+                        _ => kept.push(function),
+                    }
+                }
+
+                // Contractions are chosen first, so that no function is
+                // forwarded to one that is about to disappear.
+                let mut remaining = vec![];
+                for function in kept {
+                    match self.eta_target(function) {
+                        Some(target) => {
+                            self.substitutions.insert(function.name, target);
+                            self.changed = true;
+                        }
+                        None => remaining.push(function),
+                    }
+                }
+
+                let functions = remaining
+                    .into_iter()
+                    .map(|function| Function {
+                        name: function.name,
+                        params: function.params.clone(),
+                        body: self.rewrite(function.body),
+                    })
+                    .collect::<Vec<_>>();
+                let continuation = self.rewrite(continuation);
+                if functions.is_empty() {
+                    return continuation;
+                }
+                self.arena.alloc(ContAst::Fix {
+                    functions,
+                    continuation,
+                })
+            }
+            &ContAst::Call {
+                target,
+                ref params,
+                continuation,
+            } => {
+                let target = self.resolve(target);
+                let params = params.iter().map(|&p| self.resolve(p)).collect::<Vec<_>>();
+                let continuation = continuation.map(|c| self.resolve(c));
+
+                if let Terminal::Ident(name) = target {
+                    let arity = params.len() + continuation.iter().count();
+                    let contracted = match self.contractions.get(&name) {
+                        Some(function) => function.params.len() == arity,
+                        None => false,
+                    };
+                    if contracted {
+                        let function = self.contractions.remove(&name).unwrap();
+                        let args = params.iter().cloned().chain(continuation);
+                        for (&param, arg) in function.params.iter().zip(args) {
+                            self.substitutions.insert(param, arg);
+                        }
+                        return self.rewrite(function.body);
+                    }
+                }
+
+                self.arena.alloc(ContAst::Call {
+                    target,
+                    params,
+                    continuation,
+                })
+            }
+            &ContAst::Primop {
+                op,
+                ref terminals,
+                ref exports,
+                ref continuations,
+            } => {
+                let terminals = terminals.iter().map(|&t| self.resolve(t)).collect::<Vec<_>>();
+                let known = match op {
+                    PrimOpKind::Bind => Some(terminals[0]),
+                    _ => op
+                        .arithmetic()
+                        .and_then(|op| fold(op, terminals[0], terminals[1])),
+                };
+                if let Some(value) = known {
+                    self.substitutions.insert(exports[0], value);
+                    self.changed = true;
+                    return self.rewrite(continuations[0]);
+                }
+                if op == PrimOpKind::MapEmpty && exports.iter().all(|&e| self.uses(e) == 0) {
+                    self.changed = true;
+                    return self.rewrite(continuations[0]);
+                }
+
+                let continuations = continuations
+                    .iter()
+                    .map(|&c| self.rewrite(c))
+                    .collect::<Vec<_>>();
+                self.arena.alloc(ContAst::Primop {
+                    op,
+                    terminals,
+                    exports: exports.clone(),
+                    continuations,
+                })
+            }
+        }
+    }
+}
+
+/// Records the identifiers that `node` uses without binding them, and the
+/// highest phantom it mentions.
+fn scan<'c>(
+    node: ContAstPtr<'c>,
+    bound: &mut Vec<Ident<'c>>,
+    free: &mut HashSet<Ident<'c>>,
+    max_phantom: &mut Option<u32>,
+) {
+    fn saw<'c>(ident: Ident<'c>, max_phantom: &mut Option<u32>) {
+        if let Ident::Phantom(n) = ident {
+            *max_phantom = Some(max_phantom.map_or(n, |m| m.max(n)));
+        }
+    }
+    fn read<'c>(
+        terminal: Terminal<'c>,
+        bound: &[Ident<'c>],
+        free: &mut HashSet<Ident<'c>>,
+        max_phantom: &mut Option<u32>,
+    ) {
+        if let Terminal::Ident(ident) = terminal {
+            saw(ident, max_phantom);
+            if !bound.contains(&ident) {
+                free.insert(ident);
+            }
+        }
+    }
+
+    let mark = bound.len();
+    match node {
+        &ContAst::Fix {
+            ref functions,
+            continuation,
+        } => {
+            for function in functions {
+                saw(function.name, max_phantom);
+                bound.push(function.name);
+            }
+            for function in functions {
+                let inner = bound.len();
+                for &param in &function.params {
+                    saw(param, max_phantom);
+                    bound.push(param);
+                }
+                scan(function.body, bound, free, max_phantom);
+                bound.truncate(inner);
+            }
+            scan(continuation, bound, free, max_phantom);
+        }
+        &ContAst::Call {
+            target,
+            ref params,
+            continuation,
+        } => {
+            read(target, bound, free, max_phantom);
+            for &param in params {
+                read(param, bound, free, max_phantom);
+            }
+            if let Some(continuation) = continuation {
+                read(continuation, bound, free, max_phantom);
+            }
+        }
+        &ContAst::Primop {
+            ref terminals,
+            ref exports,
+            ref continuations,
+            ..
+        } => {
+            for &terminal in terminals {
+                read(terminal, bound, free, max_phantom);
+            }
+            for &export in exports {
+                saw(export, max_phantom);
+                bound.push(export);
+            }
+            for &continuation in continuations {
+                scan(continuation, bound, free, max_phantom);
+            }
+        }
+    }
+    bound.truncate(mark);
+}
+
+/// Renames binders so that no name is bound twice, or bound while it also
+/// refers to something outside the program.
+pub(crate) fn unique_binders<'c>(program: ContAstPtr<'c>, arena: &'c Arena<ContAst<'c>>) -> ContAstPtr<'c> {
+    let mut free = HashSet::new();
+    let mut max_phantom = None;
+    scan(program, &mut vec![], &mut free, &mut max_phantom);
+
+    let mut renamer = Renamer {
+        arena,
+        taken: free,
+        next_phantom: max_phantom.map_or(0, |m| m + 1),
+        scope: vec![],
+    };
+    renamer.rename(program)
+}
+
+struct Renamer<'c> {
+    arena: &'c Arena<ContAst<'c>>,
+    taken: HashSet<Ident<'c>>,
+    next_phantom: u32,
+    /// Each binder in scope with the name it was given, innermost last.
+    scope: Vec<(Ident<'c>, Ident<'c>)>,
+}
+
+impl<'c> Renamer<'c> {
+    fn bind(&mut self, ident: Ident<'c>) -> Ident<'c> {
+        let renamed = if self.taken.insert(ident) {
+            ident
+        } else {
+            let fresh = Ident::Phantom(self.next_phantom);
+            self.next_phantom += 1;
+            self.taken.insert(fresh);
+            fresh
+        };
+        self.scope.push((ident, renamed));
+        renamed
+    }
+
+    fn read(&self, terminal: Terminal<'c>) -> Terminal<'c> {
+        match terminal {
+            Terminal::Ident(ident) => match self.scope.iter().rev().find(|b| b.0 == ident) {
+                Some(&(_, renamed)) => Terminal::Ident(renamed),
+                None => terminal,
+            },
+            other => other,
+        }
+    }
+
+    fn rename(&mut self, node: ContAstPtr<'c>) -> ContAstPtr<'c> {
+        let mark = self.scope.len();
+        let renamed = match node {
+            &ContAst::Fix {
+                ref functions,
+                continuation,
+            } => {
+                let names = functions
+                    .iter()
+                    .map(|function| self.bind(function.name))
+                    .collect::<Vec<_>>();
+                let functions = functions
+                    .iter()
+                    .zip(names)
+                    .map(|(function, name)| {
+                        let inner = self.scope.len();
+                        let params = function.params.iter().map(|&p| self.bind(p)).collect();
+                        let body = self.rename(function.body);
+                        self.scope.truncate(inner);
+                        Function { name, params, body }
+                    })
+                    .collect();
+                ContAst::Fix {
+                    functions,
+                    continuation: self.rename(continuation),
+                }
+            }
+            &ContAst::Call {
+                target,
+                ref params,
+                continuation,
+            } => ContAst::Call {
+                target: self.read(target),
+                params: params.iter().map(|&p| self.read(p)).collect(),
+                continuation: continuation.map(|c| self.read(c)),
+            },
+            &ContAst::Primop {
+                op,
+                ref terminals,
+                ref exports,
+                ref continuations,
+            } => {
+                let terminals = terminals.iter().map(|&t| self.read(t)).collect();
+                let exports = exports.iter().map(|&e| self.bind(e)).collect();
+                ContAst::Primop {
+                    op,
+                    terminals,
+                    exports,
+                    continuations: continuations.iter().map(|&c| self.rename(c)).collect(),
+                }
+            }
+        };
+        self.scope.truncate(mark);
+        self.arena.alloc(renamed)
+    }
+}
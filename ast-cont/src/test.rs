@@ -1,44 +1,60 @@
-use super::{translate, ContAst, ContAstPtr, IdGet, PrimOpKind, Terminal};
+use super::{shrink, translate_top, ContAstPtr, IdGet};
+use ast_lower::{desugar, lower};
 use difference::Changeset;
 use lexer::{lex, remove_whitespace};
-use parser::parse_expression;
-use std::collections::HashMap;
+use parser::{parse_expression, parse_module};
 use typed_arena::Arena;
 
 fn with_parsed_expression_cont<F>(string: &'static str, f: F)
 where
     F: FnOnce(ContAstPtr),
 {
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
     let id_builder = IdGet::new();
-    let mut lexed = lex(string);
-    remove_whitespace(&mut lexed);
-    let arena = Arena::new();
     let new_arena = Arena::new();
-    let mut cache = HashMap::new();
-    let parsed = parse_expression(&lexed, &arena, &mut cache).unwrap().0;
-    println!("{:#?}", parsed);
-    let parsed_cont =
-        translate(parsed, Box::new(|t| generate_terminal(&new_arena, t)), &id_builder, &new_arena);
+
+    let lexed = lex(string, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_expression(lexed, &mut alloc).unwrap().0;
+    let lower_arena = Arena::new();
+    let lowered = lower(desugar(parsed, &mut alloc), &lower_arena);
+    let parsed_cont = translate_top(lowered, &id_builder, &new_arena);
     f(parsed_cont)
 }
 
-fn generate_terminal<'a>(arena: &'a Arena<ContAst<'a>>, terminal: Terminal<'a>) -> ContAstPtr<'a> {
-    arena.alloc(ContAst::Primop {
-        op: PrimOpKind::Term,
-        terminals: vec![terminal],
-        exports: vec![],
-        continuations: vec![],
-    })
+fn with_parsed_module_cont<F>(string: &'static str, f: F)
+where
+    F: FnOnce(ContAstPtr),
+{
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let id_builder = IdGet::new();
+    let new_arena = Arena::new();
+
+    let lexed = lex(string, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap().0;
+    let lower_arena = Arena::new();
+    let lowered = lower(desugar(parsed, &mut alloc), &lower_arena);
+    let parsed_cont = translate_top(lowered, &id_builder, &new_arena);
+    f(parsed_cont)
 }
 
 fn compile_eq(program: &'static str, expected: &str) {
-    fn str_rep_eq(actual: String, expected: &str) {
-        if actual.trim() != expected.trim() {
-            panic!("\n{}", Changeset::new(actual.trim(), expected.trim(), "\n"));
-        }
+    with_parsed_expression_cont(program, |r| {
+        str_rep_eq(format!("{:?}", r), expected);
+    });
+}
+
+fn str_rep_eq(actual: String, expected: &str) {
+    if actual.trim() != expected.trim() {
+        panic!("\n{}", Changeset::new(actual.trim(), expected.trim(), "\n"));
     }
+}
 
-    with_parsed_expression_cont(program, |r| {
+fn compile_module_eq(program: &'static str, expected: &str) {
+    with_parsed_module_cont(program, |r| {
         str_rep_eq(format!("{:?}", r), expected);
     });
 }
@@ -134,3 +150,643 @@ Mul([b, c]) -> ([id_1]) =>
         Term([id_0]) -> ([]) =>"#,
     );
 }
+
+
+#[test]
+fn block_without_declarations() {
+    compile_eq(
+        "{ a(); b }",
+        r#"
+fix fn id_0([id_1]) =>
+    Term([b]) -> ([]) =>
+    continue with:
+        call a([]) -> id_0
+    "#,
+    );
+}
+
+#[test]
+fn block_with_variable_decl() {
+    compile_eq(
+        "{ let x = 1; x + 2 }",
+        r#"
+fix fn id_0([id_1]) =>
+    Term([id_1]) -> ([]) =>
+    continue with:
+        Bind([1]) -> ([x]) =>
+            Add([x, 2]) -> ([id_2]) =>
+                call id_0([id_2])
+    "#,
+    );
+}
+
+#[test]
+fn inner_block_does_not_leak_declarations() {
+    compile_eq(
+        "{ let x = 1; let y = { let x = 2; x }; x + y }",
+        r#"
+fix fn id_0([id_1]) =>
+    Term([id_1]) -> ([]) =>
+    continue with:
+        Bind([1]) -> ([x]) =>
+            fix fn id_2([id_3]) =>
+                Bind([id_3]) -> ([y]) =>
+                    Add([x, y]) -> ([id_4]) =>
+                        call id_0([id_4])
+                continue with:
+                    Bind([2]) -> ([x]) =>
+                        call id_2([x])
+    "#,
+    );
+}
+
+#[test]
+fn anon_func() {
+    compile_eq(
+        "(x) => x + 1",
+        r#"
+fix fn id_0([x, id_1]) =>
+    Add([x, 1]) -> ([id_2]) =>
+        call id_1([id_2])
+    continue with:
+        Term([id_0]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn no_arg_anon_func() {
+    compile_eq(
+        "() => 5",
+        r#"
+fix fn id_0([id_1]) =>
+    call id_1([5])
+    continue with:
+        Term([id_0]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn function_decl() {
+    compile_eq(
+        "{ let f(a, b) = a * b; f(1, 2) }",
+        r#"
+fix fn id_0([id_1]) =>
+    Term([id_1]) -> ([]) =>
+    continue with:
+        fix fn id_2([a, b, id_3]) =>
+            Mul([a, b]) -> ([id_4]) =>
+                call id_3([id_4])
+            continue with:
+                Bind([id_2]) -> ([f]) =>
+                    fix fn id_5([id_6]) =>
+                        call id_0([id_6])
+                        continue with:
+                            call f([1, 2]) -> id_5
+    "#,
+    );
+}
+
+#[test]
+fn recursive_function_decl() {
+    compile_eq(
+        "{ let f(n) = f(n); f }",
+        r#"
+fix fn id_0([id_1]) =>
+    Term([id_1]) -> ([]) =>
+    continue with:
+        fix fn f([n, id_2]) =>
+            fix fn id_3([id_4]) =>
+                call id_2([id_4])
+                continue with:
+                    call f([n]) -> id_3
+            continue with:
+                call id_0([f])
+    "#,
+    );
+}
+
+#[test]
+fn pipeline() {
+    compile_eq(
+        "1 |> f",
+        r#"
+fix fn id_0([id_1]) =>
+    Term([id_1]) -> ([]) =>
+    continue with:
+        call f([1]) -> id_0
+    "#,
+    );
+}
+
+#[test]
+fn pipeline_evaluates_left_first() {
+    compile_eq(
+        "a(1) |> b(2)",
+        r#"
+fix fn id_0([id_1]) =>
+    Term([id_1]) -> ([]) =>
+    continue with:
+        fix fn id_2([id_3]) =>
+            Bind([id_3]) -> ([tmp_0]) =>
+                fix fn id_4([id_5]) =>
+                    call id_0([id_5])
+                    continue with:
+                        fix fn id_6([id_7]) =>
+                            call id_7([tmp_0]) -> id_4
+                            continue with:
+                                call b([2]) -> id_6
+            continue with:
+                call a([1]) -> id_2
+    "#,
+    );
+}
+
+#[test]
+fn field_access() {
+    compile_eq(
+        "a.b.c",
+        r#"
+MapGet([a, 'b]) -> ([id_0]) =>
+    MapGet([id_0, 'c]) -> ([id_1]) =>
+        Term([id_1]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn debug_call() {
+    compile_module_eq(
+        "debug(1);",
+        r#"
+Debug([1]) -> ([id_0]) =>
+    MapEmpty([]) -> ([id_1]) =>
+        Term([id_1]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn module_variable_decl() {
+    compile_module_eq(
+        "let x = 5; debug(x);",
+        r#"
+Bind([5]) -> ([x]) =>
+    Debug([x]) -> ([id_0]) =>
+        MapEmpty([]) -> ([id_1]) =>
+            Term([id_1]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn module_function_decl() {
+    compile_module_eq(
+        "let f(a) = a + 1; debug(f(2));",
+        r#"
+fix fn id_0([a, id_1]) =>
+    Add([a, 1]) -> ([id_2]) =>
+        call id_1([id_2])
+    continue with:
+        Bind([id_0]) -> ([f]) =>
+            fix fn id_3([id_4]) =>
+                Debug([id_4]) -> ([id_5]) =>
+                    MapEmpty([]) -> ([id_6]) =>
+                        Term([id_6]) -> ([]) =>
+                continue with:
+                    call f([2]) -> id_3
+    "#,
+    );
+}
+
+#[test]
+fn module_expression_statement() {
+    compile_module_eq(
+        "1 + 2;",
+        r#"
+Add([1, 2]) -> ([id_0]) =>
+    MapEmpty([]) -> ([id_1]) =>
+        Term([id_1]) -> ([]) =>
+    "#,
+    );
+}
+
+fn eval_module(program: &'static str) -> Result<Vec<String>, String> {
+    use eval::Interpreter;
+
+    let mut result = Err(String::new());
+    with_parsed_module_cont(program, |cont| {
+        let mut interpreter = Interpreter::new();
+        result = match interpreter.run(cont) {
+            Ok(_) => Ok(interpreter.debug_values.iter().map(|v| format!("{:?}", v)).collect()),
+            Err(e) => Err(format!("{}", e)),
+        };
+    });
+    result
+}
+
+#[test]
+fn eval_arithmetic_promotes_like_the_vm() {
+    assert_eq!(
+        eval_module("debug(1 + 2 * 3); debug(7 / 2); debug(1 / 2.0);").unwrap(),
+        vec!["7", "3", "0.5"]
+    );
+}
+
+#[test]
+fn eval_functions_and_closures() {
+    assert_eq!(
+        eval_module(
+            "let adder(n) = (x) => x + n;
+             let add2 = adder(2);
+             debug(add2(5));
+             debug(10 |> add2);",
+        ).unwrap(),
+        vec!["7", "12"]
+    );
+}
+
+#[test]
+fn eval_blocks_do_not_leak_declarations() {
+    assert_eq!(
+        eval_module("let x = 1; let y = { let x = 2; x * 10 }; debug(x + y);").unwrap(),
+        vec!["21"]
+    );
+}
+
+#[test]
+fn eval_recursion_in_blocks() {
+    assert_eq!(
+        eval_module("let f() = { let g() = { debug(1); g }; g }; f()()();").unwrap(),
+        vec!["1", "1"]
+    );
+}
+
+#[test]
+fn eval_errors() {
+    assert_eq!(eval_module("debug(1 / 0);"), Err("division by zero".into()));
+    assert_eq!(eval_module("let f(x) = x; f(1, 2);"), Err(
+        "expected 2 arguments (with the continuation), found 3".into()
+    ));
+    assert_eq!(eval_module("debug(1(2));"), Err("1 is not a function".into()));
+    assert_eq!(eval_module("debug(x);"), Err("x is not bound".into()));
+}
+
+fn shrink_eq(program: &'static str, expected: &str) {
+    with_parsed_expression_cont(program, |r| {
+        let arena = Arena::new();
+        str_rep_eq(format!("{:?}", shrink::shrink(r, &arena)), expected);
+    });
+}
+
+fn shrink_module_eq(program: &'static str, expected: &str) {
+    with_parsed_module_cont(program, |r| {
+        let arena = Arena::new();
+        str_rep_eq(format!("{:?}", shrink::shrink(r, &arena)), expected);
+    });
+}
+
+#[test]
+fn shrink_folds_constants_through_bindings() {
+    shrink_eq("{ let x = 1 + 2; let y = x * 2; y - 1 }", "Term([5]) -> ([]) =>");
+}
+
+#[test]
+fn shrink_keeps_failing_arithmetic() {
+    shrink_eq(
+        "{ let unused = 1 / 0; 2 }",
+        r#"
+Div([1, 0]) -> ([id_2]) =>
+    Term([2]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn shrink_contracts_functions_called_once() {
+    shrink_eq("{ let f(a, b) = a * b; f(1, 2) }", "Term([2]) -> ([]) =>");
+}
+
+#[test]
+fn shrink_keeps_recursive_functions() {
+    shrink_eq(
+        "{ let f(n) = f(n); f }",
+        r#"
+fix fn f([n, id_2]) =>
+    call f([n]) -> id_2
+    continue with:
+        Term([f]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn shrink_replaces_forwarding_functions() {
+    shrink_eq(
+        "{ let f(x) = g(x); f(1) + f(2) }",
+        r#"
+fix fn id_7([id_8]) =>
+    fix fn id_9([id_10]) =>
+        Add([id_8, id_10]) -> ([id_6]) =>
+            Term([id_6]) -> ([]) =>
+        continue with:
+            call g([2]) -> id_9
+    continue with:
+        call g([1]) -> id_7
+    "#,
+    );
+}
+
+#[test]
+fn shrink_keeps_calls_to_unknown_functions() {
+    shrink_eq(
+        "a(1) |> b(2)",
+        r#"
+fix fn id_2([id_3]) =>
+    fix fn id_4([id_5]) =>
+        Term([id_5]) -> ([]) =>
+        continue with:
+            fix fn id_6([id_7]) =>
+                call id_7([id_3]) -> id_4
+                continue with:
+                    call b([2]) -> id_6
+    continue with:
+        call a([1]) -> id_2
+    "#,
+    );
+}
+
+#[test]
+fn shrink_respects_shadowing() {
+    shrink_eq("{ let x = 1; let y = { let x = 2; x }; x + y }", "Term([3]) -> ([]) =>");
+}
+
+#[test]
+fn shrink_keeps_debug_calls() {
+    shrink_module_eq(
+        "let f(a) = a + 1; debug(f(2));",
+        r#"
+Debug([3]) -> ([id_5]) =>
+    MapEmpty([]) -> ([id_6]) =>
+        Term([id_6]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn shrink_renames_reused_parameter_names() {
+    shrink_module_eq(
+        "let f(x) = x * 2; let g(x) = f(x); debug(g(1)); debug(g(2));",
+        r#"
+fix fn id_3([id_14, id_4]) =>
+    Mul([id_14, 2]) -> ([id_2]) =>
+        call id_4([id_2])
+    continue with:
+        fix fn id_7([id_8]) =>
+            Debug([id_8]) -> ([id_9]) =>
+                fix fn id_10([id_11]) =>
+                    Debug([id_11]) -> ([id_12]) =>
+                        MapEmpty([]) -> ([id_13]) =>
+                            Term([id_13]) -> ([]) =>
+                    continue with:
+                        call id_3([2]) -> id_10
+            continue with:
+                call id_3([1]) -> id_7
+    "#,
+    );
+}
+
+#[test]
+fn shrink_preserves_evaluation() {
+    use eval::Interpreter;
+
+    let programs = [
+        "let adder(n) = (x) => x + n; let add2 = adder(2); debug(add2(5)); debug(10 |> add2);",
+        "let x = 1; let y = { let x = 2; x * 10 }; debug(x + y);",
+        "let f() = { let g() = { debug(1); g }; g }; f()()();",
+        "let f(x) = x * 2; let g(x) = f(x); let h(x) = g(x) + g(x + 1); debug(h(3));",
+    ];
+    for program in programs.iter() {
+        with_parsed_module_cont(program, |cont| {
+            let arena = Arena::new();
+            let shrunk = shrink::shrink(cont, &arena);
+            let mut direct = Interpreter::new();
+            let mut reduced = Interpreter::new();
+            direct.run(cont).unwrap();
+            reduced.run(shrunk).unwrap();
+            assert_eq!(
+                format!("{:?}", direct.debug_values),
+                format!("{:?}", reduced.debug_values)
+            );
+        });
+    }
+}
+
+fn run_compiled(program: &'static str, shrunk: bool) -> Result<Vec<String>, String> {
+    use codegen::compile_top;
+    use vm::vm::Vm;
+
+    let mut result = Err(String::new());
+    with_parsed_module_cont(program, |cont| {
+        let arena = Arena::new();
+        let cont = if shrunk { shrink::shrink(cont, &arena) } else { cont };
+        let function = match compile_top(cont, &arena) {
+            Ok(function) => function,
+            Err(e) => {
+                result = Err(format!("{:?}", e));
+                return;
+            }
+        };
+        let mut vm = Vm::new();
+        result = match vm.run_function(function) {
+            Ok(_) => Ok(vm.debug_values.iter().map(|v| format!("{:?}", v)).collect()),
+            Err(e) => Err(format!("{:?}", e.error)),
+        };
+    });
+    result
+}
+
+#[test]
+fn compiled_arithmetic_and_bindings() {
+    let program = "let x = 1 + 2 * 3; debug(x); debug(x / 2.0); debug({ let y = x; y - 1 });";
+    assert_eq!(run_compiled(program, false).unwrap(), vec!["7", "3.5", "6"]);
+    assert_eq!(run_compiled(program, true).unwrap(), vec!["7", "3.5", "6"]);
+}
+
+#[test]
+fn compiled_constants_keep_the_sign_of_zero() {
+    let program = "debug(0.0); debug((0.0 - 1.0) * 0.0);";
+    assert_eq!(run_compiled(program, false).unwrap(), vec!["0.0", "-0.0"]);
+    assert_eq!(run_compiled(program, true).unwrap(), vec!["0.0", "-0.0"]);
+}
+
+#[test]
+fn compiled_closures_capture_values() {
+    let program = "let adder(n) = (x) => x + n;
+                   let add2 = adder(2);
+                   let add3 = adder(3);
+                   debug(add2(5));
+                   debug(10 |> add3);
+                   debug(add2(add3(1)));";
+    assert_eq!(run_compiled(program, false).unwrap(), vec!["7", "13", "6"]);
+    assert_eq!(run_compiled(program, true).unwrap(), vec!["7", "13", "6"]);
+}
+
+#[test]
+fn compiled_functions_refer_to_themselves() {
+    let program = "let f() = { let g() = { debug(1); g }; g }; f()()();";
+    assert_eq!(run_compiled(program, false).unwrap(), vec!["1", "1"]);
+    assert_eq!(run_compiled(program, true).unwrap(), vec!["1", "1"]);
+}
+
+#[test]
+fn compiled_calls_inside_arguments() {
+    let program = "let double(x) = x * 2; let add(a, b) = a + b; debug(add(double(1), double(add(2, 3))));";
+    assert_eq!(run_compiled(program, false).unwrap(), vec!["12"]);
+    assert_eq!(run_compiled(program, true).unwrap(), vec!["12"]);
+}
+
+#[test]
+fn compiled_errors() {
+    assert_eq!(run_compiled("debug(x);", false), Err("UnboundIdentifier(x)".into()));
+    assert_eq!(
+        run_compiled("let f(a) = a; f(1, 2);", false),
+        Err("ArityMismatch { actual: 2, expected: 1 }".into())
+    );
+}
+
+#[test]
+fn compiled_functions_refer_to_their_siblings() {
+    use super::{ContAst, Function, Ident, PrimOpKind, Terminal};
+    use codegen::compile_top;
+    use vm::value::Value;
+    use vm::vm::Vm;
+
+    // f() = g(1) + n, g(x) = { f; x * 2 }, debug(f())
+    let arena = Arena::new();
+    let ident = |name| Ident::Identifier(name);
+    let n = ident("n");
+    let (f, g, x, k, j, y, z, w, r) = (
+        ident("f"),
+        ident("g"),
+        ident("x"),
+        ident("k"),
+        ident("j"),
+        ident("y"),
+        ident("z"),
+        ident("w"),
+        ident("r"),
+    );
+    let f_body = arena.alloc(ContAst::Fix {
+        functions: vec![Function {
+            name: j,
+            params: vec![y],
+            body: arena.alloc(ContAst::Primop {
+                op: PrimOpKind::Add,
+                terminals: vec![Terminal::Ident(y), Terminal::Ident(n)],
+                exports: vec![z],
+                continuations: vec![arena.alloc(ContAst::Call {
+                    target: Terminal::Ident(k),
+                    params: vec![Terminal::Ident(z)],
+                    continuation: None,
+                })],
+            }),
+        }],
+        continuation: arena.alloc(ContAst::Call {
+            target: Terminal::Ident(g),
+            params: vec![Terminal::Integer(1)],
+            continuation: Some(Terminal::Ident(j)),
+        }),
+    });
+    let g_body = arena.alloc(ContAst::Primop {
+        op: PrimOpKind::Bind,
+        terminals: vec![Terminal::Ident(f)],
+        exports: vec![w],
+        continuations: vec![arena.alloc(ContAst::Primop {
+            op: PrimOpKind::Mul,
+            terminals: vec![Terminal::Ident(x), Terminal::Integer(2)],
+            exports: vec![z],
+            continuations: vec![arena.alloc(ContAst::Call {
+                target: Terminal::Ident(k),
+                params: vec![Terminal::Ident(z)],
+                continuation: None,
+            })],
+        })],
+    });
+    let done = arena.alloc(ContAst::Primop {
+        op: PrimOpKind::Debug,
+        terminals: vec![Terminal::Ident(r)],
+        exports: vec![y],
+        continuations: vec![arena.alloc(ContAst::Primop {
+            op: PrimOpKind::Term,
+            terminals: vec![Terminal::Ident(y)],
+            exports: vec![],
+            continuations: vec![],
+        })],
+    });
+    let program = arena.alloc(ContAst::Primop {
+        op: PrimOpKind::Bind,
+        terminals: vec![Terminal::Integer(10)],
+        exports: vec![n],
+        continuations: vec![arena.alloc(ContAst::Fix {
+            functions: vec![
+                Function { name: f, params: vec![k], body: f_body },
+                Function { name: g, params: vec![x, k], body: g_body },
+            ],
+            continuation: arena.alloc(ContAst::Fix {
+                functions: vec![Function { name: j, params: vec![r], body: done }],
+                continuation: arena.alloc(ContAst::Call {
+                    target: Terminal::Ident(f),
+                    params: vec![],
+                    continuation: Some(Terminal::Ident(j)),
+                }),
+            }),
+        })],
+    });
+
+    let function = compile_top(program, &arena).unwrap();
+    let mut vm = Vm::new();
+    assert_eq!(vm.run_function(function).unwrap(), Value::Integer(12));
+    assert_eq!(vm.debug_values, vec![Value::Integer(12)]);
+}
+
+#[test]
+fn dot_links_calls_to_known_functions() {
+    with_parsed_expression_cont("{ let f(x) = x * 2; f(1) }", |r| {
+        str_rep_eq(
+            super::dot::to_dot(r),
+            r#"
+digraph cont {
+    node [shape=box, fontname="monospace"];
+    n0 [label="fix"];
+    n1 [label="fn id_0([id_1])"];
+    n0 -> n1;
+    n2 [label="Term([id_1]) -> ([])"];
+    n1 -> n2 [label="body"];
+    n3 [label="fix"];
+    n4 [label="fn id_2([x, id_3])"];
+    n3 -> n4;
+    n5 [label="Mul([x, 2]) -> ([id_4])"];
+    n6 [label="call id_3([id_4])"];
+    n5 -> n6;
+    n4 -> n5 [label="body"];
+    n7 [label="Bind([id_2]) -> ([f])"];
+    n8 [label="fix"];
+    n9 [label="fn id_5([id_6])"];
+    n8 -> n9;
+    n10 [label="call id_0([id_6])"];
+    n10 -> n1 [label="call", style=dashed];
+    n9 -> n10 [label="body"];
+    n11 [label="call f([1]) -> id_5"];
+    n11 -> n4 [label="call", style=dashed];
+    n11 -> n9 [label="continuation", style=dashed];
+    n8 -> n11 [label="continue"];
+    n7 -> n8;
+    n3 -> n7 [label="continue"];
+    n0 -> n3 [label="continue"];
+}
+    "#,
+        );
+    });
+}
@@ -1,10 +1,41 @@
+extern crate copy_arena;
 extern crate lexer;
 extern crate parser;
+extern crate typed_arena;
+extern crate vm;
 
-use lexer::Token;
-use parser::AstPtr;
+#[cfg(test)]
+mod test;
 
-#[derive(Debug)]
+use copy_arena::Allocator;
+use lexer::{Span, Token, TokenKind};
+use parser::{function_group, ArgumentSyntax, Ast, AstPtr, Param, TypeAst, TypePtr};
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use typed_arena::Arena;
+use vm::value::ValueKind;
+
+// Lowering removes the syntax that only exists for convenience, so later
+// phases see a smaller language.  `desugar` rewrites the parsed tree, and
+// both the binder and `lower` take what it returns:
+//
+//  * `l |> r` becomes the call `r(l)`.  `l` is evaluated first, so unless it
+//    is a literal or an identifier it goes into a temporary beforehand.
+//  * A call with `_` arguments becomes an anonymous function with one
+//    parameter per `_`.  The target and the other arguments are evaluated
+//    where the call is written, once, not every time the function runs.
+//
+// `lower` then turns a desugared tree into a `LoweredAst`, where a named
+// function that does not refer to itself becomes a variable bound to an
+// anonymous function.  Functions that have to be declared together, the
+// way the binder groups them, become a `FunctionGroup`.  Type annotations
+// become `Check`s of the values they describe, where the VM would check
+// them too.
+//
+// Names that desugaring invents start with `$`, which no identifier in the
+// source can, and are lowered to `Identifier::Phantom`s.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Identifier<'parse> {
     Ident(&'parse Token<'parse>, &'parse str),
     Phantom(u32),
@@ -18,40 +49,557 @@ pub enum LoweredAst<'parse> {
     Integer(&'parse Token<'parse>, i64),
     Float(&'parse Token<'parse>, f64),
     FunctionCall {
-        target: AstPtr<'parse>,
-        args: Vec<AstPtr<'parse>>,
+        target: LoweredAstPtr<'parse>,
+        args: Vec<LoweredAstPtr<'parse>>,
     },
-    DebugCall(AstPtr<'parse>),
-    Add(AstPtr<'parse>, AstPtr<'parse>),
-    Sub(AstPtr<'parse>, AstPtr<'parse>),
-    Div(AstPtr<'parse>, AstPtr<'parse>),
-    Mul(AstPtr<'parse>, AstPtr<'parse>),
+    DebugCall(LoweredAstPtr<'parse>),
+    Add(LoweredAstPtr<'parse>, LoweredAstPtr<'parse>),
+    Sub(LoweredAstPtr<'parse>, LoweredAstPtr<'parse>),
+    Div(LoweredAstPtr<'parse>, LoweredAstPtr<'parse>),
+    Mul(LoweredAstPtr<'parse>, LoweredAstPtr<'parse>),
     AnonFunc {
-        params: Vec<(&'parse str, AstPtr<'parse>)>,
-        body: AstPtr<'parse>,
+        params: Vec<Identifier<'parse>>,
+        body: LoweredAstPtr<'parse>,
     },
+    /// A function that refers to itself by `name` in its body.
     FunctionDecl {
-        name: &'parse str,
-        name_ast: AstPtr<'parse>,
-        params: Vec<(&'parse str, AstPtr<'parse>)>,
-        body: AstPtr<'parse>,
+        name: Identifier<'parse>,
+        params: Vec<Identifier<'parse>>,
+        body: LoweredAstPtr<'parse>,
     },
     VariableDecl {
-        name: &'parse str,
-        name_ast: AstPtr<'parse>,
-        expression: AstPtr<'parse>,
+        name: Identifier<'parse>,
+        expression: LoweredAstPtr<'parse>,
     },
+    /// `FunctionDecl`s that refer to each other, each in scope in all of
+    /// their bodies.
+    FunctionGroup(Vec<LoweredAstPtr<'parse>>),
     FieldAccess {
-        target: AstPtr<'parse>,
-        field: AstPtr<'parse>,
+        target: LoweredAstPtr<'parse>,
         field_name: &'parse str,
     },
+    /// The value of the expression, which has to be of the kind.
+    Check(ValueKind, LoweredAstPtr<'parse>),
     Module {
-        statements: Vec<AstPtr<'parse>>,
+        statements: Vec<LoweredAstPtr<'parse>>,
         module_id: &'parse str,
     },
     BlockExpr {
-        statements: Vec<AstPtr<'parse>>,
-        final_expression: AstPtr<'parse>,
+        statements: Vec<LoweredAstPtr<'parse>>,
+        final_expression: LoweredAstPtr<'parse>,
     },
 }
+
+/// Desugars a module, a statement or an expression.  Temporaries are
+/// numbered from 0 for every call.
+pub fn desugar<'a>(ast: AstPtr<'a>, alloc: &mut Allocator<'a>) -> AstPtr<'a> {
+    let mut desugarer = Desugarer {
+        alloc,
+        next_temporary: 0,
+    };
+    desugarer.desugar(ast)
+}
+
+/// Lowers a module, a statement or an expression that has been desugared.
+pub fn lower<'a>(ast: AstPtr<'a>, arena: &'a Arena<LoweredAst<'a>>) -> LoweredAstPtr<'a> {
+    let lowerer = Lowerer {
+        arena,
+        scope: RefCell::new(vec![]),
+    };
+    lowerer.lower(ast)
+}
+
+fn identifier<'a>(name: &'a str, ast: AstPtr<'a>) -> Identifier<'a> {
+    match ast {
+        &Ast::Identifier(_, name) if name.starts_with('$') => {
+            Identifier::Phantom(name[1..].parse().unwrap())
+        }
+        &Ast::Identifier(token, _) => Identifier::Ident(token, name),
+        other => panic!("expected an identifier, found {:?}", other),
+    }
+}
+
+/// The kind of value a type annotation promises.  Only the outermost kind
+/// is checked at runtime: a `List[Int]` is any list, and a function any
+/// function.
+pub fn value_kind(annotation: &TypeAst) -> ValueKind {
+    match annotation {
+        &TypeAst::Int(_) => ValueKind::Integer,
+        &TypeAst::Float(_) => ValueKind::Float,
+        &TypeAst::Symbol(_) => ValueKind::Symbol,
+        &TypeAst::Map(_) => ValueKind::Map,
+        &TypeAst::List(..) => ValueKind::List,
+        &TypeAst::Function { .. } => ValueKind::Function,
+    }
+}
+
+/// Whether evaluating `ast` has no effect, so that it can be moved past
+/// other evaluations.
+fn is_trivial(ast: &Ast) -> bool {
+    match ast {
+        &Ast::Identifier(..) | &Ast::Integer(..) | &Ast::Float(..) => true,
+        _ => false,
+    }
+}
+
+fn is_placeholder(arg: &ArgumentSyntax) -> bool {
+    match arg {
+        &ArgumentSyntax::Underscore => true,
+        &ArgumentSyntax::Expression(_) => false,
+    }
+}
+
+struct Desugarer<'x, 'a: 'x> {
+    alloc: &'x mut Allocator<'a>,
+    next_temporary: u32,
+}
+
+impl<'x, 'a> Desugarer<'x, 'a> {
+    fn alloc(&mut self, node: Ast<'a>) -> AstPtr<'a> {
+        self.alloc.alloc(node)
+    }
+
+    /// A new name, and an identifier for it covering `span`.
+    fn temporary(&mut self, span: Span) -> (&'a str, AstPtr<'a>) {
+        let name = self.alloc.alloc_str(format!("${}", self.next_temporary));
+        self.next_temporary += 1;
+        let token = self.alloc.alloc(Token {
+            kind: TokenKind::Identifier(name),
+            start_byte: span.start,
+            end_byte: span.end,
+        });
+        (name, self.alloc(Ast::Identifier(token, name)))
+    }
+
+    /// Desugars `ast` and, unless it is trivial, declares a temporary for
+    /// it in `declarations` and returns the temporary instead.
+    fn evaluate_early(&mut self, ast: AstPtr<'a>, declarations: &mut Vec<AstPtr<'a>>) -> AstPtr<'a> {
+        let desugared = self.desugar(ast);
+        if is_trivial(ast) {
+            return desugared;
+        }
+        let span = ast.span();
+        let (name, name_ast) = self.temporary(span);
+        let declaration = self.alloc(Ast::VariableDecl {
+            name,
+            name_ast,
+            ty: None,
+            expression: desugared,
+            span,
+        });
+        declarations.push(declaration);
+        name_ast
+    }
+
+    fn block(
+        &mut self,
+        statements: Vec<AstPtr<'a>>,
+        final_expression: AstPtr<'a>,
+        span: Span,
+    ) -> AstPtr<'a> {
+        if statements.is_empty() {
+            return final_expression;
+        }
+        let statements = self.alloc.alloc_slice(&statements);
+        self.alloc(Ast::BlockExpr {
+            statements,
+            final_expression,
+            span,
+        })
+    }
+
+    fn call(&mut self, target: AstPtr<'a>, args: &'a [ArgumentSyntax<'a>], span: Span) -> AstPtr<'a> {
+        if !args.iter().any(is_placeholder) {
+            let target = self.desugar(target);
+            let args = args.iter()
+                .map(|arg| match arg {
+                    &ArgumentSyntax::Expression(arg) => ArgumentSyntax::Expression(self.desugar(arg)),
+                    &ArgumentSyntax::Underscore => unreachable!(),
+                })
+                .collect::<Vec<_>>();
+            let args = self.alloc.alloc_slice(&args);
+            return self.alloc(Ast::FunctionCall { target, args, span });
+        }
+
+        let mut declarations = vec![];
+        let mut params: Vec<Param<'a>> = vec![];
+        let target = self.evaluate_early(target, &mut declarations);
+        let args = args.iter()
+            .map(|arg| match arg {
+                &ArgumentSyntax::Expression(arg) => {
+                    ArgumentSyntax::Expression(self.evaluate_early(arg, &mut declarations))
+                }
+                &ArgumentSyntax::Underscore => {
+                    let (name, param) = self.temporary(span);
+                    params.push((name, param, None));
+                    ArgumentSyntax::Expression(param)
+                }
+            })
+            .collect::<Vec<_>>();
+        let args = self.alloc.alloc_slice(&args);
+        let body = self.alloc(Ast::FunctionCall { target, args, span });
+        let params = self.alloc.alloc_slice(&params);
+        let function = self.alloc(Ast::AnonFunc { params, body, span });
+        self.block(declarations, function, span)
+    }
+
+    fn pipeline(&mut self, l: AstPtr<'a>, r: AstPtr<'a>) -> AstPtr<'a> {
+        let span = l.span().to(r.span());
+        let mut declarations = vec![];
+        let arg = self.evaluate_early(l, &mut declarations);
+        let target = self.desugar(r);
+        let args = self.alloc.alloc_slice(&[ArgumentSyntax::Expression(arg)]);
+        let call = self.alloc(Ast::FunctionCall { target, args, span });
+        self.block(declarations, call, span)
+    }
+
+    fn statements(&mut self, statements: &'a [AstPtr<'a>]) -> &'a [AstPtr<'a>] {
+        let statements = statements.iter().map(|s| self.desugar(s)).collect::<Vec<_>>();
+        self.alloc.alloc_slice(&statements)
+    }
+
+    fn desugar(&mut self, ast: AstPtr<'a>) -> AstPtr<'a> {
+        let desugared = match *ast {
+            Ast::Identifier(..) | Ast::Integer(..) | Ast::Float(..) => return ast,
+            Ast::FunctionCall { target, args, span } => return self.call(target, args, span),
+            Ast::Pipeline(l, r) => return self.pipeline(l, r),
+            Ast::DebugCall(expr) => Ast::DebugCall(self.desugar(expr)),
+            Ast::Add(l, r) => Ast::Add(self.desugar(l), self.desugar(r)),
+            Ast::Sub(l, r) => Ast::Sub(self.desugar(l), self.desugar(r)),
+            Ast::Div(l, r) => Ast::Div(self.desugar(l), self.desugar(r)),
+            Ast::Mul(l, r) => Ast::Mul(self.desugar(l), self.desugar(r)),
+            Ast::AnonFunc { params, body, span } => Ast::AnonFunc {
+                params,
+                body: self.desugar(body),
+                span,
+            },
+            Ast::FunctionDecl {
+                name,
+                name_ast,
+                params,
+                result,
+                body,
+                span,
+            } => Ast::FunctionDecl {
+                name,
+                name_ast,
+                params,
+                result,
+                body: self.desugar(body),
+                span,
+            },
+            Ast::VariableDecl {
+                name,
+                name_ast,
+                ty,
+                expression,
+                span,
+            } => Ast::VariableDecl {
+                name,
+                name_ast,
+                ty,
+                expression: self.desugar(expression),
+                span,
+            },
+            Ast::FieldAccess {
+                target,
+                field,
+                field_name,
+                span,
+            } => Ast::FieldAccess {
+                target: self.desugar(target),
+                field,
+                field_name,
+                span,
+            },
+            Ast::Module {
+                statements,
+                module_id,
+                span,
+            } => Ast::Module {
+                statements: self.statements(statements),
+                module_id,
+                span,
+            },
+            Ast::BlockExpr {
+                statements,
+                final_expression,
+                span,
+            } => Ast::BlockExpr {
+                statements: self.statements(statements),
+                final_expression: self.desugar(final_expression),
+                span,
+            },
+        };
+        self.alloc(desugared)
+    }
+}
+
+struct Lowerer<'a> {
+    arena: &'a Arena<LoweredAst<'a>>,
+    /// The names declared around the node being lowered, which decide what
+    /// makes a `FunctionGroup`.
+    scope: RefCell<Vec<&'a str>>,
+}
+
+impl<'a> Lowerer<'a> {
+    fn alloc(&self, node: LoweredAst<'a>) -> LoweredAstPtr<'a> {
+        self.arena.alloc(node)
+    }
+
+    /// Lowers `f` with `names` in scope.
+    fn with_scope<T, F: FnOnce() -> T>(&self, names: &[&'a str], f: F) -> T {
+        let mark = self.scope.borrow().len();
+        self.scope.borrow_mut().extend(names);
+        let result = f();
+        self.scope.borrow_mut().truncate(mark);
+        result
+    }
+
+    /// `value`, checked against `annotation` if there is one.
+    fn checked(
+        &self,
+        value: LoweredAstPtr<'a>,
+        annotation: Option<TypePtr<'a>>,
+    ) -> LoweredAstPtr<'a> {
+        match annotation {
+            Some(annotation) => self.alloc(LoweredAst::Check(value_kind(annotation), value)),
+            None => value,
+        }
+    }
+
+    /// Lowers the body of a function, which first checks the annotated
+    /// parameters and then the result, if it is annotated.
+    fn function_body(
+        &self,
+        params: &'a [Param<'a>],
+        result: Option<TypePtr<'a>>,
+        body: AstPtr<'a>,
+    ) -> LoweredAstPtr<'a> {
+        let body = self.checked(self.lower(body), result);
+        let checks = params
+            .iter()
+            .filter(|&&(_, _, annotation)| annotation.is_some())
+            .map(|&(p, ast, annotation)| {
+                let param = self.alloc(LoweredAst::Identifier(identifier(p, ast)));
+                self.checked(param, annotation)
+            })
+            .collect::<Vec<_>>();
+        if checks.is_empty() {
+            body
+        } else {
+            self.alloc(LoweredAst::BlockExpr {
+                statements: checks,
+                final_expression: body,
+            })
+        }
+    }
+
+    /// The name, parameters and lowered body of a function declaration,
+    /// which is in scope in its body.
+    fn function(
+        &self,
+        ast: AstPtr<'a>,
+    ) -> (Identifier<'a>, Vec<Identifier<'a>>, LoweredAstPtr<'a>) {
+        match ast {
+            &Ast::FunctionDecl {
+                name,
+                name_ast,
+                params,
+                result,
+                body,
+                ..
+            } => {
+                let mut names = params.iter().map(|&(p, _, _)| p).collect::<Vec<_>>();
+                names.push(name);
+                let body = self.with_scope(&names, || self.function_body(params, result, body));
+                let params = params.iter().map(|&(p, ast, _)| identifier(p, ast)).collect();
+                (identifier(name, name_ast), params, body)
+            }
+            other => panic!("expected a function declaration, found {:?}", other),
+        }
+    }
+
+    /// Lowers the statements of a block or module, whose declarations stay
+    /// in scope after them.
+    fn statements(&self, statements: &'a [AstPtr<'a>]) -> Vec<LoweredAstPtr<'a>> {
+        let mut lowered = vec![];
+        let mut rest = statements;
+        while let Some(&statement) = rest.first() {
+            let group = function_group(rest, &self.scope.borrow());
+            match group {
+                Some(run) => {
+                    let names = run.iter().filter_map(|s| s.declared_name());
+                    self.scope.borrow_mut().extend(names);
+                    let functions = run.iter()
+                        .map(|&function| {
+                            let (name, params, body) = self.function(function);
+                            self.alloc(LoweredAst::FunctionDecl { name, params, body })
+                        })
+                        .collect();
+                    lowered.push(self.alloc(LoweredAst::FunctionGroup(functions)));
+                    rest = &rest[run.len()..];
+                }
+                None => {
+                    lowered.push(self.lower(statement));
+                    self.scope.borrow_mut().extend(statement.declared_name());
+                    rest = &rest[1..];
+                }
+            }
+        }
+        lowered
+    }
+
+    fn lower(&self, ast: AstPtr<'a>) -> LoweredAstPtr<'a> {
+        let lowered = match ast {
+            &Ast::Identifier(_, name) => LoweredAst::Identifier(identifier(name, ast)),
+            &Ast::Integer(token, value) => LoweredAst::Integer(token, value),
+            &Ast::Float(token, value) => LoweredAst::Float(token, value),
+            &Ast::FunctionCall { target, args, .. } => LoweredAst::FunctionCall {
+                target: self.lower(target),
+                args: args.iter()
+                    .map(|arg| match arg {
+                        &ArgumentSyntax::Expression(arg) => self.lower(arg),
+                        &ArgumentSyntax::Underscore => panic!("`_` is desugared before lowering"),
+                    })
+                    .collect(),
+            },
+            &Ast::Pipeline(..) => panic!("`|>` is desugared before lowering"),
+            &Ast::DebugCall(expr) => LoweredAst::DebugCall(self.lower(expr)),
+            &Ast::Add(l, r) => LoweredAst::Add(self.lower(l), self.lower(r)),
+            &Ast::Sub(l, r) => LoweredAst::Sub(self.lower(l), self.lower(r)),
+            &Ast::Div(l, r) => LoweredAst::Div(self.lower(l), self.lower(r)),
+            &Ast::Mul(l, r) => LoweredAst::Mul(self.lower(l), self.lower(r)),
+            &Ast::AnonFunc { params, body, .. } => {
+                let names = params.iter().map(|&(p, _, _)| p).collect::<Vec<_>>();
+                LoweredAst::AnonFunc {
+                    params: params.iter().map(|&(p, ast, _)| identifier(p, ast)).collect(),
+                    body: self.with_scope(&names, || self.function_body(params, None, body)),
+                }
+            }
+            &Ast::FunctionDecl { name, body, .. } => {
+                let is_recursive = body.mentions(name);
+                let (name, params, body) = self.function(ast);
+                if is_recursive {
+                    LoweredAst::FunctionDecl { name, params, body }
+                } else {
+                    LoweredAst::VariableDecl {
+                        name,
+                        expression: self.alloc(LoweredAst::AnonFunc { params, body }),
+                    }
+                }
+            }
+            &Ast::VariableDecl {
+                name,
+                name_ast,
+                ty,
+                expression,
+                ..
+            } => LoweredAst::VariableDecl {
+                name: identifier(name, name_ast),
+                expression: self.checked(self.lower(expression), ty),
+            },
+            &Ast::FieldAccess {
+                target, field_name, ..
+            } => LoweredAst::FieldAccess {
+                target: self.lower(target),
+                field_name,
+            },
+            &Ast::Module {
+                statements,
+                module_id,
+                ..
+            } => self.with_scope(&[], || LoweredAst::Module {
+                statements: self.statements(statements),
+                module_id,
+            }),
+            &Ast::BlockExpr {
+                statements,
+                final_expression,
+                ..
+            } => self.with_scope(&[], || LoweredAst::BlockExpr {
+                statements: self.statements(statements),
+                final_expression: self.lower(final_expression),
+            }),
+        };
+        self.alloc(lowered)
+    }
+}
+
+impl<'a> Display for Identifier<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &Identifier::Ident(_, name) => write!(f, "{}", name),
+            &Identifier::Phantom(n) => write!(f, "tmp_{}", n),
+        }
+    }
+}
+
+fn write_list<T: Display>(f: &mut Formatter, items: &[T], separator: &str) -> FmtResult {
+    for (i, item) in items.iter().enumerate() {
+        if i != 0 {
+            write!(f, "{}", separator)?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+/// Prints the tree in surface syntax, with every operator parenthesized.
+impl<'a> Display for LoweredAst<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &LoweredAst::Identifier(ident) => write!(f, "{}", ident),
+            &LoweredAst::Integer(_, value) => write!(f, "{}", value),
+            &LoweredAst::Float(_, value) => write!(f, "{:?}", value),
+            &LoweredAst::FunctionCall { target, ref args } => {
+                write!(f, "{}(", target)?;
+                write_list(f, args, ", ")?;
+                write!(f, ")")
+            }
+            &LoweredAst::DebugCall(expr) => write!(f, "debug({})", expr),
+            &LoweredAst::Add(l, r) => write!(f, "({} + {})", l, r),
+            &LoweredAst::Sub(l, r) => write!(f, "({} - {})", l, r),
+            &LoweredAst::Div(l, r) => write!(f, "({} / {})", l, r),
+            &LoweredAst::Mul(l, r) => write!(f, "({} * {})", l, r),
+            &LoweredAst::AnonFunc { ref params, body } => {
+                write!(f, "(")?;
+                write_list(f, params, ", ")?;
+                write!(f, ") => {}", body)
+            }
+            &LoweredAst::FunctionDecl {
+                name,
+                ref params,
+                body,
+            } => {
+                write!(f, "let {}(", name)?;
+                write_list(f, params, ", ")?;
+                write!(f, ") = {}", body)
+            }
+            &LoweredAst::VariableDecl { name, expression } => {
+                write!(f, "let {} = {}", name, expression)
+            }
+            &LoweredAst::FunctionGroup(ref functions) => write_list(f, functions, "; "),
+            &LoweredAst::FieldAccess { target, field_name } => {
+                write!(f, "{}.{}", target, field_name)
+            }
+            &LoweredAst::Check(kind, value) => write!(f, "check({}, {})", kind.name(), value),
+            &LoweredAst::Module { ref statements, .. } => {
+                for statement in statements {
+                    writeln!(f, "{};", statement)?;
+                }
+                Ok(())
+            }
+            &LoweredAst::BlockExpr {
+                ref statements,
+                final_expression,
+            } => {
+                write!(f, "{{ ")?;
+                for statement in statements {
+                    write!(f, "{}; ", statement)?;
+                }
+                write!(f, "{} }}", final_expression)
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use super::*;
+use lexer::{lex, remove_whitespace};
+use parser::{parse_expression, parse_module};
+
+fn lowered_expression(program: &'static str) -> String {
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let arena = Arena::new();
+
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_expression(lexed, &mut alloc).unwrap().0;
+    format!("{}", lower(desugar(parsed, &mut alloc), &arena))
+}
+
+fn lowered_module(program: &'static str) -> String {
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let arena = Arena::new();
+
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap().0;
+    format!("{}", lower(desugar(parsed, &mut alloc), &arena))
+}
+
+#[test]
+fn plain_expressions_are_unchanged() {
+    assert_eq!(lowered_expression("a.b + f(1, 2.5) * 3"), "(a.b + (f(1, 2.5) * 3))");
+    assert_eq!(lowered_expression("(x, y) => x - y"), "(x, y) => (x - y)");
+}
+
+#[test]
+fn pipelines_become_calls() {
+    assert_eq!(lowered_expression("x |> f"), "f(x)");
+    assert_eq!(lowered_expression("1 |> f |> g"), "{ let tmp_0 = f(1); g(tmp_0) }");
+}
+
+#[test]
+fn pipelines_evaluate_the_left_side_first() {
+    assert_eq!(lowered_expression("a(1) |> b(2)"), "{ let tmp_0 = a(1); b(2)(tmp_0) }");
+}
+
+#[test]
+fn underscores_become_parameters() {
+    assert_eq!(lowered_expression("f(_, 2)"), "(tmp_0) => f(tmp_0, 2)");
+    assert_eq!(lowered_expression("f(_, x, _)"), "(tmp_0, tmp_1) => f(tmp_0, x, tmp_1)");
+}
+
+#[test]
+fn partial_application_evaluates_arguments_once() {
+    assert_eq!(
+        lowered_expression("g(1)(_, h(2))"),
+        "{ let tmp_0 = g(1); let tmp_2 = h(2); (tmp_1) => tmp_0(tmp_1, tmp_2) }"
+    );
+}
+
+#[test]
+fn underscores_belong_to_the_innermost_call() {
+    assert_eq!(lowered_expression("f(g(_))"), "f((tmp_0) => g(tmp_0))");
+}
+
+#[test]
+fn pipelines_into_partial_application() {
+    assert_eq!(lowered_expression("3 |> f(_, 2)"), "(tmp_0) => f(tmp_0, 2)(3)");
+}
+
+#[test]
+fn non_recursive_functions_become_variables() {
+    assert_eq!(
+        lowered_module("let f(a) = a + 1; let g(n) = g(n - 1); debug(f(2));"),
+        "let f = (a) => (a + 1);\nlet g(n) = g((n - 1));\ndebug(f(2));\n"
+    );
+}
+
+#[test]
+fn functions_nested_in_blocks() {
+    assert_eq!(
+        lowered_expression("{ let f(x) = { let g() = f; g }; f }"),
+        "{ let f(x) = { let g = () => f; g }; f }"
+    );
+}
+
+#[test]
+fn functions_that_refer_forward_are_grouped() {
+    assert_eq!(
+        lowered_module("let f(n) = g(n); let g(n) = f(n); let h() = 1; debug(f(1));"),
+        "let f(n) = g(n); let g(n) = f(n); let h() = 1;\ndebug(f(1));\n"
+    );
+    assert_eq!(
+        lowered_expression("{ let g = 1; let f() = g; let g() = f; g }"),
+        "{ let g = 1; let f = () => g; let g = () => f; g }"
+    );
+}
+
+#[test]
+fn annotations_become_checks() {
+    assert_eq!(lowered_expression("{ let x: Int = 1; x }"), "{ let x = check(integer, 1); x }");
+    assert_eq!(
+        lowered_module("let f(a: Int, b): Float = a * b;"),
+        "let f = (a, b) => { check(integer, a); check(float, (a * b)) };\n"
+    );
+}
@@ -23,11 +23,18 @@ impl<'a, 'bound> Binder<'bound> for BlockBinder<'a, 'bound> {
         self.parent.add_declaration(new, binding_state)
     }
 
-    fn lookup(&mut self, symbol: &DeclarationKind<'bound>) -> Result<BindingKind<'bound>, Error> {
+    fn lookup(&mut self, symbol: &DeclarationKind<'bound>) -> Option<BindingKind<'bound>> {
         if let Some(resolved) = self.definitions.get(symbol) {
             self.parent.lookup(resolved)
         } else {
             self.parent.lookup(symbol)
         }
     }
+
+    fn names_in_scope(&self, names: &mut Vec<&'bound str>) {
+        let mut defined = self.definitions.keys().map(DeclarationKind::name).collect::<Vec<_>>();
+        defined.sort();
+        names.extend(defined);
+        self.parent.names_in_scope(names);
+    }
 }
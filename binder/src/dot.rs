@@ -0,0 +1,194 @@
+use super::*;
+use graphviz::{Graph, NodeId};
+
+/// Renders `bound` as a Graphviz DOT tree.  Identifiers and declarations
+/// are annotated with the binding they resolved to, and functions list
+/// their locals and upvars.
+pub fn to_dot(bound: &Bound) -> String {
+    let mut graph = Graph::new("bound");
+    add_node(bound, &mut graph);
+    graph.finish()
+}
+
+fn declaration_label(declaration: &DeclarationKind) -> String {
+    match declaration {
+        &DeclarationKind::Named(name) => name.into(),
+        &DeclarationKind::Generated(id, name) => format!("{}#{}", name, id),
+    }
+}
+
+fn binding_label(kind: &BindingKind) -> String {
+    match kind {
+        &BindingKind::FunctionLocal(i) => format!("local {}", i),
+        &BindingKind::Argument(i) => format!("arg {}", i),
+        &BindingKind::Upvar(i) => format!("upvar {}", i),
+        &BindingKind::CurrentFunction => "current function".into(),
+        &BindingKind::Sibling(i) => format!("sibling {}", i),
+        &BindingKind::Module {
+            module_id,
+            ref symbol,
+        } => format!("module {}.{}", module_id, declaration_label(symbol)),
+    }
+}
+
+/// The header of a function node, followed by its locals and its upvars
+/// in upvar order, each with the binding it captures.
+fn function_label(
+    header: String,
+    locals: &[DeclarationKind],
+    upvars: &HashMap<DeclarationKind, (BindingKind, u32)>,
+) -> String {
+    let mut label = header;
+    if !locals.is_empty() {
+        let locals = locals.iter().map(declaration_label).collect::<Vec<_>>();
+        label.push_str(&format!("\nlocals: {}", locals.join(", ")));
+    }
+    let mut upvars = upvars.iter().collect::<Vec<_>>();
+    upvars.sort_by_key(|&(_, &(_, index))| index);
+    for (declaration, &(ref kind, index)) in upvars {
+        label.push_str(&format!(
+            "\nupvar {}: {} = {}",
+            index,
+            declaration_label(declaration),
+            binding_label(kind)
+        ));
+    }
+    label
+}
+
+fn params_label(params: &[(DeclarationKind, &Ast)]) -> String {
+    params
+        .iter()
+        .map(|&(ref declaration, _)| declaration_label(declaration))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn add_node(bound: &Bound, graph: &mut Graph) -> NodeId {
+    let binary = |graph: &mut Graph, op: &str, l: &Bound, r: &Bound| {
+        let node = graph.node(op);
+        let l = add_node(l, graph);
+        graph.edge(node, l, "left");
+        let r = add_node(r, graph);
+        graph.edge(node, r, "right");
+        node
+    };
+
+    match bound {
+        &Bound::Integer { value, .. } => graph.node(&value.to_string()),
+        &Bound::Float { value, .. } => graph.node(&format!("{:?}", value)),
+        &Bound::Identifier {
+            ident,
+            ref binding_kind,
+            ..
+        } => graph.node(&format!("{}\n{}", ident, binding_label(binding_kind))),
+        &Bound::DebugCall { arg, .. } => {
+            let node = graph.node("debug");
+            let arg = add_node(arg, graph);
+            graph.edge(node, arg, "");
+            node
+        }
+        &Bound::FunctionCall {
+            target, ref args, ..
+        } => {
+            let node = graph.node("call");
+            let target = add_node(target, graph);
+            graph.edge(node, target, "target");
+            for (i, arg) in args.iter().enumerate() {
+                let arg = add_node(arg, graph);
+                graph.edge(node, arg, &format!("arg{}", i));
+            }
+            node
+        }
+        &Bound::Add { left, right, .. } => binary(graph, "+", left, right),
+        &Bound::Sub { left, right, .. } => binary(graph, "-", left, right),
+        &Bound::Div { left, right, .. } => binary(graph, "/", left, right),
+        &Bound::Mul { left, right, .. } => binary(graph, "*", left, right),
+        &Bound::FunctionDecl {
+            name,
+            ref params,
+            body,
+            ref locals,
+            ref upvars,
+            ref location,
+            ..
+        } => {
+            let header = format!(
+                "fn {}({})\n{}",
+                name,
+                params_label(params),
+                binding_label(location)
+            );
+            let node = graph.node(&function_label(header, locals, upvars));
+            let body = add_node(body, graph);
+            graph.edge(node, body, "body");
+            node
+        }
+        &Bound::FunctionGroup { ref functions } => {
+            let node = graph.node("function group");
+            for (i, function) in functions.iter().enumerate() {
+                let function = add_node(function, graph);
+                graph.edge(node, function, &i.to_string());
+            }
+            node
+        }
+        &Bound::AnonFunc {
+            ref params,
+            body,
+            ref locals,
+            ref upvars,
+            ..
+        } => {
+            let header = format!("fn ({})", params_label(params));
+            let node = graph.node(&function_label(header, locals, upvars));
+            let body = add_node(body, graph);
+            graph.edge(node, body, "body");
+            node
+        }
+        &Bound::VariableDecl {
+            name,
+            expression,
+            ref location,
+            ..
+        } => {
+            let node = graph.node(&format!("let {}\n{}", name, binding_label(location)));
+            let expression = add_node(expression, graph);
+            graph.edge(node, expression, "value");
+            node
+        }
+        &Bound::FieldAccess {
+            target, field_name, ..
+        } => {
+            let node = graph.node(&format!(".{}", field_name));
+            let target = add_node(target, graph);
+            graph.edge(node, target, "target");
+            node
+        }
+        &Bound::BlockExpr {
+            ref statements,
+            final_expression,
+            ..
+        } => {
+            let node = graph.node("block");
+            for (i, statement) in statements.iter().enumerate() {
+                let statement = add_node(statement, graph);
+                graph.edge(node, statement, &i.to_string());
+            }
+            let result = add_node(final_expression, graph);
+            graph.edge(node, result, "result");
+            node
+        }
+        &Bound::Module {
+            ref statements,
+            ref binder,
+            ..
+        } => {
+            let node = graph.node(&format!("module {}", binder.module_id));
+            for (i, statement) in statements.iter().enumerate() {
+                let statement = add_node(statement, graph);
+                graph.edge(node, statement, &i.to_string());
+            }
+            node
+        }
+    }
+}
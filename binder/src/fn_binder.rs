@@ -1,12 +1,35 @@
 use super::*;
 use std::collections::HashMap;
+use parser::Param;
 
 pub struct FnBinder<'a, 'bound: 'a> {
     parent: &'a mut Binder<'bound>,
     locals: Vec<DeclarationKind<'bound>>,
-    arguments: &'bound [(&'bound str, &'bound Ast<'bound>)],
-    upvars: HashMap<DeclarationKind<'bound>, (BindingKind<'bound>, u32)>,
-    name: &'bound str,
+    arguments: &'bound [Param<'bound>],
+    upvars: Upvars<'bound>,
+    name: Option<&'bound str>,
+    /// The names of the functions in the group this function was declared
+    /// in, if it was declared in one.
+    group: &'a [&'bound str],
+}
+
+impl<'a, 'bound> FnBinder<'a, 'bound> {
+    fn new(
+        parent: &'a mut Binder<'bound>,
+        arguments: &'bound [Param<'bound>],
+        name: Option<&'bound str>,
+        group: &'a [&'bound str],
+        upvars: Upvars<'bound>,
+    ) -> FnBinder<'a, 'bound> {
+        FnBinder {
+            parent,
+            locals: vec![],
+            arguments,
+            upvars,
+            name,
+            group,
+        }
+    }
 }
 
 impl<'a, 'bound> Binder<'bound> for FnBinder<'a, 'bound> {
@@ -16,37 +39,83 @@ impl<'a, 'bound> Binder<'bound> for FnBinder<'a, 'bound> {
         BindingKind::FunctionLocal(pos as u32)
     }
 
-    fn lookup(&mut self, symbol: &DeclarationKind<'bound>) -> Result<BindingKind<'bound>, Error> {
+    fn lookup(&mut self, symbol: &DeclarationKind<'bound>) -> Option<BindingKind<'bound>> {
         if let Some(pos) = self.arguments
             .iter()
-            .rposition(|&(l, _)| &DeclarationKind::Named(l) == symbol)
+            .rposition(|&(l, _, _)| &DeclarationKind::Named(l) == symbol)
         {
-            return Ok(BindingKind::Argument(pos as u32));
+            return Some(BindingKind::Argument(pos as u32));
         }
         if let Some(pos) = self.locals.iter().rposition(|l| l == symbol) {
-            return Ok(BindingKind::FunctionLocal(pos as u32));
+            return Some(BindingKind::FunctionLocal(pos as u32));
         }
         if let Some(&(_, p)) = self.upvars.get(&symbol) {
-            return Ok(BindingKind::Upvar(p));
+            return Some(BindingKind::Upvar(p));
         }
 
-        if &DeclarationKind::Named(self.name) == symbol {
-            return Ok(BindingKind::CurrentFunction);
+        if let Some(name) = self.name {
+            if &DeclarationKind::Named(name) == symbol {
+                return Some(BindingKind::CurrentFunction);
+            }
         }
-
-        if let Ok(bk) = self.parent.lookup(symbol) {
-            let num = self.upvars.len() as u32;
-            self.upvars.insert(symbol.clone(), (bk, num));
-            return Ok(BindingKind::Upvar(num));
+        if let Some(pos) = self.group
+            .iter()
+            .position(|&n| &DeclarationKind::Named(n) == symbol)
+        {
+            return Some(BindingKind::Sibling(pos as u32));
         }
 
-        match symbol {
-            &DeclarationKind::Named(s) => Err(Error::UnboundIdentifier(s.into())),
-            &DeclarationKind::Generated(_, s) => Err(Error::UnboundIdentifier(s.into())),
+        let bk = self.parent.lookup(symbol)?;
+        let num = self.upvars.len() as u32;
+        self.upvars.insert(symbol.clone(), (bk, num));
+        Some(BindingKind::Upvar(num))
+    }
+
+    fn names_in_scope(&self, names: &mut Vec<&'bound str>) {
+        names.extend(self.arguments.iter().map(|&(name, _, _)| name));
+        names.extend(self.locals.iter().map(DeclarationKind::name));
+        let mut upvars = self.upvars.keys().map(DeclarationKind::name).collect::<Vec<_>>();
+        upvars.sort();
+        names.extend(upvars);
+        names.extend(self.name);
+        names.extend(self.group.iter().filter(|&&n| Some(n) != self.name));
+        self.parent.names_in_scope(names);
+    }
+}
+
+/// Reports every parameter that has the same name as an earlier one.
+fn check_params(params: &[Param], binding_state: &mut BindingState) {
+    for (i, &(name, ast, _)) in params.iter().enumerate() {
+        if let Some(&(_, previous, _)) = params[..i].iter().find(|&&(other, _, _)| other == name) {
+            binding_state.error(Error::DuplicateParameter {
+                name: name.into(),
+                span: ast.span(),
+                previous: previous.span(),
+            });
         }
     }
 }
 
+type Upvars<'bound> = HashMap<DeclarationKind<'bound>, (BindingKind<'bound>, u32)>;
+
+type BoundBody<'bound> = (
+    Vec<DeclarationKind<'bound>>,
+    Upvars<'bound>,
+    &'bound Bound<'bound>,
+);
+
+fn bind_function_body<'a, 'bound>(
+    mut binder: FnBinder<'a, 'bound>,
+    arena: &'bound Arena<Bound<'bound>>,
+    binding_state: &mut BindingState,
+
+    body: &'bound Ast<'bound>,
+) -> BoundBody<'bound> {
+    check_params(binder.arguments, binding_state);
+    let body = arena.alloc(bind_node(arena, &mut binder, binding_state, body));
+    (binder.locals, binder.upvars, body)
+}
+
 pub fn bind_function_decl<'bound>(
     parent: &mut Binder<'bound>,
     full_ast: &'bound Ast<'bound>,
@@ -54,23 +123,13 @@ pub fn bind_function_decl<'bound>(
     binding_state: &mut BindingState,
 
     name: &'bound str,
-    params: &'bound [(&'bound str, &'bound Ast<'bound>)],
+    params: &'bound [Param<'bound>],
     body: &'bound Ast<'bound>,
-) -> Result<Bound<'bound>, Error> {
-    let (locals, upvars, body) = {
-        let mut binder = FnBinder {
-            parent: parent,
-            locals: vec![],
-            arguments: &params,
-            upvars: HashMap::new(),
-            name: name,
-        };
+) -> Bound<'bound> {
+    let binder = FnBinder::new(parent, params, Some(name), &[], HashMap::new());
+    let (locals, upvars, body) = bind_function_body(binder, arena, binding_state, body);
 
-        let body = arena.alloc(bind(arena, &mut binder, binding_state, body)?);
-        (binder.locals, binder.upvars, body)
-    };
-
-    return Ok(Bound::FunctionDecl {
+    Bound::FunctionDecl {
         name,
         body,
         locals,
@@ -78,8 +137,87 @@ pub fn bind_function_decl<'bound>(
         ast: full_ast,
         params: params
             .into_iter()
-            .map(|&(n, ast)| (DeclarationKind::Named(n.into()), ast))
+            .map(|&(n, ast, _)| (DeclarationKind::Named(n.into()), ast))
             .collect(),
         location: parent.add_declaration(DeclarationKind::Named(name.into()), binding_state),
-    });
+    }
+}
+
+pub fn bind_anon_func<'bound>(
+    parent: &mut Binder<'bound>,
+    full_ast: &'bound Ast<'bound>,
+    arena: &'bound Arena<Bound<'bound>>,
+    binding_state: &mut BindingState,
+
+    params: &'bound [Param<'bound>],
+    body: &'bound Ast<'bound>,
+) -> Bound<'bound> {
+    let binder = FnBinder::new(parent, params, None, &[], HashMap::new());
+    let (locals, upvars, body) = bind_function_body(binder, arena, binding_state, body);
+
+    Bound::AnonFunc {
+        body,
+        locals,
+        upvars,
+        ast: full_ast,
+        params: params
+            .into_iter()
+            .map(|&(n, ast, _)| (DeclarationKind::Named(n.into()), ast))
+            .collect(),
+    }
+}
+
+/// Binds `declarations`, a run of function declarations, as a group in
+/// which every function can refer to every other.  They are all declared
+/// before any body is bound, and share the upvars that any of them needs,
+/// so that each can build the others from its own upvars.
+pub fn bind_function_group<'bound>(
+    parent: &mut Binder<'bound>,
+    arena: &'bound Arena<Bound<'bound>>,
+    binding_state: &mut BindingState,
+
+    declarations: &[&'bound Ast<'bound>],
+) -> Bound<'bound> {
+    let names = declarations
+        .iter()
+        .map(|declaration| match declaration {
+            &&Ast::FunctionDecl { name, .. } => name,
+            other => panic!("{:?} is not a function declaration", other),
+        })
+        .collect::<Vec<_>>();
+    let locations = names
+        .iter()
+        .map(|&name| parent.add_declaration(DeclarationKind::Named(name), binding_state))
+        .collect::<Vec<_>>();
+
+    let mut upvars = HashMap::new();
+    let mut bodies = vec![];
+    for (&full_ast, &name) in declarations.iter().zip(&names) {
+        if let &Ast::FunctionDecl { params, body, .. } = full_ast {
+            let binder = FnBinder::new(&mut *parent, params, Some(name), &names, upvars);
+            let (locals, all_upvars, body) = bind_function_body(binder, arena, binding_state, body);
+            upvars = all_upvars;
+            bodies.push((full_ast, name, params, locals, body));
+        }
+    }
+
+    let functions = bodies
+        .into_iter()
+        .zip(locations)
+        .map(
+            |((full_ast, name, params, locals, body), location)| Bound::FunctionDecl {
+                name,
+                body,
+                locals,
+                upvars: upvars.clone(),
+                ast: full_ast,
+                params: params
+                    .iter()
+                    .map(|&(n, ast, _)| (DeclarationKind::Named(n), ast))
+                    .collect(),
+                location,
+            },
+        )
+        .collect();
+    Bound::FunctionGroup { functions }
 }
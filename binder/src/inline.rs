@@ -0,0 +1,738 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use typed_arena::Arena;
+use parser::{Ast, TypePtr};
+use super::{BindingKind, Bound, DeclarationKind};
+
+// Replaces calls to small module functions with their bodies.
+//
+// A call `f(a, b)` becomes the block `{ let x = a; let y = b; <body> }`,
+// where `x` and `y` are fresh `Generated` declarations in the caller's
+// frame standing in for the parameters, and every local of `f` gets a
+// fresh declaration there too.  Arguments are still evaluated once and in
+// order, and nothing in the body can capture a name of the caller.  The
+// declarations keep the parameters' type annotations, and an annotated
+// result is stored in one more declaration, so every value is checked as
+// it would have been on the way into and out of `f`.
+//
+// Only functions that capture nothing but module definitions can be moved,
+// since their body has to run in someone else's frame.  A definition that
+// is declared once has the same value whenever it can be read, so reading
+// it where the call was is the same as capturing it.  A function that
+// refers to itself never qualifies, which also rules out recursion.
+//
+// A call is only inlined when the statement containing it comes after the
+// function's declaration: any function built there can only run once the
+// module has added the callee, so inlining never turns a missing
+// definition into a working call.
+
+/// The largest body, counted in bound nodes, that gets inlined.
+pub const MAX_INLINE_SIZE: usize = 24;
+
+struct Callee<'b> {
+    params: Vec<(DeclarationKind<'b>, Option<TypePtr<'b>>)>,
+    result: Option<TypePtr<'b>>,
+    upvars: Vec<BindingKind<'b>>,
+    locals: Vec<DeclarationKind<'b>>,
+    body: &'b Bound<'b>,
+}
+
+/// Where the declarations that stand in for a callee's parameters and
+/// locals are added.
+enum Frame<'b> {
+    /// An expression outside of any module or function, which has nowhere
+    /// to declare anything.
+    Nowhere,
+    Module {
+        module_id: &'b str,
+        added: Vec<DeclarationKind<'b>>,
+    },
+    Function {
+        locals_count: u32,
+        /// The module definition behind each upvar, if there is one.
+        upvars: Vec<Option<BindingKind<'b>>>,
+        added: Vec<DeclarationKind<'b>>,
+    },
+}
+
+impl<'b> Frame<'b> {
+    fn declare(&mut self, symbol: DeclarationKind<'b>) -> BindingKind<'b> {
+        match self {
+            &mut Frame::Nowhere => unreachable!(),
+            &mut Frame::Module {
+                module_id,
+                ref mut added,
+            } => {
+                added.push(symbol.clone());
+                BindingKind::Module {
+                    module_id,
+                    symbol: Rc::new(symbol),
+                }
+            }
+            &mut Frame::Function {
+                locals_count,
+                ref mut added,
+                ..
+            } => {
+                added.push(symbol);
+                BindingKind::FunctionLocal(locals_count + added.len() as u32 - 1)
+            }
+        }
+    }
+
+    /// The module definition that `kind` reads here, if it reads one.
+    fn module_definition(&self, kind: &BindingKind<'b>) -> Option<BindingKind<'b>> {
+        match (kind, self) {
+            (&BindingKind::Module { .. }, _) => Some(kind.clone()),
+            (&BindingKind::Upvar(n), &Frame::Function { ref upvars, .. }) => {
+                upvars[n as usize].clone()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Inlines calls to small, non-recursive module functions that capture
+/// nothing.
+pub fn inline<'b>(arena: &'b Arena<Bound<'b>>, node: &Bound<'b>) -> Bound<'b> {
+    let mut inliner = Inliner {
+        arena,
+        known: HashMap::new(),
+        declared: HashMap::new(),
+        next_id: max_generated_id(node) + 1,
+    };
+    inliner.rewrite(node, &mut Frame::Nowhere)
+}
+
+fn name<'b>(symbol: &DeclarationKind<'b>) -> &'b str {
+    match symbol {
+        &DeclarationKind::Named(name) | &DeclarationKind::Generated(_, name) => name,
+    }
+}
+
+/// The largest id in a `Generated` declaration, so that new ones can't
+/// collide with what the binder handed out.
+fn max_generated_id(node: &Bound) -> u64 {
+    fn id(symbol: &DeclarationKind) -> u64 {
+        match symbol {
+            &DeclarationKind::Generated(id, _) => id,
+            &DeclarationKind::Named(_) => 0,
+        }
+    }
+
+    let mut max = 0;
+    each_node(node, 0, &mut |node, _| {
+        let found = match node {
+            &Bound::FunctionDecl { ref locals, .. } | &Bound::AnonFunc { ref locals, .. } => {
+                locals.iter().map(id).max()
+            }
+            &Bound::Module { ref binder, .. } => binder.definitions.iter().map(id).max(),
+            _ => None,
+        };
+        max = max.max(found.unwrap_or(0));
+    });
+    max
+}
+
+/// Calls `f` on `node` and every node below it, including the bodies of
+/// nested functions, along with how many functions deep each node is.
+fn each_node<'b, F>(node: &Bound<'b>, depth: usize, f: &mut F)
+where
+    F: FnMut(&Bound<'b>, usize),
+{
+    f(node, depth);
+    match node {
+        &Bound::Integer { .. } | &Bound::Float { .. } | &Bound::Identifier { .. } => {}
+        &Bound::DebugCall { arg, .. } => each_node(arg, depth, f),
+        &Bound::FunctionCall {
+            target, ref args, ..
+        } => {
+            each_node(target, depth, f);
+            for arg in args {
+                each_node(arg, depth, f);
+            }
+        }
+        &Bound::Add { left, right, .. }
+        | &Bound::Sub { left, right, .. }
+        | &Bound::Div { left, right, .. }
+        | &Bound::Mul { left, right, .. } => {
+            each_node(left, depth, f);
+            each_node(right, depth, f);
+        }
+        &Bound::FunctionDecl { body, .. } | &Bound::AnonFunc { body, .. } => {
+            each_node(body, depth + 1, f)
+        }
+        &Bound::VariableDecl { expression, .. } => each_node(expression, depth, f),
+        &Bound::FieldAccess { target, .. } => each_node(target, depth, f),
+        &Bound::BlockExpr {
+            ref statements,
+            final_expression,
+            ..
+        } => {
+            for statement in statements {
+                each_node(statement, depth, f);
+            }
+            each_node(final_expression, depth, f);
+        }
+        &Bound::Module { ref statements, .. } => for statement in statements {
+            each_node(statement, depth, f);
+        },
+        &Bound::FunctionGroup { ref functions } => for function in functions {
+            each_node(function, depth, f);
+        },
+    }
+}
+
+/// Whether `body` is small enough to inline and never refers to the
+/// function it belongs to or to the group it was declared in, either
+/// directly or by being captured.
+fn is_inlinable(body: &Bound) -> bool {
+    let mut size = 0;
+    let mut refers_to_itself = false;
+    each_node(body, 0, &mut |node, depth| {
+        size += 1;
+        if depth > 0 {
+            return;
+        }
+        let is_self = |kind: &BindingKind| match kind {
+            &BindingKind::CurrentFunction | &BindingKind::Sibling(_) => true,
+            _ => false,
+        };
+        refers_to_itself |= match node {
+            &Bound::Identifier {
+                ref binding_kind, ..
+            } => is_self(binding_kind),
+            &Bound::FunctionDecl { ref upvars, .. } | &Bound::AnonFunc { ref upvars, .. } => {
+                upvars.values().any(|&(ref kind, _)| is_self(kind))
+            }
+            _ => false,
+        };
+    });
+    !refers_to_itself && size <= MAX_INLINE_SIZE
+}
+
+/// Counts how often each module definition is declared by the statements
+/// of a module.  A definition declared twice depends on which one ran last.
+fn declaration_counts<'b>(statements: &[Bound<'b>]) -> HashMap<Rc<DeclarationKind<'b>>, usize> {
+    fn count<'b>(statement: &Bound<'b>, counts: &mut HashMap<Rc<DeclarationKind<'b>>, usize>) {
+        match statement {
+            &Bound::FunctionDecl {
+                location: BindingKind::Module { ref symbol, .. },
+                ..
+            }
+            | &Bound::VariableDecl {
+                location: BindingKind::Module { ref symbol, .. },
+                ..
+            } => *counts.entry(symbol.clone()).or_insert(0) += 1,
+            &Bound::FunctionGroup { ref functions } => for function in functions {
+                count(function, counts);
+            },
+            _ => {}
+        }
+    }
+
+    let mut counts = HashMap::new();
+    for statement in statements {
+        count(statement, &mut counts);
+    }
+    counts
+}
+
+/// The binding kinds that a callee's arguments and locals are moved to.
+struct Renaming<'b> {
+    args: Vec<BindingKind<'b>>,
+    upvars: Vec<BindingKind<'b>>,
+    locals: Vec<BindingKind<'b>>,
+}
+
+impl<'b> Renaming<'b> {
+    fn rename(&self, kind: &BindingKind<'b>) -> BindingKind<'b> {
+        match kind {
+            &BindingKind::Argument(n) => self.args[n as usize].clone(),
+            &BindingKind::Upvar(n) => self.upvars[n as usize].clone(),
+            &BindingKind::FunctionLocal(n) => self.locals[n as usize].clone(),
+            other => other.clone(),
+        }
+    }
+
+    fn rename_upvars(
+        &self,
+        upvars: &HashMap<DeclarationKind<'b>, (BindingKind<'b>, u32)>,
+    ) -> HashMap<DeclarationKind<'b>, (BindingKind<'b>, u32)> {
+        upvars
+            .iter()
+            .map(|(symbol, &(ref kind, pos))| (symbol.clone(), (self.rename(kind), pos)))
+            .collect()
+    }
+}
+
+struct Inliner<'b> {
+    arena: &'b Arena<Bound<'b>>,
+    known: HashMap<Rc<DeclarationKind<'b>>, Callee<'b>>,
+    /// How often the module declares each of its definitions.
+    declared: HashMap<Rc<DeclarationKind<'b>>, usize>,
+    next_id: u64,
+}
+
+impl<'b> Inliner<'b> {
+    fn alloc(&self, node: Bound<'b>) -> &'b Bound<'b> {
+        self.arena.alloc(node)
+    }
+
+    fn generate(&mut self, symbol: &DeclarationKind<'b>) -> DeclarationKind<'b> {
+        self.next_id += 1;
+        DeclarationKind::Generated(self.next_id - 1, name(symbol))
+    }
+
+    /// Remembers `statement` if it declares a module function that can be
+    /// inlined.
+    fn learn(&mut self, statement: &Bound<'b>) {
+        if let &Bound::FunctionDecl {
+            ref params,
+            body,
+            ref locals,
+            ref upvars,
+            ast,
+            location: BindingKind::Module { ref symbol, .. },
+            ..
+        } = statement
+        {
+            let (annotations, result) = match ast {
+                &Ast::FunctionDecl {
+                    params, result, ..
+                } => (params, result),
+                _ => unreachable!(),
+            };
+            let declared_once = |symbol: &Rc<DeclarationKind<'b>>| {
+                self.declared.get(symbol) == Some(&1)
+            };
+            let mut captured = upvars.values().collect::<Vec<_>>();
+            captured.sort_by_key(|&&(_, pos)| pos);
+            let captures_definitions = captured.iter().all(|&&(ref kind, _)| match kind {
+                &BindingKind::Module { ref symbol, .. } => declared_once(symbol),
+                _ => false,
+            });
+
+            if declared_once(symbol) && captures_definitions && is_inlinable(body) {
+                let callee = Callee {
+                    params: params
+                        .iter()
+                        .zip(annotations)
+                        .map(|(&(ref param, _), &(_, _, ty))| (param.clone(), ty))
+                        .collect(),
+                    result,
+                    upvars: captured.iter().map(|&&(ref kind, _)| kind.clone()).collect(),
+                    locals: locals.clone(),
+                    body,
+                };
+                self.known.insert(symbol.clone(), callee);
+            }
+        }
+    }
+
+    fn rewrite_function_body(
+        &mut self,
+        body: &'b Bound<'b>,
+        locals: &[DeclarationKind<'b>],
+        upvars: &HashMap<DeclarationKind<'b>, (BindingKind<'b>, u32)>,
+        parent: &Frame<'b>,
+    ) -> (&'b Bound<'b>, Vec<DeclarationKind<'b>>) {
+        let mut resolved = vec![None; upvars.len()];
+        for &(ref kind, pos) in upvars.values() {
+            resolved[pos as usize] = parent.module_definition(kind);
+        }
+        let mut frame = Frame::Function {
+            locals_count: locals.len() as u32,
+            upvars: resolved,
+            added: vec![],
+        };
+        let body = self.rewrite(body, &mut frame);
+        let mut locals = locals.to_vec();
+        if let Frame::Function { added, .. } = frame {
+            locals.extend(added);
+        }
+        (self.alloc(body), locals)
+    }
+
+    /// Replaces the call `node` with the body of the function it calls, if
+    /// that is known and takes as many arguments as the call passes.
+    fn inline_call(
+        &mut self,
+        node: &Bound<'b>,
+        args: Vec<Bound<'b>>,
+        frame: &mut Frame<'b>,
+    ) -> Result<Bound<'b>, Vec<Bound<'b>>> {
+        let (ast, definition) = match (node, &*frame) {
+            (_, &Frame::Nowhere) => return Err(args),
+            (
+                &Bound::FunctionCall {
+                    ast,
+                    target:
+                        &Bound::Identifier {
+                            ref binding_kind, ..
+                        },
+                    ..
+                },
+                frame,
+            ) => (ast, frame.module_definition(binding_kind)),
+            _ => return Err(args),
+        };
+        let callee = match definition {
+            Some(BindingKind::Module { ref symbol, .. }) => self.known.get(symbol),
+            _ => None,
+        };
+        let (params, result, upvars, callee_locals, body) = match callee {
+            Some(callee) if callee.params.len() == args.len() => (
+                callee.params.clone(),
+                callee.result,
+                callee.upvars.clone(),
+                callee.locals.clone(),
+                callee.body,
+            ),
+            _ => return Err(args),
+        };
+
+        let mut statements = vec![];
+        let mut renaming = Renaming {
+            args: vec![],
+            upvars,
+            locals: vec![],
+        };
+        for (&(ref param, ty), arg) in params.iter().zip(args) {
+            let location = {
+                let param = self.generate(param);
+                frame.declare(param)
+            };
+            renaming.args.push(location.clone());
+            statements.push(Bound::VariableDecl {
+                name: name(param),
+                ast,
+                expression_ast: ast,
+                expression: self.alloc(arg),
+                ty,
+                location,
+            });
+        }
+        for local in &callee_locals {
+            let local = self.generate(local);
+            renaming.locals.push(frame.declare(local));
+        }
+
+        let mut body = self.substitute(body, &renaming);
+        if result.is_some() {
+            let location = {
+                let result = self.generate(&DeclarationKind::Named("result"));
+                frame.declare(result)
+            };
+            statements.push(Bound::VariableDecl {
+                name: "result",
+                ast,
+                expression_ast: ast,
+                expression: self.alloc(body),
+                ty: result,
+                location: location.clone(),
+            });
+            body = Bound::Identifier {
+                ast,
+                ident: "result",
+                binding_kind: location,
+            };
+        }
+        if statements.is_empty() {
+            return Ok(body);
+        }
+        Ok(Bound::BlockExpr {
+            statements,
+            ast,
+            final_expression: self.alloc(body),
+        })
+    }
+
+    /// Moves a piece of a callee's body into the caller's frame.  Nested
+    /// functions keep their own frames and only change what they capture.
+    fn substitute(&mut self, node: &Bound<'b>, renaming: &Renaming<'b>) -> Bound<'b> {
+        match node {
+            &Bound::Integer { .. } | &Bound::Float { .. } => node.clone(),
+            &Bound::Identifier {
+                ast,
+                ident,
+                ref binding_kind,
+            } => Bound::Identifier {
+                ast,
+                ident,
+                binding_kind: renaming.rename(binding_kind),
+            },
+            &Bound::DebugCall { ast, arg } => {
+                let arg = self.substitute(arg, renaming);
+                Bound::DebugCall {
+                    ast,
+                    arg: self.alloc(arg),
+                }
+            }
+            &Bound::FunctionCall {
+                ast,
+                target,
+                ref args,
+            } => {
+                let target = self.substitute(target, renaming);
+                Bound::FunctionCall {
+                    ast,
+                    target: self.alloc(target),
+                    args: args.iter()
+                        .map(|arg| self.substitute(arg, renaming))
+                        .collect(),
+                }
+            }
+            &Bound::Add { left, right, .. }
+            | &Bound::Sub { left, right, .. }
+            | &Bound::Div { left, right, .. }
+            | &Bound::Mul { left, right, .. } => {
+                let left = self.substitute(left, renaming);
+                let right = self.substitute(right, renaming);
+                node.with_operands(self.alloc(left), self.alloc(right))
+            }
+            &Bound::FunctionDecl {
+                name,
+                ref params,
+                body,
+                ref locals,
+                ref upvars,
+                ast,
+                ref location,
+            } => Bound::FunctionDecl {
+                name,
+                params: params.clone(),
+                body,
+                locals: locals.clone(),
+                upvars: renaming.rename_upvars(upvars),
+                ast,
+                location: renaming.rename(location),
+            },
+            &Bound::AnonFunc {
+                ref params,
+                body,
+                ref locals,
+                ref upvars,
+                ast,
+            } => Bound::AnonFunc {
+                params: params.clone(),
+                body,
+                locals: locals.clone(),
+                upvars: renaming.rename_upvars(upvars),
+                ast,
+            },
+            &Bound::VariableDecl {
+                name,
+                ast,
+                expression_ast,
+                expression,
+                ty,
+                ref location,
+            } => {
+                let expression = self.substitute(expression, renaming);
+                Bound::VariableDecl {
+                    name,
+                    ast,
+                    expression_ast,
+                    expression: self.alloc(expression),
+                    ty,
+                    location: renaming.rename(location),
+                }
+            }
+            &Bound::FieldAccess {
+                target_ast,
+                field_ast,
+                field_name,
+                target,
+            } => {
+                let target = self.substitute(target, renaming);
+                Bound::FieldAccess {
+                    target_ast,
+                    field_ast,
+                    field_name,
+                    target: self.alloc(target),
+                }
+            }
+            &Bound::BlockExpr {
+                ref statements,
+                ast,
+                final_expression,
+            } => {
+                let final_expression = self.substitute(final_expression, renaming);
+                Bound::BlockExpr {
+                    statements: statements
+                        .iter()
+                        .map(|statement| self.substitute(statement, renaming))
+                        .collect(),
+                    ast,
+                    final_expression: self.alloc(final_expression),
+                }
+            }
+            &Bound::FunctionGroup { ref functions } => Bound::FunctionGroup {
+                functions: functions
+                    .iter()
+                    .map(|function| self.substitute(function, renaming))
+                    .collect(),
+            },
+            &Bound::Module { .. } => unreachable!("a module inside a function"),
+        }
+    }
+
+    fn rewrite(&mut self, node: &Bound<'b>, frame: &mut Frame<'b>) -> Bound<'b> {
+        match node {
+            &Bound::Integer { .. } | &Bound::Float { .. } | &Bound::Identifier { .. } => {
+                node.clone()
+            }
+            &Bound::DebugCall { ast, arg } => {
+                let arg = self.rewrite(arg, frame);
+                Bound::DebugCall {
+                    ast,
+                    arg: self.alloc(arg),
+                }
+            }
+            &Bound::FunctionCall {
+                ast,
+                target,
+                ref args,
+            } => {
+                let args = args.iter().map(|arg| self.rewrite(arg, frame)).collect();
+                match self.inline_call(node, args, frame) {
+                    Ok(inlined) => inlined,
+                    Err(args) => {
+                        let target = self.rewrite(target, frame);
+                        Bound::FunctionCall {
+                            ast,
+                            target: self.alloc(target),
+                            args,
+                        }
+                    }
+                }
+            }
+            &Bound::Add { left, right, .. }
+            | &Bound::Sub { left, right, .. }
+            | &Bound::Div { left, right, .. }
+            | &Bound::Mul { left, right, .. } => {
+                let left = self.rewrite(left, frame);
+                let right = self.rewrite(right, frame);
+                node.with_operands(self.alloc(left), self.alloc(right))
+            }
+            &Bound::FunctionDecl {
+                name,
+                ref params,
+                body,
+                ref locals,
+                ref upvars,
+                ast,
+                ref location,
+            } => {
+                let (body, locals) = self.rewrite_function_body(body, locals, upvars, frame);
+                Bound::FunctionDecl {
+                    name,
+                    params: params.clone(),
+                    body,
+                    locals,
+                    upvars: upvars.clone(),
+                    ast,
+                    location: location.clone(),
+                }
+            }
+            &Bound::FunctionGroup { ref functions } => Bound::FunctionGroup {
+                functions: functions
+                    .iter()
+                    .map(|function| self.rewrite(function, frame))
+                    .collect(),
+            },
+            &Bound::AnonFunc {
+                ref params,
+                body,
+                ref locals,
+                ref upvars,
+                ast,
+            } => {
+                let (body, locals) = self.rewrite_function_body(body, locals, upvars, frame);
+                Bound::AnonFunc {
+                    params: params.clone(),
+                    body,
+                    locals,
+                    upvars: upvars.clone(),
+                    ast,
+                }
+            }
+            &Bound::VariableDecl {
+                name,
+                ast,
+                expression_ast,
+                expression,
+                ty,
+                ref location,
+            } => {
+                let expression = self.rewrite(expression, frame);
+                Bound::VariableDecl {
+                    name,
+                    ast,
+                    expression_ast,
+                    expression: self.alloc(expression),
+                    ty,
+                    location: location.clone(),
+                }
+            }
+            &Bound::FieldAccess {
+                target_ast,
+                field_ast,
+                field_name,
+                target,
+            } => {
+                let target = self.rewrite(target, frame);
+                Bound::FieldAccess {
+                    target_ast,
+                    field_ast,
+                    field_name,
+                    target: self.alloc(target),
+                }
+            }
+            &Bound::BlockExpr {
+                ref statements,
+                ast,
+                final_expression,
+            } => {
+                let statements = statements
+                    .iter()
+                    .map(|statement| self.rewrite(statement, frame))
+                    .collect();
+                let final_expression = self.rewrite(final_expression, frame);
+                Bound::BlockExpr {
+                    statements,
+                    ast,
+                    final_expression: self.alloc(final_expression),
+                }
+            }
+            &Bound::Module {
+                ast,
+                ref statements,
+                ref binder,
+            } => {
+                self.declared = declaration_counts(statements);
+                let mut frame = Frame::Module {
+                    module_id: binder.module_id,
+                    added: vec![],
+                };
+                let mut rewritten = vec![];
+                for statement in statements {
+                    let statement = self.rewrite(statement, &mut frame);
+                    self.learn(&statement);
+                    rewritten.push(statement);
+                }
+
+                let mut binder = binder.clone();
+                if let Frame::Module { added, .. } = frame {
+                    binder.definitions.extend(added);
+                }
+                Bound::Module {
+                    ast,
+                    statements: rewritten,
+                    binder,
+                }
+            }
+        }
+    }
+}
@@ -1,40 +1,74 @@
+extern crate graphviz;
 extern crate lexer;
 extern crate parser;
 extern crate typed_arena;
+extern crate vm;
+#[cfg(test)]
+extern crate copy_arena;
+#[cfg(test)]
+extern crate ast_lower;
 
 mod fn_binder;
 mod module_binder;
 mod block_binder;
 mod buck_stops_here_binder;
+pub mod dot;
+pub mod inline;
+pub mod lint;
+pub mod optimize;
+pub mod slots;
+mod suggest;
 #[cfg(test)]
 mod test;
 
 use std::rc::Rc;
 use std::collections::{HashMap, HashSet};
-use parser::Ast;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use lexer::Span;
+use parser::{function_group, ArgumentSyntax, Ast, TypePtr};
 use typed_arena::Arena;
 
 pub use module_binder::ModuleBinder;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum BindingKind<'bound> {
     FunctionLocal(u32),
     Argument(u32),
     Upvar(u32),
     CurrentFunction,
+    /// Another function of the `FunctionGroup` that the current function
+    /// was declared in, by its position in the group.
+    Sibling(u32),
     Module {
         module_id: &'bound str,
         symbol: Rc<DeclarationKind<'bound>>,
     },
 }
 
+#[derive(Default)]
 pub struct BindingState {
     pub gen_id: u64,
+    errors: Vec<Error>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Error {
-    UnboundIdentifier(String),
+    UnboundIdentifier {
+        name: String,
+        span: Span,
+        /// The closest name in scope, if one is close enough to be a typo.
+        suggestion: Option<String>,
+    },
+    DuplicateParameter {
+        name: String,
+        span: Span,
+        previous: Span,
+    },
+    DuplicateModuleDefinition {
+        name: String,
+        span: Span,
+        previous: Span,
+    },
 }
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
@@ -49,10 +83,13 @@ pub trait Binder<'bound> {
         symbol: DeclarationKind<'bound>,
         &mut BindingState,
     ) -> BindingKind<'bound>;
-    fn lookup(&mut self, symbol: &DeclarationKind<'bound>) -> Result<BindingKind<'bound>, Error>;
+    fn lookup(&mut self, symbol: &DeclarationKind<'bound>) -> Option<BindingKind<'bound>>;
+    /// Adds the names that `lookup` can resolve to `names`, innermost
+    /// binder first.
+    fn names_in_scope(&self, names: &mut Vec<&'bound str>);
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Bound<'bound> {
     Integer {
         ast: &'bound Ast<'bound>,
@@ -76,31 +113,29 @@ pub enum Bound<'bound> {
         target: &'bound Bound<'bound>,
         args: Vec<Bound<'bound>>,
     },
-    Pipeline {
-        ast_left: &'bound Ast<'bound>,
-        ast_right: &'bound Ast<'bound>,
-        left: &'bound Bound<'bound>,
-        right: &'bound Bound<'bound>,
-    },
     Add {
+        ast: &'bound Ast<'bound>,
         ast_left: &'bound Ast<'bound>,
         ast_right: &'bound Ast<'bound>,
         left: &'bound Bound<'bound>,
         right: &'bound Bound<'bound>,
     },
     Sub {
+        ast: &'bound Ast<'bound>,
         ast_left: &'bound Ast<'bound>,
         ast_right: &'bound Ast<'bound>,
         left: &'bound Bound<'bound>,
         right: &'bound Bound<'bound>,
     },
     Div {
+        ast: &'bound Ast<'bound>,
         ast_left: &'bound Ast<'bound>,
         ast_right: &'bound Ast<'bound>,
         left: &'bound Bound<'bound>,
         right: &'bound Bound<'bound>,
     },
     Mul {
+        ast: &'bound Ast<'bound>,
         ast_left: &'bound Ast<'bound>,
         ast_right: &'bound Ast<'bound>,
         left: &'bound Bound<'bound>,
@@ -115,10 +150,26 @@ pub enum Bound<'bound> {
         ast: &'bound Ast<'bound>,
         location: BindingKind<'bound>,
     },
+    AnonFunc {
+        params: Vec<(DeclarationKind<'bound>, &'bound Ast<'bound>)>,
+        body: &'bound Bound<'bound>,
+        locals: Vec<DeclarationKind<'bound>>,
+        upvars: HashMap<DeclarationKind<'bound>, (BindingKind<'bound>, u32)>,
+        ast: &'bound Ast<'bound>,
+    },
+    /// Function declarations that follow each other, where one refers to
+    /// one declared after it that would otherwise not be bound, so each is
+    /// in scope in all of their bodies.  Every one is a `FunctionDecl` with
+    /// the same upvars, and refers to the others as `BindingKind::Sibling`.
+    FunctionGroup { functions: Vec<Bound<'bound>> },
     VariableDecl {
         name: &'bound str,
+        ast: &'bound Ast<'bound>,
         expression_ast: &'bound Ast<'bound>,
         expression: &'bound Bound<'bound>,
+        /// The type the value is annotated with, which is checked before it
+        /// is stored.
+        ty: Option<TypePtr<'bound>>,
         location: BindingKind<'bound>,
     },
     FieldAccess {
@@ -139,9 +190,150 @@ pub enum Bound<'bound> {
     },
 }
 
+impl<'bound> DeclarationKind<'bound> {
+    /// The name as it was written in the source.
+    pub fn name(&self) -> &'bound str {
+        match self {
+            &DeclarationKind::Named(name) | &DeclarationKind::Generated(_, name) => name,
+        }
+    }
+}
+
+impl<'bound> Bound<'bound> {
+    /// The span of source text this node was bound from.
+    pub fn span(&self) -> Span {
+        match self {
+            &Bound::Integer { ast, .. }
+            | &Bound::Float { ast, .. }
+            | &Bound::Identifier { ast, .. }
+            | &Bound::DebugCall { ast, .. }
+            | &Bound::FunctionCall { ast, .. }
+            | &Bound::FunctionDecl { ast, .. }
+            | &Bound::AnonFunc { ast, .. }
+            | &Bound::VariableDecl { ast, .. }
+            | &Bound::BlockExpr { ast, .. }
+            | &Bound::Module { ast, .. }
+            | &Bound::Add { ast, .. }
+            | &Bound::Sub { ast, .. }
+            | &Bound::Div { ast, .. }
+            | &Bound::Mul { ast, .. } => ast.span(),
+            &Bound::FieldAccess {
+                target_ast,
+                field_ast,
+                ..
+            } => target_ast.span().to(field_ast.span()),
+            &Bound::FunctionGroup { ref functions } => {
+                let first = functions.first().expect("empty function group");
+                let last = functions.last().expect("empty function group");
+                first.span().to(last.span())
+            }
+        }
+    }
+
+    /// A copy of this arithmetic node with new operands.
+    pub(crate) fn with_operands(
+        &self,
+        left: &'bound Bound<'bound>,
+        right: &'bound Bound<'bound>,
+    ) -> Bound<'bound> {
+        match self.clone() {
+            Bound::Add {
+                ast,
+                ast_left,
+                ast_right,
+                ..
+            } => Bound::Add {
+                ast,
+                ast_left,
+                ast_right,
+                left,
+                right,
+            },
+            Bound::Sub {
+                ast,
+                ast_left,
+                ast_right,
+                ..
+            } => Bound::Sub {
+                ast,
+                ast_left,
+                ast_right,
+                left,
+                right,
+            },
+            Bound::Mul {
+                ast,
+                ast_left,
+                ast_right,
+                ..
+            } => Bound::Mul {
+                ast,
+                ast_left,
+                ast_right,
+                left,
+                right,
+            },
+            Bound::Div {
+                ast,
+                ast_left,
+                ast_right,
+                ..
+            } => Bound::Div {
+                ast,
+                ast_left,
+                ast_right,
+                left,
+                right,
+            },
+            other => panic!("{:?} has no operands", other),
+        }
+    }
+}
+
+impl Error {
+    pub fn span(&self) -> Span {
+        match self {
+            &Error::UnboundIdentifier { span, .. }
+            | &Error::DuplicateParameter { span, .. }
+            | &Error::DuplicateModuleDefinition { span, .. } => span,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &Error::UnboundIdentifier {
+                ref name,
+                ref suggestion,
+                ..
+            } => {
+                write!(f, "`{}` is not bound", name)?;
+                if let &Some(ref suggestion) = suggestion {
+                    write!(f, ", did you mean `{}`?", suggestion)?;
+                }
+                Ok(())
+            }
+            &Error::DuplicateParameter { ref name, .. } => {
+                write!(f, "the parameter `{}` is declared twice", name)
+            }
+            &Error::DuplicateModuleDefinition { ref name, .. } => {
+                write!(f, "`{}` is already defined in this module", name)
+            }
+        }
+    }
+}
+
 impl BindingState {
-    fn new() -> BindingState {
-        BindingState { gen_id: 0 }
+    pub fn new() -> BindingState {
+        BindingState {
+            gen_id: 0,
+            errors: vec![],
+        }
+    }
+
+    pub(crate) fn error(&mut self, error: Error) {
+        self.errors.push(error);
     }
 
     pub fn gen_id(&mut self) -> u64 {
@@ -153,113 +345,152 @@ impl BindingState {
 pub fn bind_top<'bound>(
     arena: &'bound Arena<Bound<'bound>>,
     ast: &'bound Ast<'bound>,
-) -> Result<Bound<'bound>, Error> {
+) -> Result<Bound<'bound>, Vec<Error>> {
     let mut top_binder = buck_stops_here_binder::BuckStopsHereBinder;
     let mut binding_state = BindingState::new();
     bind(arena, &mut top_binder, &mut binding_state, ast)
 }
 
+/// Binds `ast`, returning every error found in it if there are any.  The
+/// tree must have been desugared by `ast_lower::desugar`, so that it has
+/// no pipelines or `_` arguments left.
 pub fn bind<'bound>(
     arena: &'bound Arena<Bound<'bound>>,
     binder: &mut Binder<'bound>,
     binding_state: &mut BindingState,
     ast: &'bound Ast<'bound>,
-) -> Result<Bound<'bound>, Error> {
-    let bound = match ast {
+) -> Result<Bound<'bound>, Vec<Error>> {
+    let before = binding_state.errors.len();
+    let bound = bind_node(arena, binder, binding_state, ast);
+    if binding_state.errors.len() > before {
+        // Module definitions are checked before anything is bound, so the
+        // errors are only in source order once sorted.
+        let mut errors = binding_state.errors.split_off(before);
+        errors.sort_by_key(|error| error.span().start);
+        Err(errors)
+    } else {
+        Ok(bound)
+    }
+}
+
+/// Binds `ast`, recording errors in `binding_state` and carrying on past
+/// them so that one pass finds them all.  The tree is thrown away when
+/// there are errors, so wherever one is found any node will do.
+pub(crate) fn bind_node<'bound>(
+    arena: &'bound Arena<Bound<'bound>>,
+    binder: &mut Binder<'bound>,
+    binding_state: &mut BindingState,
+    ast: &'bound Ast<'bound>,
+) -> Bound<'bound> {
+    match ast {
         &Ast::Integer(_, value) => Bound::Integer { ast, value },
         &Ast::Float(_, value) => Bound::Float { ast, value },
         &Ast::Add(ast_left, ast_right) => Bound::Add {
+            ast,
             ast_left,
             ast_right,
-            left: arena.alloc(bind(arena, binder, binding_state, ast_left)?),
-            right: arena.alloc(bind(arena, binder, binding_state, ast_right)?),
+            left: arena.alloc(bind_node(arena, binder, binding_state, ast_left)),
+            right: arena.alloc(bind_node(arena, binder, binding_state, ast_right)),
         },
         &Ast::Sub(ast_left, ast_right) => Bound::Sub {
+            ast,
             ast_left,
             ast_right,
-            left: arena.alloc(bind(arena, binder, binding_state, ast_left)?),
-            right: arena.alloc(bind(arena, binder, binding_state, ast_right)?),
+            left: arena.alloc(bind_node(arena, binder, binding_state, ast_left)),
+            right: arena.alloc(bind_node(arena, binder, binding_state, ast_right)),
         },
         &Ast::Mul(ast_left, ast_right) => Bound::Mul {
+            ast,
             ast_left,
             ast_right,
-            left: arena.alloc(bind(arena, binder, binding_state, ast_left)?),
-            right: arena.alloc(bind(arena, binder, binding_state, ast_right)?),
+            left: arena.alloc(bind_node(arena, binder, binding_state, ast_left)),
+            right: arena.alloc(bind_node(arena, binder, binding_state, ast_right)),
         },
         &Ast::FieldAccess {
             target,
             field,
             field_name,
+            ..
         } => Bound::FieldAccess {
             target_ast: target,
             field_ast: field,
             field_name,
-            target: arena.alloc(bind(arena, binder, binding_state, target)?),
+            target: arena.alloc(bind_node(arena, binder, binding_state, target)),
         },
         &Ast::Div(ast_left, ast_right) => Bound::Div {
+            ast,
             ast_left,
             ast_right,
-            left: arena.alloc(bind(arena, binder, binding_state, ast_left)?),
-            right: arena.alloc(bind(arena, binder, binding_state, ast_right)?),
-        },
-        &Ast::Pipeline(ast_left, ast_right) => Bound::Pipeline {
-            ast_left,
-            ast_right,
-            left: arena.alloc(bind(arena, binder, binding_state, ast_left)?),
-            right: arena.alloc(bind(arena, binder, binding_state, ast_right)?),
+            left: arena.alloc(bind_node(arena, binder, binding_state, ast_left)),
+            right: arena.alloc(bind_node(arena, binder, binding_state, ast_right)),
         },
-        &Ast::Identifier(_, ident) => Bound::Identifier {
+        &Ast::Pipeline(..) => panic!("`|>` is desugared before binding"),
+        &Ast::Identifier(token, ident) => Bound::Identifier {
             ast,
             ident,
-            binding_kind: binder.lookup(&DeclarationKind::Named(ident.into()))?,
+            binding_kind: match binder.lookup(&DeclarationKind::Named(ident.into())) {
+                Some(binding_kind) => binding_kind,
+                None => {
+                    let mut names = vec![];
+                    binder.names_in_scope(&mut names);
+                    binding_state.error(Error::UnboundIdentifier {
+                        name: ident.into(),
+                        span: token.span(),
+                        suggestion: suggest::closest(ident, &names).map(Into::into),
+                    });
+                    BindingKind::CurrentFunction
+                }
+            },
         },
         &Ast::DebugCall(arg) => Bound::DebugCall {
             ast,
-            arg: arena.alloc(bind(arena, binder, binding_state, arg)?),
+            arg: arena.alloc(bind_node(arena, binder, binding_state, arg)),
         },
-        &Ast::FunctionCall { target, ref args } => Bound::FunctionCall {
+        &Ast::FunctionCall {
+            target, ref args, ..
+        } => Bound::FunctionCall {
             ast,
-            target: arena.alloc(bind(arena, binder, binding_state, target)?),
+            target: arena.alloc(bind_node(arena, binder, binding_state, target)),
             args: args.iter()
-                .map(|arg| bind(arena, binder, binding_state, arg))
-                .collect::<Result<Vec<_>, _>>()?,
+                .map(|arg| match arg {
+                    &ArgumentSyntax::Expression(arg) => bind_node(arena, binder, binding_state, arg),
+                    &ArgumentSyntax::Underscore => panic!("`_` is desugared before binding"),
+                })
+                .collect(),
         },
         &Ast::BlockExpr {
             ref statements,
             ref final_expression,
+            ..
         } => {
             let mut block_binder = block_binder::BlockBinder {
                 parent: binder,
                 definitions: HashMap::new(),
             };
             Bound::BlockExpr {
-                statements: statements
-                    .iter()
-                    .map(|a| bind(arena, &mut block_binder, binding_state, a))
-                    .collect::<Result<Vec<_>, _>>()?,
-                final_expression: arena.alloc(bind(
+                statements: bind_statements(arena, &mut block_binder, binding_state, statements),
+                final_expression: arena.alloc(bind_node(
                     arena,
                     &mut block_binder,
                     binding_state,
                     final_expression,
-                )?),
+                )),
                 ast: ast,
             }
         }
         &Ast::Module {
             ref statements,
             module_id,
+            ..
         } => {
+            check_module_definitions(statements, binding_state);
             let mut module_binder = module_binder::ModuleBinder {
                 module_id,
                 definitions: HashSet::new(),
             };
             Bound::Module {
                 ast,
-                statements: statements
-                    .iter()
-                    .map(|stmt| bind(arena, &mut module_binder, binding_state, stmt))
-                    .collect::<Result<Vec<_>, _>>()?,
+                statements: bind_statements(arena, &mut module_binder, binding_state, statements),
                 binder: module_binder,
             }
         }
@@ -267,10 +498,14 @@ pub fn bind<'bound>(
             name,
             name_ast: _,
             expression,
+            ty,
+            ..
         } => Bound::VariableDecl {
             name,
+            ast,
             expression_ast: expression,
-            expression: arena.alloc(bind(arena, binder, binding_state, expression)?),
+            expression: arena.alloc(bind_node(arena, binder, binding_state, expression)),
+            ty,
             location: binder.add_declaration(DeclarationKind::Named(name.into()), binding_state),
         },
         &Ast::FunctionDecl {
@@ -278,8 +513,61 @@ pub fn bind<'bound>(
             ref params,
             body,
             ..
-        } => fn_binder::bind_function_decl(binder, ast, arena, binding_state, name, params, body)?,
-    };
+        } => fn_binder::bind_function_decl(binder, ast, arena, binding_state, name, params, body),
+        &Ast::AnonFunc { ref params, body, .. } => {
+            fn_binder::bind_anon_func(binder, ast, arena, binding_state, params, body)
+        }
+    }
+}
 
-    Ok(bound)
+/// Binds the statements of a block or module in order, except that a run
+/// of function declarations in which one refers to a later one is bound
+/// as a `FunctionGroup`, as `parser::function_group` finds them.
+fn bind_statements<'bound>(
+    arena: &'bound Arena<Bound<'bound>>,
+    binder: &mut Binder<'bound>,
+    binding_state: &mut BindingState,
+    statements: &'bound [&'bound Ast<'bound>],
+) -> Vec<Bound<'bound>> {
+    let mut bound = vec![];
+    let mut in_scope = vec![];
+    binder.names_in_scope(&mut in_scope);
+    let mut rest = statements;
+    while let Some(&statement) = rest.first() {
+        let run = match function_group(rest, &in_scope) {
+            Some(run) => {
+                bound.push(fn_binder::bind_function_group(binder, arena, binding_state, run));
+                run
+            }
+            None => {
+                bound.push(bind_node(arena, binder, binding_state, statement));
+                &rest[..1]
+            }
+        };
+        in_scope.extend(run.iter().filter_map(|s| s.declared_name()));
+        rest = &rest[run.len()..];
+    }
+    bound
+}
+
+/// Reports every name that more than one statement of a module declares.
+fn check_module_definitions(statements: &[&Ast], binding_state: &mut BindingState) {
+    let mut defined: HashMap<&str, Span> = HashMap::new();
+    for statement in statements {
+        let (name, span) = match statement {
+            &&Ast::VariableDecl { name, name_ast, .. }
+            | &&Ast::FunctionDecl { name, name_ast, .. } => (name, name_ast.span()),
+            _ => continue,
+        };
+        match defined.get(name) {
+            Some(&previous) => binding_state.error(Error::DuplicateModuleDefinition {
+                name: name.into(),
+                span,
+                previous,
+            }),
+            None => {
+                defined.insert(name, span);
+            }
+        }
+    }
 }
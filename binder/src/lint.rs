@@ -0,0 +1,397 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use lexer::Span;
+use parser::Ast;
+use super::{BindingKind, Bound, DeclarationKind};
+use super::slots::{count_uses, resolve, Frame, Resolved, Slot};
+
+// Finds code that is probably a mistake in a bound tree.
+//
+// Uses are counted per storage slot, the same way `optimize` counts them: a
+// nested function uses the slots it captures where it is built.  Names starting
+// with `_` are never reported as unused.
+//
+// The language has no conditionals, so a function that calls itself from
+// its body, or calls another function that never returns, never returns
+// either.  Statements after a call to one can never run.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// A variable or function declared in a block is never used.
+    UnusedVariables,
+    /// A function parameter is never used.
+    UnusedParameters,
+    /// A block declares a name that is already in scope.
+    Shadowing,
+    /// A definition at the top level of a module is never used in it.
+    UnusedDefinitions,
+    /// A statement comes after one that never finishes.
+    UnreachableCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// The level of every lint; all of them warn unless told otherwise.
+#[derive(Debug, Clone)]
+pub struct LintLevels {
+    levels: HashMap<Lint, Level>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub lint: Lint,
+    pub level: Level,
+    pub span: Span,
+    pub message: String,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 5] = [
+        Lint::UnusedVariables,
+        Lint::UnusedParameters,
+        Lint::Shadowing,
+        Lint::UnusedDefinitions,
+        Lint::UnreachableCode,
+    ];
+
+    /// The name used to set the level of the lint on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Lint::UnusedVariables => "unused_variables",
+            &Lint::UnusedParameters => "unused_parameters",
+            &Lint::Shadowing => "shadowing",
+            &Lint::UnusedDefinitions => "unused_definitions",
+            &Lint::UnreachableCode => "unreachable_code",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.iter().cloned().find(|lint| lint.name() == name)
+    }
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Level::Allow => "allow",
+            &Level::Warn => "warning",
+            &Level::Deny => "error",
+        }
+    }
+}
+
+impl Default for LintLevels {
+    fn default() -> LintLevels {
+        LintLevels {
+            levels: Lint::ALL.iter().map(|&lint| (lint, Level::Warn)).collect(),
+        }
+    }
+}
+
+impl LintLevels {
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.levels.insert(lint, level);
+    }
+
+    pub fn get(&self, lint: Lint) -> Level {
+        self.levels[&lint]
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{} [{}]", self.message, self.lint.name())
+    }
+}
+
+/// Runs every lint that is not allowed over `bound`, returning what they
+/// found in source order.
+pub fn lint(bound: &Bound, levels: &LintLevels) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        levels,
+        diagnostics: vec![],
+        declarations: vec![],
+        never_return: HashSet::new(),
+        frames: vec![],
+        names: vec![],
+        group: vec![],
+    };
+    linter.walk(bound);
+
+    let uses = count_uses(bound);
+    let declarations = ::std::mem::take(&mut linter.declarations);
+    for (slot, lint, name, span) in declarations {
+        if !name.starts_with('_') && !uses.contains_key(&slot) {
+            let message = match lint {
+                Lint::UnusedParameters => format!("the parameter `{}` is never used", name),
+                _ => format!("`{}` is never used", name),
+            };
+            linter.report(lint, span, message);
+        }
+    }
+    linter
+        .diagnostics
+        .sort_by_key(|d| (d.span.start, d.span.end));
+    linter.diagnostics
+}
+
+struct Linter<'a, 'b: 'a> {
+    levels: &'a LintLevels,
+    diagnostics: Vec<Diagnostic>,
+    declarations: Vec<(Slot<'b>, Lint, &'b str, Span)>,
+    /// The slots holding functions that never return.
+    never_return: HashSet<Slot<'b>>,
+    frames: Vec<Frame<'a, 'b>>,
+    /// The names in scope, innermost last.
+    names: Vec<&'b str>,
+    /// The locations of the group that the next function walked belongs
+    /// to, if it belongs to one.
+    group: Vec<BindingKind<'b>>,
+}
+
+fn name_span(ast: &Ast) -> Span {
+    match ast {
+        &Ast::VariableDecl { name_ast, .. } | &Ast::FunctionDecl { name_ast, .. } => {
+            name_ast.span()
+        }
+        other => other.span(),
+    }
+}
+
+impl<'a, 'b> Linter<'a, 'b> {
+    fn report(&mut self, lint: Lint, span: Span, message: String) {
+        let level = self.levels.get(lint);
+        if level != Level::Allow {
+            self.diagnostics.push(Diagnostic {
+                lint,
+                level,
+                span,
+                message,
+            });
+        }
+    }
+
+    fn current_slot(&self, kind: &BindingKind<'b>) -> Option<Slot<'b>> {
+        match resolve(&self.frames, kind) {
+            Resolved::Slot(slot) => Some(slot),
+            _ => None,
+        }
+    }
+
+    /// Whether calling `target` certainly never returns.
+    fn never_returns(&self, target: &Bound<'b>) -> bool {
+        match target {
+            &Bound::Identifier {
+                binding_kind: BindingKind::CurrentFunction,
+                ..
+            } => true,
+            &Bound::Identifier {
+                ref binding_kind, ..
+            } => self.current_slot(binding_kind)
+                .is_some_and(|slot| self.never_return.contains(&slot)),
+            _ => false,
+        }
+    }
+
+    fn declare(&mut self, location: &BindingKind<'b>, lint: Lint, name: &'b str, span: Span) {
+        if let Some(slot) = self.current_slot(location) {
+            self.declarations.push((slot, lint, name, span));
+        }
+    }
+
+    /// Walks a function, returning whether its body never returns.
+    fn walk_function(
+        &mut self,
+        name: Option<&'b str>,
+        params: &[(DeclarationKind<'b>, &'b Ast<'b>)],
+        body: &'b Bound<'b>,
+        upvars: &'a HashMap<DeclarationKind<'b>, (BindingKind<'b>, u32)>,
+    ) -> bool {
+        let depth = self.names.len();
+        self.names.extend(name);
+        let siblings = ::std::mem::take(&mut self.group);
+        self.frames.push(Frame {
+            body,
+            upvars,
+            siblings,
+        });
+        for (i, &(ref param, ast)) in params.iter().enumerate() {
+            let param = param.name();
+            self.names.push(param);
+            self.declare(
+                &BindingKind::Argument(i as u32),
+                Lint::UnusedParameters,
+                param,
+                ast.span(),
+            );
+        }
+        let diverges = self.walk(body);
+        self.frames.pop();
+        self.names.truncate(depth);
+        diverges
+    }
+
+    /// Walks the statements of a block or module and then the final
+    /// expression of a block, reporting the first of them that cannot be
+    /// reached.  Returns whether one of them never finishes.
+    fn walk_sequence(
+        &mut self,
+        statements: &'a [Bound<'b>],
+        final_expression: Option<&'a Bound<'b>>,
+    ) -> bool {
+        let in_block = final_expression.is_some();
+        let mut diverges = false;
+        let mut reported = false;
+        for statement in statements {
+            if diverges && !reported {
+                self.report(
+                    Lint::UnreachableCode,
+                    statement.span(),
+                    "this statement is never reached".into(),
+                );
+                reported = true;
+            }
+            diverges |= self.walk_statement(statement, in_block);
+        }
+        if let Some(final_expression) = final_expression {
+            if diverges && !reported {
+                self.report(
+                    Lint::UnreachableCode,
+                    final_expression.span(),
+                    "this expression is never reached".into(),
+                );
+            }
+            diverges |= self.walk(final_expression);
+        }
+        diverges
+    }
+
+    fn walk_statement(&mut self, statement: &'a Bound<'b>, in_block: bool) -> bool {
+        let (name, ast, location) = match statement {
+            &Bound::VariableDecl {
+                name,
+                ast,
+                ref location,
+                ..
+            }
+            | &Bound::FunctionDecl {
+                name,
+                ast,
+                ref location,
+                ..
+            } => (name, ast, location),
+            &Bound::FunctionGroup { ref functions } => {
+                let locations = functions.iter().map(|function| match function {
+                    &Bound::FunctionDecl { ref location, .. } => location.clone(),
+                    other => panic!("{:?} is not a function declaration", other),
+                });
+                let locations = locations.collect::<Vec<_>>();
+                for function in functions {
+                    self.group = locations.clone();
+                    self.walk_statement(function, in_block);
+                }
+                return false;
+            }
+            other => return self.walk(other),
+        };
+
+        let span = name_span(ast);
+        if in_block && self.names.contains(&name) {
+            self.report(
+                Lint::Shadowing,
+                span,
+                format!("`{}` shadows an earlier binding", name),
+            );
+        }
+        let diverges = self.walk(statement);
+        let lint = if in_block {
+            Lint::UnusedVariables
+        } else {
+            Lint::UnusedDefinitions
+        };
+        self.declare(location, lint, name, span);
+        self.names.push(name);
+        diverges
+    }
+
+    /// Walks `node`, returning whether evaluating it never finishes.
+    fn walk(&mut self, node: &'a Bound<'b>) -> bool {
+        match node {
+            &Bound::Integer { .. } | &Bound::Float { .. } | &Bound::Identifier { .. } => false,
+            &Bound::DebugCall { arg, .. } => self.walk(arg),
+            &Bound::FunctionCall {
+                target, ref args, ..
+            } => {
+                let mut diverges = self.walk(target);
+                for arg in args {
+                    diverges |= self.walk(arg);
+                }
+                diverges || self.never_returns(target)
+            }
+            &Bound::Add { left, right, .. }
+            | &Bound::Sub { left, right, .. }
+            | &Bound::Div { left, right, .. }
+            | &Bound::Mul { left, right, .. } => self.walk(left) | self.walk(right),
+            &Bound::FunctionDecl {
+                name,
+                ref params,
+                body,
+                ref upvars,
+                ref location,
+                ..
+            } => {
+                if self.walk_function(Some(name), params, body, upvars) {
+                    if let Some(slot) = self.current_slot(location) {
+                        self.never_return.insert(slot);
+                    }
+                }
+                false
+            }
+            &Bound::AnonFunc {
+                ref params,
+                body,
+                ref upvars,
+                ..
+            } => {
+                self.walk_function(None, params, body, upvars);
+                false
+            }
+            &Bound::VariableDecl {
+                expression,
+                ref location,
+                ..
+            } => {
+                let diverges = self.walk(expression);
+                // Another name for a function that never returns.
+                if let &Bound::Identifier {
+                    ref binding_kind, ..
+                } = expression
+                {
+                    let never_returns = self.current_slot(binding_kind)
+                        .is_some_and(|slot| self.never_return.contains(&slot));
+                    if let (true, Some(slot)) = (never_returns, self.current_slot(location)) {
+                        self.never_return.insert(slot);
+                    }
+                }
+                diverges
+            }
+            &Bound::FieldAccess { target, .. } => self.walk(target),
+            &Bound::BlockExpr {
+                ref statements,
+                final_expression,
+                ..
+            } => {
+                let depth = self.names.len();
+                let diverges = self.walk_sequence(statements, Some(final_expression));
+                self.names.truncate(depth);
+                diverges
+            }
+            &Bound::Module { ref statements, .. } => self.walk_sequence(statements, None),
+            &Bound::FunctionGroup { .. } => self.walk_statement(node, false),
+        }
+    }}
@@ -1,7 +1,7 @@
 use super::*;
 use std::collections::HashSet;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ModuleBinder<'bound> {
     pub module_id: &'bound str,
     pub definitions: HashSet<DeclarationKind<'bound>>,
@@ -20,17 +20,19 @@ impl<'bound> Binder<'bound> for ModuleBinder<'bound> {
         }
     }
 
-    fn lookup(&mut self, symbol: &DeclarationKind<'bound>) -> Result<BindingKind<'bound>, Error> {
+    fn lookup(&mut self, symbol: &DeclarationKind<'bound>) -> Option<BindingKind<'bound>> {
         if self.definitions.contains(symbol) {
-            return Ok(BindingKind::Module {
+            return Some(BindingKind::Module {
                 module_id: self.module_id,
                 symbol: Rc::new(symbol.clone()),
             });
         }
+        None
+    }
 
-        match symbol {
-            &DeclarationKind::Named(s) => Err(Error::UnboundIdentifier(s.into())),
-            &DeclarationKind::Generated(_, s) => Err(Error::UnboundIdentifier(s.into())),
-        }
+    fn names_in_scope(&self, names: &mut Vec<&'bound str>) {
+        let mut defined = self.definitions.iter().map(DeclarationKind::name).collect::<Vec<_>>();
+        defined.sort();
+        names.extend(defined);
     }
 }
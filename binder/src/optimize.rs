@@ -0,0 +1,266 @@
+use std::ptr;
+use typed_arena::Arena;
+use super::{BindingKind, Bound};
+use super::slots::{count_uses, Slot, Uses};
+use vm::value::Value;
+use vm::vm::Arithmetic;
+
+// Rewrites a bound tree into one that computes the same values with less
+// work.  Nothing here changes which bindings exist or where they live: the
+// `locals` and `upvars` of every function stay as the binder laid them out,
+// so a removed declaration only leaves an unused slot behind.
+//
+// The language has no assignment, so a declaration that no identifier reads
+// can go as soon as evaluating its expression cannot fail or be observed.
+// Removing one can make another unused, which is why `optimize` repeats
+// until a pass removes nothing.
+
+/// Folds constant arithmetic, drops unused pure declarations and pure
+/// expression statements from blocks, and replaces blocks that are left
+/// with no statements by their final expression.
+pub fn optimize<'b>(arena: &'b Arena<Bound<'b>>, node: &Bound<'b>) -> Bound<'b> {
+    let mut current = node.clone();
+    loop {
+        let mut optimizer = Optimizer {
+            arena,
+            uses: count_uses(&current),
+            changed: false,
+        };
+        let next = optimizer.rewrite(&current, ptr::null());
+        if !optimizer.changed {
+            return next;
+        }
+        current = next;
+    }
+}
+
+/// Whether evaluating `node` can neither fail nor be observed.  Reading a
+/// module definition fails if it has not been added yet.
+fn is_pure(node: &Bound) -> bool {
+    match node {
+        &Bound::Integer { .. } | &Bound::Float { .. } | &Bound::AnonFunc { .. } => true,
+        &Bound::Identifier {
+            binding_kind: BindingKind::Module { .. },
+            ..
+        } => false,
+        &Bound::Identifier { .. } => true,
+        _ => false,
+    }
+}
+
+/// Evaluates arithmetic on two literals exactly as `Vm::step` would, or
+/// gives up when the VM would fail.
+fn fold<'b>(
+    op: Arithmetic,
+    node: &Bound<'b>,
+    left: &Bound<'b>,
+    right: &Bound<'b>,
+) -> Option<Bound<'b>> {
+    let ast = match node {
+        &Bound::Add { ast, .. }
+        | &Bound::Sub { ast, .. }
+        | &Bound::Mul { ast, .. }
+        | &Bound::Div { ast, .. } => ast,
+        _ => return None,
+    };
+    let literal = |node: &Bound| match node {
+        &Bound::Integer { value, .. } => Some(Value::Integer(value)),
+        &Bound::Float { value, .. } => Some(Value::Float(value)),
+        _ => None,
+    };
+
+    match op.apply(&literal(left)?, &literal(right)?) {
+        Ok(Value::Integer(value)) => Some(Bound::Integer { ast, value }),
+        Ok(Value::Float(value)) => Some(Bound::Float { ast, value }),
+        _ => None,
+    }
+}
+
+struct Optimizer<'b> {
+    arena: &'b Arena<Bound<'b>>,
+    uses: Uses<'b>,
+    changed: bool,
+}
+
+impl<'b> Optimizer<'b> {
+    fn alloc(&self, node: Bound<'b>) -> &'b Bound<'b> {
+        self.arena.alloc(node)
+    }
+
+    fn is_dead(&self, statement: &Bound<'b>, scope: *const Bound<'b>) -> bool {
+        match statement {
+            // Storing the value checks it against the annotation, which can
+            // fail.
+            &Bound::VariableDecl { ty: Some(_), .. } => false,
+            &Bound::VariableDecl {
+                expression,
+                ref location,
+                ..
+            } => {
+                is_pure(expression)
+                    && Slot::of(location, scope)
+                        .map_or(false, |slot| !self.uses.contains_key(&slot))
+            }
+            other => is_pure(other),
+        }
+    }
+
+    fn binary(&mut self, op: Arithmetic, node: &Bound<'b>, scope: *const Bound<'b>) -> Bound<'b> {
+        let (left, right) = match node {
+            &Bound::Add { left, right, .. }
+            | &Bound::Sub { left, right, .. }
+            | &Bound::Mul { left, right, .. }
+            | &Bound::Div { left, right, .. } => (left, right),
+            _ => unreachable!(),
+        };
+        let left = self.rewrite(left, scope);
+        let right = self.rewrite(right, scope);
+        if let Some(folded) = fold(op, node, &left, &right) {
+            return folded;
+        }
+
+        node.with_operands(self.alloc(left), self.alloc(right))
+    }
+
+    fn rewrite(&mut self, node: &Bound<'b>, scope: *const Bound<'b>) -> Bound<'b> {
+        match node {
+            &Bound::Integer { .. } | &Bound::Float { .. } | &Bound::Identifier { .. } => {
+                node.clone()
+            }
+            &Bound::Add { .. } => self.binary(Arithmetic::Add, node, scope),
+            &Bound::Sub { .. } => self.binary(Arithmetic::Sub, node, scope),
+            &Bound::Mul { .. } => self.binary(Arithmetic::Mul, node, scope),
+            &Bound::Div { .. } => self.binary(Arithmetic::Div, node, scope),
+            &Bound::DebugCall { ast, arg } => Bound::DebugCall {
+                ast,
+                arg: {
+                    let arg = self.rewrite(arg, scope);
+                    self.alloc(arg)
+                },
+            },
+            &Bound::FunctionCall {
+                ast,
+                target,
+                ref args,
+            } => {
+                let target = self.rewrite(target, scope);
+                Bound::FunctionCall {
+                    ast,
+                    target: self.alloc(target),
+                    args: args.iter().map(|arg| self.rewrite(arg, scope)).collect(),
+                }
+            }
+            &Bound::FunctionDecl {
+                name,
+                ref params,
+                body,
+                ref locals,
+                ref upvars,
+                ast,
+                ref location,
+            } => {
+                let new_body = self.rewrite(body, body);
+                Bound::FunctionDecl {
+                    name,
+                    params: params.clone(),
+                    body: self.alloc(new_body),
+                    locals: locals.clone(),
+                    upvars: upvars.clone(),
+                    ast,
+                    location: location.clone(),
+                }
+            }
+            &Bound::FunctionGroup { ref functions } => Bound::FunctionGroup {
+                functions: functions
+                    .iter()
+                    .map(|function| self.rewrite(function, scope))
+                    .collect(),
+            },
+            &Bound::AnonFunc {
+                ref params,
+                body,
+                ref locals,
+                ref upvars,
+                ast,
+            } => {
+                let new_body = self.rewrite(body, body);
+                Bound::AnonFunc {
+                    params: params.clone(),
+                    body: self.alloc(new_body),
+                    locals: locals.clone(),
+                    upvars: upvars.clone(),
+                    ast,
+                }
+            }
+            &Bound::VariableDecl {
+                name,
+                ast,
+                expression_ast,
+                expression,
+                ty,
+                ref location,
+            } => {
+                let expression = self.rewrite(expression, scope);
+                Bound::VariableDecl {
+                    name,
+                    ast,
+                    expression_ast,
+                    expression: self.alloc(expression),
+                    ty,
+                    location: location.clone(),
+                }
+            }
+            &Bound::FieldAccess {
+                target_ast,
+                field_ast,
+                field_name,
+                target,
+            } => {
+                let target = self.rewrite(target, scope);
+                Bound::FieldAccess {
+                    target_ast,
+                    field_ast,
+                    field_name,
+                    target: self.alloc(target),
+                }
+            }
+            &Bound::BlockExpr {
+                ref statements,
+                ast,
+                final_expression,
+            } => {
+                let mut kept = vec![];
+                for statement in statements {
+                    // Folding first can make a declaration pure.
+                    let statement = self.rewrite(statement, scope);
+                    if self.is_dead(&statement, scope) {
+                        self.changed = true;
+                    } else {
+                        kept.push(statement);
+                    }
+                }
+                let final_expression = self.rewrite(final_expression, scope);
+                if kept.is_empty() {
+                    return final_expression;
+                }
+                Bound::BlockExpr {
+                    statements: kept,
+                    ast,
+                    final_expression: self.alloc(final_expression),
+                }
+            }
+            &Bound::Module {
+                ast,
+                ref statements,
+                ref binder,
+            } => Bound::Module {
+                ast,
+                statements: statements
+                    .iter()
+                    .map(|statement| self.rewrite(statement, scope))
+                    .collect(),
+                binder: binder.clone(),
+            },
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::ptr;
+use std::rc::Rc;
+use super::{BindingKind, Bound, DeclarationKind};
+
+// Where the bindings of a bound tree are stored, and which of them are read.
+//
+// The binder refers to a binding differently from each function that can
+// see it: a local of one function is an upvar of the functions nested in
+// it.  The passes that follow bindings around, `optimize`, `lint` and the
+// type checker, name them by the slot that holds them instead.
+
+/// Where a declaration is stored.  Locals and arguments are told apart by
+/// the body of the function that owns them; the top level has no body.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Slot<'b> {
+    Local(*const Bound<'b>, u32),
+    Argument(*const Bound<'b>, u32),
+    Module(&'b str, Rc<DeclarationKind<'b>>),
+}
+
+impl<'b> Slot<'b> {
+    /// The slot that `kind` names inside the function whose body is
+    /// `scope`, or a null `scope` at the top level.  Upvars and the
+    /// functions of the current group are stored elsewhere.
+    pub fn of(kind: &BindingKind<'b>, scope: *const Bound<'b>) -> Option<Slot<'b>> {
+        match kind {
+            &BindingKind::FunctionLocal(n) => Some(Slot::Local(scope, n)),
+            &BindingKind::Argument(n) => Some(Slot::Argument(scope, n)),
+            &BindingKind::Module {
+                module_id,
+                ref symbol,
+            } => Some(Slot::Module(module_id, symbol.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// A function that a walk over a bound tree is inside: its body, where its
+/// upvars come from and where the other functions of its group are
+/// declared.
+pub struct Frame<'a, 'b: 'a> {
+    pub body: *const Bound<'b>,
+    pub upvars: &'a HashMap<DeclarationKind<'b>, (BindingKind<'b>, u32)>,
+    pub siblings: Vec<BindingKind<'b>>,
+}
+
+/// What a binding refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolved<'b> {
+    Slot(Slot<'b>),
+    /// The function with this many frames around it, counting its own.
+    Function(usize),
+    /// An upvar or group that the frames do not account for.
+    Unknown,
+}
+
+/// Resolves `kind`, as seen from inside the innermost of `frames`,
+/// following upvars and the functions of groups out to where they were
+/// declared.
+pub fn resolve<'a, 'b>(frames: &[Frame<'a, 'b>], kind: &BindingKind<'b>) -> Resolved<'b> {
+    let depth = frames.len();
+    let (outer, current) = match frames.split_last() {
+        Some((current, outer)) => (outer, Some(current)),
+        None => (frames, None),
+    };
+    match (kind, current) {
+        (&BindingKind::Upvar(n), Some(current)) => {
+            let captured = current
+                .upvars
+                .values()
+                .find(|&&(_, index)| index == n)
+                .map(|&(ref captured, _)| captured);
+            match captured {
+                Some(captured) => resolve(outer, captured),
+                None => Resolved::Unknown,
+            }
+        }
+        (&BindingKind::Sibling(n), Some(current)) => match current.siblings.get(n as usize) {
+            Some(location) => resolve(outer, location),
+            None => Resolved::Unknown,
+        },
+        (&BindingKind::CurrentFunction, Some(_)) => Resolved::Function(depth),
+        (kind, current) => {
+            let body = current.map_or(ptr::null(), |current| current.body);
+            Slot::of(kind, body).map_or(Resolved::Unknown, Resolved::Slot)
+        }
+    }
+}
+
+pub type Uses<'b> = HashMap<Slot<'b>, usize>;
+
+/// Counts the identifiers that read each slot of `node`.  A nested
+/// function reads the slots it captures when it is built, in the scope
+/// around it, and the functions of a group read each other's slots there.
+pub fn count_uses<'b>(node: &Bound<'b>) -> Uses<'b> {
+    let mut uses = HashMap::new();
+    count(node, ptr::null(), &[], &mut uses);
+    uses
+}
+
+fn count_use<'b>(
+    kind: &BindingKind<'b>,
+    scope: *const Bound<'b>,
+    siblings: &[Slot<'b>],
+    uses: &mut Uses<'b>,
+) {
+    let slot = match kind {
+        &BindingKind::Sibling(n) => siblings.get(n as usize).cloned(),
+        other => Slot::of(other, scope),
+    };
+    if let Some(slot) = slot {
+        *uses.entry(slot).or_insert(0) += 1;
+    }
+}
+
+fn count<'b>(
+    node: &Bound<'b>,
+    scope: *const Bound<'b>,
+    siblings: &[Slot<'b>],
+    uses: &mut Uses<'b>,
+) {
+    match node {
+        &Bound::Integer { .. } | &Bound::Float { .. } => {}
+        &Bound::Identifier {
+            ref binding_kind, ..
+        } => count_use(binding_kind, scope, siblings, uses),
+        &Bound::DebugCall { arg, .. } => count(arg, scope, siblings, uses),
+        &Bound::FunctionCall {
+            target, ref args, ..
+        } => {
+            count(target, scope, siblings, uses);
+            for arg in args {
+                count(arg, scope, siblings, uses);
+            }
+        }
+        &Bound::Add { left, right, .. }
+        | &Bound::Sub { left, right, .. }
+        | &Bound::Div { left, right, .. }
+        | &Bound::Mul { left, right, .. } => {
+            count(left, scope, siblings, uses);
+            count(right, scope, siblings, uses);
+        }
+        &Bound::FunctionDecl { .. } | &Bound::AnonFunc { .. } => {
+            count_function(node, scope, siblings, &[], uses)
+        }
+        &Bound::VariableDecl { expression, .. } => count(expression, scope, siblings, uses),
+        &Bound::FieldAccess { target, .. } => count(target, scope, siblings, uses),
+        &Bound::BlockExpr {
+            ref statements,
+            final_expression,
+            ..
+        } => {
+            for statement in statements {
+                count(statement, scope, siblings, uses);
+            }
+            count(final_expression, scope, siblings, uses);
+        }
+        &Bound::Module { ref statements, .. } => for statement in statements {
+            count(statement, scope, siblings, uses);
+        },
+        &Bound::FunctionGroup { ref functions } => {
+            let group = functions
+                .iter()
+                .filter_map(|function| match function {
+                    &Bound::FunctionDecl { ref location, .. } => Slot::of(location, scope),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            for function in functions {
+                count_function(function, scope, siblings, &group, uses);
+            }
+        }
+    }
+}
+
+/// Counts the uses of the function `node`, declared in `scope` alongside
+/// the functions whose slots are `group`.
+fn count_function<'b>(
+    node: &Bound<'b>,
+    scope: *const Bound<'b>,
+    siblings: &[Slot<'b>],
+    group: &[Slot<'b>],
+    uses: &mut Uses<'b>,
+) {
+    if let &Bound::FunctionDecl {
+        body, ref upvars, ..
+    }
+    | &Bound::AnonFunc {
+        body, ref upvars, ..
+    } = node
+    {
+        for &(ref kind, _) in upvars.values() {
+            count_use(kind, scope, siblings, uses);
+        }
+        count(body, body, group, uses);
+    }
+}
@@ -0,0 +1,63 @@
+/// The number of single character insertions, deletions, substitutions
+/// and swaps of neighbouring characters that turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    // distances[i][j] is the distance between a[..i] and b[..j].
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..a.len() + 1 {
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// The candidate closest to `name`, if it is close enough to be a typo: at
+/// most a third of the length of `name` in edits, or one edit, but never
+/// as many edits as `name` has characters.  Ties go to the earliest
+/// candidate, so candidates should come innermost first.
+pub fn closest<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let length = name.chars().count();
+    let limit = (length / 3).max(1).min(length.saturating_sub(1));
+    let mut best: Option<(usize, &'a str)> = None;
+    for &candidate in candidates {
+        if candidate == name {
+            continue;
+        }
+        let distance = edit_distance(name, candidate);
+        if distance <= limit && best.is_none_or(|(d, _)| distance < d) {
+            best = Some((distance, candidate));
+        }
+    }
+    best.map(|(_, candidate)| candidate)
+}
+
+#[test]
+fn edit_distances() {
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(edit_distance("count", "cuont"), 1);
+    assert_eq!(edit_distance("same", "same"), 0);
+}
+
+#[test]
+fn closest_prefers_nearer_and_earlier_names() {
+    assert_eq!(closest("lenght", &["len", "length", "height"]), Some("length"));
+    assert_eq!(closest("ab", &["ba", "xb", "ab"]), Some("ba"));
+    assert_eq!(closest("a", &["b", "c"]), None);
+    assert_eq!(closest("total", &["x", "y"]), None);
+}
@@ -20,29 +20,21 @@ macro_rules! matches {
 
 fn with_bind<F>(program: &'static str, f: F)
 where
-    F: for<'a> FnOnce(Result<Bound<'a>, Error>),
+    F: for<'a> FnOnce(::std::result::Result<Bound<'a>, Vec<Error>>),
 {
     use typed_arena::Arena;
-    let mut lexed = lex(program);
-    let parse_arena = Arena::new();
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
     let bind_arena = Arena::new();
 
-    remove_whitespace(&mut lexed);
-    let parsed = parse_module(&lexed, "my_module", &parse_arena).unwrap();
-    let bound = bind_top(&bind_arena, parsed.0);
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap();
+    let desugared = ast_lower::desugar(parsed.0, &mut alloc);
+    let bound = bind_top(&bind_arena, desugared);
     f(bound)
 }
 
-fn remove_whitespace(tokens: &mut Vec<Token>) {
-    tokens.retain(|token| {
-        if let TokenKind::Whitespace(_) = token.kind {
-            false
-        } else {
-            true
-        }
-    })
-}
-
 #[test]
 fn bind_binary_operator() {
     with_bind("1 + 2;", |res| {
@@ -140,7 +132,13 @@ fn bind_module_fn_decl_with_some_locals_bad() {
 #[test]
 fn bind_module_fn_decl_with_bad_reference() {
     with_bind("let x(y) = z;", |res| {
-        matches!(res, Err(Error::UnboundIdentifier(ref s)), &*s == "z");
+        matches!(res, Err(ref errors),
+            errors.len() == 1,
+            matches!(errors[0],
+                Error::UnboundIdentifier { ref name, span: Span { start: 11, end: 12 }, .. },
+                name == "z"
+            )
+        );
     });
 }
 
@@ -158,9 +156,617 @@ fn bind_module_fn_decl_with_upvar() {
     });
 }
 
+#[test]
+fn bind_anon_func_with_upvar() {
+    with_bind("let f(x) = (y) => x + y;", |res| {
+        let r = res.unwrap();
+        matches!(r,
+            Bound::Module{ statements, .. },
+            matches!(&statements[0],
+                &Bound::FunctionDecl{
+                    body: &Bound::AnonFunc{ ref params, ref upvars, .. },
+                    ..
+                },
+                params.len() == 1,
+                upvars.len() == 1)
+        );
+    });
+}
+
 #[test]
 fn bind_upvar_to_module_fn() {
     with_bind("let x = 10; let f() = x;", |res| {
         assert!(res.is_ok());
     });
 }
+
+#[test]
+fn bind_mutually_recursive_module_functions() {
+    with_bind("let even(n) = odd(n); let odd(n) = even(n);", |res| {
+        let r = res.unwrap();
+        matches!(r,
+            Bound::Module { statements, .. },
+            statements.len() == 1,
+            matches!(&statements[0],
+                &Bound::FunctionGroup { ref functions },
+                functions.len() == 2,
+                matches!(&functions[0],
+                    &Bound::FunctionDecl {
+                        name: "even",
+                        body: &Bound::FunctionCall {
+                            target: &Bound::Identifier { binding_kind: BindingKind::Sibling(1), .. },
+                            ..
+                        },
+                        location: BindingKind::Module { .. },
+                        ..
+                    }
+                ),
+                matches!(&functions[1],
+                    &Bound::FunctionDecl {
+                        name: "odd",
+                        body: &Bound::FunctionCall {
+                            target: &Bound::Identifier { binding_kind: BindingKind::Sibling(0), .. },
+                            ..
+                        },
+                        location: BindingKind::Module { .. },
+                        ..
+                    }
+                )
+            )
+        );
+    });
+}
+
+#[test]
+fn bind_mutually_recursive_block_functions_share_upvars() {
+    with_bind("let f(x) = { let a() = b(); let b() = x + a(); a };", |res| {
+        let r = res.unwrap();
+        matches!(r,
+            Bound::Module { statements, .. },
+            matches!(&statements[0],
+                &Bound::FunctionDecl { body: &Bound::BlockExpr { ref statements, .. }, .. },
+                statements.len() == 1,
+                matches!(&statements[0],
+                    &Bound::FunctionGroup { ref functions },
+                    matches!(&functions[0],
+                        &Bound::FunctionDecl {
+                            name: "a",
+                            ref upvars,
+                            location: BindingKind::FunctionLocal(0),
+                            ..
+                        },
+                        upvars.len() == 1,
+                        matches!(upvars[&DeclarationKind::Named("x")], (BindingKind::Argument(0), 0))
+                    ),
+                    matches!(&functions[1],
+                        &Bound::FunctionDecl {
+                            name: "b",
+                            ref upvars,
+                            location: BindingKind::FunctionLocal(1),
+                            ..
+                        },
+                        upvars.len() == 1
+                    )
+                )
+            )
+        );
+    });
+}
+
+#[test]
+fn bind_captures_siblings_in_nested_functions() {
+    with_bind("let f() = { let h() = g(); h }; let g() = f;", |res| {
+        let r = res.unwrap();
+        matches!(r,
+            Bound::Module { statements, .. },
+            matches!(&statements[0],
+                &Bound::FunctionGroup { ref functions },
+                matches!(&functions[0],
+                    &Bound::FunctionDecl {
+                        body: &Bound::BlockExpr { ref statements, .. },
+                        ..
+                    },
+                    matches!(&statements[0],
+                        &Bound::FunctionDecl { ref upvars, .. },
+                        matches!(upvars[&DeclarationKind::Named("g")], (BindingKind::Sibling(1), 0))
+                    )
+                ),
+                matches!(&functions[1],
+                    &Bound::FunctionDecl {
+                        body: &Bound::Identifier { binding_kind: BindingKind::Sibling(0), .. },
+                        ..
+                    }
+                )
+            )
+        );
+    });
+}
+
+#[test]
+fn bind_functions_without_forward_references_separately() {
+    with_bind("let f() = 1; let g() = f(); let h() = { let k() = f(); let f() = 2; k };", |res| {
+        let r = res.unwrap();
+        matches!(r,
+            Bound::Module { statements, .. },
+            statements.len() == 3,
+            matches!(&statements[2],
+                &Bound::FunctionDecl { body: &Bound::BlockExpr { ref statements, .. }, .. },
+                // `k` still calls the `f` of the module, through `h`.
+                matches!(&statements[0],
+                    &Bound::FunctionDecl { ref upvars, .. },
+                    matches!(upvars[&DeclarationKind::Named("f")], (BindingKind::Upvar(0), 0))
+                ),
+                matches!(&statements[1], &Bound::FunctionDecl { name: "f", .. })
+            )
+        );
+    });
+}
+
+#[test]
+fn bind_shadowed_names_do_not_form_groups() {
+    let program = "let f(g) = g(); let h() = { let g = 2; g }; let k() = (g) => g; let g() = 1;";
+    with_bind(program, |res| {
+        let r = res.unwrap();
+        matches!(r,
+            Bound::Module { statements, .. },
+            statements.len() == 4,
+            statements.iter().all(|s| match s {
+                &Bound::FunctionDecl { .. } => true,
+                _ => false,
+            })
+        );
+    });
+}
+
+fn with_optimized<F>(program: &'static str, f: F)
+where
+    F: for<'a> FnOnce(Bound<'a>),
+{
+    use typed_arena::Arena;
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let bind_arena = Arena::new();
+
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap();
+    let bound = bind_top(&bind_arena, parsed.0).unwrap();
+    f(optimize::optimize(&bind_arena, &bound))
+}
+
+#[test]
+fn optimize_folds_arithmetic() {
+    with_optimized("1 + 2 * 3; 1 / 2.0; 1 / 0; 9223372036854775807 + 1;", |r| {
+        matches!(r,
+            Bound::Module { statements, .. },
+            statements.len() == 4,
+            matches!(statements[0], Bound::Integer { value: 7, .. }),
+            matches!(statements[1], Bound::Float { value, .. }, value == 0.5),
+            matches!(statements[2], Bound::Div { .. }),
+            matches!(statements[3], Bound::Add { .. })
+        );
+    });
+}
+
+#[test]
+fn optimize_removes_unused_pure_declarations() {
+    with_optimized("let f(a) = { let b = a; let c = b; let d = 1 + 2; 5; a + d };", |r| {
+        matches!(r,
+            Bound::Module { statements, .. },
+            matches!(statements[0],
+                Bound::FunctionDecl { body: &Bound::BlockExpr { ref statements, .. }, .. },
+                statements.len() == 1,
+                matches!(statements[0],
+                    Bound::VariableDecl { expression: &Bound::Integer { value: 3, .. }, .. }
+                )
+            )
+        );
+    });
+}
+
+#[test]
+fn optimize_keeps_declarations_that_may_fail_or_are_captured() {
+    with_optimized("let f(a) = { let b = a + 1; let c = a; () => c };", |r| {
+        matches!(r,
+            Bound::Module { statements, .. },
+            matches!(statements[0],
+                Bound::FunctionDecl { body: &Bound::BlockExpr { ref statements, .. }, .. },
+                statements.len() == 2
+            )
+        );
+    });
+}
+
+#[test]
+fn optimize_inlines_trivial_blocks() {
+    with_optimized("let f(a) = { let b = 1; { a } };", |r| {
+        matches!(r,
+            Bound::Module { statements, .. },
+            matches!(statements[0],
+                Bound::FunctionDecl { body: &Bound::Identifier { ident: "a", .. }, .. }
+            )
+        );
+    });
+}
+
+#[test]
+fn optimize_leaves_module_definitions() {
+    with_optimized("let x = 5; let y = x;", |r| {
+        matches!(r,
+            Bound::Module { statements, .. },
+            statements.len() == 2
+        );
+    });
+}
+
+fn with_inlined<F>(program: &'static str, f: F)
+where
+    F: for<'a> FnOnce(Bound<'a>),
+{
+    use typed_arena::Arena;
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let bind_arena = Arena::new();
+
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap();
+    let bound = bind_top(&bind_arena, parsed.0).unwrap();
+    f(inline::inline(&bind_arena, &bound))
+}
+
+#[test]
+fn inline_replaces_calls_with_bodies() {
+    with_inlined("let double(x) = x * 2; let f(a) = { let b = 1; double(a) };", |r| {
+        matches!(r,
+            Bound::Module { statements, .. },
+            matches!(statements[1],
+                Bound::FunctionDecl {
+                    ref locals,
+                    body: &Bound::BlockExpr {
+                        final_expression: &Bound::BlockExpr {
+                            ref statements,
+                            final_expression: &Bound::Mul {
+                                left: &Bound::Identifier {
+                                    binding_kind: BindingKind::FunctionLocal(1),
+                                    ..
+                                },
+                                ..
+                            },
+                            ..
+                        },
+                        ..
+                    },
+                    ..
+                },
+                locals.len() == 2,
+                matches!(locals[1], DeclarationKind::Generated(_, "x")),
+                locals[0] != locals[1],
+                matches!(statements[0],
+                    Bound::VariableDecl {
+                        location: BindingKind::FunctionLocal(1),
+                        expression: &Bound::Identifier {
+                            binding_kind: BindingKind::Argument(0),
+                            ..
+                        },
+                        ..
+                    }
+                )
+            )
+        );
+    });
+}
+
+#[test]
+fn inline_declares_top_level_arguments_in_the_module() {
+    with_inlined("let double(x) = x * 2; double(3);", |r| {
+        matches!(r,
+            Bound::Module { statements, binder, .. },
+            binder.definitions.len() == 2,
+            matches!(statements[1],
+                Bound::BlockExpr {
+                    final_expression: &Bound::Mul {
+                        left: &Bound::Identifier {
+                            binding_kind: BindingKind::Module { .. },
+                            ..
+                        },
+                        ..
+                    },
+                    ..
+                }
+            )
+        );
+    });
+}
+
+#[test]
+fn inline_skips_functions_that_refer_to_themselves() {
+    with_inlined("let me(x) = { me; x }; let again(x) = () => again; me(1); again(1);", |r| {
+        matches!(r,
+            Bound::Module { statements, .. },
+            matches!(statements[2], Bound::FunctionCall { .. }),
+            matches!(statements[3], Bound::FunctionCall { .. })
+        );
+    });
+}
+
+#[test]
+fn inline_skips_large_functions() {
+    with_inlined(
+        "let big(x) = x + x + x + x + x + x + x + x + x + x + x + x + x; big(1);",
+        |r| {
+            matches!(r,
+                Bound::Module { statements, .. },
+                matches!(statements[1], Bound::FunctionCall { .. })
+            );
+        },
+    );
+}
+
+#[test]
+fn dot_annotates_bindings() {
+    with_bind("let x = 1; let f(a) = { let y = a; let g() = y + x; g() };", |res| {
+        let r = res.unwrap();
+        assert_eq!(
+            super::dot::to_dot(&r),
+            r#"digraph bound {
+    node [shape=box, fontname="monospace"];
+    n0 [label="module my_module"];
+    n1 [label="let x\lmodule my_module.x\l"];
+    n2 [label="1"];
+    n1 -> n2 [label="value"];
+    n0 -> n1 [label="0"];
+    n3 [label="fn f(a)\lmodule my_module.f\llocals: y#1, g#2\lupvar 0: x = module my_module.x\l"];
+    n4 [label="block"];
+    n5 [label="let y\llocal 0\l"];
+    n6 [label="a\larg 0\l"];
+    n5 -> n6 [label="value"];
+    n4 -> n5 [label="0"];
+    n7 [label="fn g()\llocal 1\lupvar 0: y = local 0\lupvar 1: x = upvar 0\l"];
+    n8 [label="+"];
+    n9 [label="y\lupvar 0\l"];
+    n8 -> n9 [label="left"];
+    n10 [label="x\lupvar 1\l"];
+    n8 -> n10 [label="right"];
+    n7 -> n8 [label="body"];
+    n4 -> n7 [label="1"];
+    n11 [label="call"];
+    n12 [label="g\llocal 1\l"];
+    n11 -> n12 [label="target"];
+    n4 -> n11 [label="result"];
+    n3 -> n4 [label="body"];
+    n0 -> n3 [label="1"];
+}
+"#
+        );
+    });
+}
+
+fn bind_errors(program: &'static str) -> Vec<(String, Span)> {
+    let mut errors = vec![];
+    with_bind(program, |res| {
+        errors = res.unwrap_err()
+            .iter()
+            .map(|e| (e.to_string(), e.span()))
+            .collect();
+    });
+    errors
+}
+
+#[test]
+fn bind_reports_every_unbound_identifier() {
+    assert_eq!(
+        bind_errors("let f(a) = b + c; debug(d);"),
+        vec![
+            ("`b` is not bound".to_string(), Span { start: 11, end: 12 }),
+            ("`c` is not bound".to_string(), Span { start: 15, end: 16 }),
+            ("`d` is not bound".to_string(), Span { start: 24, end: 25 }),
+        ]
+    );
+}
+
+#[test]
+fn bind_suggests_names_from_every_enclosing_binder() {
+    let suggestions = |program| {
+        bind_errors(program)
+            .into_iter()
+            .map(|(message, _)| message)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        suggestions("let count = 1; let f(value) = { let total = 2; let g() = { totl + valeu + cont }; g };"),
+        vec![
+            "`totl` is not bound, did you mean `total`?",
+            "`valeu` is not bound, did you mean `value`?",
+            "`cont` is not bound, did you mean `count`?",
+        ]
+    );
+    assert_eq!(
+        suggestions("let f(xs) = { let ys = xs; let g() = zs; g };"),
+        vec!["`zs` is not bound, did you mean `ys`?"]
+    );
+    assert_eq!(suggestions("let f(x) = unrelated;"), vec!["`unrelated` is not bound"]);
+}
+
+#[test]
+fn bind_reports_duplicate_parameters() {
+    assert_eq!(
+        bind_errors("let f(a, b, a) = a; (x, x) => x;"),
+        vec![
+            ("the parameter `a` is declared twice".to_string(), Span { start: 12, end: 13 }),
+            ("the parameter `x` is declared twice".to_string(), Span { start: 24, end: 25 }),
+        ]
+    );
+}
+
+#[test]
+fn bind_reports_errors_in_source_order() {
+    assert_eq!(
+        bind_errors("debug(y); let x = 1; let x = 2;"),
+        vec![
+            ("`y` is not bound".to_string(), Span { start: 6, end: 7 }),
+            (
+                "`x` is already defined in this module".to_string(),
+                Span { start: 25, end: 26 },
+            ),
+        ]
+    );
+}
+
+#[test]
+fn bind_reports_duplicate_module_definitions() {
+    with_bind("let x = 1; let f() = x; let x = 2; let f() = 3;", |res| {
+        let errors = res.unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                Error::DuplicateModuleDefinition {
+                    name: "x".into(),
+                    span: Span { start: 28, end: 29 },
+                    previous: Span { start: 4, end: 5 },
+                },
+                Error::DuplicateModuleDefinition {
+                    name: "f".into(),
+                    span: Span { start: 39, end: 40 },
+                    previous: Span { start: 15, end: 16 },
+                },
+            ]
+        );
+    });
+}
+
+#[test]
+fn bind_allows_shadowing_in_blocks() {
+    with_bind("let f(x) = { let x = x + 1; let x = x * 2; x };", |res| {
+        assert!(res.is_ok());
+    });
+}
+
+#[test]
+fn bind_desugared_placeholders() {
+    with_bind("let add(a, b) = a + b; let inc = add(_, 1); 2 |> inc;", |res| {
+        let r = res.unwrap();
+        matches!(r,
+            Bound::Module { statements, .. },
+            statements.len() == 3,
+            matches!(statements[1],
+                Bound::VariableDecl {
+                    expression: &Bound::AnonFunc { ref params, .. },
+                    ..
+                },
+                params.len() == 1
+            ),
+            matches!(statements[2],
+                Bound::FunctionCall {
+                    target: &Bound::Identifier { ident: "inc", .. },
+                    ref args,
+                    ..
+                },
+                matches!(args[0], Bound::Integer { value: 2, .. })
+            )
+        );
+    });
+}
+
+fn lints(program: &'static str, levels: &lint::LintLevels) -> Vec<(String, &'static str)> {
+    let mut diagnostics = vec![];
+    with_bind(program, |res| {
+        diagnostics = lint::lint(&res.unwrap(), levels)
+            .iter()
+            .map(|d| (d.to_string(), &program[d.span.start..d.span.end]))
+            .collect();
+    });
+    diagnostics
+}
+
+#[test]
+fn lint_reports_unused_variables_and_parameters() {
+    assert_eq!(
+        lints(
+            "let f(a, b, _c) = { let x = 1; let _y = 2; b }; debug(f(1, 2, 3));",
+            &Default::default()
+        ),
+        vec![
+            ("the parameter `a` is never used [unused_parameters]".into(), "a"),
+            ("`x` is never used [unused_variables]".into(), "x"),
+        ]
+    );
+}
+
+#[test]
+fn lint_reports_shadowing_in_blocks() {
+    assert_eq!(
+        lints(
+            "let x = 1; let f(a) = { let x = a; let a = x; a }; debug(f(x));",
+            &Default::default()
+        ),
+        vec![
+            ("`x` shadows an earlier binding [shadowing]".into(), "x"),
+            ("`a` shadows an earlier binding [shadowing]".into(), "a"),
+        ]
+    );
+}
+
+#[test]
+fn lint_reports_unused_module_definitions() {
+    assert_eq!(
+        lints(
+            "let helper(a) = helper(a) + 1; let unused = 1; let used = 2; debug(used);",
+            &Default::default()
+        ),
+        vec![
+            ("`helper` is never used [unused_definitions]".into(), "helper"),
+            ("`unused` is never used [unused_definitions]".into(), "unused"),
+        ]
+    );
+}
+
+#[test]
+fn lint_counts_uses_within_function_groups() {
+    assert_eq!(
+        lints(
+            "let f(x) = { let a() = b(); let b() = x; let c() = a; a }; debug(f(1));",
+            &Default::default()
+        ),
+        vec![("`c` is never used [unused_variables]".into(), "c")]
+    );
+}
+
+#[test]
+fn lint_reports_code_after_calls_that_never_return() {
+    assert_eq!(
+        lints(
+            "let forever(a) = forever(a);
+             let f(a) = { forever(a); a + 1; a * 2; a };
+             let g(a) = { let spin() = spin(); let h() = spin(); h(); a };
+             f(1);
+             g(2);",
+            &Default::default()
+        ),
+        vec![
+            ("this statement is never reached [unreachable_code]".into(), "a + 1"),
+            ("this expression is never reached [unreachable_code]".into(), "a"),
+            ("this statement is never reached [unreachable_code]".into(), "g(2)"),
+        ]
+    );
+}
+
+#[test]
+fn lint_levels() {
+    let mut levels = lint::LintLevels::default();
+    levels.set(lint::Lint::Shadowing, lint::Level::Allow);
+    levels.set(lint::Lint::UnusedVariables, lint::Level::Deny);
+    with_bind("let f(a) = { let a = 1; let b = a; 2 }; debug(f(1));", |res| {
+        let found = lint::lint(&res.unwrap(), &levels)
+            .iter()
+            .map(|d| (d.lint, d.level))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                (lint::Lint::UnusedParameters, lint::Level::Warn),
+                (lint::Lint::UnusedVariables, lint::Level::Deny),
+            ]
+        );
+    });
+}
@@ -1,7 +1,657 @@
+extern crate ast_cont;
+extern crate binder;
+extern crate parser;
+extern crate typed_arena;
 #[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+extern crate ast_lower;
+#[cfg(test)]
+extern crate copy_arena;
+#[cfg(test)]
+extern crate difference;
+#[cfg(test)]
+extern crate lexer;
+
+#[cfg(test)]
+mod test;
+
+pub use ast_cont::PrimOpKind;
+
+use binder::{BindingKind, Bound, DeclarationKind};
+use parser::Ast;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult, Write};
+use typed_arena::Arena;
+
+// Converts bound trees into continuation-passing style.
+//
+// Unlike `ast_cont`, which names variables by the strings in the source,
+// every variable here is resolved through the `BindingKind` the binder
+// gave it.  Two declarations of the same name are different variables,
+// and an upvar is replaced by the variable of the enclosing function that
+// it captures, so the output never depends on shadowing rules.
+
+pub type BoundContPtr<'c> = &'c BoundCont<'c>;
+type WithContinue<'c> = Box<FnOnce(Terminal<'c>) -> BoundContPtr<'c> + 'c>;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Var<'c> {
+    /// Argument `index` of the function numbered `function`.
+    Argument {
+        function: u32,
+        index: u32,
+        name: &'c str,
+    },
+    /// Local `index` of the function numbered `function`.
+    Local {
+        function: u32,
+        index: u32,
+        name: &'c str,
+    },
+    /// A definition of the module.  Names declared inside of blocks have
+    /// the generation the binder gave them.
+    Module {
+        name: &'c str,
+        generation: Option<u64>,
+    },
+    /// The function numbered `function` itself.
+    Function(u32),
+    Phantom(u32),
+}
+
+#[derive(PartialEq, Copy, Clone)]
+pub enum Terminal<'c> {
+    Integer(i64),
+    Float(f64),
+    Var(Var<'c>),
+    /// The name of a field.
+    Symbol(&'c str),
+}
+
+#[derive(PartialEq, Debug)]
+pub struct Function<'c> {
+    pub name: Var<'c>,
+    pub params: Vec<Var<'c>>,
+    pub body: BoundContPtr<'c>,
+}
+
+/// The same shape as `ast_cont::ContAst`, with resolved variables.
+#[derive(PartialEq)]
+pub enum BoundCont<'c> {
+    Fix {
+        functions: Vec<Function<'c>>,
+        continuation: BoundContPtr<'c>,
+    },
+    Call {
+        target: Terminal<'c>,
+        params: Vec<Terminal<'c>>,
+        continuation: Option<Terminal<'c>>,
+    },
+    Primop {
+        op: PrimOpKind,
+        terminals: Vec<Terminal<'c>>,
+        exports: Vec<Var<'c>>,
+        continuations: Vec<BoundContPtr<'c>>,
+    },
+}
+
+/// The function that is being converted, with the variables that its
+/// upvars capture, in upvar order, and the numbers of the functions in the
+/// group it was declared in.
+struct Scope<'c> {
+    function: u32,
+    upvars: Vec<Var<'c>>,
+    siblings: Vec<u32>,
+}
+
+pub struct Converter<'c> {
+    arena: &'c Arena<BoundCont<'c>>,
+    scopes: Arena<Scope<'c>>,
+    next_phantom: Cell<u32>,
+    next_function: Cell<u32>,
+}
+
+impl<'c> Converter<'c> {
+    pub fn new(arena: &'c Arena<BoundCont<'c>>) -> Converter<'c> {
+        Converter {
+            arena,
+            scopes: Arena::new(),
+            next_phantom: Cell::new(0),
+            next_function: Cell::new(0),
+        }
+    }
+
+    fn phantom(&self) -> Var<'c> {
+        let id = self.next_phantom.get();
+        self.next_phantom.set(id + 1);
+        Var::Phantom(id)
+    }
+
+    fn function(&self) -> u32 {
+        let id = self.next_function.get();
+        self.next_function.set(id + 1);
+        id
+    }
+
+    fn alloc(&self, cont: BoundCont<'c>) -> BoundContPtr<'c> {
+        self.arena.alloc(cont)
+    }
+}
+
+fn declaration_name<'c>(declaration: &DeclarationKind<'c>) -> &'c str {
+    match declaration {
+        &DeclarationKind::Named(name) | &DeclarationKind::Generated(_, name) => name,
+    }
+}
+
+fn resolve<'c>(kind: &BindingKind<'c>, name: &'c str, scope: Option<&Scope<'c>>) -> Var<'c> {
+    match (kind, scope) {
+        (&BindingKind::Module { ref symbol, .. }, _) => match **symbol {
+            DeclarationKind::Named(name) => Var::Module {
+                name,
+                generation: None,
+            },
+            DeclarationKind::Generated(generation, name) => Var::Module {
+                name,
+                generation: Some(generation),
+            },
+        },
+        (&BindingKind::Argument(index), Some(scope)) => Var::Argument {
+            function: scope.function,
+            index,
+            name,
+        },
+        (&BindingKind::FunctionLocal(index), Some(scope)) => Var::Local {
+            function: scope.function,
+            index,
+            name,
+        },
+        (&BindingKind::Upvar(index), Some(scope)) => scope.upvars[index as usize],
+        (&BindingKind::CurrentFunction, Some(scope)) => Var::Function(scope.function),
+        (&BindingKind::Sibling(index), Some(scope)) => {
+            Var::Function(scope.siblings[index as usize])
+        }
+        (kind, None) => panic!("{:?} binding of {} outside of a function", kind, name),
+    }
+}
+
+/// Converts a whole program, which ends by handing its value to a `Term`
+/// primop.
+pub fn convert_top<'c>(bound: &'c Bound<'c>, cv: &'c Converter<'c>) -> BoundContPtr<'c> {
+    let halt = move |t| {
+        cv.alloc(BoundCont::Primop {
+            op: PrimOpKind::Term,
+            terminals: vec![t],
+            exports: vec![],
+            continuations: vec![],
+        })
+    };
+    convert(bound, None, Box::new(halt), cv)
+}
+
+fn convert<'c>(
+    bound: &'c Bound<'c>,
+    scope: Option<&'c Scope<'c>>,
+    c: WithContinue<'c>,
+    cv: &'c Converter<'c>,
+) -> BoundContPtr<'c> {
+    match bound {
+        &Bound::Integer { value, .. } => c(Terminal::Integer(value)),
+        &Bound::Float { value, .. } => c(Terminal::Float(value)),
+        &Bound::Identifier {
+            ident,
+            ref binding_kind,
+            ..
+        } => c(Terminal::Var(resolve(binding_kind, ident, scope))),
+        &Bound::Add { left, right, .. } => do_binary(left, right, PrimOpKind::Add, scope, c, cv),
+        &Bound::Sub { left, right, .. } => do_binary(left, right, PrimOpKind::Sub, scope, c, cv),
+        &Bound::Mul { left, right, .. } => do_binary(left, right, PrimOpKind::Mul, scope, c, cv),
+        &Bound::Div { left, right, .. } => do_binary(left, right, PrimOpKind::Div, scope, c, cv),
+        &Bound::DebugCall { arg, .. } => convert(
+            arg,
+            scope,
+            Box::new(move |v| {
+                let id = cv.phantom();
+                cv.alloc(BoundCont::Primop {
+                    op: PrimOpKind::Debug,
+                    terminals: vec![v],
+                    exports: vec![id],
+                    continuations: vec![c(Terminal::Var(id))],
+                })
+            }),
+            cv,
+        ),
+        &Bound::FieldAccess {
+            target, field_name, ..
+        } => convert(
+            target,
+            scope,
+            Box::new(move |v| {
+                let id = cv.phantom();
+                cv.alloc(BoundCont::Primop {
+                    op: PrimOpKind::MapGet,
+                    terminals: vec![v, Terminal::Symbol(field_name)],
+                    exports: vec![id],
+                    continuations: vec![c(Terminal::Var(id))],
+                })
+            }),
+            cv,
+        ),
+        &Bound::FunctionCall {
+            target, ref args, ..
+        } => build_cont(
+            c,
+            Box::new(move |k| {
+                convert(
+                    target,
+                    scope,
+                    Box::new(move |target| {
+                        convert_all(
+                            args,
+                            vec![],
+                            scope,
+                            Box::new(move |params| {
+                                cv.alloc(BoundCont::Call {
+                                    target,
+                                    params,
+                                    continuation: Some(k),
+                                })
+                            }),
+                            cv,
+                        )
+                    }),
+                    cv,
+                )
+            }),
+            cv,
+        ),
+        &Bound::FunctionDecl {
+            name,
+            ref params,
+            body,
+            ref upvars,
+            ref location,
+            ..
+        } => do_function(
+            params,
+            body,
+            upvars,
+            scope,
+            Box::new(move |f| do_bind(f, resolve(location, name, scope), c, cv)),
+            cv,
+        ),
+        &Bound::FunctionGroup { ref functions } => do_function_group(functions, scope, c, cv),
+        &Bound::AnonFunc {
+            ref params,
+            body,
+            ref upvars,
+            ..
+        } => do_function(params, body, upvars, scope, c, cv),
+        &Bound::VariableDecl {
+            name,
+            expression,
+            ref location,
+            ..
+        } => convert(
+            expression,
+            scope,
+            Box::new(move |v| do_bind(v, resolve(location, name, scope), c, cv)),
+            cv,
+        ),
+        &Bound::BlockExpr {
+            ref statements,
+            final_expression,
+            ..
+        } => do_statements(
+            statements,
+            scope,
+            Box::new(move || convert(final_expression, scope, c, cv)),
+            cv,
+        ),
+        &Bound::Module { ref statements, .. } => do_statements(
+            statements,
+            scope,
+            Box::new(move || {
+                let id = cv.phantom();
+                cv.alloc(BoundCont::Primop {
+                    op: PrimOpKind::MapEmpty,
+                    terminals: vec![],
+                    exports: vec![id],
+                    continuations: vec![c(Terminal::Var(id))],
+                })
+            }),
+            cv,
+        ),
+    }
+}
+
+fn do_binary<'c>(
+    l: &'c Bound<'c>,
+    r: &'c Bound<'c>,
+    op: PrimOpKind,
+    scope: Option<&'c Scope<'c>>,
+    c: WithContinue<'c>,
+    cv: &'c Converter<'c>,
+) -> BoundContPtr<'c> {
+    let id = cv.phantom();
+    convert(
+        l,
+        scope,
+        Box::new(move |lv| {
+            convert(
+                r,
+                scope,
+                Box::new(move |rv| {
+                    cv.alloc(BoundCont::Primop {
+                        op,
+                        terminals: vec![lv, rv],
+                        exports: vec![id],
+                        continuations: vec![c(Terminal::Var(id))],
+                    })
+                }),
+                cv,
+            )
+        }),
+        cv,
+    )
+}
+
+fn do_bind<'c>(
+    value: Terminal<'c>,
+    var: Var<'c>,
+    c: WithContinue<'c>,
+    cv: &'c Converter<'c>,
+) -> BoundContPtr<'c> {
+    cv.alloc(BoundCont::Primop {
+        op: PrimOpKind::Bind,
+        terminals: vec![value],
+        exports: vec![var],
+        continuations: vec![c(Terminal::Var(var))],
+    })
+}
+
+/// Makes a continuation that runs `c` on the value it is called with, and
+/// hands it to `body`.
+fn build_cont<'c>(
+    c: WithContinue<'c>,
+    body: WithContinue<'c>,
+    cv: &'c Converter<'c>,
+) -> BoundContPtr<'c> {
+    let name = cv.phantom();
+    let param = cv.phantom();
+    cv.alloc(BoundCont::Fix {
+        functions: vec![Function {
+            name,
+            params: vec![param],
+            body: c(Terminal::Var(param)),
+        }],
+        continuation: body(Terminal::Var(name)),
+    })
+}
+
+type WithContinueAll<'c> = Box<FnOnce(Vec<Terminal<'c>>) -> BoundContPtr<'c> + 'c>;
+
+fn convert_all<'c>(
+    bounds: &'c [Bound<'c>],
+    mut done: Vec<Terminal<'c>>,
+    scope: Option<&'c Scope<'c>>,
+    c: WithContinueAll<'c>,
+    cv: &'c Converter<'c>,
+) -> BoundContPtr<'c> {
+    match bounds.split_first() {
+        None => c(done),
+        Some((first, rest)) => convert(
+            first,
+            scope,
+            Box::new(move |v| {
+                done.push(v);
+                convert_all(rest, done, scope, c, cv)
+            }),
+            cv,
+        ),
+    }
+}
+
+/// Evaluates each statement for its effects, then continues with `rest`.
+/// Every declaration has its own variable, so nothing has to be done to
+/// end the scope of a block.
+fn do_statements<'c>(
+    statements: &'c [Bound<'c>],
+    scope: Option<&'c Scope<'c>>,
+    rest: Box<FnOnce() -> BoundContPtr<'c> + 'c>,
+    cv: &'c Converter<'c>,
+) -> BoundContPtr<'c> {
+    match statements.split_first() {
+        None => rest(),
+        Some((first, others)) => convert(
+            first,
+            scope,
+            Box::new(move |_| do_statements(others, scope, rest, cv)),
+            cv,
+        ),
+    }
+}
+
+/// Converts a function into a `Fix` of a new function number, whose
+/// upvars are resolved in the scope around it.  The function takes the
+/// continuation it returns to as an extra last parameter.
+fn do_function<'c>(
+    params: &'c [(DeclarationKind<'c>, &'c Ast<'c>)],
+    body: &'c Bound<'c>,
+    upvars: &'c HashMap<DeclarationKind<'c>, (BindingKind<'c>, u32)>,
+    scope: Option<&'c Scope<'c>>,
+    c: WithContinue<'c>,
+    cv: &'c Converter<'c>,
+) -> BoundContPtr<'c> {
+    let function = cv.function();
+    let converted = convert_function(function, vec![], params, body, upvars, scope, cv);
+    cv.alloc(BoundCont::Fix {
+        functions: vec![converted],
+        continuation: c(Terminal::Var(Var::Function(function))),
+    })
+}
+
+/// Converts a group of function declarations into a single `Fix` of all
+/// of them, so that they can call each other, and binds each to where it
+/// was declared.
+fn do_function_group<'c>(
+    functions: &'c [Bound<'c>],
+    scope: Option<&'c Scope<'c>>,
+    c: WithContinue<'c>,
+    cv: &'c Converter<'c>,
+) -> BoundContPtr<'c> {
+    let numbers = functions.iter().map(|_| cv.function()).collect::<Vec<_>>();
+    let converted = functions
+        .iter()
+        .zip(&numbers)
+        .map(|(function, &number)| match function {
+            &Bound::FunctionDecl {
+                ref params,
+                body,
+                ref upvars,
+                ..
+            } => convert_function(number, numbers.clone(), params, body, upvars, scope, cv),
+            other => panic!("{:?} is not a function declaration", other),
+        })
+        .collect();
+    cv.alloc(BoundCont::Fix {
+        functions: converted,
+        continuation: bind_functions(functions, numbers, scope, c, cv),
+    })
+}
+
+/// Binds each function of a group to where it was declared, in order.
+fn bind_functions<'c>(
+    functions: &'c [Bound<'c>],
+    numbers: Vec<u32>,
+    scope: Option<&'c Scope<'c>>,
+    c: WithContinue<'c>,
+    cv: &'c Converter<'c>,
+) -> BoundContPtr<'c> {
+    let (first, rest) = functions.split_first().expect("empty function group");
+    let var = match first {
+        &Bound::FunctionDecl {
+            name, ref location, ..
+        } => resolve(location, name, scope),
+        other => panic!("{:?} is not a function declaration", other),
+    };
+    let value = Terminal::Var(Var::Function(numbers[0]));
+    if rest.is_empty() {
+        return do_bind(value, var, c, cv);
+    }
+    let numbers = numbers[1..].to_vec();
+    do_bind(
+        value,
+        var,
+        Box::new(move |_| bind_functions(rest, numbers, scope, c, cv)),
+        cv,
+    )
+}
+
+/// Converts a function numbered `function`, which can refer to the
+/// functions numbered `siblings` as the functions of its group.
+fn convert_function<'c>(
+    function: u32,
+    siblings: Vec<u32>,
+    params: &'c [(DeclarationKind<'c>, &'c Ast<'c>)],
+    body: &'c Bound<'c>,
+    upvars: &'c HashMap<DeclarationKind<'c>, (BindingKind<'c>, u32)>,
+    scope: Option<&'c Scope<'c>>,
+    cv: &'c Converter<'c>,
+) -> Function<'c> {
+    let mut captured = vec![None; upvars.len()];
+    for (declaration, &(ref kind, index)) in upvars {
+        captured[index as usize] = Some(resolve(kind, declaration_name(declaration), scope));
+    }
+    let inner = cv.scopes.alloc(Scope {
+        function,
+        upvars: captured.into_iter().map(Option::unwrap).collect(),
+        siblings,
+    });
+
+    let return_id = cv.phantom();
+    let mut param_ids = params
+        .iter()
+        .enumerate()
+        .map(|(index, &(ref declaration, _))| Var::Argument {
+            function,
+            index: index as u32,
+            name: declaration_name(declaration),
+        })
+        .collect::<Vec<_>>();
+    param_ids.push(return_id);
+
+    let body = convert(
+        body,
+        Some(inner),
+        Box::new(move |v| {
+            cv.alloc(BoundCont::Call {
+                target: Terminal::Var(return_id),
+                params: vec![v],
+                continuation: None,
+            })
+        }),
+        cv,
+    );
+    Function {
+        name: Var::Function(function),
+        params: param_ids,
+        body,
+    }
+}
+
+impl<'c> Debug for BoundCont<'c> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        smart_print(self, f, 0)
+    }
+}
+
+impl<'c> Debug for Terminal<'c> {
+    fn fmt(&self, out: &mut Formatter) -> FmtResult {
+        match self {
+            &Terminal::Float(f) => write!(out, "{}", f),
+            &Terminal::Integer(i) => write!(out, "{}", i),
+            &Terminal::Var(v) => write!(out, "{:?}", v),
+            &Terminal::Symbol(s) => write!(out, "'{}", s),
+        }
+    }
+}
+
+impl<'c> Debug for Var<'c> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &Var::Argument {
+                function,
+                index,
+                name,
+            } => write!(f, "{}@fn{}.arg{}", name, function, index),
+            &Var::Local {
+                function,
+                index,
+                name,
+            } => write!(f, "{}@fn{}.local{}", name, function, index),
+            &Var::Module {
+                name,
+                generation: None,
+            } => write!(f, "{}", name),
+            &Var::Module {
+                name,
+                generation: Some(generation),
+            } => write!(f, "{}#{}", name, generation),
+            &Var::Function(function) => write!(f, "fn{}", function),
+            &Var::Phantom(n) => write!(f, "id_{}", n),
+        }
+    }
+}
+
+/// Prints `cont` in the layout of `ast_cont::smart_print`.
+pub fn smart_print<'c>(cont: BoundContPtr<'c>, out: &mut Formatter, indent_count: u32) -> FmtResult {
+    fn indent(out: &mut Formatter, n: u32) -> FmtResult {
+        for _ in 0..(n * 4) {
+            out.write_char(' ')?;
+        }
+        Ok(())
+    }
+
+    match cont {
+        &BoundCont::Primop {
+            ref op,
+            ref terminals,
+            ref exports,
+            ref continuations,
+        } => {
+            indent(out, indent_count)?;
+            writeln!(out, "{:?}({:?}) -> ({:?}) =>", op, terminals, exports)?;
+            for cont in continuations {
+                smart_print(cont, out, indent_count + 1)?;
+            }
+        }
+        &BoundCont::Fix {
+            ref functions,
+            continuation,
+        } => {
+            for function in functions {
+                indent(out, indent_count)?;
+                writeln!(out, "fix fn {:?}({:?}) =>", function.name, function.params)?;
+                smart_print(function.body, out, indent_count + 1)?;
+            }
+            indent(out, indent_count + 1)?;
+            writeln!(out, "continue with:")?;
+            smart_print(continuation, out, indent_count + 2)?;
+        }
+        &BoundCont::Call {
+            ref target,
+            ref params,
+            ref continuation,
+        } => {
+            indent(out, indent_count)?;
+            write!(out, "call {:?}({:?})", target, params)?;
+            if let &Some(ref continuation) = continuation {
+                write!(out, " -> {:?}", continuation)?;
+            }
+            out.write_char('\n')?;
+        }
     }
+
+    Ok(())
 }
@@ -0,0 +1,190 @@
+use super::{convert_top, BoundCont, Converter};
+use binder::bind_top;
+use difference::Changeset;
+use lexer::{lex, remove_whitespace};
+use parser::parse_module;
+use typed_arena::Arena;
+
+fn convert_module_eq(program: &'static str, expected: &str) {
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let bind_arena = Arena::new();
+    let cont_arena: Arena<BoundCont> = Arena::new();
+
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap().0;
+    let desugared = ast_lower::desugar(parsed, &mut alloc);
+    let bound = bind_arena.alloc(bind_top(&bind_arena, desugared).unwrap());
+    let converter = Converter::new(&cont_arena);
+    let actual = format!("{:?}", convert_top(bound, &converter));
+
+    if actual.trim() != expected.trim() {
+        panic!("\n{}", Changeset::new(actual.trim(), expected.trim(), "\n"));
+    }
+}
+
+#[test]
+fn module_definitions_refer_to_earlier_ones() {
+    convert_module_eq(
+        "let x = 1; let y = x + 1; debug(y);",
+        r#"
+Bind([1]) -> ([x]) =>
+    Add([x, 1]) -> ([id_0]) =>
+        Bind([id_0]) -> ([y]) =>
+            Debug([y]) -> ([id_1]) =>
+                MapEmpty([]) -> ([id_2]) =>
+                    Term([id_2]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn shadowing_in_nested_blocks() {
+    convert_module_eq(
+        "let x = 1; let y = { let x = 2; { let x = x * 10; x } }; debug(x + y);",
+        r#"
+Bind([1]) -> ([x]) =>
+    Bind([2]) -> ([x#1]) =>
+        Mul([x#1, 10]) -> ([id_0]) =>
+            Bind([id_0]) -> ([x#3]) =>
+                Bind([x#3]) -> ([y]) =>
+                    Add([x, y]) -> ([id_1]) =>
+                        Debug([id_1]) -> ([id_2]) =>
+                            MapEmpty([]) -> ([id_3]) =>
+                                Term([id_3]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn local_shadows_argument_shadowing_module_variable() {
+    convert_module_eq(
+        "let x = 1; let f(x) = { let x = x + 1; x }; debug(f(x));",
+        r#"
+Bind([1]) -> ([x]) =>
+    fix fn fn0([x@fn0.arg0, id_0]) =>
+        Add([x@fn0.arg0, 1]) -> ([id_1]) =>
+            Bind([id_1]) -> ([x@fn0.local0]) =>
+                call id_0([x@fn0.local0])
+        continue with:
+            Bind([fn0]) -> ([f]) =>
+                fix fn id_2([id_3]) =>
+                    Debug([id_3]) -> ([id_4]) =>
+                        MapEmpty([]) -> ([id_5]) =>
+                            Term([id_5]) -> ([]) =>
+                    continue with:
+                        call f([x]) -> id_2
+    "#,
+    );
+}
+
+#[test]
+fn upvars_resolve_to_the_captured_variable() {
+    convert_module_eq(
+        "let f(a) = { let g(b) = a + b; g(a) };",
+        r#"
+fix fn fn0([a@fn0.arg0, id_0]) =>
+    fix fn fn1([b@fn1.arg0, id_1]) =>
+        Add([a@fn0.arg0, b@fn1.arg0]) -> ([id_2]) =>
+            call id_1([id_2])
+        continue with:
+            Bind([fn1]) -> ([g@fn0.local0]) =>
+                fix fn id_3([id_4]) =>
+                    call id_0([id_4])
+                    continue with:
+                        call g@fn0.local0([a@fn0.arg0]) -> id_3
+    continue with:
+        Bind([fn0]) -> ([f]) =>
+            MapEmpty([]) -> ([id_5]) =>
+                Term([id_5]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn inner_parameter_shadows_outer_parameter() {
+    convert_module_eq(
+        "let h(x) = (x) => x;",
+        r#"
+fix fn fn0([x@fn0.arg0, id_0]) =>
+    fix fn fn1([x@fn1.arg0, id_1]) =>
+        call id_1([x@fn1.arg0])
+        continue with:
+            call id_0([fn1])
+    continue with:
+        Bind([fn0]) -> ([h]) =>
+            MapEmpty([]) -> ([id_2]) =>
+                Term([id_2]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn recursive_call_refers_to_the_current_function() {
+    convert_module_eq(
+        "let r(n) = r(n);",
+        r#"
+fix fn fn0([n@fn0.arg0, id_0]) =>
+    fix fn id_1([id_2]) =>
+        call id_0([id_2])
+        continue with:
+            call fn0([n@fn0.arg0]) -> id_1
+    continue with:
+        Bind([fn0]) -> ([r]) =>
+            MapEmpty([]) -> ([id_3]) =>
+                Term([id_3]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn captured_local_is_not_the_shadowing_one() {
+    convert_module_eq(
+        "let a = 1; let f(x) = { let a = x; let g() = { let a = a * 2; a }; g() + a };",
+        r#"
+Bind([1]) -> ([a]) =>
+    fix fn fn0([x@fn0.arg0, id_0]) =>
+        Bind([x@fn0.arg0]) -> ([a@fn0.local0]) =>
+            fix fn fn1([id_1]) =>
+                Mul([a@fn0.local0, 2]) -> ([id_2]) =>
+                    Bind([id_2]) -> ([a@fn1.local0]) =>
+                        call id_1([a@fn1.local0])
+                continue with:
+                    Bind([fn1]) -> ([g@fn0.local1]) =>
+                        fix fn id_4([id_5]) =>
+                            Add([id_5, a@fn0.local0]) -> ([id_3]) =>
+                                call id_0([id_3])
+                            continue with:
+                                call g@fn0.local1([]) -> id_4
+        continue with:
+            Bind([fn0]) -> ([f]) =>
+                MapEmpty([]) -> ([id_6]) =>
+                    Term([id_6]) -> ([]) =>
+    "#,
+    );
+}
+
+#[test]
+fn function_groups_share_a_fix() {
+    convert_module_eq(
+        "let even(n) = odd(n); let odd(n) = even(n);",
+        r#"
+fix fn fn0([n@fn0.arg0, id_0]) =>
+    fix fn id_1([id_2]) =>
+        call id_0([id_2])
+        continue with:
+            call fn1([n@fn0.arg0]) -> id_1
+fix fn fn1([n@fn1.arg0, id_3]) =>
+    fix fn id_4([id_5]) =>
+        call id_3([id_5])
+        continue with:
+            call fn0([n@fn1.arg0]) -> id_4
+    continue with:
+        Bind([fn0]) -> ([even]) =>
+            Bind([fn1]) -> ([odd]) =>
+                MapEmpty([]) -> ([id_6]) =>
+                    Term([id_6]) -> ([]) =>
+    "#,
+    );
+}
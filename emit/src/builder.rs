@@ -0,0 +1,241 @@
+use super::*;
+use std::mem;
+use peephole::same_constant;
+use vm::debug_info::{LineTable, Span};
+use vm::value::Code;
+
+// The VM only returns from a call by resuming the continuation that was
+// passed to it, so every call site splits the enclosing function in two.
+// The code after the call is emitted into a new segment which becomes a
+// one-argument continuation function.  That continuation captures the whole
+// frame of the segment that made the call (args, upvars, locals and any
+// scratch values) as its upvars, so its stack looks like:
+//
+// [continuation]
+// [call result]
+// [captured frame...]
+//
+// and every frame position is shifted by `frame_base` (2 for continuations).
+//
+// Each segment has its own constant pool, and its own line table.  Instructions are attributed to
+// whichever span was set with `set_span` when they were pushed, so the first
+// instruction of a continuation points back at the call that created it.
+
+struct Segment {
+    instructions: Vec<Instruction>,
+    constants: Vec<Value>,
+    line_table: LineTable,
+    upvars_count: u32,
+}
+
+struct Patch {
+    segment: usize,
+    constant: usize,
+    continuation: usize,
+}
+
+pub struct CallSite {
+    segment: usize,
+    constant: usize,
+    captured: u32,
+}
+
+pub struct FunctionBuilder {
+    name: Option<String>,
+    info: FunctionInfo,
+    segments: Vec<Segment>,
+    patches: Vec<Patch>,
+    out: Vec<Instruction>,
+    constants: Vec<Value>,
+    line_table: LineTable,
+    span: Option<Span>,
+    upvars_count: u32,
+    depth: u32,
+}
+
+impl FunctionBuilder {
+    pub fn new(name: Option<String>, info: FunctionInfo) -> FunctionBuilder {
+        FunctionBuilder {
+            name,
+            info,
+            segments: vec![],
+            patches: vec![],
+            out: vec![],
+            constants: vec![],
+            line_table: LineTable::new(),
+            span: None,
+            upvars_count: info.upvars_count,
+            depth: 1 + info.args_count + info.upvars_count + info.locals_count,
+        }
+    }
+
+    /// Attributes the instructions pushed from now on to `span`, returning
+    /// the previous span so that it can be restored afterwards.
+    pub fn set_span(&mut self, span: Option<Span>) -> Option<Span> {
+        mem::replace(&mut self.span, span)
+    }
+
+    fn push_raw(&mut self, instruction: Instruction) {
+        if let Some(span) = self.span {
+            self.line_table.push(self.out.len(), span);
+        }
+        self.out.push(instruction);
+    }
+
+    pub fn push(&mut self, instruction: Instruction) {
+        use vm::vm::Instruction::*;
+        match instruction {
+            Add | Sub | Mul | Div => self.depth -= 1,
+            PushConst(_) | GetFromStackPosition(_) | Dup | MapEmpty | CurrentContinuation => {
+                self.depth += 1
+            }
+            SetToStackPosition(_) | Pop | Debug | MapGet | ModuleGet => self.depth -= 1,
+            Swap | Print | CheckType(_) => {}
+            ModuleAdd => self.depth -= 3,
+            MapInsert => self.depth -= 2,
+            BuildFunction => {
+                let upvars = match self.out.last() {
+                    Some(&PushConst(index)) => match self.constants[index as usize] {
+                        Value::Function(ref f) => f.upvars_count,
+                        _ => panic!("BuildFunction must follow the function it builds"),
+                    },
+                    _ => panic!("BuildFunction must follow the function it builds"),
+                };
+                self.depth -= upvars;
+            }
+            Call(_) | Terminate | Reset | Shift | Resume | CallNative(_) => {
+                panic!("control transfer must go through the builder")
+            }
+        }
+        self.push_raw(instruction);
+    }
+
+    /// Builds the function on top of the stack from the `upvars_count`
+    /// values below it, for functions that were not pushed as a constant
+    /// just before, such as ones read from an upvar.
+    pub fn build_function(&mut self, upvars_count: u32) {
+        self.depth -= upvars_count;
+        self.push_raw(Instruction::BuildFunction);
+    }
+
+    /// Pushes `value` from the current segment's constant pool.  Equal
+    /// constants share an entry, except functions, which are never merged.
+    pub fn push_const(&mut self, value: Value) {
+        let index = self.add_constant(value);
+        self.push(Instruction::PushConst(index as u32));
+    }
+
+    fn add_constant(&mut self, value: Value) -> usize {
+        if let Some(index) = self.constants.iter().position(|c| same_constant(c, &value)) {
+            return index;
+        }
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    pub fn emit_getter(&mut self, binding_kind: &BindingKind) {
+        let info = self.info;
+        info.emit_binding_kind_getter(binding_kind, self);
+    }
+
+    pub fn emit_setter(&mut self, binding_kind: &BindingKind) {
+        let info = self.info;
+        info.emit_binding_kind_setter(binding_kind, self);
+    }
+
+    /// Pushes the continuation for a call that is about to be emitted.  The
+    /// target and arguments go on top of it, followed by `end_call`.
+    pub fn begin_call(&mut self) -> CallSite {
+        let frame_base = self.info.frame_base;
+        let captured = self.depth - frame_base;
+        for pos in frame_base..self.depth {
+            self.push(Instruction::GetFromStackPosition(pos));
+        }
+
+        // A placeholder with the right upvar count; `finish` swaps in the
+        // real continuation once it has been built.
+        self.push_const(Value::Function(new_func(Function::new(Code {
+            name: self.name.clone(),
+            instructions: vec![],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: captured,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }))));
+        let site = CallSite {
+            segment: self.segments.len(),
+            constant: self.constants.len() - 1,
+            captured,
+        };
+        self.push(Instruction::BuildFunction);
+        site
+    }
+
+    /// Ends the current segment with a call and continues emitting into the
+    /// continuation that `begin_call` pushed, with the result on top.
+    pub fn end_call(&mut self, site: CallSite, arg_count: u32) {
+        self.push_raw(Instruction::Call(arg_count));
+        self.finish_segment();
+        self.patches.push(Patch {
+            segment: site.segment,
+            constant: site.constant,
+            continuation: self.segments.len(),
+        });
+
+        self.info.frame_base = 2;
+        self.upvars_count = site.captured;
+        self.depth = 2 + site.captured;
+        self.push(Instruction::GetFromStackPosition(1));
+    }
+
+    /// Returns the value on top of the stack to the current continuation.
+    pub fn emit_return(&mut self) {
+        self.push_raw(Instruction::Resume);
+        self.finish_segment();
+    }
+
+    fn finish_segment(&mut self) {
+        let instructions = mem::replace(&mut self.out, vec![]);
+        let constants = mem::replace(&mut self.constants, vec![]);
+        let line_table = mem::replace(&mut self.line_table, LineTable::new());
+        self.segments.push(Segment {
+            instructions,
+            constants,
+            line_table,
+            upvars_count: self.upvars_count,
+        });
+    }
+
+    pub fn finish(mut self) -> Function {
+        assert!(self.out.is_empty(), "function body was not terminated");
+
+        let mut built: Vec<Option<Function>> = (0..self.segments.len()).map(|_| None).collect();
+        while let Some(segment) = self.segments.pop() {
+            let id = self.segments.len();
+            let mut constants = segment.constants;
+            for patch in self.patches.iter().filter(|p| p.segment == id) {
+                let continuation = built[patch.continuation].clone().unwrap();
+                constants[patch.constant] = Value::Function(new_func(continuation));
+            }
+
+            let (args_count, locals_count) = if id == 0 {
+                (self.info.args_count, self.info.locals_count)
+            } else {
+                (1, 0)
+            };
+
+            built[id] = Some(Function::new(Code {
+                name: self.name.clone(),
+                instructions: segment.instructions,
+                constants,
+                args_count,
+                upvars_count: segment.upvars_count,
+                locals_count,
+                line_table: segment.line_table,
+            }));
+        }
+
+        built.swap_remove(0).unwrap()
+    }
+}
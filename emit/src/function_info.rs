@@ -2,18 +2,28 @@ use super::*;
 use std::ops::Deref;
 
 // Function Local layouts
+// [self]
 // [args]
 // [upvars]
 // [locals]
 // [..scratch space..]
+//
+// A function declared in a `FunctionGroup` has the code of every function
+// in the group as its last `siblings` upvars, and builds a sibling from it
+// and a copy of all of its own upvars whenever it refers to one.
+//
+// `frame_base` is the stack position of `self`; it is 0 in the function's
+// first segment and moves up in the continuations split off at call sites.
 #[derive(Clone, Copy)]
 pub struct FunctionInfo {
     pub args_count: u32,
     pub upvars_count: u32,
     pub locals_count: u32,
+    pub frame_base: u32,
+    pub siblings: u32,
 }
 
-fn fallback_emit_binding_kind_prelude(binding_kind: &BindingKind, out: &mut Vec<Instruction>) {
+fn fallback_emit_binding_kind_prelude(binding_kind: &BindingKind, out: &mut FunctionBuilder) {
     match binding_kind {
         &BindingKind::Module {
             module_id,
@@ -23,39 +33,53 @@ fn fallback_emit_binding_kind_prelude(binding_kind: &BindingKind, out: &mut Vec<
                 &DeclarationKind::Named(s) => s.into(),
                 &DeclarationKind::Generated(n, s) => format!("{}${}", s, n),
             };
-            out.push(Instruction::Push(Value::Symbol(Symbol(module_id.into()))));
-            out.push(Instruction::Push(Value::Symbol(Symbol(stringed))));
+            out.push_const(Value::Symbol(Symbol(stringed)));
+            out.push_const(Value::Symbol(Symbol(module_id.into())));
         }
         _ => panic!(),
     }
 }
 
-pub fn fallback_emit_binding_kind_getter(binding_kind: &BindingKind, out: &mut Vec<Instruction>) {
+pub fn fallback_emit_binding_kind_getter(binding_kind: &BindingKind, out: &mut FunctionBuilder) {
     fallback_emit_binding_kind_prelude(binding_kind, out);
     out.push(Instruction::ModuleGet);
 }
 
-pub fn fallback_emit_binding_kind_setter(binding_kind: &BindingKind, out: &mut Vec<Instruction>) {
+pub fn fallback_emit_binding_kind_setter(binding_kind: &BindingKind, out: &mut FunctionBuilder) {
     fallback_emit_binding_kind_prelude(binding_kind, out);
     out.push(Instruction::ModuleAdd);
 }
 
 impl FunctionInfo {
-    pub fn emit_binding_kind_getter(&self, binding_kind: &BindingKind, out: &mut Vec<Instruction>) {
+    pub fn emit_binding_kind_getter(&self, binding_kind: &BindingKind, out: &mut FunctionBuilder) {
         match binding_kind {
-            &BindingKind::CurrentFunction => out.push(Instruction::GetFromStackPosition(0)),
+            &BindingKind::CurrentFunction => {
+                out.push(Instruction::GetFromStackPosition(self.frame_base))
+            }
             &BindingKind::Argument(arg_index) => {
-                out.push(Instruction::GetFromStackPosition(1 + arg_index));
+                out.push(Instruction::GetFromStackPosition(
+                    self.frame_base + 1 + arg_index,
+                ));
             }
             &BindingKind::Upvar(upvar_index) => {
                 out.push(Instruction::GetFromStackPosition(
-                    1 + self.args_count + upvar_index,
+                    self.frame_base + 1 + self.args_count + upvar_index,
                 ));
             }
             &BindingKind::FunctionLocal(local_idx) => {
                 out.push(Instruction::GetFromStackPosition(
-                    1 + self.args_count + self.upvars_count + local_idx,
+                    self.frame_base + 1 + self.args_count + self.upvars_count + local_idx,
+                ));
+            }
+            &BindingKind::Sibling(index) => {
+                let first_upvar = self.frame_base + 1 + self.args_count;
+                for pos in first_upvar..first_upvar + self.upvars_count {
+                    out.push(Instruction::GetFromStackPosition(pos));
+                }
+                out.push(Instruction::GetFromStackPosition(
+                    first_upvar + self.upvars_count - self.siblings + index,
                 ));
+                out.build_function(self.upvars_count);
             }
             &BindingKind::Module { .. } => {
                 fallback_emit_binding_kind_getter(binding_kind, out);
@@ -63,20 +87,21 @@ impl FunctionInfo {
         }
     }
 
-    pub fn emit_binding_kind_setter(&self, binding_kind: &BindingKind, out: &mut Vec<Instruction>) {
+    pub fn emit_binding_kind_setter(&self, binding_kind: &BindingKind, out: &mut FunctionBuilder) {
         match binding_kind {
-            &BindingKind::CurrentFunction => out.push(Instruction::GetFromStackPosition(0)),
+            &BindingKind::CurrentFunction => panic!("cannot assign to the current function"),
+            &BindingKind::Sibling(_) => panic!("cannot assign to a sibling function"),
             &BindingKind::Argument(arg_index) => {
-                out.push(Instruction::SetToStackPosition(1 + arg_index));
+                out.push(Instruction::SetToStackPosition(self.frame_base + 1 + arg_index));
             }
             &BindingKind::Upvar(upvar_index) => {
                 out.push(Instruction::SetToStackPosition(
-                    1 + self.args_count + upvar_index,
+                    self.frame_base + 1 + self.args_count + upvar_index,
                 ));
             }
             &BindingKind::FunctionLocal(local_idx) => {
                 out.push(Instruction::SetToStackPosition(
-                    1 + self.args_count + self.upvars_count + local_idx,
+                    self.frame_base + 1 + self.args_count + self.upvars_count + local_idx,
                 ));
             }
             &BindingKind::Module { .. } => {
@@ -1,75 +1,229 @@
+extern crate ast_lower;
 extern crate binder;
 extern crate lexer;
 extern crate parser;
 extern crate typed_arena;
 extern crate vm;
+#[cfg(test)]
+extern crate copy_arena;
 
 #[cfg(test)]
 mod test;
+mod builder;
 mod function_info;
+pub mod peephole;
 
 use binder::{BindingKind, Bound, DeclarationKind};
+use std::collections::HashMap;
+use vm::debug_info::Span;
+use parser::{Ast, TypeAst};
 use vm::value::{new_func, Function, Value};
 use vm::vm::Instruction;
 use vm::value::Symbol;
-use function_info::*;
+pub use builder::FunctionBuilder;
+pub use function_info::*;
+
+fn top_level_builder(name: Option<String>) -> FunctionBuilder {
+    FunctionBuilder::new(
+        name,
+        FunctionInfo {
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            frame_base: 0,
+            siblings: 0,
+        },
+    )
+}
 
 pub fn emit_top(node: &Bound) -> Value {
     match node {
-        &Bound::Module { ref statements, .. } => {
-            let mut instructions = vec![];
+        &Bound::Module {
+            ref statements,
+            ref binder,
+            ..
+        } => {
+            let mut out = top_level_builder(Some(binder.module_id.into()));
+            out.set_span(debug_span(node.span()));
             for statement in statements {
-                if emit(statement, &mut instructions, None) {
-                    instructions.push(Instruction::Pop);
+                if emit(statement, &mut out) {
+                    out.push(Instruction::Pop);
                 }
             }
 
-            instructions.push(Instruction::MapEmpty);
-            panic!();
-            //instructions.push(Instruction::Ret);
-
-            Value::Function(new_func(Function {
-                instructions,
-                upvars: vec![],
-                name: None,
-                args_count: 0,
-                upvars_count: 0,
-                locals_count: 0,
-            }))
+            out.push(Instruction::MapEmpty);
+            out.emit_return();
+
+            Value::Function(new_func(out.finish()))
         }
         b => panic!("unexpected bound node {:?}", b),
     }
 }
 
-pub fn emit(
-    node: &Bound,
-    out: &mut Vec<Instruction>,
-    current_function: Option<FunctionInfo>,
-) -> bool {
+/// Emits a function that evaluates a single expression and returns its value.
+pub fn emit_top_expression(node: &Bound) -> Value {
+    let mut out = top_level_builder(None);
+    assert!(emit(node, &mut out));
+    out.emit_return();
+    Value::Function(new_func(out.finish()))
+}
+
+/// Checks that the value on top of the stack, which came from the code at
+/// `span`, matches `annotation`.  Annotated code can be called from, and
+/// call, code without annotations, so nothing the checker proved about it
+/// holds unless the values crossing into it are checked as they arrive.
+fn emit_check(annotation: Option<&TypeAst>, span: lexer::Span, out: &mut FunctionBuilder) {
+    if let Some(annotation) = annotation {
+        let outer = out.set_span(debug_span(span));
+        out.push(Instruction::CheckType(ast_lower::value_kind(annotation)));
+        out.set_span(outer);
+    }
+}
+
+/// Compiles the body of a function into code that still has to be built
+/// with its upvars.
+fn compile_function(name: Option<&str>, ast: &Ast, body: &Bound, fn_info: FunctionInfo) -> Value {
+    let (annotations, result) = match ast {
+        &Ast::FunctionDecl {
+            params, result, ..
+        } => (params, result),
+        &Ast::AnonFunc { params, .. } => (params, None),
+        _ => (&[] as &[_], None),
+    };
+
+    let mut body_out = FunctionBuilder::new(name.map(Into::into), fn_info);
+    for (i, &(_, param, annotation)) in annotations.iter().enumerate() {
+        if annotation.is_some() {
+            body_out.emit_getter(&BindingKind::Argument(i as u32));
+            emit_check(annotation, param.span(), &mut body_out);
+            body_out.push(Instruction::Pop);
+        }
+    }
+    assert!(emit(body, &mut body_out));
+    emit_check(result, body.span(), &mut body_out);
+    body_out.emit_return();
+    Value::Function(new_func(body_out.finish()))
+}
+
+/// Pushes every upvar in `upvars` in order, read from the enclosing frame.
+fn emit_upvars(upvars: &HashMap<DeclarationKind, (BindingKind, u32)>, out: &mut FunctionBuilder) {
+    let mut upvars = upvars.values().collect::<Vec<_>>();
+    upvars.sort_by_key(|&&(_, pos)| pos);
+    for &&(ref upvar, _) in &upvars {
+        out.emit_getter(upvar);
+    }
+}
+
+fn emit_function(
+    name: Option<&str>,
+    ast: &Ast,
+    params: &[(DeclarationKind, &Ast)],
+    body: &Bound,
+    locals: &[DeclarationKind],
+    upvars: &HashMap<DeclarationKind, (BindingKind, u32)>,
+    out: &mut FunctionBuilder,
+) {
+    let fn_info = FunctionInfo {
+        args_count: params.len() as u32,
+        upvars_count: upvars.len() as u32,
+        locals_count: locals.len() as u32,
+        frame_base: 0,
+        siblings: 0,
+    };
+    let function_value = compile_function(name, ast, body, fn_info);
+
+    emit_upvars(upvars, out);
+    out.push_const(function_value);
+    out.push(Instruction::BuildFunction);
+}
+
+/// Builds every function of a group and stores it in its location.  Each
+/// function's upvars are the ones the group shares followed by the code of
+/// every function in the group, still unbuilt, which is all it needs to
+/// build any of them again when it refers to them.
+fn emit_function_group(functions: &[Bound], out: &mut FunctionBuilder) {
+    let shared = match functions.first() {
+        Some(&Bound::FunctionDecl { ref upvars, .. }) => upvars,
+        _ => panic!("a function group must start with a function declaration"),
+    };
+    let siblings = functions.len() as u32;
+    let codes = functions
+        .iter()
+        .map(|function| match function {
+            &Bound::FunctionDecl {
+                ref params,
+                ref body,
+                ref locals,
+                name,
+                ast,
+                ..
+            } => {
+                let fn_info = FunctionInfo {
+                    args_count: params.len() as u32,
+                    upvars_count: shared.len() as u32 + siblings,
+                    locals_count: locals.len() as u32,
+                    frame_base: 0,
+                    siblings,
+                };
+                compile_function(Some(name), ast, body, fn_info)
+            }
+            other => panic!("{:?} is not a function declaration", other),
+        })
+        .collect::<Vec<_>>();
+
+    for (function, code) in functions.iter().zip(&codes) {
+        if let &Bound::FunctionDecl { ref location, .. } = function {
+            let outer = out.set_span(debug_span(function.span()));
+            emit_upvars(shared, out);
+            for sibling in &codes {
+                out.push_const(sibling.clone());
+            }
+            out.push_const(code.clone());
+            out.push(Instruction::BuildFunction);
+            out.emit_setter(location);
+            out.set_span(outer);
+        }
+    }
+}
+
+fn debug_span(span: lexer::Span) -> Option<Span> {
+    Some(Span {
+        start: span.start,
+        end: span.end,
+    })
+}
+
+pub fn emit(node: &Bound, out: &mut FunctionBuilder) -> bool {
+    let outer = out.set_span(debug_span(node.span()));
+    let produced_value = emit_node(node, out);
+    out.set_span(outer);
+    produced_value
+}
+
+fn emit_node(node: &Bound, out: &mut FunctionBuilder) -> bool {
     fn emit_binary(
         left: &Bound,
         right: &Bound,
-        out: &mut Vec<Instruction>,
+        out: &mut FunctionBuilder,
         instruction: Instruction,
-        current_function: Option<FunctionInfo>,
     ) -> bool {
-        assert!(emit(left, out, current_function));
-        assert!(emit(right, out, current_function));
+        assert!(emit(left, out));
+        assert!(emit(right, out));
         out.push(instruction);
         true
     }
 
     match node {
         &Bound::Integer { value, .. } => {
-            out.push(Instruction::Push(Value::Integer(value)));
+            out.push_const(Value::Integer(value));
             true
         }
         &Bound::Float { value, .. } => {
-            out.push(Instruction::Push(Value::Float(value)));
+            out.push_const(Value::Float(value));
             true
         }
         &Bound::DebugCall { arg, .. } => {
-            assert!(emit(arg, out, current_function));
+            assert!(emit(arg, out));
             out.push(Instruction::Debug);
             false
         }
@@ -77,33 +231,33 @@ pub fn emit(
             ref left,
             ref right,
             ..
-        } => emit_binary(left, right, out, Instruction::Add, current_function),
+        } => emit_binary(left, right, out, Instruction::Add),
         &Bound::Sub {
             ref left,
             ref right,
             ..
-        } => emit_binary(left, right, out, Instruction::Sub, current_function),
+        } => emit_binary(left, right, out, Instruction::Sub),
         &Bound::Mul {
             ref left,
             ref right,
             ..
-        } => emit_binary(left, right, out, Instruction::Mul, current_function),
+        } => emit_binary(left, right, out, Instruction::Mul),
         &Bound::Div {
             ref left,
             ref right,
             ..
-        } => emit_binary(left, right, out, Instruction::Div, current_function),
+        } => emit_binary(left, right, out, Instruction::Div),
         &Bound::BlockExpr {
             ref statements,
             ref final_expression,
             ..
         } => {
             for statement in statements {
-                if emit(statement, out, current_function) {
+                if emit(statement, out) {
                     out.push(Instruction::Pop);
                 }
             }
-            assert!(emit(final_expression, out, current_function));
+            assert!(emit(final_expression, out));
             true
         }
         &Bound::FieldAccess {
@@ -111,34 +265,26 @@ pub fn emit(
             field_name,
             ..
         } => {
-            assert!(emit(target, out, current_function));
-            out.push(Instruction::Push(Value::Symbol(Symbol(field_name.into()))));
+            assert!(emit(target, out));
+            out.push_const(Value::Symbol(Symbol(field_name.into())));
             out.push(Instruction::MapGet);
             true
         }
         &Bound::Identifier {
             ref binding_kind, ..
         } => {
-            if let Some(ref fi) = current_function {
-                fi.emit_binding_kind_getter(binding_kind, out);
-            } else {
-                fallback_emit_binding_kind_getter(binding_kind, out);
-            }
+            out.emit_getter(binding_kind);
             true
         }
         &Bound::VariableDecl {
             ref expression,
+            ty,
             ref location,
             ..
         } => {
-            assert!(emit(expression, out, current_function));
-
-            if let Some(ref fi) = current_function {
-                fi.emit_binding_kind_setter(location, out);
-            } else {
-                fallback_emit_binding_kind_setter(location, out);
-            }
-
+            assert!(emit(expression, out));
+            emit_check(ty, expression.span(), out);
+            out.emit_setter(location);
             false
         }
         &Bound::FunctionCall {
@@ -146,11 +292,12 @@ pub fn emit(
             ref args,
             ..
         } => {
-            assert!(emit(target, out, current_function));
+            let site = out.begin_call();
+            assert!(emit(target, out));
             for arg in args {
-                assert!(emit(arg, out, current_function));
+                assert!(emit(arg, out));
             }
-            out.push(Instruction::Call(args.len() as u32));
+            out.end_call(site, args.len() as u32);
             true
         }
         &Bound::FunctionDecl {
@@ -160,47 +307,28 @@ pub fn emit(
             ref upvars,
             ref location,
             name,
+            ast,
             ..
         } => {
-            let fn_info = FunctionInfo {
-                args_count: params.len() as u32,
-                upvars_count: upvars.len() as u32,
-                locals_count: locals.len() as u32,
-            };
-
-            let mut instrs = vec![];
-            assert!(emit(body, &mut instrs, Some(fn_info)));
-            panic!();
-            //instrs.push(Instruction::Ret);
-
-            let function_value = Value::Function(new_func(Function {
-                instructions: instrs,
-                upvars: vec![],
-                name: Some(name.into()),
-                args_count: params.len() as u32,
-                upvars_count: upvars.len() as u32,
-                locals_count: locals.len() as u32,
-            }));
-
-            for (_, &(ref upvar, _)) in upvars {
-                if let Some(ref fi) = current_function {
-                    fi.emit_binding_kind_getter(upvar, out);
-                } else {
-                    fallback_emit_binding_kind_getter(upvar, out);
-                }
-            }
-
-            out.push(Instruction::Push(function_value));
-            out.push(Instruction::BuildFunction);
-
-            if let Some(ref fi) = current_function {
-                fi.emit_binding_kind_setter(location, out);
-            } else {
-                fallback_emit_binding_kind_setter(location, out);
-            }
-
+            emit_function(Some(name), ast, params, body, locals, upvars, out);
+            out.emit_setter(location);
             false
         }
+        &Bound::FunctionGroup { ref functions } => {
+            emit_function_group(functions, out);
+            false
+        }
+        &Bound::AnonFunc {
+            ref params,
+            ref body,
+            ref locals,
+            ref upvars,
+            ast,
+            ..
+        } => {
+            emit_function(None, ast, params, body, locals, upvars, out);
+            true
+        }
         other => unimplemented!("emit({:?}) is not implemented", other),
     }
 }
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use vm::debug_info::{LineTable, Span};
+use vm::value::{new_func, Code, Function, FunctionPtr, Value};
+use vm::vm::Instruction;
+use vm::vm::Instruction::*;
+
+// Rewrites short windows of instructions into cheaper equivalents.
+//
+// Calls end a segment of the emitter's output and nothing jumps, so every
+// function is one straight line of code and a window never straddles a
+// control transfer.  Continuations are ordinary function constants and are
+// optimized on their own.  Stack positions are absolute, so dropping a
+// push together with the pop that discards it moves no other slot.
+//
+// A frame slot (self, args, upvars and locals) always sits below every
+// scratch value, so reading one is pure and cannot observe the values that
+// a window pushes.  Rewrites that rely on that only apply to those slots.
+
+/// Optimizes `function` and every function reachable from its constants.
+/// Functions that shared code before still share it afterwards.
+pub fn optimize(function: &FunctionPtr) -> FunctionPtr {
+    let mut done = HashMap::new();
+    optimize_shared(function, &mut done)
+}
+
+fn optimize_shared(function: &FunctionPtr, done: &mut HashMap<*const Code, Rc<Code>>) -> FunctionPtr {
+    let key = &*function.code as *const Code;
+    let code = match done.get(&key) {
+        Some(code) => code.clone(),
+        None => {
+            let code = Rc::new(optimize_code(&function.code, done));
+            done.insert(key, code.clone());
+            code
+        }
+    };
+    let mut optimized = Function::new(code);
+    optimized.is_built = function.is_built;
+    optimized.built = function.built.clone();
+    new_func(optimized)
+}
+
+fn optimize_code(code: &Code, done: &mut HashMap<*const Code, Rc<Code>>) -> Code {
+    let mut constants = code.constants
+        .iter()
+        .map(|constant| match constant {
+            &Value::Function(ref f) => Value::Function(optimize_shared(f, done)),
+            other => other.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    let frame_size = 1 + code.args_count + code.upvars_count + code.locals_count;
+    let mut out: Vec<(Instruction, Option<Span>)> = vec![];
+    for (i, &instruction) in code.instructions.iter().enumerate() {
+        out.push((instruction, code.line_table.lookup(i)));
+        while rewrite(&mut out, &mut constants, frame_size) {}
+    }
+
+    let (instructions, constants) = compact_constants(out.iter().map(|&(i, _)| i), constants);
+    let mut line_table = LineTable::new();
+    for (index, &(_, span)) in out.iter().enumerate() {
+        if let Some(span) = span {
+            line_table.push(index, span);
+        }
+    }
+
+    Code {
+        name: code.name.clone(),
+        instructions,
+        constants,
+        args_count: code.args_count,
+        upvars_count: code.upvars_count,
+        locals_count: code.locals_count,
+        line_table,
+    }
+}
+
+/// Pushes a value without looking at or changing anything else.
+fn is_pure_push(instruction: Instruction, frame_size: u32) -> bool {
+    match instruction {
+        PushConst(_) | MapEmpty => true,
+        GetFromStackPosition(pos) => pos < frame_size,
+        _ => false,
+    }
+}
+
+/// Tries one rewrite on the end of `out`, returning whether it changed.
+fn rewrite(
+    out: &mut Vec<(Instruction, Option<Span>)>,
+    constants: &mut Vec<Value>,
+    frame_size: u32,
+) -> bool {
+    let len = out.len();
+    let last = |n: usize| if len >= n { Some(out[len - n].0) } else { None };
+
+    match (last(3), last(2), last(1)) {
+        // `5;` pushes a value only to discard it.
+        (_, Some(push), Some(Pop)) if is_pure_push(push, frame_size) || push == Dup => {
+            out.truncate(len - 2);
+            true
+        }
+        (_, Some(GetFromStackPosition(a)), Some(SetToStackPosition(b)))
+            if a == b && a < frame_size =>
+        {
+            out.truncate(len - 2);
+            true
+        }
+        // A constant always passes or always fails its check.
+        (_, Some(PushConst(index)), Some(CheckType(kind)))
+            if constants[index as usize].kind() == kind =>
+        {
+            out.truncate(len - 1);
+            true
+        }
+        (_, Some(Swap), Some(Swap)) => {
+            out.truncate(len - 2);
+            true
+        }
+        // Both copies on top are the same value.
+        (_, Some(Dup), Some(Swap)) => {
+            out.truncate(len - 1);
+            true
+        }
+        (Some(PushConst(a)), Some(PushConst(b)), Some(op)) => {
+            let folded = op.arithmetic()
+                .and_then(|op| op.apply(&constants[a as usize], &constants[b as usize]).ok());
+            match folded {
+                Some(value) => {
+                    let span = out[len - 1].1;
+                    out.truncate(len - 3);
+                    constants.push(value);
+                    out.push((PushConst(constants.len() as u32 - 1), span));
+                    true
+                }
+                None => swap_pushes(out, frame_size),
+            }
+        }
+        (Some(_), Some(_), Some(Swap)) => swap_pushes(out, frame_size),
+        _ => false,
+    }
+}
+
+/// `a; b; Swap` is `b; a` when neither push can see the other.
+fn swap_pushes(out: &mut Vec<(Instruction, Option<Span>)>, frame_size: u32) -> bool {
+    let len = out.len();
+    let (a, b) = (out[len - 3].0, out[len - 2].0);
+    if out[len - 1].0 != Swap || !is_pure_push(a, frame_size) || !is_pure_push(b, frame_size) {
+        return false;
+    }
+    out.pop();
+    out.swap(len - 3, len - 2);
+    true
+}
+
+/// Rebuilds the constant pool in order of first use, the way the emitter
+/// lays it out, dropping constants that nothing refers to any more and
+/// merging equal ones that folding produced.
+fn compact_constants<I>(instructions: I, constants: Vec<Value>) -> (Vec<Instruction>, Vec<Value>)
+where
+    I: Iterator<Item = Instruction>,
+{
+    let mut kept: Vec<Value> = vec![];
+    let mut renumbered: Vec<Option<u32>> = vec![None; constants.len()];
+    let mut renumber = |index: u32| {
+        if let Some(new) = renumbered[index as usize] {
+            return new;
+        }
+        let constant = &constants[index as usize];
+        let existing = match constant {
+            &Value::Function(_) => None,
+            _ => kept.iter().position(|c| same_constant(c, constant)),
+        };
+        let new = existing.unwrap_or_else(|| {
+            kept.push(constant.clone());
+            kept.len() - 1
+        }) as u32;
+        renumbered[index as usize] = Some(new);
+        new
+    };
+
+    let instructions = instructions
+        .map(|instruction| match instruction {
+            PushConst(index) => PushConst(renumber(index)),
+            CallNative(index) => CallNative(renumber(index)),
+            other => other,
+        })
+        .collect();
+    (instructions, kept)
+}
+
+pub(crate) fn same_constant(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        // Bitwise, so that 0.0 and -0.0 stay apart.
+        (&Value::Float(a), &Value::Float(b)) => a.to_bits() == b.to_bits(),
+        (&Value::Function(_), _) | (_, &Value::Function(_)) => false,
+        (a, b) => a == b,
+    }
+}
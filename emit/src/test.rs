@@ -3,130 +3,497 @@ use parser::*;
 use binder::*;
 use lexer::*;
 
+use vm::debug_info::{self, LineTable};
+use vm::value::{Code, FunctionPtr, ValueKind};
 use vm::vm::Instruction::*;
 
-fn emit_module(input: &str) -> Vec<Instruction> {
-    use std::collections::HashMap;
+fn emit_module(input: &str) -> (Vec<Instruction>, Vec<Value>) {
+    let f = emit_module_function(input);
+    (f.instructions.clone(), f.constants.clone())
+}
+
+fn emit_module_function(input: &str) -> FunctionPtr {
     use typed_arena::Arena;
-    use lexer::lex;
 
-    let mut lexed = lex(input);
-    let parse_arena = Arena::new();
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
     let bind_arena = Arena::new();
 
-    remove_whitespace(&mut lexed);
-    let mut cache = HashMap::new();
-    let parsed = parse_module(&lexed, "my_module", &parse_arena, &mut cache).unwrap();
-    let bound = bind_top(&bind_arena, parsed.0).unwrap();
-    let emitted = emit_top(&bound);
-    let f = emitted.into_function().unwrap().function;
-    let &Function {
-        ref instructions, ..
-    } = &*f.borrow();
-    instructions.clone()
+    let lexed = lex(input, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap();
+    let desugared = ast_lower::desugar(parsed.0, &mut alloc);
+    let bound = bind_top(&bind_arena, desugared).unwrap();
+    emit_top(&bound).into_function().unwrap()
+}
+
+fn source_span(start: usize, end: usize) -> debug_info::Span {
+    debug_info::Span { start, end }
+}
+
+fn unbuilt(
+    name: &str,
+    instructions: Vec<Instruction>,
+    args_count: u32,
+    body: debug_info::Span,
+) -> Value {
+    let mut line_table = LineTable::new();
+    line_table.push(0, body);
+    Value::Function(new_func(Function::new(Code {
+        name: Some(name.into()),
+        instructions: instructions,
+        constants: vec![],
+        args_count: args_count,
+        upvars_count: 0,
+        locals_count: 0,
+        line_table,
+    })))
 }
 
 #[test]
 fn emit_module_with_expression_statement() {
-    let instrs = emit_module("5;");
-    assert_eq!(&instrs, &[Push(Value::Integer(5)), Pop, MapEmpty, Ret]);
+    let (instrs, constants) = emit_module("5;");
+    assert_eq!(&instrs, &[PushConst(0), Pop, MapEmpty, Resume]);
+    assert_eq!(&constants, &[Value::Integer(5)]);
 }
 
 #[test]
 fn emit_module_with_expression_statement_float() {
-    let instrs = emit_module("1.234;");
-    assert_eq!(&instrs, &[Push(Value::Float(1.234)), Pop, MapEmpty, Ret]);
+    let (instrs, constants) = emit_module("1.234;");
+    assert_eq!(&instrs, &[PushConst(0), Pop, MapEmpty, Resume]);
+    assert_eq!(&constants, &[Value::Float(1.234)]);
 }
 
 #[test]
 fn emit_module_with_variable_declaration() {
-    let instrs = emit_module("let x = 5;");
+    let (instrs, constants) = emit_module("let x = 5;");
     assert_eq!(
         &instrs,
         &[
-            Push(Value::Integer(5)),
-            Push(Value::symbol("my_module")),
-            Push(Value::symbol("x")),
+            PushConst(0),
+            PushConst(1),
+            PushConst(2),
             ModuleAdd,
             MapEmpty,
-            Ret
+            Resume
+        ]
+    );
+    assert_eq!(
+        &constants,
+        &[
+            Value::Integer(5),
+            Value::symbol("x"),
+            Value::symbol("my_module")
         ]
     );
 }
 
 #[test]
 fn emit_module_with_variable_declaration_and_variable_access() {
-    let instrs = emit_module("let x = 5; x;");
+    let (instrs, constants) = emit_module("let x = 5; x;");
     assert_eq!(
         &instrs,
         &[
-            Push(Value::Integer(5)),
-            Push(Value::symbol("my_module")),
-            Push(Value::symbol("x")),
+            PushConst(0),
+            PushConst(1),
+            PushConst(2),
             ModuleAdd,
-            Push(Value::symbol("my_module")),
-            Push(Value::symbol("x")),
+            PushConst(1),
+            PushConst(2),
             ModuleGet,
             Pop,
             MapEmpty,
-            Ret
+            Resume
+        ]
+    );
+    assert_eq!(
+        &constants,
+        &[
+            Value::Integer(5),
+            Value::symbol("x"),
+            Value::symbol("my_module")
         ]
     );
 }
 
 #[test]
 fn emit_self_referring_declaration() {
-    let instrs = emit_module("let f() = f;");
+    let (instrs, constants) = emit_module("let f() = f;");
     assert_eq!(
         &instrs[..],
         &[
-            Push(Value::Function(new_func(Function {
-                instructions: vec![GetFromStackPosition(0), Ret],
-                name: Some("f".into()),
-                upvars: vec![],
-                args_count: 0,
-                locals_count: 0,
-                upvars_count: 0,
-            }))),
+            PushConst(0),
             BuildFunction,
-            Push(Value::symbol("my_module")),
-            Push(Value::symbol("f")),
+            PushConst(1),
+            PushConst(2),
             ModuleAdd,
             MapEmpty,
-            Ret
+            Resume
+        ]
+    );
+    assert_eq!(
+        &constants[..],
+        &[
+            unbuilt(
+                "f",
+                vec![GetFromStackPosition(0), Resume],
+                0,
+                source_span(10, 11)
+            ),
+            Value::symbol("f"),
+            Value::symbol("my_module")
         ]
     );
 }
 
 #[test]
 fn emit_fn_delcaration() {
-    let instrs = emit_module("let id(x) = x;");
+    let (instrs, constants) = emit_module("let id(x) = x;");
     assert_eq!(
         &instrs[..],
         &[
-            Push(Value::Function(new_func(Function {
-                instructions: vec![GetFromStackPosition(1), Ret],
-                name: Some("id".into()),
-                upvars: vec![],
-                args_count: 1,
-                locals_count: 0,
-                upvars_count: 0,
-            }))),
+            PushConst(0),
             BuildFunction,
-            Push(Value::symbol("my_module")),
-            Push(Value::symbol("id")),
+            PushConst(1),
+            PushConst(2),
             ModuleAdd,
             MapEmpty,
-            Ret
+            Resume
+        ]
+    );
+    assert_eq!(
+        &constants[..],
+        &[
+            unbuilt(
+                "id",
+                vec![GetFromStackPosition(1), Resume],
+                1,
+                source_span(12, 13)
+            ),
+            Value::symbol("id"),
+            Value::symbol("my_module")
+        ]
+    );
+}
+
+#[test]
+fn emit_type_checks_at_annotations() {
+    let (instrs, constants) = emit_module("let half(x: Int): Float = x / 2.0; let y: Map = 1;");
+    let half = constants[0].clone().into_function().unwrap();
+    assert_eq!(
+        &half.instructions[..],
+        &[
+            GetFromStackPosition(1),
+            CheckType(ValueKind::Integer),
+            Pop,
+            GetFromStackPosition(1),
+            PushConst(0),
+            Div,
+            CheckType(ValueKind::Float),
+            Resume
+        ]
+    );
+    assert_eq!(half.line_table.lookup(1), Some(source_span(9, 10)));
+    assert_eq!(half.line_table.lookup(6), Some(source_span(26, 33)));
+    assert_eq!(&instrs[5..7], &[PushConst(3), CheckType(ValueKind::Map)]);
+}
+
+#[test]
+fn untyped_callers_are_checked_at_runtime() {
+    use vm::vm::{Vm, VmError};
+
+    let program = emit_module_function("let inc(x: Int) = x + 1; let f(g) = g(2.5); f(inc);");
+    let error = Vm::new().run_function(program).unwrap_err();
+    assert_eq!(
+        error.error,
+        VmError::UnexpectedType {
+            expected: ValueKind::Integer,
+            found: Value::Float(2.5),
+        }
+    );
+}
+
+fn debug_values(program: FunctionPtr) -> Vec<Value> {
+    use vm::vm::Vm;
+
+    let mut vm = Vm::new();
+    vm.run_function(program).unwrap();
+    vm.debug_values
+}
+
+#[test]
+fn emit_function_group() {
+    let (instrs, constants) = emit_module("let ping(n) = { let p = pong; n + 1 }; let pong(n) = ping(n) * 2;");
+    assert_eq!(
+        &instrs[..8],
+        &[
+            PushConst(0),
+            PushConst(1),
+            PushConst(2),
+            BuildFunction,
+            PushConst(3),
+            PushConst(4),
+            ModuleAdd,
+            PushConst(5),
         ]
     );
+    let ping = constants[0].clone().into_function().unwrap();
+    assert_eq!(ping.upvars_count, 2);
+    // `pong` is built from copies of the upvars of `ping`.
+    assert_eq!(
+        &ping.instructions[..4],
+        &[
+            GetFromStackPosition(2),
+            GetFromStackPosition(3),
+            GetFromStackPosition(3),
+            BuildFunction,
+        ]
+    );
+}
+
+#[test]
+fn mutually_referencing_module_functions_run() {
+    let program = emit_module_function(
+        "let ping(n) = { let p = pong; n + 1 }; let pong(n) = ping(n) * 2; \
+         let first() = second; let second() = 10; debug(pong(1)); debug(first()());",
+    );
+    assert_eq!(debug_values(program.clone()), vec![Value::Integer(4), Value::Integer(10)]);
+    assert_eq!(
+        debug_values(peephole::optimize(&program)),
+        vec![Value::Integer(4), Value::Integer(10)]
+    );
+}
+
+#[test]
+fn mutually_referencing_block_functions_run() {
+    let program = emit_module_function(
+        "let f(x) = { \
+             let a(n) = b(n) + x; \
+             let b(n) = n * 2; \
+             let c() = { let d() = a(1); d }; \
+             c()() \
+         }; \
+         debug(f(10));",
+    );
+    assert_eq!(debug_values(program), vec![Value::Integer(12)]);
 }
 
 #[test]
 fn emit_debug_statement() {
-    let instrs = emit_module("debug(10);");
+    let (instrs, constants) = emit_module("debug(10);");
+    assert_eq!(&instrs[..], &[PushConst(0), Debug, MapEmpty, Resume]);
+    assert_eq!(&constants[..], &[Value::Integer(10)]);
+}
+
+#[test]
+fn functions_are_never_shared_in_the_constant_pool() {
+    let (instrs, constants) = emit_module("let f() = 1; let g() = 1;");
     assert_eq!(
         &instrs[..],
-        &[Push(Value::Integer(10)), Debug, MapEmpty, Ret]
+        &[
+            PushConst(0),
+            BuildFunction,
+            PushConst(1),
+            PushConst(2),
+            ModuleAdd,
+            PushConst(3),
+            BuildFunction,
+            PushConst(4),
+            PushConst(2),
+            ModuleAdd,
+            MapEmpty,
+            Resume
+        ]
+    );
+    assert_eq!(constants.len(), 5);
+}
+
+#[test]
+fn emit_call_continues_in_a_new_segment() {
+    let (instrs, constants) = emit_module("let f() = 1; f();");
+    assert_eq!(
+        &instrs[5..],
+        &[
+            GetFromStackPosition(0),
+            PushConst(3),
+            BuildFunction,
+            PushConst(1),
+            PushConst(2),
+            ModuleGet,
+            Call(0),
+        ]
+    );
+    let continuation = match constants[3] {
+        Value::Function(ref f) => f.clone(),
+        ref other => panic!("expected a continuation, found {:?}", other),
+    };
+    assert_eq!(
+        continuation.instructions,
+        vec![GetFromStackPosition(1), Pop, MapEmpty, Resume]
+    );
+    assert_eq!((continuation.args_count, continuation.upvars_count), (1, 1));
+}
+
+#[test]
+fn emit_line_table() {
+    let f = emit_module_function("debug(1 + 2);");
+    assert_eq!(
+        f.line_table.entries(),
+        &[
+            (0, source_span(6, 7)),
+            (1, source_span(10, 11)),
+            (2, source_span(6, 11)),
+            (4, source_span(0, 13)),
+        ]
     );
 }
+
+#[test]
+fn continuation_starts_at_its_call() {
+    let f = emit_module_function("let f() = 1; debug(f());");
+    let continuation = f.constants
+        .iter()
+        .filter_map(|c| match c {
+            &Value::Function(ref f) if f.args_count == 1 => Some(f.clone()),
+            _ => None,
+        })
+        .next()
+        .unwrap();
+    assert_eq!(continuation.line_table.lookup(0), Some(source_span(19, 22)));
+}
+
+fn optimized(assembly: &str) -> String {
+    let function = vm::asm::assemble(assembly).unwrap();
+    vm::asm::disassemble(&peephole::optimize(&function))
+}
+
+#[test]
+fn peephole_optimizes_emitted_code() {
+    let f = peephole::optimize(&emit_module_function("5; debug(1 + 2 * 3);"));
+    assert_eq!(&f.instructions[..], &[PushConst(0), Debug, MapEmpty, Resume]);
+    assert_eq!(&f.constants[..], &[Value::Integer(7)]);
+    assert_eq!(f.line_table.lookup(0), Some(source_span(9, 18)));
+}
+
+#[test]
+fn peephole_removes_redundant_stack_traffic() {
+    assert_eq!(
+        optimized(
+            "fn @f args=1 locals=1
+    get 1
+    set 1
+    push 'unused
+    pop
+    dup
+    swap
+    swap
+    swap
+    get 2
+    pop
+    resume"
+        ),
+        "fn @anon args=1 upvars=0 locals=1
+    dup
+    resume
+"
+    );
+}
+
+#[test]
+fn peephole_drops_checks_that_always_pass() {
+    assert_eq!(
+        optimized(
+            "fn @f args=1
+    push 1
+    check integer
+    push 1
+    check float
+    get 1
+    check integer
+    resume"
+        ),
+        "fn @anon args=1 upvars=0 locals=0
+    push 1
+    push 1
+    check float
+    get 1
+    check integer
+    resume
+"
+    );
+}
+
+#[test]
+fn peephole_reorders_pushes_instead_of_swapping() {
+    assert_eq!(
+        optimized(
+            "fn @f args=1
+    push 1
+    get 1
+    swap
+    call 1"
+        ),
+        "fn @anon args=1 upvars=0 locals=0
+    get 1
+    push 1
+    call 1
+"
+    );
+}
+
+#[test]
+fn peephole_leaves_scratch_values_alone() {
+    // Slot 2 is the value pushed by `push 1`, so the swap is not redundant.
+    let source = "fn @f args=1
+    push 1
+    get 2
+    swap
+    resume";
+    assert_eq!(
+        optimized(source),
+        vm::asm::disassemble(&vm::asm::assemble(source).unwrap())
+    );
+}
+
+#[test]
+fn peephole_folds_only_what_the_vm_would_compute() {
+    assert_eq!(
+        optimized(
+            "fn @f
+    push 9223372036854775807
+    push 1
+    add
+    push 1
+    push 0
+    div
+    push 1
+    push 0.5
+    sub
+    terminate"
+        ),
+        "fn @anon args=0 upvars=0 locals=0
+    push 9223372036854775807
+    push 1
+    add
+    push 1
+    push 0
+    div
+    push 0.5
+    terminate
+"
+    );
+}
+
+#[test]
+fn peephole_optimizes_continuations() {
+    let f = peephole::optimize(&emit_module_function("let f() = 1; debug(f() + (2 + 3));"));
+    let continuation = f.constants
+        .iter()
+        .filter_map(|c| match c {
+            &Value::Function(ref f) if f.args_count == 1 => Some(f.clone()),
+            _ => None,
+        })
+        .next()
+        .unwrap();
+    assert_eq!(&continuation.constants[..], &[Value::Integer(5)]);
+}
@@ -0,0 +1,107 @@
+//! A small writer for Graphviz DOT graphs.
+//!
+//! Nodes are numbered in the order they are added, and nodes and edges are
+//! written in that order too, so the same input always gives the same
+//! text.
+
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(u32);
+
+/// How an edge is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeStyle {
+    Solid,
+    Dashed,
+}
+
+pub struct Graph {
+    name: String,
+    body: String,
+    next_node: u32,
+}
+
+impl Graph {
+    pub fn new(name: &str) -> Graph {
+        Graph {
+            name: name.into(),
+            body: String::new(),
+            next_node: 0,
+        }
+    }
+
+    /// Adds a node, whose label may span several lines.
+    pub fn node(&mut self, label: &str) -> NodeId {
+        let id = NodeId(self.next_node);
+        self.next_node += 1;
+        writeln!(self.body, "    n{} [label=\"{}\"];", id.0, escape(label)).unwrap();
+        id
+    }
+
+    pub fn edge(&mut self, from: NodeId, to: NodeId, label: &str) {
+        self.styled_edge(from, to, label, EdgeStyle::Solid);
+    }
+
+    pub fn styled_edge(&mut self, from: NodeId, to: NodeId, label: &str, style: EdgeStyle) {
+        write!(self.body, "    n{} -> n{}", from.0, to.0).unwrap();
+        let mut attributes = vec![];
+        if !label.is_empty() {
+            attributes.push(format!("label=\"{}\"", escape(label)));
+        }
+        if style == EdgeStyle::Dashed {
+            attributes.push("style=dashed".into());
+        }
+        if !attributes.is_empty() {
+            write!(self.body, " [{}]", attributes.join(", ")).unwrap();
+        }
+        self.body.push_str(";\n");
+    }
+
+    pub fn finish(self) -> String {
+        format!(
+            "digraph {} {{\n    node [shape=box, fontname=\"monospace\"];\n{}}}\n",
+            self.name, self.body
+        )
+    }
+}
+
+/// Escapes `s` for a quoted DOT string, with newlines becoming
+/// left-aligned line breaks.
+fn escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\l"),
+            c => out.push(c),
+        }
+    }
+    if s.contains('\n') && !s.ends_with('\n') {
+        out.push_str("\\l");
+    }
+    out
+}
+
+#[test]
+fn writes_nodes_and_edges_in_order() {
+    let mut graph = Graph::new("g");
+    let a = graph.node("a \"quoted\"");
+    let b = graph.node("two\nlines");
+    graph.edge(a, b, "");
+    graph.styled_edge(b, a, "back", EdgeStyle::Dashed);
+    assert_eq!(
+        graph.finish(),
+        r#"digraph g {
+    node [shape=box, fontname="monospace"];
+    n0 [label="a \"quoted\""];
+    n1 [label="two\llines\l"];
+    n0 -> n1;
+    n1 -> n0 [label="back", style=dashed];
+}
+"#
+    );
+}
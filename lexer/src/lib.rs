@@ -11,6 +11,32 @@ pub struct Token<'a> {
     pub end_byte: usize,
 }
 
+/// A range of bytes in the source text, `start` inclusive and `end` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+impl<'a> Token<'a> {
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.start_byte,
+            end: self.end_byte,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub enum TokenKind<'a> {
     DebugKeyword,
@@ -21,6 +47,7 @@ pub enum TokenKind<'a> {
     OpenBracket,
     CloseBracket,
     Semicolon,
+    Colon,
     Comma,
     Pipeline,
     Dot,
@@ -31,6 +58,7 @@ pub enum TokenKind<'a> {
     Let,
     Underscore,
     WideArrow,
+    Arrow,
     Equal,
     Whitespace(&'a str),
     Identifier(&'a str),
@@ -70,9 +98,11 @@ pub fn lex<'i, 'a>(input: &'i str, alloc: &mut Allocator<'a>) -> &'a [Token<'a>]
         (r"\}", Box::new(|_, _| TokenKind::CloseBrace)),
         (r";", Box::new(|_, _| TokenKind::Semicolon)),
         (r",", Box::new(|_, _| TokenKind::Comma)),
+        (r":", Box::new(|_, _| TokenKind::Colon)),
         (r"\.", Box::new(|_, _| TokenKind::Dot)),
         (r"\|>", Box::new(|_, _| TokenKind::Pipeline)),
         (r"\+", Box::new(|_, _| TokenKind::Plus)),
+        (r"->", Box::new(|_, _| TokenKind::Arrow)),
         (r"-", Box::new(|_, _| TokenKind::Minus)),
         (r"/", Box::new(|_, _| TokenKind::Div)),
         (
@@ -523,6 +553,32 @@ fn lex_math() {
     );
 }
 
+#[test]
+fn lex_annotations() {
+    let mut arena = Arena::new();
+    let mut alloc = arena.allocator();
+    assert_eq!(
+        lex(":->-", &mut alloc),
+        &[
+            Token {
+                kind: TokenKind::Colon,
+                start_byte: 0,
+                end_byte: 1,
+            },
+            Token {
+                kind: TokenKind::Arrow,
+                start_byte: 1,
+                end_byte: 3,
+            },
+            Token {
+                kind: TokenKind::Minus,
+                start_byte: 3,
+                end_byte: 4,
+            },
+        ]
+    );
+}
+
 #[test]
 fn lex_multiple() {
     let mut arena = Arena::new();
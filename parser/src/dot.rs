@@ -0,0 +1,172 @@
+use super::*;
+use graphviz::{Graph, NodeId};
+
+/// Renders `ast` as a Graphviz DOT tree, with one node per syntax node and
+/// edges labelled by the role of the child.
+pub fn to_dot(ast: &Ast) -> String {
+    let mut graph = Graph::new("ast");
+    add_node(ast, &mut graph);
+    graph.finish()
+}
+
+fn params_label(params: &[Param]) -> String {
+    params
+        .iter()
+        .map(|&(name, _, ty)| annotated(name, ty))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn annotated(name: &str, ty: Option<TypePtr>) -> String {
+    match ty {
+        Some(ty) => format!("{}: {}", name, ty),
+        None => name.to_string(),
+    }
+}
+
+fn add_node(ast: &Ast, graph: &mut Graph) -> NodeId {
+    let binary = |graph: &mut Graph, op: &str, l: &Ast, r: &Ast| {
+        let node = graph.node(op);
+        let l = add_node(l, graph);
+        graph.edge(node, l, "left");
+        let r = add_node(r, graph);
+        graph.edge(node, r, "right");
+        node
+    };
+
+    match ast {
+        &Ast::Identifier(_, name) => graph.node(name),
+        &Ast::Integer(_, i) => graph.node(&i.to_string()),
+        &Ast::Float(_, f) => graph.node(&format!("{:?}", f)),
+        &Ast::FunctionCall { target, args, .. } => {
+            let node = graph.node("call");
+            let target = add_node(target, graph);
+            graph.edge(node, target, "target");
+            for (i, arg) in args.iter().enumerate() {
+                let arg = match arg {
+                    &ArgumentSyntax::Expression(arg) => add_node(arg, graph),
+                    &ArgumentSyntax::Underscore => graph.node("_"),
+                };
+                graph.edge(node, arg, &format!("arg{}", i));
+            }
+            node
+        }
+        &Ast::DebugCall(arg) => {
+            let node = graph.node("debug");
+            let arg = add_node(arg, graph);
+            graph.edge(node, arg, "");
+            node
+        }
+        &Ast::Pipeline(l, r) => binary(graph, "|>", l, r),
+        &Ast::Add(l, r) => binary(graph, "+", l, r),
+        &Ast::Sub(l, r) => binary(graph, "-", l, r),
+        &Ast::Div(l, r) => binary(graph, "/", l, r),
+        &Ast::Mul(l, r) => binary(graph, "*", l, r),
+        &Ast::AnonFunc { params, body, .. } => {
+            let node = graph.node(&format!("fn ({})", params_label(params)));
+            let body = add_node(body, graph);
+            graph.edge(node, body, "body");
+            node
+        }
+        &Ast::FunctionDecl {
+            name,
+            params,
+            result,
+            body,
+            ..
+        } => {
+            let label = format!("fn {}({})", name, params_label(params));
+            let node = graph.node(&match result {
+                Some(result) => format!("{}: {}", label, result),
+                None => label,
+            });
+            let body = add_node(body, graph);
+            graph.edge(node, body, "body");
+            node
+        }
+        &Ast::VariableDecl {
+            name,
+            ty,
+            expression,
+            ..
+        } => {
+            let node = graph.node(&format!("let {}", annotated(name, ty)));
+            let expression = add_node(expression, graph);
+            graph.edge(node, expression, "value");
+            node
+        }
+        &Ast::FieldAccess {
+            target, field_name, ..
+        } => {
+            let node = graph.node(&format!(".{}", field_name));
+            let target = add_node(target, graph);
+            graph.edge(node, target, "target");
+            node
+        }
+        &Ast::Module {
+            statements,
+            module_id,
+            ..
+        } => {
+            let node = graph.node(&format!("module {}", module_id));
+            for (i, statement) in statements.iter().enumerate() {
+                let statement = add_node(statement, graph);
+                graph.edge(node, statement, &i.to_string());
+            }
+            node
+        }
+        &Ast::BlockExpr {
+            statements,
+            final_expression,
+            ..
+        } => {
+            let node = graph.node("block");
+            for (i, statement) in statements.iter().enumerate() {
+                let statement = add_node(statement, graph);
+                graph.edge(node, statement, &i.to_string());
+            }
+            let result = add_node(final_expression, graph);
+            graph.edge(node, result, "result");
+            node
+        }
+    }
+}
+
+#[test]
+fn dot_of_expression() {
+    use test_util::with_parsed_expression;
+
+    with_parsed_expression("{ let f(x) = x * 2; 1 |> f(_, y.z) }", |res| {
+        let (res, _) = res.unwrap();
+        assert_eq!(
+            to_dot(res),
+            r#"digraph ast {
+    node [shape=box, fontname="monospace"];
+    n0 [label="block"];
+    n1 [label="fn f(x)"];
+    n2 [label="*"];
+    n3 [label="x"];
+    n2 -> n3 [label="left"];
+    n4 [label="2"];
+    n2 -> n4 [label="right"];
+    n1 -> n2 [label="body"];
+    n0 -> n1 [label="0"];
+    n5 [label="|>"];
+    n6 [label="1"];
+    n5 -> n6 [label="left"];
+    n7 [label="call"];
+    n8 [label="f"];
+    n7 -> n8 [label="target"];
+    n9 [label="_"];
+    n7 -> n9 [label="arg0"];
+    n10 [label=".z"];
+    n11 [label="y"];
+    n10 -> n11 [label="target"];
+    n7 -> n10 [label="arg1"];
+    n5 -> n7 [label="right"];
+    n0 -> n5 [label="result"];
+}
+"#
+        );
+    });
+}
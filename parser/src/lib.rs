@@ -1,17 +1,24 @@
 extern crate copy_arena;
+extern crate graphviz;
 extern crate lexer;
 
 #[macro_use]
 mod macros;
 mod parts;
 mod test_util;
+pub mod dot;
 
 use copy_arena::Allocator;
-use lexer::{Token, TokenKind};
+use lexer::{Span, Token, TokenKind};
 pub use parts::*;
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::result::Result as StdResult;
 
 pub type AstPtr<'a> = &'a Ast<'a>;
+pub type TypePtr<'a> = &'a TypeAst<'a>;
+/// A parameter's name, the identifier it was parsed from, and its type if
+/// it has one.
+pub type Param<'a> = (&'a str, AstPtr<'a>, Option<TypePtr<'a>>);
 pub type Result<'a> = StdResult<(AstPtr<'a>, &'a [Token<'a>]), (ParseError<'a>, &'a [Token<'a>])>;
 
 #[derive(Clone, Debug)]
@@ -38,6 +45,7 @@ pub enum Ast<'a> {
     FunctionCall {
         target: AstPtr<'a>,
         args: &'a [ArgumentSyntax<'a>],
+        span: Span,
     },
     DebugCall(AstPtr<'a>),
     Pipeline(AstPtr<'a>, AstPtr<'a>),
@@ -46,31 +54,204 @@ pub enum Ast<'a> {
     Div(AstPtr<'a>, AstPtr<'a>),
     Mul(AstPtr<'a>, AstPtr<'a>),
     AnonFunc {
-        params: &'a [(&'a str, AstPtr<'a>)],
+        params: &'a [Param<'a>],
         body: AstPtr<'a>,
+        span: Span,
     },
     FunctionDecl {
         name: &'a str,
         name_ast: AstPtr<'a>,
-        params: &'a [(&'a str, AstPtr<'a>)],
+        params: &'a [Param<'a>],
+        /// The type of the result, if it is annotated.
+        result: Option<TypePtr<'a>>,
         body: AstPtr<'a>,
+        span: Span,
     },
     VariableDecl {
         name: &'a str,
         name_ast: AstPtr<'a>,
+        ty: Option<TypePtr<'a>>,
         expression: AstPtr<'a>,
+        span: Span,
     },
     FieldAccess {
         target: AstPtr<'a>,
         field: AstPtr<'a>,
         field_name: &'a str,
+        span: Span,
     },
     Module {
         statements: &'a [AstPtr<'a>],
         module_id: &'a str,
+        span: Span,
     },
     BlockExpr {
         statements: &'a [AstPtr<'a>],
         final_expression: AstPtr<'a>,
+        span: Span,
     },
 }
+
+/// A type annotation, such as `Int`, `List[Float]` or `(Int, Map) -> Int`.
+#[derive(Debug, Copy, Clone)]
+pub enum TypeAst<'a> {
+    Int(Span),
+    Float(Span),
+    Symbol(Span),
+    Map(Span),
+    List(TypePtr<'a>, Span),
+    Function {
+        params: &'a [TypePtr<'a>],
+        result: TypePtr<'a>,
+        span: Span,
+    },
+}
+
+impl<'a> TypeAst<'a> {
+    pub fn span(&self) -> Span {
+        match self {
+            &TypeAst::Int(span)
+            | &TypeAst::Float(span)
+            | &TypeAst::Symbol(span)
+            | &TypeAst::Map(span)
+            | &TypeAst::List(_, span)
+            | &TypeAst::Function { span, .. } => span,
+        }
+    }
+}
+
+impl<'a> Display for TypeAst<'a> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &TypeAst::Int(_) => write!(f, "Int"),
+            &TypeAst::Float(_) => write!(f, "Float"),
+            &TypeAst::Symbol(_) => write!(f, "Symbol"),
+            &TypeAst::Map(_) => write!(f, "Map"),
+            &TypeAst::List(element, _) => write!(f, "List[{}]", element),
+            &TypeAst::Function { params, result, .. } => {
+                let params = params.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                write!(f, "({}) -> {}", params.join(", "), result)
+            }
+        }
+    }
+}
+
+impl<'a> Ast<'a> {
+    /// The bytes of source text this node was parsed from.  Operators and
+    /// debug statements cover their operands; every other composite node
+    /// records the full range of tokens it consumed.
+    pub fn span(&self) -> Span {
+        match self {
+            &Ast::Identifier(token, _) | &Ast::Integer(token, _) | &Ast::Float(token, _) => {
+                token.span()
+            }
+            &Ast::DebugCall(expr) => expr.span(),
+            &Ast::Pipeline(l, r)
+            | &Ast::Add(l, r)
+            | &Ast::Sub(l, r)
+            | &Ast::Div(l, r)
+            | &Ast::Mul(l, r) => l.span().to(r.span()),
+            &Ast::FunctionCall { span, .. }
+            | &Ast::AnonFunc { span, .. }
+            | &Ast::FunctionDecl { span, .. }
+            | &Ast::VariableDecl { span, .. }
+            | &Ast::FieldAccess { span, .. }
+            | &Ast::Module { span, .. }
+            | &Ast::BlockExpr { span, .. } => span,
+        }
+    }
+
+    /// The name a declaration statement adds to its block or module.
+    pub fn declared_name(&self) -> Option<&'a str> {
+        match self {
+            &Ast::FunctionDecl { name, .. } | &Ast::VariableDecl { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Whether `name`, as it is bound around this node, is used in it.  Uses
+    /// under a parameter of the same name, inside a function of that name,
+    /// or after a declaration of it in a block refer to something else and
+    /// do not count.  A use before such a declaration still does, even when
+    /// a function group would make it refer to the declaration.
+    pub fn mentions(&self, name: &str) -> bool {
+        match self {
+            &Ast::Identifier(_, n) => n == name,
+            &Ast::Integer(..) | &Ast::Float(..) => false,
+            &Ast::FunctionCall { target, args, .. } => {
+                target.mentions(name) || args.iter().any(|arg| match arg {
+                    &ArgumentSyntax::Expression(arg) => arg.mentions(name),
+                    &ArgumentSyntax::Underscore => false,
+                })
+            }
+            &Ast::DebugCall(expr) => expr.mentions(name),
+            &Ast::Pipeline(l, r)
+            | &Ast::Add(l, r)
+            | &Ast::Sub(l, r)
+            | &Ast::Div(l, r)
+            | &Ast::Mul(l, r) => l.mentions(name) || r.mentions(name),
+            &Ast::FunctionDecl { name: n, .. } if n == name => false,
+            &Ast::AnonFunc { params, body, .. } | &Ast::FunctionDecl { params, body, .. } => {
+                !params.iter().any(|&(param, _, _)| param == name) && body.mentions(name)
+            }
+            &Ast::VariableDecl { expression, .. } => expression.mentions(name),
+            &Ast::FieldAccess { target, .. } => target.mentions(name),
+            &Ast::Module { statements, .. } => statements.iter().any(|s| s.mentions(name)),
+            &Ast::BlockExpr {
+                statements,
+                final_expression,
+                ..
+            } => {
+                for statement in statements {
+                    if statement.mentions(name) {
+                        return true;
+                    }
+                    if statement.declared_name() == Some(name) {
+                        return false;
+                    }
+                }
+                final_expression.mentions(name)
+            }
+        }
+    }
+}
+
+/// The run of function declarations at the start of `statements` that
+/// have to be declared together because one refers to a later one, if
+/// there is one.  The run stops before a declaration of a name it already
+/// declares, and only a name that is not in `in_scope` makes a group, so a
+/// later declaration never changes what an earlier one refers to in a
+/// program that makes sense without it.
+pub fn function_group<'s, 'a>(
+    statements: &'s [&'a Ast<'a>],
+    in_scope: &[&str],
+) -> Option<&'s [&'a Ast<'a>]> {
+    let mut names = vec![];
+    for statement in statements {
+        match statement {
+            &&Ast::FunctionDecl { name, .. } if !names.contains(&name) => names.push(name),
+            _ => break,
+        }
+    }
+    let run = &statements[..names.len()];
+    let refers_forward = run.iter().enumerate().any(|(i, earlier)| {
+        names[i + 1..]
+            .iter()
+            .any(|later| !in_scope.contains(later) && earlier.mentions(later))
+    });
+    if refers_forward {
+        Some(run)
+    } else {
+        None
+    }
+}
+
+/// The span of the tokens consumed between `before` and `after`, where
+/// `after` is a suffix of `before`.
+fn consumed_span<'a>(before: &'a [Token<'a>], after: &'a [Token<'a>]) -> Span {
+    let consumed = &before[..before.len() - after.len()];
+    match (consumed.first(), consumed.last()) {
+        (Some(first), Some(last)) => first.span().to(last.span()),
+        _ => Span { start: 0, end: 0 },
+    }
+}
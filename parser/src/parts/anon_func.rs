@@ -1,6 +1,7 @@
 use *;
 
 pub fn parse_anon_func<'a>(tokens: &'a [Token<'a>], alloc: &mut Allocator<'a>) -> Result<'a> {
+    let start = tokens;
     let (_, tokens) = expect_token_type!(tokens, TokenKind::OpenParen, "open parenthesis")?;
     let (params, tokens) = if let Ok((_, tokens)) =
         expect_token_type!(tokens, TokenKind::CloseParen, "close parenthesis")
@@ -13,7 +14,8 @@ pub fn parse_anon_func<'a>(tokens: &'a [Token<'a>], alloc: &mut Allocator<'a>) -
     let (_, tokens) = expect_token_type!(tokens, TokenKind::WideArrow, "=>")?;
     let (body, tokens) = parse_expression(tokens, alloc)?;
 
-    Ok((alloc.alloc(Ast::AnonFunc { params, body }) as &_, tokens))
+    let span = consumed_span(start, tokens);
+    Ok((alloc.alloc(Ast::AnonFunc { params, body, span }) as &_, tokens))
 }
 
 #[test]
@@ -40,7 +42,7 @@ fn one_arg_anon_func() {
         let (res, _) = res.unwrap();
         matches!{res,
             &Ast::AnonFunc{
-                params: &[("a", _)],
+                params: &[("a", _, None)],
                 body: &Ast::Integer(_, 5),
                 ..
             },
@@ -56,7 +58,7 @@ fn two_arg_anon_func() {
         let (res, _) = res.unwrap();
         matches!{res,
             &Ast::AnonFunc{
-                params: &[("a", _), ("b", _)],
+                params: &[("a", _, None), ("b", _, None)],
                 body: &Ast::Integer(_, 5),
                 ..
             },
@@ -72,10 +74,11 @@ fn nested_anon_func() {
         let (res, _) = res.unwrap();
         matches!{res,
             &Ast::AnonFunc{
-                params: &[("a", _)],
+                params: &[("a", _, None)],
                 body: &Ast::AnonFunc {
-                    params: &[("b", _)],
+                    params: &[("b", _, None)],
                     body: &Ast::Integer(_, 5),
+                    ..
                 },
                 ..
             }
@@ -4,6 +4,7 @@ pub fn parse_block_expression<'a>(
     tokens: &'a [Token<'a>],
     alloc: &mut Allocator<'a>,
 ) -> Result<'a> {
+    let start = tokens;
     let (_, mut tokens) = expect_token_type!(tokens, TokenKind::OpenBrace, "'{' open brace")?;
 
     let mut statements = vec![];
@@ -23,6 +24,7 @@ pub fn parse_block_expression<'a>(
         alloc.alloc(Ast::BlockExpr {
             statements,
             final_expression: expr,
+            span: consumed_span(start, tokens),
         }),
         tokens,
     ));
@@ -37,7 +39,8 @@ fn block_with_single_expression() {
         matches!{ res,
             &Ast::BlockExpr {
                 ref statements,
-                final_expression: &Ast::Identifier(_, _)
+                final_expression: &Ast::Identifier(_, _),
+                ..
             },
             statements.len() == 0
         };
@@ -53,7 +56,8 @@ fn block_with_a_statement_and_then_an_expression() {
         matches!{ res,
             &Ast::BlockExpr {
                 statements: &[&Ast::FunctionCall{..}],
-                final_expression: &Ast::Identifier(_, _)
+                final_expression: &Ast::Identifier(_, _),
+                ..
             }
         };
     });
@@ -71,7 +75,8 @@ fn block_with_multiple_statements_and_then_an_expression() {
                     &Ast::FunctionCall{..},
                     &Ast::FunctionCall{..},
                 ],
-                final_expression: &Ast::Identifier(_, _)
+                final_expression: &Ast::Identifier(_, _),
+                ..
             },
         };
     });
@@ -19,6 +19,7 @@ fn parse_field_access_right<'parse>(
         target: prev,
         field: right,
         field_name: name_s,
+        span: prev.span().to(right.span()),
     });
     parse_field_access_right(tokens, alloc, prev)
 }
@@ -82,6 +83,7 @@ fn nested_function_call() {
                     ..
                 },
                 args: &[],
+                ..
             },
         };
     });
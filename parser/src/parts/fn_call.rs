@@ -44,7 +44,12 @@ fn parse_function_call_right<'a>(
         }
     }
     let args = alloc.alloc_iter(args);
-    let current = alloc.alloc(Ast::FunctionCall { target: prev, args });
+    let span = prev.span().to(consumed_span(tokens, tokens_u));
+    let current = alloc.alloc(Ast::FunctionCall {
+        target: prev,
+        args,
+        span,
+    });
 
     parse_function_call_right(tokens_u, alloc, lower, current)
 }
@@ -68,6 +73,7 @@ fn basic_function_call() {
             &Ast::FunctionCall {
                 target: &Ast::Identifier(_, "abc"),
                 args: &[],
+                ..
             },
         };
     });
@@ -83,6 +89,7 @@ fn one_arg_function_call() {
             &Ast::FunctionCall {
                 target: &Ast::Identifier(_, "abc"),
                 args: &[ArgumentSyntax::Expression(&Ast::Integer(_, 123))],
+                ..
             },
         };
     });
@@ -101,6 +108,7 @@ fn two_arg_function_call_with_one_underscore() {
                     ArgumentSyntax::Expression(&Ast::Integer(_, 123)),
                     ArgumentSyntax::Underscore,
                 ],
+                ..
             },
         };
     });
@@ -115,7 +123,8 @@ fn one_arg_function_call_with_underscore() {
         matches!{res,
             &Ast::FunctionCall {
                 target: &Ast::Identifier(_, "abc"),
-                args: &[ArgumentSyntax::Underscore]
+                args: &[ArgumentSyntax::Underscore],
+                ..
             },
         };
     });
@@ -134,6 +143,7 @@ fn two_arg_function_call_with_underscore() {
                     ArgumentSyntax::Underscore,
                     ArgumentSyntax::Underscore
                 ],
+                ..
             },
         };
     });
@@ -151,7 +161,8 @@ fn multi_arg_function_call() {
                 args: &[
                     ArgumentSyntax::Expression(&Ast::Integer(_, 123)),
                     ArgumentSyntax::Expression(&Ast::Identifier(_, "cde")),
-                ]
+                ],
+                ..
             },
         };
     });
@@ -169,7 +180,8 @@ fn nested_function_call() {
                 args: &[
                     ArgumentSyntax::Expression(&Ast::FunctionCall{args: &[], ..}),
                     ArgumentSyntax::Expression(&Ast::FunctionCall{args: &[_], ..}),
-                ]
+                ],
+                ..
             },
         };
     });
@@ -184,3 +196,13 @@ fn double_function_call() {
         matches!{res, &Ast::FunctionCall{ target: &Ast::FunctionCall{ ..}, ..} };
     });
 }
+
+#[test]
+fn function_call_span_includes_close_paren() {
+    use test_util::with_parsed_expression;
+
+    with_parsed_expression("abc(1, d)", |res| {
+        let (res, _) = res.unwrap();
+        assert_eq!(res.span(), Span { start: 0, end: 9 });
+    });
+}
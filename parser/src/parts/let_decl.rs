@@ -4,17 +4,19 @@ pub fn parse_arg_list<'a>(
     mut tokens_u: &'a [Token<'a>],
     alloc: &mut Allocator<'a>,
 ) -> std::result::Result<
-    (&'a [(&'a str, AstPtr<'a>)], &'a [Token<'a>]),
+    (&'a [Param<'a>], &'a [Token<'a>]),
     (ParseError<'a>, &'a [Token<'a>]),
 > {
     let mut params = vec![];
     loop {
         let (param, tokens) = parse_identifier(tokens_u, alloc)?;
-        if let &Ast::Identifier(_, s) = param {
-            params.push((s, param));
+        let name = if let &Ast::Identifier(_, s) = param {
+            s
         } else {
             unreachable!();
-        }
+        };
+        let (ty, tokens) = parse_annotation(tokens, alloc)?;
+        params.push((name, param, ty));
 
         let (comma_or_end, tokens) = expect_token_type!(
             tokens,
@@ -33,7 +35,22 @@ pub fn parse_arg_list<'a>(
     return Ok((alloc.alloc_iter(params), tokens_u));
 }
 
+type AnnotationResult<'a> =
+    StdResult<(Option<TypePtr<'a>>, &'a [Token<'a>]), (ParseError<'a>, &'a [Token<'a>])>;
+
+/// Parses an optional `: type` annotation.
+fn parse_annotation<'a>(tokens: &'a [Token<'a>], alloc: &mut Allocator<'a>) -> AnnotationResult<'a> {
+    match expect_token_type!(tokens, TokenKind::Colon, "':' (colon)") {
+        Ok((_, tokens)) => {
+            let (ty, tokens) = parse_type(tokens, alloc)?;
+            Ok((Some(ty), tokens))
+        }
+        Err(_) => Ok((None, tokens)),
+    }
+}
+
 fn parse_function_params<'a>(
+    start: &'a [Token<'a>],
     tokens: &'a [Token<'a>],
     arena: &mut Allocator<'a>,
     name: &'a str,
@@ -47,6 +64,7 @@ fn parse_function_params<'a>(
         parse_arg_list(tokens, arena)?
     };
 
+    let (result, tokens) = parse_annotation(tokens, arena)?;
     let (_, tokens) = expect_token_type!(tokens, TokenKind::Equal, "= (equal)")?;
     let (body, tokens) = parse_expression(tokens, arena)?;
     let (_, tokens) = expect_token_type!(tokens, TokenKind::Semicolon, "; (semicolon)")?;
@@ -55,13 +73,16 @@ fn parse_function_params<'a>(
             name,
             name_ast,
             params,
+            result,
             body,
+            span: consumed_span(start, tokens),
         }),
         tokens,
     ))
 }
 
 pub fn parse_let_decl<'a>(tokens: &'a [Token<'a>], arena: &mut Allocator<'a>) -> Result<'a> {
+    let start = tokens;
     let (_, tokens) = expect_token_type!(tokens, TokenKind::Let, "let (keyword)")?;
     let (name, tokens) = parse_identifier(tokens, arena)?;
     let name_s = if let &Ast::Identifier(_, s) = name {
@@ -69,21 +90,24 @@ pub fn parse_let_decl<'a>(tokens: &'a [Token<'a>], arena: &mut Allocator<'a>) ->
     } else {
         unreachable!()
     };
-    let (tok, tokens) = expect_token_type!(
-        tokens,
-        TokenKind::OpenParen | TokenKind::Equal,
-        "'(' (open paren), '=' (equals)"
-    )?;
-    if tok.kind == TokenKind::OpenParen {
-        parse_function_params(tokens, arena, name_s, name)
+    if let Ok((_, tokens)) = expect_token_type!(tokens, TokenKind::OpenParen, "'(' (open paren)") {
+        parse_function_params(start, tokens, arena, name_s, name)
     } else {
+        let (ty, tokens) = parse_annotation(tokens, arena)?;
+        let (_, tokens) = expect_token_type!(
+            tokens,
+            TokenKind::Equal,
+            "'(' (open paren), ':' (colon), '=' (equals)"
+        )?;
         let (expression, tokens) = parse_expression(tokens, arena)?;
         let (_, tokens) = expect_token_type!(tokens, TokenKind::Semicolon, "';' (semicolon)")?;
         Ok((
             arena.alloc(Ast::VariableDecl {
                 name: name_s,
                 name_ast: name,
+                ty,
                 expression,
+                span: consumed_span(start, tokens),
             }),
             tokens,
         ))
@@ -133,7 +157,7 @@ fn fn_body_with_single_param() {
         matches!{res,
             &Ast::FunctionDecl {
                 name: "abc",
-                params: &[("a", _)],
+                params: &[("a", _, None)],
                 body: &Ast::Integer(_, 10),
                 ..
             }
@@ -150,10 +174,57 @@ fn fn_body_with_multiple_params() {
         matches!{res,
             &Ast::FunctionDecl {
                 name: "abc",
-                params: &[("a", _), ("b", _)],
+                params: &[("a", _, None), ("b", _, None)],
                 body: &Ast::Add(_, _),
                 ..
             }
         };
     });
 }
+
+#[test]
+fn function_decl_span_covers_whole_statement() {
+    use test_util::with_parsed_statement;
+
+    with_parsed_statement("let f(a) = a + 1;", |res| {
+        let (res, _) = res.unwrap();
+        assert_eq!(res.span(), Span { start: 0, end: 17 });
+        matches!{res,
+            &Ast::FunctionDecl { body, .. },
+            body.span() == Span { start: 11, end: 16 }
+        };
+    });
+}
+
+#[test]
+fn annotated_function_decl() {
+    use test_util::with_parsed_statement;
+
+    with_parsed_statement("let f(x: Int, y): List[Float] = y;", |res| {
+        let (res, _) = res.unwrap();
+        matches!{res,
+            &Ast::FunctionDecl {
+                params: &[("x", _, Some(&TypeAst::Int(_))), ("y", _, None)],
+                result: Some(&TypeAst::List(&TypeAst::Float(_), _)),
+                ..
+            }
+        };
+    });
+}
+
+#[test]
+fn annotated_variable_decl() {
+    use test_util::with_parsed_statement;
+
+    with_parsed_statement("let f: (Int) -> Int = (x) => x;", |res| {
+        let (res, _) = res.unwrap();
+        matches!{res,
+            &Ast::VariableDecl {
+                name: "f",
+                ty: Some(&TypeAst::Function { .. }),
+                expression: &Ast::AnonFunc { .. },
+                ..
+            }
+        };
+    });
+}
@@ -12,6 +12,7 @@ mod numbers;
 mod parenthesized;
 mod pipeline;
 mod statement;
+mod type_annotation;
 
 pub use self::anon_func::*;
 pub use self::block::*;
@@ -27,3 +28,4 @@ pub use self::numbers::*;
 pub use self::parenthesized::*;
 pub use self::pipeline::*;
 pub use self::statement::*;
+pub use self::type_annotation::*;
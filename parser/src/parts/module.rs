@@ -5,6 +5,7 @@ pub fn parse_module<'a>(
     module_id: &'a str,
     alloc: &mut Allocator<'a>,
 ) -> Result<'a> {
+    let start = tokens;
     let mut statements = vec![];
 
     while !tokens.is_empty() {
@@ -18,6 +19,7 @@ pub fn parse_module<'a>(
         alloc.alloc(Ast::Module {
             statements,
             module_id,
+            span: consumed_span(start, tokens),
         }),
         tokens,
     ));
@@ -40,7 +42,8 @@ fn multiple_function_call_statement_module() {
         let (res, _) = res.unwrap();
         matches!(res, &Ast::Module{
             statements: &[_, _],
-            module_id: "module-name"
+            module_id: "module-name",
+            ..
         });
     });
 }
@@ -53,7 +56,8 @@ fn single_let_decl() {
         let (res, _) = res.unwrap();
         matches!(res, &Ast::Module {
             statements: &[_],
-            module_id: "module-name"
+            module_id: "module-name",
+            ..
         });
     });
 }
@@ -66,7 +70,8 @@ fn multiple_let_decl() {
         let (res, _) = res.unwrap();
         matches!(res, &Ast::Module {
             statements: &[_, _],
-            module_id: "module-name"
+            module_id: "module-name",
+            ..
         });
     });
 }
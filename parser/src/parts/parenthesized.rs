@@ -58,7 +58,8 @@ fn nested_parens_with_function_call() {
         matches!{ res,
             &Ast::FunctionCall{
                 target: &Ast::Identifier(_, "a"),
-                ref args
+                ref args,
+                ..
             },
             args.len() == 1
         };
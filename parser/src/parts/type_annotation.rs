@@ -0,0 +1,124 @@
+use *;
+
+pub type TypeResult<'a> =
+    StdResult<(TypePtr<'a>, &'a [Token<'a>]), (ParseError<'a>, &'a [Token<'a>])>;
+
+/// Parses a type: one of the named types `Int`, `Float`, `Symbol` and `Map`,
+/// a list such as `List[Int]`, or a function such as `(Int, Float) -> Float`.
+pub fn parse_type<'a>(tokens: &'a [Token<'a>], alloc: &mut Allocator<'a>) -> TypeResult<'a> {
+    let start = tokens;
+    let (first, tokens) = expect_token_type!(
+        tokens,
+        TokenKind::Identifier(_) | TokenKind::OpenParen,
+        "a type"
+    )?;
+    let name = match first.kind {
+        TokenKind::Identifier(name) => name,
+        _ => return parse_function_type(start, tokens, alloc),
+    };
+    let span = first.span();
+    let ty = match name {
+        "Int" => TypeAst::Int(span),
+        "Float" => TypeAst::Float(span),
+        "Symbol" => TypeAst::Symbol(span),
+        "Map" => TypeAst::Map(span),
+        "List" => {
+            let (_, tokens) = expect_token_type!(tokens, TokenKind::OpenBracket, "'[' (open bracket)")?;
+            let (element, tokens) = parse_type(tokens, alloc)?;
+            let (_, tokens) =
+                expect_token_type!(tokens, TokenKind::CloseBracket, "']' (close bracket)")?;
+            let ty = TypeAst::List(element, consumed_span(start, tokens));
+            return Ok((alloc.alloc(ty), tokens));
+        }
+        _ => {
+            return Err((
+                ParseError::UnexpectedToken {
+                    found: first,
+                    expected: "a type",
+                },
+                tokens,
+            ))
+        }
+    };
+    Ok((alloc.alloc(ty), tokens))
+}
+
+fn parse_function_type<'a>(
+    start: &'a [Token<'a>],
+    mut tokens: &'a [Token<'a>],
+    alloc: &mut Allocator<'a>,
+) -> TypeResult<'a> {
+    let mut params = vec![];
+    if let Ok((_, rest)) = expect_token_type!(tokens, TokenKind::CloseParen, "close parenthesis") {
+        tokens = rest;
+    } else {
+        loop {
+            let (param, rest) = parse_type(tokens, alloc)?;
+            params.push(param);
+            let (comma_or_end, rest) = expect_token_type!(
+                rest,
+                TokenKind::CloseParen | TokenKind::Comma,
+                "comma or close parenthesis"
+            )?;
+            tokens = rest;
+            if comma_or_end.kind == TokenKind::CloseParen {
+                break;
+            }
+        }
+    }
+    let (_, tokens) = expect_token_type!(tokens, TokenKind::Arrow, "-> (arrow)")?;
+    let (result, tokens) = parse_type(tokens, alloc)?;
+    let ty = TypeAst::Function {
+        params: alloc.alloc_iter(params),
+        result,
+        span: consumed_span(start, tokens),
+    };
+    Ok((alloc.alloc(ty), tokens))
+}
+
+#[cfg(test)]
+fn with_parsed_type<F>(string: &'static str, f: F)
+where
+    F: FnOnce(TypeResult),
+{
+    use lexer::{lex, remove_whitespace};
+
+    let mut arena = copy_arena::Arena::new();
+    let mut alloc = arena.allocator();
+
+    let lexed = lex(string, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    f(parse_type(lexed, &mut alloc))
+}
+
+#[test]
+fn named_types() {
+    for &name in &["Int", "Float", "Symbol", "Map"] {
+        with_parsed_type(name, |res| {
+            let (res, rest) = res.unwrap();
+            assert_eq!(res.to_string(), name);
+            assert!(rest.is_empty());
+        });
+    }
+}
+
+#[test]
+fn nested_types() {
+    with_parsed_type("(List[Int], () -> Float) -> Map", |res| {
+        let (res, _) = res.unwrap();
+        assert_eq!(res.to_string(), "(List[Int], () -> Float) -> Map");
+        matches!{res,
+            &TypeAst::Function { params: &[&TypeAst::List(..), &TypeAst::Function { .. }], .. },
+            res.span() == Span { start: 0, end: 31 }
+        };
+    });
+}
+
+#[test]
+fn unknown_type() {
+    with_parsed_type("Integer", |res| {
+        matches!{res,
+            Err((ParseError::UnexpectedToken { expected: "a type", .. }, _))
+        };
+    });
+}
@@ -0,0 +1,68 @@
+//! Times the VM on generated arithmetic- and call-heavy programs.
+//!
+//! Run with `cargo run --release -p repl --example bench`.
+
+extern crate repl;
+extern crate vm;
+
+use repl::OptLevel;
+use std::time::{Duration, Instant};
+use vm::value::FunctionPtr;
+use vm::vm::Vm;
+
+fn arithmetic_program() -> String {
+    let mut expression = String::from("x");
+    for i in 1..200 {
+        let op = ["+", "-", "*", "/"][i % 4];
+        expression = format!("({} {} {})", expression, op, i % 7 + 1);
+    }
+
+    let mut source = String::new();
+    for i in 0..50 {
+        source.push_str(&format!("let x = {};\nlet r{} = {};\n", i, i, expression));
+    }
+    source
+}
+
+fn call_program() -> String {
+    let mut source = String::from("let inc(x) = x + 1;\nlet twice(f, x) = f(f(x));\n");
+    for i in 0..50 {
+        let mut call = String::from("0");
+        for _ in 0..100 {
+            call = format!("twice(inc, {})", call);
+        }
+        source.push_str(&format!("let r{} = {};\n", i, call));
+    }
+    source
+}
+
+fn time(program: &FunctionPtr, iterations: u32) -> Duration {
+    let started = Instant::now();
+    for _ in 0..iterations {
+        let mut vm = Vm::new();
+        vm.run_function(program.clone()).unwrap();
+    }
+    started.elapsed()
+}
+
+fn main() {
+    let benchmarks = vec![
+        ("arithmetic", arithmetic_program()),
+        ("calls", call_program()),
+    ];
+
+    for (name, source) in benchmarks {
+        let program = repl::compile_file(&source, name, OptLevel::O0).unwrap();
+        // Warm up allocators and caches before measuring.
+        time(&program, 10);
+
+        let iterations = 200;
+        let elapsed = time(&program, iterations);
+        let per_run = elapsed / iterations;
+        println!(
+            "{:12} {:>8.3} ms per run",
+            name,
+            per_run.as_secs() as f64 * 1e3 + f64::from(per_run.subsec_nanos()) / 1e6
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use vm::debug_info::SourceLines;
+use vm::debugger::{Breakpoint, Debugger, Execution, Stop};
+use vm::value::Value;
+use vm::vm::{Completion, Vm};
+
+/// An interactive debugging session over a single source file, driven by
+/// text commands from `ares debug`.
+pub struct DebugSession {
+    source: String,
+    vm: Vm,
+    debugger: Debugger,
+    execution: Option<Execution>,
+}
+
+pub const HELP: &str = "\
+break <function|line>  set a breakpoint (alias: b)
+delete <id>            remove a breakpoint (alias: d)
+breakpoints            list breakpoints
+step                   run one instruction (alias: s)
+next                   run one instruction, stepping over calls (alias: n)
+continue               run until a breakpoint or the end (alias: c)
+stack                  show the current function's stack
+args, upvars, locals   show parts of the current frame
+backtrace              show the continuation chain (alias: bt)
+list                   show the current source line (alias: l)
+quit                   leave the debugger (alias: q)";
+
+impl DebugSession {
+    pub fn new(source: &str, module_id: &str) -> Result<DebugSession, String> {
+        let function = ::compile_file(source, module_id, ::OptLevel::O0)?;
+        let mut vm = Vm::new();
+        let execution = vm
+            .begin_function(function)
+            .map_err(|e| format!("{}", e.with_lines(&SourceLines::new(source))))?;
+        Ok(DebugSession {
+            source: source.into(),
+            vm,
+            debugger: Debugger::new(source),
+            execution: Some(execution),
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.execution.is_none()
+    }
+
+    /// Runs one command and returns what should be printed.
+    pub fn command(&mut self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let argument = words.next();
+
+        match (command, argument) {
+            ("break", Some(target)) | ("b", Some(target)) => {
+                let breakpoint = match target.parse::<usize>() {
+                    Ok(line) => Breakpoint::Line(line),
+                    Err(_) => Breakpoint::Function(target.into()),
+                };
+                let id = self.debugger.add_breakpoint(breakpoint.clone());
+                format!("breakpoint {}: {}", id, describe_breakpoint(&breakpoint))
+            }
+            ("delete", Some(id)) | ("d", Some(id)) => match id.parse() {
+                Ok(id) if self.debugger.remove_breakpoint(id) => {
+                    format!("deleted breakpoint {}", id)
+                }
+                _ => format!("no breakpoint {}", id),
+            },
+            ("breakpoints", None) => self.debugger
+                .breakpoints()
+                .into_iter()
+                .map(|(id, bp)| format!("{}: {}", id, describe_breakpoint(bp)))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ("help", None) | ("h", None) => HELP.into(),
+            ("step", None) | ("s", None) => self.run(Debugger::step_instruction),
+            ("next", None) | ("n", None) => self.run(Debugger::step_over),
+            ("continue", None) | ("c", None) => self.run(Debugger::resume),
+            _ => match self.execution {
+                Some(ref execution) => self.inspect(command, execution),
+                None => "the program has finished".into(),
+            },
+        }
+    }
+
+    fn inspect(&self, command: &str, execution: &Execution) -> String {
+        match command {
+            "stack" => show_values(execution.stack()),
+            "args" => show_values(execution.args()),
+            "upvars" => show_values(execution.upvars()),
+            "locals" => show_values(execution.locals()),
+            "backtrace" | "bt" => {
+                let mut out = String::new();
+                for frame in execution.backtrace().frames {
+                    out.push_str(&format!("{}\n", frame.with_lines(self.debugger.lines())));
+                }
+                out.trim_end().into()
+            }
+            "list" | "l" => self.location(execution),
+            _ => format!("unknown command '{}', try 'help'", command),
+        }
+    }
+
+    fn run<F>(&mut self, action: F) -> String
+    where
+        F: Fn(&mut Debugger, &mut Vm, &mut Execution) -> ::vm::trace::RunResult<Stop>,
+    {
+        let result = match self.execution {
+            Some(ref mut execution) => action(&mut self.debugger, &mut self.vm, execution),
+            None => return "the program has finished".into(),
+        };
+
+        let mut out = String::new();
+        for value in self.vm.debug_values.drain(..) {
+            out.push_str(&format!("debug: {:?}\n", value));
+        }
+
+        match result {
+            Ok(Stop::Finished(Completion::Done(_))) => {
+                self.execution = None;
+                out.push_str("program finished");
+            }
+            Ok(Stop::Finished(Completion::Pending(token))) => {
+                self.execution = None;
+                out.push_str(&format!("program suspended on {:?}", token.request()));
+            }
+            Ok(stop) => {
+                if let Stop::Breakpoint(id) = stop {
+                    out.push_str(&format!("hit breakpoint {}\n", id));
+                }
+                let execution = self.execution.as_ref().unwrap();
+                out.push_str(&self.location(execution));
+            }
+            Err(e) => {
+                self.execution = None;
+                out.push_str(&format!("{}", e.with_lines(self.debugger.lines())).trim_end());
+            }
+        }
+        out
+    }
+
+    /// The next instruction and, when debug info is present, the source
+    /// line it belongs to with the span underlined.
+    fn location(&self, execution: &Execution) -> String {
+        let name = execution
+            .function()
+            .name
+            .clone()
+            .unwrap_or_else(|| "<unnamed>".into());
+        let instruction = match execution.next_instruction() {
+            Some(instruction) => format!("{:?}", instruction),
+            None => "<end of function>".into(),
+        };
+        let mut out = format!("{} @ {}: {}", name, execution.ip(), instruction);
+
+        if let Some(span) = execution.span() {
+            let line = self.debugger.line_of(span.start);
+            let line_start = self.source[..span.start]
+                .rfind('\n')
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            let line_text = self.source[line_start..].lines().next().unwrap_or("");
+            let prefix = format!("{:4} | ", line);
+            let underline_len = (span.end.min(line_start + line_text.len()) - span.start).max(1);
+            out.push_str(&format!(
+                "\n{}{}\n{}{}",
+                prefix,
+                line_text,
+                " ".repeat(prefix.len() + span.start - line_start),
+                "^".repeat(underline_len)
+            ));
+        }
+        out
+    }
+}
+
+fn describe_breakpoint(breakpoint: &Breakpoint) -> String {
+    match breakpoint {
+        &Breakpoint::Function(ref name) => format!("function {}", name),
+        &Breakpoint::Line(line) => format!("line {}", line),
+    }
+}
+
+fn show_values(values: &[Value]) -> String {
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| format!("[{}] {:?}", i, v))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
@@ -1,25 +1,61 @@
+extern crate ast_cont;
+extern crate ast_lower;
 extern crate binder;
+extern crate copy_arena;
 extern crate emit;
 extern crate lexer;
 extern crate parser;
 extern crate typed_arena;
+extern crate types;
 extern crate vm;
 
-use binder::{bind, BindingState, Bound, DeclarationKind, ModuleBinder};
-use lexer::{lex, remove_whitespace, Token};
-use parser::Ast;
-use parser::{parse_expression, parse_statement};
-use std::collections::HashMap;
+pub mod debug;
+
+use binder::{bind, bind_top, BindingState, Bound, DeclarationKind, ModuleBinder};
+use binder::lint::{Level, LintLevels};
+use copy_arena::Allocator;
+use lexer::{lex, remove_whitespace, Span, Token};
+use parser::AstPtr;
+use parser::{parse_expression, parse_module, parse_statement};
 use std::collections::HashSet;
+use std::fmt::Display;
 use typed_arena::Arena;
-use vm::value::Value;
-use vm::value::{new_func, Function};
+use vm::compiled::CompiledModule;
+use vm::debug_info::SourceLines;
+use vm::value::{FunctionPtr, Value};
 use vm::vm::Vm;
 
 #[derive(Clone, Debug)]
 pub struct StorableModuleBinder {
     pub name: String,
     pub definitions: HashSet<String>,
+    /// The types inferred for the definitions so far.
+    pub types: types::Env,
+}
+
+/// How much work compiling a file spends on making the program faster.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptLevel {
+    /// Emits the program as written.
+    O0,
+    /// Folds constants and removes dead code before emitting.
+    O1,
+    /// Also inlines small functions, and runs the peephole optimizer over
+    /// the emitted code.
+    O2,
+}
+
+/// A stage of the compiler whose output `dump_dot` can draw.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrStage {
+    /// The syntax tree from the parser.
+    Ast,
+    /// The syntax tree with every identifier bound.
+    Bound,
+    /// The continuation-passing form of the program.
+    Cont,
+    /// The functions the emitter produces.
+    Vm,
 }
 
 pub enum ReplOutKind {
@@ -54,38 +90,50 @@ impl StorableModuleBinder {
                     _ => None,
                 })
                 .collect(),
+            types: types::Env::new(),
         }
     }
     fn add_additional(&mut self, mb: &StorableModuleBinder) {
         for def in &mb.definitions {
             self.definitions.insert(def.clone());
         }
+        for (name, scheme) in mb.types.iter() {
+            if self.types.get(name).is_none() {
+                self.types.insert(name.clone(), scheme.clone());
+            }
+        }
     }
 }
 
+/// One line for each error, starting with the line of `source` it is on.
+fn describe_errors<E: Display>(source: &str, errors: &[E], span: fn(&E) -> Span) -> String {
+    let lines = SourceLines::new(source);
+    errors
+        .iter()
+        .map(|e| format!("line {}: {}", lines.line_of(span(e).start), e))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 fn repl_parse_expression<'parse>(
     lexed: &'parse [Token<'parse>],
-    arena: &'parse Arena<Ast<'parse>>,
+    alloc: &mut Allocator<'parse>,
 ) -> Result<AstPtr<'parse>, parser::ParseError<'parse>> {
-    let mut cache = HashMap::new();
-
-    let parsed = parse_expression(&lexed, &arena, &mut cache);
-
-    match parsed {
-        Ok((ast, _)) => Ok(ast),
+    match parse_expression(lexed, alloc) {
+        Ok((ast, rest)) if rest.is_empty() => Ok(ast),
+        Ok((_, rest)) => Err(parser::ParseError::UnexpectedToken {
+            found: &rest[0],
+            expected: "end of input",
+        }),
         Err((e, _)) => Err(e),
     }
 }
 
 fn repl_parse_statement<'parse>(
     lexed: &'parse [Token<'parse>],
-    arena: &'parse Arena<Ast<'parse>>,
+    alloc: &mut Allocator<'parse>,
 ) -> Result<AstPtr<'parse>, parser::ParseError<'parse>> {
-    let mut cache = HashMap::new();
-
-    let parsed = parse_statement(&lexed, &arena, &mut cache);
-
-    match parsed {
+    match parse_statement(lexed, alloc) {
         Ok((ast, _)) => Ok(ast),
         Err((e, _)) => Err(e),
     }
@@ -93,15 +141,14 @@ fn repl_parse_statement<'parse>(
 
 fn do_parse<'parse>(
     lexed: &'parse [Token<'parse>],
-    arena: &'parse Arena<Ast<'parse>>,
+    alloc: &mut Allocator<'parse>,
 ) -> ReplParseResult<'parse> {
-    match (
-        repl_parse_expression(lexed, arena),
-        repl_parse_statement(lexed, arena),
-    ) {
-        (Ok(e), _) => ReplParseResult::Expression(e),
-        (_, Ok(s)) => ReplParseResult::Statement(s),
-        (_, Err(e)) => ReplParseResult::Error(e),
+    if let Ok(e) = repl_parse_expression(lexed, alloc) {
+        return ReplParseResult::Expression(e);
+    }
+    match repl_parse_statement(lexed, alloc) {
+        Ok(s) => ReplParseResult::Statement(s),
+        Err(e) => ReplParseResult::Error(e),
     }
 }
 
@@ -110,44 +157,35 @@ pub fn run(
     vm: &mut Vm,
     past_work: StorableModuleBinder,
 ) -> Result<(ReplOutKind, StorableModuleBinder), String> {
-    use emit::emit_top;
-
-    let mut lexed = lex(program);
-    remove_whitespace(&mut lexed);
+    use emit::{emit_top, emit_top_expression};
 
-    let parse_arena = Arena::new();
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
     let bind_arena = Arena::new();
 
-    let parsed = do_parse(&lexed, &parse_arena);
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+
+    let parsed = do_parse(lexed, &mut alloc);
     let (emitted, new_mod_binder, is_expression) = match parsed {
         ReplParseResult::Expression(e) => {
+            let e = ast_lower::desugar(e, &mut alloc);
             let mut module_binder = past_work.to_module_binder();
-            let mut binder_state = BindingState { gen_id: 0 };
+            let mut binder_state = BindingState::new();
             let bound = match bind(&bind_arena, &mut module_binder, &mut binder_state, e) {
                 Ok(b) => b,
-                Err(e) => return Err(format!("{:?}", e)),
+                Err(errors) => return Err(describe_errors(program, &errors, binder::Error::span)),
             };
 
-            let mut instrs = vec![];
-            emit::emit(&bound, &mut instrs, None);
-            panic!(); //instrs.push(Instruction::Ret);
-            let function = new_func(Function {
-                name: None,
-                instructions: instrs,
-                upvars: vec![],
-
-                args_count: 0,
-                upvars_count: 0,
-                locals_count: 0,
-            });
-            (Value::Function(function), past_work.clone(), true)
+            (emit_top_expression(&bound), past_work.clone(), true)
         }
         ReplParseResult::Statement(s) => {
+            let s = ast_lower::desugar(s, &mut alloc);
             let mut module_binder = past_work.to_module_binder();
-            let mut binder_state = BindingState { gen_id: 0 };
+            let mut binder_state = BindingState::new();
             let bound = match bind(&bind_arena, &mut module_binder, &mut binder_state, &s) {
                 Ok(b) => b,
-                Err(e) => return Err(format!("{:?}", e)),
+                Err(errors) => return Err(describe_errors(program, &errors, binder::Error::span)),
             };
             let bound = bind_arena.alloc(Bound::Module {
                 ast: s,
@@ -157,6 +195,7 @@ pub fn run(
             let emitted = emit_top(&bound);
             if let &Bound::Module { ref binder, .. } = bound {
                 let mut new = StorableModuleBinder::from_module_binder(&binder);
+                new.types = types::infer(bound, &past_work.types).definitions;
                 new.add_additional(&past_work);
                 (emitted, new, false)
             } else {
@@ -176,8 +215,216 @@ pub fn run(
     let value = match (vm.run_function(f), is_expression) {
         (Ok(v), true) => ReplOutKind::Expression(v),
         (Ok(v), false) => ReplOutKind::Statement(v),
-        (Err(e), _) => return Err(format!("{:?}", e)),
+        (Err(e), _) => return Err(format!("{}", e.with_lines(&SourceLines::new(program)))),
     };
 
     Ok((value, new_mod_binder))
 }
+
+/// Evaluates `expression` and disassembles the function it produces.
+pub fn disassemble(
+    expression: &str,
+    vm: &mut Vm,
+    past_work: StorableModuleBinder,
+) -> Result<String, String> {
+    match run(expression, vm, past_work)? {
+        (ReplOutKind::Expression(Value::Function(f)), _) => Ok(vm::asm::disassemble(&f)),
+        (ReplOutKind::Expression(other), _) => Err(format!("{:?} is not a function", other)),
+        (ReplOutKind::Statement(_), _) => Err("expected an expression".into()),
+    }
+}
+
+/// Infers the type of `expression` in the module built up so far.
+pub fn type_of(expression: &str, past_work: StorableModuleBinder) -> Result<String, String> {
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let bind_arena = Arena::new();
+
+    let lexed = lex(expression, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = repl_parse_expression(lexed, &mut alloc).map_err(|e| format!("{:?}", e))?;
+    let parsed = ast_lower::desugar(parsed, &mut alloc);
+    let mut module_binder = past_work.to_module_binder();
+    let mut binder_state = BindingState::new();
+    let bound = bind(&bind_arena, &mut module_binder, &mut binder_state, parsed)
+        .map_err(|errors| describe_errors(expression, &errors, binder::Error::span))?;
+
+    let inferred = types::infer(&bound, &past_work.types);
+    if inferred.errors.is_empty() {
+        Ok(inferred.root.to_string())
+    } else {
+        Err(describe_errors(expression, &inferred.errors, types::Error::span))
+    }
+}
+
+/// Compiles a whole source file into the function that runs it as a module.
+pub fn compile_file(program: &str, module_id: &str, opt: OptLevel) -> Result<FunctionPtr, String> {
+    compile_module(program, module_id, opt).map(|module| module.program)
+}
+
+fn optimize_bound<'a>(arena: &'a Arena<Bound<'a>>, bound: Bound<'a>, opt: OptLevel) -> Bound<'a> {
+    match opt {
+        OptLevel::O0 => bound,
+        OptLevel::O1 => binder::optimize::optimize(arena, &bound),
+        OptLevel::O2 => {
+            let inlined = binder::inline::inline(arena, &bound);
+            binder::optimize::optimize(arena, &inlined)
+        }
+    }
+}
+
+/// Compiles a whole source file into a module that can be written to an
+/// `.aresc` file.
+pub fn compile_module(
+    program: &str,
+    module_id: &str,
+    opt: OptLevel,
+) -> Result<CompiledModule, String> {
+    use emit::emit_top;
+
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let bind_arena = Arena::new();
+
+    let module_id = alloc.alloc_str(module_id);
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = match parse_module(lexed, module_id, &mut alloc) {
+        Ok((ast, _)) => ast,
+        Err((e, _)) => return Err(format!("{:?}", e)),
+    };
+    let parsed = ast_lower::desugar(parsed, &mut alloc);
+    let bound = match bind_top(&bind_arena, parsed) {
+        Ok(b) => b,
+        Err(errors) => return Err(describe_errors(program, &errors, binder::Error::span)),
+    };
+
+    let mut exports = match &bound {
+        &Bound::Module { ref binder, .. } => StorableModuleBinder::from_module_binder(binder)
+            .definitions
+            .into_iter()
+            .collect::<Vec<_>>(),
+        _ => unreachable!(),
+    };
+    exports.sort();
+
+    let bound = optimize_bound(&bind_arena, bound, opt);
+    let mut program = emit_top(&bound).into_function().unwrap();
+    if opt == OptLevel::O2 {
+        program = emit::peephole::optimize(&program);
+    }
+
+    Ok(CompiledModule {
+        name: module_id.into(),
+        exports,
+        program,
+    })
+}
+
+/// Runs a whole source file as a single module, returning the values
+/// produced by its `debug` statements.
+pub fn run_file(
+    program: &str,
+    module_id: &str,
+    opt: OptLevel,
+    vm: &mut Vm,
+) -> Result<Vec<Value>, String> {
+    let f = compile_file(program, module_id, opt)?;
+    match vm.run_function(f) {
+        Ok(_) => Ok(vm.debug_values.drain(..).collect()),
+        Err(e) => Err(format!("{}", e.with_lines(&SourceLines::new(program)))),
+    }
+}
+
+/// Binds a whole source file, then checks its types and lints it.  Returns
+/// the level of each diagnostic with a line describing it; type errors are
+/// always errors.
+pub fn check(
+    program: &str,
+    module_id: &str,
+    levels: &LintLevels,
+) -> Result<Vec<(Level, String)>, String> {
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let bind_arena = Arena::new();
+
+    let module_id = alloc.alloc_str(module_id);
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = match parse_module(lexed, module_id, &mut alloc) {
+        Ok((ast, _)) => ast,
+        Err((e, _)) => return Err(format!("{:?}", e)),
+    };
+    let parsed = ast_lower::desugar(parsed, &mut alloc);
+    let bound = match bind_top(&bind_arena, parsed) {
+        Ok(b) => b,
+        Err(errors) => return Err(describe_errors(program, &errors, binder::Error::span)),
+    };
+
+    let lines = SourceLines::new(program);
+    let type_errors = types::infer(&bound, &types::Env::new())
+        .errors
+        .into_iter()
+        .map(|e| (e.span(), Level::Deny, e.to_string()));
+    let lints = binder::lint::lint(&bound, levels)
+        .into_iter()
+        .map(|d| (d.span, d.level, d.to_string()));
+    let mut diagnostics = type_errors.chain(lints).collect::<Vec<_>>();
+    diagnostics.sort_by_key(|&(span, _, _)| span.start);
+    Ok(diagnostics
+        .into_iter()
+        .map(|(span, level, message)| {
+            let line = lines.line_of(span.start);
+            (level, format!("{}: line {}: {}", level.name(), line, message))
+        })
+        .collect())
+}
+
+/// Draws `stage` of compiling a whole source file as a Graphviz DOT graph.
+/// The bound tree is optimized and the continuation-passing form is shrunk
+/// unless `opt` is `O0`.
+pub fn dump_dot(
+    program: &str,
+    module_id: &str,
+    stage: IrStage,
+    opt: OptLevel,
+) -> Result<String, String> {
+    if stage == IrStage::Vm {
+        return compile_file(program, module_id, opt).map(|f| vm::dot::to_dot(&f));
+    }
+
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let bind_arena = Arena::new();
+
+    let module_id = alloc.alloc_str(module_id);
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = match parse_module(lexed, module_id, &mut alloc) {
+        Ok((ast, _)) => ast,
+        Err((e, _)) => return Err(format!("{:?}", e)),
+    };
+
+    let desugared = ast_lower::desugar(parsed, &mut alloc);
+    match stage {
+        IrStage::Ast => Ok(parser::dot::to_dot(parsed)),
+        IrStage::Bound => {
+            let bound = bind_top(&bind_arena, desugared)
+                .map_err(|errors| describe_errors(program, &errors, binder::Error::span))?;
+            Ok(binder::dot::to_dot(&optimize_bound(&bind_arena, bound, opt)))
+        }
+        IrStage::Cont => {
+            let lower_arena = Arena::new();
+            let cont_arena = Arena::new();
+            let id_get = ast_cont::IdGet::new();
+            let lowered = ast_lower::lower(desugared, &lower_arena);
+            let cont = ast_cont::translate_top(lowered, &id_get, &cont_arena);
+            let cont = match opt {
+                OptLevel::O0 => cont,
+                _ => ast_cont::shrink::shrink(cont, &cont_arena),
+            };
+            Ok(ast_cont::dot::to_dot(cont))
+        }
+        IrStage::Vm => unreachable!(),
+    }
+}
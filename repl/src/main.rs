@@ -1,20 +1,282 @@
+extern crate binder;
 extern crate colored;
 extern crate linenoise;
 extern crate repl;
 extern crate vm;
 
+use binder::lint::{Level, Lint, LintLevels};
 use colored::*;
-use vm::value::new_func;
-use vm::value::Function;
-use repl::{ReplOutKind, StorableModuleBinder};
+use repl::{IrStage, OptLevel, ReplOutKind, StorableModuleBinder};
+use repl::debug::DebugSession;
+use vm::compiled::CompiledModule;
+use vm::debug_info::SourceLines;
+use std::fs::File;
+use std::io::{Read, Write};
+use vm::profiler::{FoldedWeight, Profile};
+use std::path::Path;
+use std::process;
+
+fn read_source(path: &str) -> (String, String) {
+    let mut source = String::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_string(&mut source)) {
+        eprintln!("{}", format!("could not read {}: {}", path, e).red());
+        process::exit(1);
+    }
+
+    let module_id = Path::new(path)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("main");
+    (source, module_id.into())
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message.red());
+    process::exit(1);
+}
+
+fn read_compiled(path: &str) -> CompiledModule {
+    let mut bytes = vec![];
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)) {
+        fail(format!("could not read {}: {}", path, e));
+    }
+    CompiledModule::from_bytes(&bytes).unwrap_or_else(|e| fail(format!("{}: {}", path, e)))
+}
+
+fn run_batch(path: &str, opt: OptLevel, profile: bool) {
+    let mut vm = vm::vm::Vm::new();
+    if profile {
+        vm.enable_profiling();
+    }
+
+    let result = if path.ends_with(".aresc") {
+        let module = read_compiled(path);
+        match vm.load_module(&module) {
+            Ok(_) => Ok(vm.debug_values.drain(..).collect()),
+            Err(e) => Err(format!("{}", e)),
+        }
+    } else {
+        let (source, module_id) = read_source(path);
+        repl::run_file(&source, &module_id, opt, &mut vm)
+    };
+    if let Some(profile) = vm.take_profile() {
+        write_profile(path, &profile);
+    }
+
+    match result {
+        Ok(values) => for value in values {
+            println!("{:?}", value);
+        },
+        Err(s) => {
+            eprintln!("{}", s.red());
+            process::exit(1);
+        }
+    }
+}
+
+/// Prints the profile report and writes folded stacks next to the script.
+fn write_profile(path: &str, profile: &Profile) {
+    eprint!("{}", profile.report());
+    let folded_path = format!("{}.folded", path);
+    let written = File::create(&folded_path)
+        .and_then(|mut f| f.write_all(profile.folded(FoldedWeight::Instructions).as_bytes()));
+    match written {
+        Ok(()) => eprintln!("folded stacks written to {}", folded_path),
+        Err(e) => eprintln!("{}", format!("could not write {}: {}", folded_path, e).red()),
+    }
+}
+
+/// Compiles `path` into an `.aresc` file at `output`, without line tables
+/// if `strip` is set.
+fn build(path: &str, output: &str, opt: OptLevel, strip: bool) {
+    let (source, module_id) = read_source(path);
+    let module = repl::compile_module(&source, &module_id, opt).unwrap_or_else(|s| fail(s));
+    let bytes = module
+        .to_bytes(!strip)
+        .unwrap_or_else(|e| fail(format!("{}", e)));
+    if let Err(e) = File::create(output).and_then(|mut f| f.write_all(&bytes)) {
+        fail(format!("could not write {}: {}", output, e));
+    }
+}
+
+/// Prints `stage` of compiling `path` in `format`, which can only be
+/// `dot` for now.
+fn dump(path: &str, stage: &str, format: &str, opt: OptLevel) {
+    let stage = match stage {
+        "ast" => IrStage::Ast,
+        "bound" => IrStage::Bound,
+        "cont" => IrStage::Cont,
+        "vm" => IrStage::Vm,
+        other => fail(format!(
+            "unknown stage {}, expected ast, bound, cont or vm",
+            other
+        )),
+    };
+    if format != "dot" {
+        fail(format!("unknown format {}, expected dot", format));
+    }
+
+    let (source, module_id) = read_source(path);
+    match repl::dump_dot(&source, &module_id, stage, opt) {
+        Ok(text) => print!("{}", text),
+        Err(s) => fail(s),
+    }
+}
+
+/// Lints the file at the end of `args`, after `-A`, `-W` or `-D` options
+/// that allow, warn about or deny a lint by name.  Fails if any lint that
+/// is denied fires.
+fn check(args: &[String]) {
+    let (path, options) = match args.split_last() {
+        Some((path, options)) if options.len() % 2 == 0 => (path, options),
+        _ => fail("usage: ares check [-A|-W|-D lint]... path".into()),
+    };
+    let mut levels = LintLevels::default();
+    for option in options.chunks(2) {
+        let level = match option[0].as_ref() {
+            "-A" => Level::Allow,
+            "-W" => Level::Warn,
+            "-D" => Level::Deny,
+            other => fail(format!("unknown option {}, expected -A, -W or -D", other)),
+        };
+        let lint = Lint::from_name(&option[1]).unwrap_or_else(|| {
+            let names = Lint::ALL.iter().map(Lint::name).collect::<Vec<_>>();
+            fail(format!("unknown lint {}, expected one of {}", option[1], names.join(", ")))
+        });
+        levels.set(lint, level);
+    }
+
+    let (source, module_id) = read_source(path);
+    let diagnostics = repl::check(&source, &module_id, &levels).unwrap_or_else(|s| fail(s));
+    let mut denied = false;
+    for (level, line) in diagnostics {
+        if level == Level::Deny {
+            denied = true;
+            eprintln!("{}", line.red());
+        } else {
+            eprintln!("{}", line.yellow());
+        }
+    }
+    if denied {
+        process::exit(1);
+    }
+}
+
+fn run_debugger(path: &str) {
+    let (source, module_id) = read_source(path);
+    let mut session = match DebugSession::new(&source, &module_id) {
+        Ok(session) => session,
+        Err(s) => {
+            eprintln!("{}", s.red());
+            process::exit(1);
+        }
+    };
+
+    println!("{}", "paused at the start of the program, type 'help' for commands".cyan());
+    while let Some(input) = linenoise::input(&format!("{}", "debug> ".cyan())) {
+        let input = input.trim();
+        if input == "quit" || input == "q" {
+            break;
+        }
+        linenoise::history_add(input);
+        let output = session.command(input);
+        if !output.is_empty() {
+            println!("{}", output);
+        }
+        if session.is_finished() {
+            break;
+        }
+    }
+}
+
+/// Runs every script in `paths`, each in a fresh VM; a script passes when
+/// it runs to completion.  With `coverage`, an LCOV record for each script
+/// is written to lcov.info.
+fn run_tests(paths: &[String], opt: OptLevel, coverage: bool) {
+    let mut lcov = String::new();
+    let mut failures = 0;
+    for path in paths {
+        let (source, module_id) = read_source(path);
+        let program = match repl::compile_file(&source, &module_id, opt) {
+            Ok(program) => program,
+            Err(s) => {
+                failures += 1;
+                println!("{} {}\n{}", "FAILED".red(), path, s);
+                continue;
+            }
+        };
+
+        let mut vm = vm::vm::Vm::new();
+        if coverage {
+            vm.enable_coverage();
+        }
+        match vm.run_function(program.clone()) {
+            Ok(_) => println!("{} {}", "ok".green(), path),
+            Err(e) => {
+                failures += 1;
+                let lines = SourceLines::new(&source);
+                println!("{} {}\n{}", "FAILED".red(), path, e.with_lines(&lines));
+            }
+        }
+        if let Some(coverage) = vm.take_coverage() {
+            lcov.push_str(&coverage.lcov(&module_id, path, &source, &program));
+        }
+    }
+
+    if coverage {
+        match File::create("lcov.info").and_then(|mut f| f.write_all(lcov.as_bytes())) {
+            Ok(()) => println!("coverage written to lcov.info"),
+            Err(e) => eprintln!("{}", format!("could not write lcov.info: {}", e).red()),
+        }
+    }
+
+    println!("{} passed, {} failed", paths.len() - failures, failures);
+    if failures > 0 {
+        process::exit(1);
+    }
+}
 
 fn main() {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let profile = args.iter().any(|a| a == "--profile");
+    let coverage = args.iter().any(|a| a == "--coverage");
+    let strip = args.iter().any(|a| a == "--strip");
+    let opt = match args.iter().rev().find(|a| a.starts_with("-O")).map(AsRef::as_ref) {
+        None | Some("-O0") => OptLevel::O0,
+        Some("-O1") => OptLevel::O1,
+        Some("-O2") => OptLevel::O2,
+        Some(other) => fail(format!("unknown optimization level {}", other)),
+    };
+    args.retain(|a| {
+        a != "--profile" && a != "--coverage" && a != "--strip" && !a.starts_with("-O")
+    });
+    if args.first().map(AsRef::as_ref) == Some("test") {
+        return run_tests(&args[1..], opt, coverage);
+    }
+    if args.first().map(AsRef::as_ref) == Some("check") {
+        return check(&args[1..]);
+    }
+    match args.iter().map(AsRef::as_ref).collect::<Vec<&str>>().as_slice() {
+        &["debug", path] => return run_debugger(path),
+        &["dump", "--ir", stage, "--format", format, path] => {
+            return dump(path, stage, format, opt)
+        }
+        &["build", path, "-o", output] => return build(path, output, opt, strip),
+        &["build", path] => {
+            let output = Path::new(path).with_extension("aresc");
+            return build(path, &output.to_string_lossy(), opt, strip);
+        }
+        &[path] => return run_batch(path, opt, profile),
+        _ => {}
+    }
+
     linenoise::set_multiline(3);
 
     let mut vm = vm::vm::Vm::new();
     let mut storable_mod_binder = StorableModuleBinder {
         name: "repl-module".into(),
         definitions: Default::default(),
+        types: Default::default(),
     };
 
     let mut buildup = String::new();
@@ -26,6 +288,20 @@ fn main() {
         };
 
         while let Some(input) = linenoise::input(&format!("{}", pre_string.cyan())) {
+            if input.starts_with(":type ") {
+                match repl::type_of(&input[6..], storable_mod_binder.clone()) {
+                    Ok(ty) => println!("{}", ty.green()),
+                    Err(s) => println!("{}", s.red()),
+                }
+                continue;
+            }
+            if input.starts_with(":dis ") {
+                match repl::disassemble(&input[5..], &mut vm, storable_mod_binder.clone()) {
+                    Ok(text) => print!("{}", text),
+                    Err(s) => println!("{}", s.red()),
+                }
+                continue;
+            }
             buildup.push_str(&input);
             buildup.push('\n');
             match repl::run(&buildup, &mut vm, storable_mod_binder.clone()) {
@@ -0,0 +1,36 @@
+#[allow(unused_imports)]
+use super::*;
+
+// Values that do not match their annotations.  `run_failing` checks that
+// every pipeline raises the same error, however much of the program it
+// inlined, removed or compiled through continuations.
+
+#[test]
+fn annotated_variables() {
+    let error = run_failing("let x: Int = 1.5; debug(x);");
+    assert_eq!(error, "expected integer, found float 1.5");
+}
+
+#[test]
+fn unused_annotated_variables() {
+    let error = run_failing("let f() = { let x: Int = 1.5; 2 }; debug(f());");
+    assert_eq!(error, "expected integer, found float 1.5");
+}
+
+#[test]
+fn annotated_parameters_of_inlined_calls() {
+    let error = run_failing("let f(x: Int): Int = x; debug(f(1.5));");
+    assert_eq!(error, "expected integer, found float 1.5");
+}
+
+#[test]
+fn annotated_results_of_inlined_calls() {
+    let error = run_failing("let f(x): Int = x; debug(f(1.5));");
+    assert_eq!(error, "expected integer, found float 1.5");
+}
+
+#[test]
+fn annotated_parameters_of_anonymous_functions() {
+    let error = run_failing("let f(g) = g(1); debug(f((x: Float) => x));");
+    assert_eq!(error, "expected float, found integer 1");
+}
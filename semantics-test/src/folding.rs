@@ -0,0 +1,73 @@
+#[allow(unused_imports)]
+use super::*;
+
+// Programs that give the optimizations on bound trees something to do.
+// `run` checks that the optimized tree produces the same debug values.
+
+#[test]
+fn folded_arithmetic_keeps_vm_promotion() {
+    let out = run("debug(1 + 2 * 3); debug(7 / 2); debug(3 * 0.5); debug(1.0 - 1); debug(1 / 2.0);");
+    assert_eq!(
+        out,
+        vec![
+            Value::Integer(7),
+            Value::Integer(3),
+            Value::Float(1.5),
+            Value::Float(0.0),
+            Value::Float(0.5),
+        ]
+    );
+}
+
+#[test]
+fn folding_inside_functions() {
+    let out = run("let f(a) = a * (2 + 3) - 10 / 5; debug(f(4));");
+    assert_eq!(out, vec![Value::Integer(18)]);
+}
+
+#[test]
+fn unused_declarations_in_blocks() {
+    let out = run("let f(a) = { let b = a; let c = 1 + 2; let d = b; a * 2 }; debug(f(5));");
+    assert_eq!(out, vec![Value::Integer(10)]);
+}
+
+#[test]
+fn used_declarations_in_blocks_are_kept() {
+    let out = run("let f(a) = { let b = a + 1; let c = 2 * 3; b + c }; debug(f(1));");
+    assert_eq!(out, vec![Value::Integer(8)]);
+}
+
+#[test]
+fn declarations_captured_by_closures_are_kept() {
+    let out = run(
+        "let f(a) = { let b = a * 10; let g = (x) => x + b; g(1) }; debug(f(2)); debug(f(3));",
+    );
+    assert_eq!(out, vec![Value::Integer(21), Value::Integer(31)]);
+}
+
+#[test]
+fn shadowed_declarations_in_blocks() {
+    let out = run("let f(a) = { let x = 1; let x = x + a; let x = 5; x + a }; debug(f(2));");
+    assert_eq!(out, vec![Value::Integer(7)]);
+}
+
+#[test]
+fn trivial_blocks() {
+    let out = run("let x = { 1 + 1 }; debug({ { x } }); let f(a) = { { a } }; debug(f(3));");
+    assert_eq!(out, vec![Value::Integer(2), Value::Integer(3)]);
+}
+
+#[test]
+fn blocks_at_the_top_level() {
+    let out = run("let x = { let unused = 4; let y = 2; y * 3 }; debug(x);");
+    assert_eq!(out, vec![Value::Integer(6)]);
+}
+
+#[test]
+fn folding_keeps_the_sign_of_zero() {
+    let out = run("let f(x) = 1.0 / x; let a = 0.0; let b = (0.0 - 1.0) * 0.0; debug(f(a)); debug(f(b));");
+    assert_eq!(
+        out,
+        vec![Value::Float(::std::f64::INFINITY), Value::Float(::std::f64::NEG_INFINITY)]
+    );
+}
@@ -34,3 +34,78 @@ fn function_returning_self() {
         vec![Value::Integer(0), Value::Integer(0), Value::Integer(0)]
     );
 }
+
+#[test]
+fn annotated_functions() {
+    let out = run(
+        r#"
+    let scale(x: Int, by: Float): Float = x * by;
+    let twice = (f: (Float) -> Float, x) => f(f(x));
+    let y: Float = twice((v) => v + 1.0, scale(2, 1.5));
+    debug(y);
+    "#,
+    );
+    assert_eq!(out, vec![Value::Float(5.0)]);
+}
+
+#[test]
+fn pipelines_and_placeholders() {
+    let out = run(
+        r#"
+    let add(a, b) = a + b;
+    let trace(x) = { debug(x); x };
+    let inc = add(_, trace(1));
+    debug(inc(2));
+    debug(inc(3));
+    debug(trace(10) |> inc);
+    "#,
+    );
+    assert_eq!(
+        out,
+        vec![
+            Value::Integer(1),
+            Value::Integer(3),
+            Value::Integer(4),
+            Value::Integer(10),
+            Value::Integer(11),
+        ]
+    );
+}
+
+#[test]
+fn mutually_recursive_module_functions() {
+    let out = run(
+        r#"
+    let ping(n, next) = next(pong, n + 1);
+    let pong(n, next) = next(ping, n * 2);
+    let stop(f, n) = n;
+    let once(f, n) = f(n, stop);
+    debug(ping(1, once));
+    debug(pong(1, once));
+    "#,
+    );
+    assert_eq!(out, vec![Value::Integer(4), Value::Integer(3)]);
+}
+
+#[test]
+fn mutually_recursive_block_functions() {
+    let out = run(
+        r#"
+    let f(x) = {
+        let ping(n, next) = next(pong, n + x);
+        let pong(n, next) = next(ping, n * x);
+        let stop(g, n) = n;
+        ping(1, (g, n) => g(n, stop))
+    };
+    let h(x) = {
+        let a(n) = b(n) + x;
+        let b(n) = n * 2;
+        let c() = { let d() = a(1); d };
+        c()()
+    };
+    debug(f(10));
+    debug(h(10));
+    "#,
+    );
+    assert_eq!(out, vec![Value::Integer(110), Value::Integer(12)]);
+}
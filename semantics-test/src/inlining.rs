@@ -0,0 +1,68 @@
+#[allow(unused_imports)]
+use super::*;
+
+// Programs with calls the inliner can replace.  `run` checks that the
+// inlined program produces the same debug values.
+
+#[test]
+fn one_line_helpers() {
+    let out = run("let double(x) = x * 2; let inc(x) = x + 1; debug(double(inc(4)));");
+    assert_eq!(out, vec![Value::Integer(10)]);
+}
+
+#[test]
+fn arguments_are_evaluated_once_and_in_order() {
+    let out = run(
+        "let one() = { debug(1); 1 };
+         let two() = { debug(2); 2 };
+         let second(a, b) = b;
+         debug(second(one(), two()));",
+    );
+    assert_eq!(
+        out,
+        vec![Value::Integer(1), Value::Integer(2), Value::Integer(2)]
+    );
+}
+
+#[test]
+fn arguments_used_twice() {
+    let out = run("let square(x) = x * x; let f(a) = square(a + 1); debug(f(2));");
+    assert_eq!(out, vec![Value::Integer(9)]);
+}
+
+#[test]
+fn callee_locals_do_not_clash() {
+    let out = run(
+        "let g(x) = { let y = x * 2; y + 1 };
+         let f(y) = { let x = g(y); x + y };
+         debug(f(3));",
+    );
+    assert_eq!(out, vec![Value::Integer(10)]);
+}
+
+#[test]
+fn closures_in_inlined_bodies() {
+    let out = run("let adder(n) = (x) => x + n; let f(a) = adder(a)(10); debug(f(5));");
+    assert_eq!(out, vec![Value::Integer(15)]);
+}
+
+#[test]
+fn functions_that_refer_to_themselves_are_left_alone() {
+    let out = run("let me(n) = { me; n + 1 }; let f(n) = me(n) * 2; debug(f(3));");
+    assert_eq!(out, vec![Value::Integer(8)]);
+}
+
+#[test]
+fn shadowed_functions() {
+    let out = run(
+        "let f(x) = x + 1;
+         let r = {
+             let g(x) = f(x);
+             let f(x) = x + 2;
+             debug(g(1));
+             f(1)
+         };
+         debug(r);",
+    );
+    assert_eq!(out, vec![Value::Integer(2), Value::Integer(3)]);
+}
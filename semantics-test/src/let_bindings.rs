@@ -14,15 +14,17 @@ fn in_function_block() {
 }
 
 #[test]
-fn shadowing_in_module_scope() {
+fn shadowing_module_scope_in_a_block() {
     let out = run(
         r#"
     let x = 10;
     let f() = {debug(x); 0};
-    let x = 20;
-    let g() = {debug(x); 0};
     f();
-    g();"#,
+    let y = {
+        let x = 20;
+        let g() = {debug(x); 0};
+        g()
+    };"#,
     );
     assert_eq!(out, vec![Value::Integer(10), Value::Integer(20)]);
 }
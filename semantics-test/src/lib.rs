@@ -1,41 +1,197 @@
+extern crate ast_cont;
+extern crate ast_lower;
 extern crate binder;
+extern crate copy_arena;
 extern crate emit;
 extern crate lexer;
 extern crate parser;
 extern crate typed_arena;
 extern crate vm;
 
-use vm::value::Value;
+use vm::compiled::CompiledModule;
+use vm::value::{FunctionPtr, Value};
 
 mod debug;
 mod literals;
 mod functions;
 mod math_operators;
 mod let_bindings;
+mod peephole;
+mod folding;
+mod inlining;
+mod annotations;
 
 #[allow(dead_code)]
 fn run(program: &str) -> Vec<Value> {
-    use std::collections::HashMap;
     use typed_arena::Arena;
     use lexer::{lex, remove_whitespace};
     use parser::parse_module;
     use binder::bind_top;
     use emit::emit_top;
 
-    let mut lexed = lex(program);
-    let parse_arena = Arena::new();
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
     let bind_arena = Arena::new();
 
-    remove_whitespace(&mut lexed);
-    let mut cache = HashMap::new();
-    let parsed = parse_module(&lexed, "my_module", &parse_arena, &mut cache).unwrap();
-    let bound = bind_top(&bind_arena, parsed.0).unwrap();
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap();
+    let desugared = ast_lower::desugar(parsed.0, &mut alloc);
+    let bound = bind_top(&bind_arena, desugared).unwrap();
     let emitted = emit_top(&bound);
-    println!("{:#?}", emitted);
     let f = emitted.into_function().unwrap();
 
+    let direct = run_function(f.clone());
+
+    // The peephole optimizer must not change what a program does.
+    let optimized = emit::peephole::optimize(&f);
+    assert_eq!(run_function(optimized), direct, "optimized code differs");
+
+    // Neither must the optimizations on the bound tree.
+    let folded = binder::optimize::optimize(&bind_arena, &bound);
+    let folded = emit_top(&folded).into_function().unwrap();
+    assert_eq!(run_function(folded), direct, "folded code differs");
+
+    // Nor must inlining.
+    let inlined = binder::inline::inline(&bind_arena, &bound);
+    let inlined = emit_top(&inlined).into_function().unwrap();
+    assert_eq!(run_function(inlined), direct, "inlined code differs");
+
+    // Every program must behave the same after a trip through `.aresc`.
+    let module = CompiledModule {
+        name: "my_module".into(),
+        exports: vec![],
+        program: f,
+    };
+    let bytes = module.to_bytes(true).unwrap();
+    let loaded = CompiledModule::from_bytes(&bytes).unwrap();
+    assert_eq!(run_function(loaded.program), direct);
+
+    // The reference interpreter must agree with the VM.
+    let cont_arena = Arena::new();
+    let id_get = ast_cont::IdGet::new();
+    let lower_arena = Arena::new();
+    let lowered = ast_lower::lower(desugared, &lower_arena);
+    let cont = ast_cont::translate_top(lowered, &id_get, &cont_arena);
+    let mut interpreter = ast_cont::eval::Interpreter::new();
+    if let Err(e) = interpreter.run(cont) {
+        panic!("reference interpreter: {}", e);
+    }
+    assert_same_values(&direct, &interpreter.debug_values);
+
+    // And it must agree after the program is shrunk.
+    let shrunk = ast_cont::shrink::shrink(cont, &cont_arena);
+    let mut interpreter = ast_cont::eval::Interpreter::new();
+    if let Err(e) = interpreter.run(shrunk) {
+        panic!("reference interpreter after shrinking: {}", e);
+    }
+    assert_same_values(&direct, &interpreter.debug_values);
+
+    // Both compiled through the CPS backend must agree with the VM as well.
+    for &cont in &[cont, shrunk] {
+        let compiled = match ast_cont::codegen::compile_top(cont, &cont_arena) {
+            Ok(compiled) => compiled,
+            Err(e) => panic!("CPS backend: {:?}", e),
+        };
+        assert_same_kinds(&direct, &run_function(compiled));
+    }
+
+    direct
+}
+
+/// Runs a program that has to fail, the same way in every pipeline `run`
+/// checks, and returns the error.
+#[allow(dead_code)]
+fn run_failing(program: &str) -> String {
+    use typed_arena::Arena;
+    use lexer::{lex, remove_whitespace};
+    use parser::parse_module;
+    use binder::bind_top;
+    use emit::emit_top;
+
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let bind_arena = Arena::new();
+
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap();
+    let desugared = ast_lower::desugar(parsed.0, &mut alloc);
+    let bound = bind_top(&bind_arena, desugared).unwrap();
+    let f = emit_top(&bound).into_function().unwrap();
+
+    let error = function_error(f.clone());
+    let folded = binder::optimize::optimize(&bind_arena, &bound);
+    let inlined = binder::inline::inline(&bind_arena, &bound);
+    let compiled = vec![
+        ("optimized", emit::peephole::optimize(&f)),
+        ("folded", emit_top(&folded).into_function().unwrap()),
+        ("inlined", emit_top(&inlined).into_function().unwrap()),
+    ];
+    for (name, f) in compiled {
+        assert_eq!(function_error(f), error, "{} code differs", name);
+    }
+
+    let cont_arena = Arena::new();
+    let id_get = ast_cont::IdGet::new();
+    let lower_arena = Arena::new();
+    let lowered = ast_lower::lower(desugared, &lower_arena);
+    let cont = ast_cont::translate_top(lowered, &id_get, &cont_arena);
+    let shrunk = ast_cont::shrink::shrink(cont, &cont_arena);
+    for &cont in &[cont, shrunk] {
+        match ast_cont::eval::Interpreter::new().run(cont) {
+            Ok(value) => panic!("reference interpreter returned {:?}", value),
+            Err(e) => assert_eq!(e.to_string(), error, "reference interpreter differs"),
+        }
+        let compiled = match ast_cont::codegen::compile_top(cont, &cont_arena) {
+            Ok(compiled) => compiled,
+            Err(e) => panic!("CPS backend: {:?}", e),
+        };
+        assert_eq!(function_error(compiled), error, "CPS backend differs");
+    }
+
+    error
+}
+
+/// Compares debug values from the VM with those from the reference
+/// interpreter.  Functions and maps only have to be of the same kind.
+fn assert_same_values(vm: &[Value], reference: &[ast_cont::eval::Value]) {
+    use ast_cont::eval::Value as Ref;
+
+    let same = vm.len() == reference.len() && vm.iter().zip(reference).all(|pair| match pair {
+        (&Value::Integer(l), &Ref::Integer(r)) => l == r,
+        (&Value::Float(l), &Ref::Float(r)) => l.to_bits() == r.to_bits(),
+        (&Value::Symbol(ref l), &Ref::Symbol(r)) => l.0 == r,
+        (&Value::Function(_), &Ref::Function(_)) | (&Value::Map(_), &Ref::Map(_)) => true,
+        _ => false,
+    });
+    assert!(same, "reference interpreter differs: {:?} vs {:?}", vm, reference);
+}
+
+/// Compares debug values from two VM runs of differently compiled code.
+/// Functions and maps only have to be of the same kind.
+fn assert_same_kinds(expected: &[Value], actual: &[Value]) {
+    let same = expected.len() == actual.len()
+        && expected.iter().zip(actual).all(|(l, r)| match (l, r) {
+            (&Value::Function(_), &Value::Function(_)) | (&Value::Map(_), &Value::Map(_)) => true,
+            (&Value::Float(l), &Value::Float(r)) => l.to_bits() == r.to_bits(),
+            (l, r) => l == r,
+        });
+    assert!(same, "CPS backend differs: {:?} vs {:?}", expected, actual);
+}
+
+fn run_function(f: FunctionPtr) -> Vec<Value> {
     let mut vm = vm::vm::Vm::new();
-    vm.run_function(f).unwrap();
+    if let Err(e) = vm.run_function(f) {
+        panic!("{}", e);
+    }
 
     vm.debug_values
 }
+
+fn function_error(f: FunctionPtr) -> String {
+    match vm::vm::Vm::new().run_function(f) {
+        Ok(value) => panic!("expected an error, returned {:?}", value),
+        Err(e) => e.error.to_string(),
+    }
+}
@@ -0,0 +1,74 @@
+#[allow(unused_imports)]
+use super::*;
+
+// Programs that give the peephole optimizer something to rewrite.  `run`
+// checks that the optimized code produces the same debug values.
+
+#[test]
+fn discarded_expression_statements() {
+    let out = run("1; 2.5; let x = 3; x; debug(x);");
+    assert_eq!(out, vec![Value::Integer(3)]);
+}
+
+#[test]
+fn discarded_statements_in_blocks() {
+    let out = run("let f(a) = {a; 7; a + 1}; debug(f(1)); debug(f(2));");
+    assert_eq!(out, vec![Value::Integer(2), Value::Integer(3)]);
+}
+
+#[test]
+fn constant_arithmetic() {
+    let out = run("debug(1 + 2 * 3); debug(7 / 2); debug(1.5 * 2); debug(10 - 0.5);");
+    assert_eq!(
+        out,
+        vec![
+            Value::Integer(7),
+            Value::Integer(3),
+            Value::Float(3.0),
+            Value::Float(9.5),
+        ]
+    );
+}
+
+#[test]
+fn unfoldable_arithmetic_is_left_to_the_vm() {
+    let out = run("debug(9223372036854775807 * 1); debug(1.0 / 0);");
+    assert_eq!(
+        out,
+        vec![Value::Integer(9223372036854775807), Value::Float(::std::f64::INFINITY)]
+    );
+}
+
+#[test]
+fn arithmetic_around_calls() {
+    let out = run(
+        r#"
+    let id(x) = x;
+    let g(a, b) = { 1; id(a) + 2 * 3 + id(b) };
+    debug(g(10, 100));
+    debug(id(1 + 1) + id(2 * 2));
+    "#,
+    );
+    assert_eq!(out, vec![Value::Integer(116), Value::Integer(6)]);
+}
+
+#[test]
+fn pipelines() {
+    let out = run("let inc(x) = x + 1; debug(1 |> inc); let y = 5; debug(y |> inc |> inc);");
+    assert_eq!(out, vec![Value::Integer(2), Value::Integer(7)]);
+}
+
+#[test]
+fn closures_over_locals() {
+    let out = run(
+        r#"
+    let make(n) = {
+        let k = n * 2;
+        let add(x) = x + k + 1 - 1;
+        add
+    };
+    debug(make(3)(4));
+    "#,
+    );
+    assert_eq!(out, vec![Value::Integer(10)]);
+}
@@ -0,0 +1,1007 @@
+extern crate binder;
+extern crate lexer;
+extern crate parser;
+#[cfg(test)]
+extern crate ast_lower;
+#[cfg(test)]
+extern crate copy_arena;
+#[cfg(test)]
+extern crate typed_arena;
+
+#[cfg(test)]
+mod test;
+
+use binder::{BindingKind, Bound, DeclarationKind};
+use binder::slots::{resolve, Frame, Resolved, Slot};
+use lexer::Span;
+use parser::{Ast, TypeAst};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+// Hindley-Milner type inference over bound trees.
+//
+// Type variables are solved by unification as the tree is walked.  Only
+// functions defined at the top level of a module are generalized; every
+// other binding has a single type, which is what the binder's locals,
+// arguments and upvars can hold at runtime anyway.
+//
+// The VM does arithmetic on any mix of integers and floats, so a variable
+// can be marked numeric instead of being solved to one of them.  An
+// operation with an integer operand has the type of its other operand, and
+// one with a float operand is a float.  When neither operand is known, the
+// operation is kept pending with a numeric variable for its result, which
+// is worked out once the operands are.  A generalized function carries the
+// arithmetic pending on its variables, written `c: Num(a, b)`, so each use
+// works it out for the types that use gives it.
+//
+// Maps are indexed by symbols and hold any mix of values, so a field is
+// never known to have more than a fresh type.
+//
+// Type annotations in the source are taken as given: an annotated
+// parameter has the annotated type instead of a fresh one, and the body of
+// a function or the value of a variable is checked against its annotation.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Float,
+    Symbol,
+    Map,
+    List(Box<Type>),
+    /// The types of the parameters and of the result.
+    Function(Vec<Type>, Box<Type>),
+    Var(TypeVar),
+}
+
+impl<'a> From<&'a TypeAst<'a>> for Type {
+    fn from(annotation: &TypeAst) -> Type {
+        match annotation {
+            &TypeAst::Int(_) => Type::Int,
+            &TypeAst::Float(_) => Type::Float,
+            &TypeAst::Symbol(_) => Type::Symbol,
+            &TypeAst::Map(_) => Type::Map,
+            &TypeAst::List(element, _) => Type::List(Box::new(element.into())),
+            &TypeAst::Function { params, result, .. } => Type::Function(
+                params.iter().map(|&param| param.into()).collect(),
+                Box::new(result.into()),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TypeVar {
+    pub id: u32,
+    /// Whether the variable can only stand for `Int` or `Float`.
+    pub numeric: bool,
+}
+
+/// Arithmetic on operands of types `left` and `right`, whose `result` is
+/// not known until they are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arithmetic {
+    pub left: Type,
+    pub right: Type,
+    pub result: Type,
+}
+
+/// A type whose variables in `vars` are replaced by fresh ones wherever
+/// the binding is used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scheme {
+    pub vars: Vec<u32>,
+    pub ty: Type,
+    /// The arithmetic pending on `vars`, worked out again at each use.
+    pub arithmetic: Vec<Arithmetic>,
+}
+
+/// The types of the definitions of a module, by name.
+#[derive(Debug, Clone, Default)]
+pub struct Env {
+    definitions: HashMap<String, Scheme>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Mismatch {
+        expected: Type,
+        found: Type,
+        span: Span,
+    },
+    NotAFunction {
+        found: Type,
+        span: Span,
+    },
+    WrongArity {
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    NotANumber {
+        found: Type,
+        span: Span,
+    },
+    /// A type would have to contain itself.
+    InfiniteType(Span),
+}
+
+/// What inferring the types of a tree found.
+#[derive(Debug)]
+pub struct Inferred {
+    /// The type of the whole tree.
+    pub root: Type,
+    pub errors: Vec<Error>,
+    /// The definitions the tree added to the module.
+    pub definitions: Env,
+    /// Each expression, and each name a statement declares, with its type.
+    nodes: Vec<(Span, Type)>,
+}
+
+impl Env {
+    pub fn new() -> Env {
+        Env::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Scheme> {
+        self.definitions.get(name)
+    }
+
+    pub fn insert(&mut self, name: String, scheme: Scheme) {
+        self.definitions.insert(name, scheme);
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a String, &'a Scheme)> + 'a {
+        self.definitions.iter()
+    }
+}
+
+impl Scheme {
+    pub fn monomorphic(ty: Type) -> Scheme {
+        Scheme {
+            vars: vec![],
+            ty,
+            arithmetic: vec![],
+        }
+    }
+}
+
+impl Inferred {
+    /// The innermost expression or declared name around `offset`, with
+    /// its type.
+    pub fn type_at(&self, offset: usize) -> Option<(Span, &Type)> {
+        self.nodes
+            .iter()
+            .filter(|&&(span, _)| span.start <= offset && offset < span.end)
+            .min_by_key(|&&(span, _)| span.end - span.start)
+            .map(|&(span, ref ty)| (span, ty))
+    }
+}
+
+impl Error {
+    pub fn span(&self) -> Span {
+        match self {
+            &Error::Mismatch { span, .. }
+            | &Error::NotAFunction { span, .. }
+            | &Error::WrongArity { span, .. }
+            | &Error::NotANumber { span, .. }
+            | &Error::InfiniteType(span) => span,
+        }
+    }
+}
+
+/// Names variables `a`, `b`, ... in the order they are first written.
+struct TypeWriter<'a> {
+    names: Vec<TypeVar>,
+    arithmetic: &'a [Arithmetic],
+}
+
+impl<'a> TypeWriter<'a> {
+    fn write(&mut self, ty: &Type, f: &mut Formatter) -> FmtResult {
+        match ty {
+            &Type::Int => write!(f, "Int"),
+            &Type::Float => write!(f, "Float"),
+            &Type::Symbol => write!(f, "Symbol"),
+            &Type::Map => write!(f, "Map"),
+            &Type::List(ref element) => {
+                write!(f, "List[")?;
+                self.write(element, f)?;
+                write!(f, "]")
+            }
+            &Type::Function(ref params, ref result) => {
+                write!(f, "(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    self.write(param, f)?;
+                }
+                write!(f, ") -> ")?;
+                self.write(result, f)
+            }
+            &Type::Var(var) => {
+                let index = match self.names.iter().position(|v| v.id == var.id) {
+                    Some(index) => index,
+                    None => {
+                        self.names.push(var);
+                        self.names.len() - 1
+                    }
+                };
+                write!(f, "{}", var_name(index))
+            }
+        }
+    }
+
+    /// Lists the variables that can only be numbers, along with the
+    /// operands of the arithmetic whose result they are.  Operands are
+    /// numbers too, so they are listed in turn.
+    fn write_constraints(&mut self, f: &mut Formatter) -> FmtResult {
+        let mut first = true;
+        let mut index = 0;
+        while index < self.names.len() {
+            let var = self.names[index];
+            if var.numeric {
+                write!(f, "{}{}: Num", if first { " where " } else { ", " }, var_name(index))?;
+                first = false;
+                let arithmetic = self.arithmetic;
+                let pending = arithmetic.iter().find(|a| match a.result {
+                    Type::Var(result) => result.id == var.id,
+                    _ => false,
+                });
+                if let Some(pending) = pending {
+                    write!(f, "(")?;
+                    self.write(&pending.left, f)?;
+                    write!(f, ", ")?;
+                    self.write(&pending.right, f)?;
+                    write!(f, ")")?;
+                }
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+fn var_name(index: usize) -> String {
+    let letter = (b'a' + (index % 26) as u8) as char;
+    if index < 26 {
+        letter.to_string()
+    } else {
+        format!("{}{}", letter, index / 26)
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let mut writer = TypeWriter {
+            names: vec![],
+            arithmetic: &[],
+        };
+        writer.write(self, f)?;
+        writer.write_constraints(f)
+    }
+}
+
+impl Display for Scheme {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let mut writer = TypeWriter {
+            names: vec![],
+            arithmetic: &self.arithmetic,
+        };
+        writer.write(&self.ty, f)?;
+        writer.write_constraints(f)
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &Error::Mismatch {
+                ref expected,
+                ref found,
+                ..
+            } => write!(f, "expected {}, found {}", expected, found),
+            &Error::NotAFunction { ref found, .. } => write!(f, "{} is not a function", found),
+            &Error::WrongArity {
+                expected, found, ..
+            } => write!(
+                f,
+                "the function takes {} argument{} but {} {} given",
+                expected,
+                if expected == 1 { "" } else { "s" },
+                found,
+                if found == 1 { "was" } else { "were" }
+            ),
+            &Error::NotANumber { ref found, .. } => {
+                write!(f, "arithmetic needs numbers, found {}", found)
+            }
+            &Error::InfiniteType(_) => write!(f, "this would have a type that contains itself"),
+        }
+    }
+}
+
+/// Infers the types in `bound`, where the module it is in already has the
+/// definitions in `env`.  Inference carries on past errors, using a fresh
+/// type wherever one was found.
+pub fn infer(bound: &Bound, env: &Env) -> Inferred {
+    let mut inferer = Inferer {
+        env,
+        vars: vec![],
+        slots: HashMap::new(),
+        frames: vec![],
+        functions: vec![],
+        definitions: vec![],
+        arithmetic: vec![],
+        errors: vec![],
+        nodes: vec![],
+    };
+    let root = inferer.infer(bound);
+
+    let root = inferer.resolve(&root);
+    let nodes = ::std::mem::take(&mut inferer.nodes)
+        .into_iter()
+        .map(|(span, ty)| (span, inferer.resolve(&ty)))
+        .collect();
+    let mut definitions = Env::new();
+    for (name, slot) in ::std::mem::take(&mut inferer.definitions) {
+        let scheme = inferer.slots[&slot].clone();
+        let ty = inferer.resolve(&scheme.ty);
+        let arithmetic = scheme
+            .arithmetic
+            .iter()
+            .map(|a| Arithmetic {
+                left: inferer.resolve(&a.left),
+                right: inferer.resolve(&a.right),
+                result: inferer.resolve(&a.result),
+            })
+            .collect();
+        definitions.insert(
+            name,
+            Scheme {
+                vars: scheme.vars,
+                ty,
+                arithmetic,
+            },
+        );
+    }
+    Inferred {
+        root,
+        errors: inferer.errors,
+        definitions,
+        nodes,
+    }
+}
+
+#[derive(Clone)]
+struct VarState {
+    solution: Option<Type>,
+    numeric: bool,
+}
+
+/// Why two types could not be unified.
+enum Conflict {
+    Mismatch,
+    Infinite,
+}
+
+struct Inferer<'a, 'b: 'a> {
+    env: &'a Env,
+    vars: Vec<VarState>,
+    slots: HashMap<Slot<'b>, Scheme>,
+    /// The functions being inferred, innermost last.
+    frames: Vec<Frame<'a, 'b>>,
+    /// The type of each function in `frames`, if it has a name to call it
+    /// by.
+    functions: Vec<Option<Type>>,
+    /// The module definitions seen so far, in order.
+    definitions: Vec<(String, Slot<'b>)>,
+    /// Arithmetic whose result is not known yet, with where it was done.
+    arithmetic: Vec<(Arithmetic, Span)>,
+    errors: Vec<Error>,
+    nodes: Vec<(Span, Type)>,
+}
+
+fn name_span(ast: &Ast) -> Span {
+    match ast {
+        &Ast::VariableDecl { name_ast, .. } | &Ast::FunctionDecl { name_ast, .. } => {
+            name_ast.span()
+        }
+        other => other.span(),
+    }
+}
+
+impl<'a, 'b> Inferer<'a, 'b> {
+    fn fresh(&mut self, numeric: bool) -> Type {
+        let id = self.vars.len() as u32;
+        self.vars.push(VarState {
+            solution: None,
+            numeric,
+        });
+        Type::Var(TypeVar { id, numeric })
+    }
+
+    /// `ty`, with the variable at its outside replaced by its solution.
+    fn prune(&self, ty: &Type) -> Type {
+        match ty {
+            &Type::Var(var) => match self.vars[var.id as usize].solution {
+                Some(ref solution) => self.prune(solution),
+                None => Type::Var(TypeVar {
+                    id: var.id,
+                    numeric: self.vars[var.id as usize].numeric,
+                }),
+            },
+            other => other.clone(),
+        }
+    }
+
+    /// `ty`, with every variable replaced by its solution.
+    fn resolve(&self, ty: &Type) -> Type {
+        match self.prune(ty) {
+            Type::List(element) => Type::List(Box::new(self.resolve(&element))),
+            Type::Function(params, result) => Type::Function(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(&result)),
+            ),
+            other => other,
+        }
+    }
+
+    fn free_vars(&self, ty: &Type, out: &mut HashSet<u32>) {
+        match self.prune(ty) {
+            Type::Var(var) => {
+                out.insert(var.id);
+            }
+            Type::List(element) => self.free_vars(&element, out),
+            Type::Function(params, result) => {
+                for param in &params {
+                    self.free_vars(param, out);
+                }
+                self.free_vars(&result, out);
+            }
+            _ => {}
+        }
+    }
+
+    fn unify(&mut self, a: &Type, b: &Type) -> Result<(), Conflict> {
+        match (self.prune(a), self.prune(b)) {
+            (Type::Var(a), Type::Var(b)) if a.id == b.id => Ok(()),
+            (Type::Var(var), other) | (other, Type::Var(var)) => self.solve(var.id, other),
+            (Type::Int, Type::Int)
+            | (Type::Float, Type::Float)
+            | (Type::Symbol, Type::Symbol)
+            | (Type::Map, Type::Map) => Ok(()),
+            (Type::List(a), Type::List(b)) => self.unify(&a, &b),
+            (Type::Function(a_params, a_result), Type::Function(b_params, b_result)) => {
+                if a_params.len() != b_params.len() {
+                    return Err(Conflict::Mismatch);
+                }
+                for (a, b) in a_params.iter().zip(&b_params) {
+                    self.unify(a, b)?;
+                }
+                self.unify(&a_result, &b_result)
+            }
+            _ => Err(Conflict::Mismatch),
+        }
+    }
+
+    fn solve(&mut self, id: u32, ty: Type) -> Result<(), Conflict> {
+        let numeric = self.vars[id as usize].numeric;
+        match ty {
+            Type::Var(other) => {
+                self.vars[other.id as usize].numeric |= numeric;
+            }
+            Type::Int | Type::Float => {}
+            _ if numeric => return Err(Conflict::Mismatch),
+            _ => {
+                let mut free = HashSet::new();
+                self.free_vars(&ty, &mut free);
+                if free.contains(&id) {
+                    return Err(Conflict::Infinite);
+                }
+            }
+        }
+        self.vars[id as usize].solution = Some(ty);
+        Ok(())
+    }
+
+    /// Unifies the type `found` at `span` with the type it should have.
+    fn expect(&mut self, expected: &Type, found: &Type, span: Span) {
+        self.expect_unsettled(expected, found, span);
+        self.settle();
+    }
+
+    fn expect_unsettled(&mut self, expected: &Type, found: &Type, span: Span) {
+        match self.unify(expected, found) {
+            Ok(()) => {}
+            Err(Conflict::Mismatch) => {
+                let error = Error::Mismatch {
+                    expected: self.resolve(expected),
+                    found: self.resolve(found),
+                    span,
+                };
+                self.errors.push(error);
+            }
+            Err(Conflict::Infinite) => self.errors.push(Error::InfiniteType(span)),
+        }
+    }
+
+    /// Checks that `ty` is a number, returning whether it is.
+    fn expect_number(&mut self, ty: &Type, span: Span) -> bool {
+        match self.prune(ty) {
+            Type::Int | Type::Float => true,
+            Type::Var(var) => {
+                self.vars[var.id as usize].numeric = true;
+                true
+            }
+            other => {
+                let found = self.resolve(&other);
+                self.errors.push(Error::NotANumber { found, span });
+                false
+            }
+        }
+    }
+
+    /// The type of arithmetic on operands of types `left` and `right`, if
+    /// they are known well enough to tell.
+    fn arithmetic_type(&self, left: &Type, right: &Type) -> Option<Type> {
+        match (self.prune(left), self.prune(right)) {
+            (Type::Int, _) => Some(right.clone()),
+            (_, Type::Int) => Some(left.clone()),
+            (Type::Float, _) | (_, Type::Float) => Some(Type::Float),
+            (Type::Var(a), Type::Var(b)) if a.id == b.id => Some(left.clone()),
+            _ => None,
+        }
+    }
+
+    /// Works out the result of each pending arithmetic whose operands are
+    /// known now, until no more can be.
+    fn settle(&mut self) {
+        loop {
+            let pending = ::std::mem::take(&mut self.arithmetic);
+            let count = pending.len();
+            for (arithmetic, span) in pending {
+                match self.arithmetic_type(&arithmetic.left, &arithmetic.right) {
+                    Some(ty) => self.expect_unsettled(&arithmetic.result, &ty, span),
+                    None => self.arithmetic.push((arithmetic, span)),
+                }
+            }
+            if self.arithmetic.len() == count {
+                break;
+            }
+        }
+    }
+
+    /// Instantiates `scheme` for a use at `span`.
+    fn instantiate(&mut self, scheme: &Scheme, span: Span) -> Type {
+        let mut fresh = HashMap::new();
+        for &id in &scheme.vars {
+            let numeric = self.vars[id as usize].numeric;
+            fresh.insert(id, self.fresh(numeric));
+        }
+        for arithmetic in &scheme.arithmetic {
+            let arithmetic = Arithmetic {
+                left: self.substitute(&arithmetic.left, &fresh),
+                right: self.substitute(&arithmetic.right, &fresh),
+                result: self.substitute(&arithmetic.result, &fresh),
+            };
+            self.arithmetic.push((arithmetic, span));
+        }
+        self.substitute(&scheme.ty, &fresh)
+    }
+
+    fn substitute(&self, ty: &Type, fresh: &HashMap<u32, Type>) -> Type {
+        match self.prune(ty) {
+            Type::Var(var) => fresh.get(&var.id).cloned().unwrap_or(Type::Var(var)),
+            Type::List(element) => Type::List(Box::new(self.substitute(&element, fresh))),
+            Type::Function(params, result) => Type::Function(
+                params.iter().map(|p| self.substitute(p, fresh)).collect(),
+                Box::new(self.substitute(&result, fresh)),
+            ),
+            other => other,
+        }
+    }
+
+    /// Quantifies the variables of `ty` that no other binding mentions,
+    /// along with the arithmetic pending on them and the variables only
+    /// that arithmetic mentions.
+    fn generalize(&self, ty: Type) -> Scheme {
+        let mut in_env = HashSet::new();
+        for scheme in self.slots.values() {
+            let mut free = HashSet::new();
+            self.free_vars(&scheme.ty, &mut free);
+            in_env.extend(free.into_iter().filter(|id| !scheme.vars.contains(id)));
+        }
+        let mut free = HashSet::new();
+        self.free_vars(&ty, &mut free);
+        let mut vars = free.into_iter()
+            .filter(|id| !in_env.contains(id))
+            .collect::<HashSet<_>>();
+
+        let mut pending = self.arithmetic.iter().map(|&(ref a, _)| a).collect::<Vec<_>>();
+        let mut arithmetic = vec![];
+        loop {
+            let (on_vars, rest): (Vec<_>, Vec<_>) = pending.into_iter().partition(|a| {
+                let mut mentioned = HashSet::new();
+                self.free_vars(&a.left, &mut mentioned);
+                self.free_vars(&a.right, &mut mentioned);
+                self.free_vars(&a.result, &mut mentioned);
+                mentioned.iter().any(|id| vars.contains(id))
+            });
+            pending = rest;
+            if on_vars.is_empty() {
+                break;
+            }
+            for a in on_vars {
+                let mut mentioned = HashSet::new();
+                self.free_vars(&a.left, &mut mentioned);
+                self.free_vars(&a.right, &mut mentioned);
+                self.free_vars(&a.result, &mut mentioned);
+                vars.extend(mentioned.into_iter().filter(|id| !in_env.contains(id)));
+                arithmetic.push(a.clone());
+            }
+        }
+
+        let mut vars = vars.into_iter().collect::<Vec<_>>();
+        vars.sort();
+        Scheme {
+            vars,
+            ty,
+            arithmetic,
+        }
+    }
+
+    fn lookup(&mut self, kind: &BindingKind<'b>, span: Span) -> Type {
+        let slot = match resolve(&self.frames, kind) {
+            Resolved::Slot(slot) => slot,
+            Resolved::Function(depth) => match self.functions[depth - 1].clone() {
+                Some(function) => return function,
+                None => return self.fresh(false),
+            },
+            Resolved::Unknown => return self.fresh(false),
+        };
+        if let Some(scheme) = self.slots.get(&slot).cloned() {
+            return self.instantiate(&scheme, span);
+        }
+        // A definition from an earlier run over the module, or one that
+        // is not known at all.
+        let known = match &slot {
+            &Slot::Module(_, ref symbol) => match &**symbol {
+                &DeclarationKind::Named(name) => self.env.get(name).cloned(),
+                _ => None,
+            },
+            _ => None,
+        };
+        match known {
+            Some(scheme) => self.instantiate_outside(&scheme, span),
+            None => {
+                let ty = self.fresh(false);
+                self.slots.insert(slot, Scheme::monomorphic(ty.clone()));
+                ty
+            }
+        }
+    }
+
+    /// Instantiates a scheme whose variables were numbered by another
+    /// inference, so that none of them is shared with this one.
+    fn instantiate_outside(&mut self, scheme: &Scheme, span: Span) -> Type {
+        let mut vars = HashMap::new();
+        for arithmetic in &scheme.arithmetic {
+            let arithmetic = Arithmetic {
+                left: self.import(&arithmetic.left, &mut vars),
+                right: self.import(&arithmetic.right, &mut vars),
+                result: self.import(&arithmetic.result, &mut vars),
+            };
+            self.arithmetic.push((arithmetic, span));
+        }
+        self.import(&scheme.ty, &mut vars)
+    }
+
+    fn import(&mut self, ty: &Type, vars: &mut HashMap<u32, Type>) -> Type {
+        match ty {
+            &Type::Var(var) => {
+                if let Some(ty) = vars.get(&var.id) {
+                    return ty.clone();
+                }
+                let fresh = self.fresh(var.numeric);
+                vars.insert(var.id, fresh.clone());
+                fresh
+            }
+            &Type::List(ref element) => Type::List(Box::new(self.import(element, vars))),
+            &Type::Function(ref params, ref result) => {
+                let params = params.iter().map(|p| self.import(p, vars)).collect();
+                Type::Function(params, Box::new(self.import(result, vars)))
+            }
+            other => other.clone(),
+        }
+    }
+
+    /// Binds the declaration at `location` to `ty`, generalized if it is a
+    /// function at the top level of a module.
+    fn declare(&mut self, name: &str, location: &BindingKind<'b>, ty: Type, function: bool) {
+        let slot = match resolve(&self.frames, location) {
+            Resolved::Slot(slot) => slot,
+            _ => return,
+        };
+        let top_level = self.frames.is_empty();
+        let scheme = if function && top_level {
+            self.generalize(ty)
+        } else {
+            Scheme::monomorphic(ty)
+        };
+        if let (true, &Slot::Module(_, ref symbol)) = (top_level, &slot) {
+            if let &DeclarationKind::Named(_) = &**symbol {
+                self.definitions.push((name.into(), slot.clone()));
+            }
+        }
+        self.slots.insert(slot, scheme);
+    }
+
+    /// Infers the type of the function declared by `ast`, using the types
+    /// its parameters and result are annotated with.
+    fn infer_function(
+        &mut self,
+        name: Option<&str>,
+        ast: &'b Ast<'b>,
+        params: &[(DeclarationKind<'b>, &'b Ast<'b>)],
+        body: &'b Bound<'b>,
+        upvars: &'a HashMap<DeclarationKind<'b>, (BindingKind<'b>, u32)>,
+        siblings: Vec<BindingKind<'b>>,
+    ) -> Type {
+        let (annotations, result) = match ast {
+            &Ast::FunctionDecl {
+                params, result, ..
+            } => (params, result),
+            &Ast::AnonFunc { params, .. } => (params, None),
+            _ => (&[] as &[_], None),
+        };
+        let param_types = (0..params.len())
+            .map(|i| match annotations.get(i) {
+                Some(&(_, _, Some(ty))) => ty.into(),
+                _ => self.fresh(false),
+            })
+            .collect::<Vec<_>>();
+        let result = match result {
+            Some(ty) => ty.into(),
+            None => self.fresh(false),
+        };
+        let function = Type::Function(param_types.clone(), Box::new(result.clone()));
+
+        self.frames.push(Frame {
+            body,
+            upvars,
+            siblings,
+        });
+        self.functions.push(name.map(|_| function.clone()));
+        for (i, (param, &(_, ast))) in param_types.iter().zip(params).enumerate() {
+            self.slots
+                .insert(Slot::Argument(body, i as u32), Scheme::monomorphic(param.clone()));
+            self.nodes.push((ast.span(), param.clone()));
+        }
+        let body_type = self.infer(body);
+        self.expect(&result, &body_type, body.span());
+        self.frames.pop();
+        self.functions.pop();
+
+        // Nothing can refer to the arguments and locals any more, and they
+        // must not keep the variables in their types from being generalized.
+        let body = body as *const Bound<'b>;
+        self.slots.retain(|slot, _| match slot {
+            &Slot::Local(owner, _) | &Slot::Argument(owner, _) => owner != body,
+            &Slot::Module(..) => true,
+        });
+        function
+    }
+
+    /// Infers the type of a call of `target` with arguments of the types
+    /// in `args`, which are at the spans alongside them.
+    fn infer_call(&mut self, target: &'a Bound<'b>, args: Vec<(Type, Span)>, span: Span) -> Type {
+        let target_type = self.infer(target);
+        match self.prune(&target_type) {
+            Type::Function(params, result) => {
+                if params.len() != args.len() {
+                    self.errors.push(Error::WrongArity {
+                        expected: params.len(),
+                        found: args.len(),
+                        span,
+                    });
+                } else {
+                    for (param, &(ref arg, span)) in params.iter().zip(&args) {
+                        self.expect(param, arg, span);
+                    }
+                }
+                *result
+            }
+            Type::Var(var) if !var.numeric => {
+                let result = self.fresh(false);
+                let params = args.into_iter().map(|(ty, _)| ty).collect();
+                let function = Type::Function(params, Box::new(result.clone()));
+                self.expect(&target_type, &function, target.span());
+                result
+            }
+            other => {
+                let found = self.resolve(&other);
+                self.errors.push(Error::NotAFunction {
+                    found,
+                    span: target.span(),
+                });
+                self.fresh(false)
+            }
+        }
+    }
+
+    fn infer_arithmetic(&mut self, left: &'a Bound<'b>, right: &'a Bound<'b>, span: Span) -> Type {
+        let left_type = self.infer(left);
+        let right_type = self.infer(right);
+        let numbers = self.expect_number(&left_type, left.span())
+            & self.expect_number(&right_type, right.span());
+        if !numbers {
+            return self.fresh(true);
+        }
+        match self.arithmetic_type(&left_type, &right_type) {
+            Some(ty) => ty,
+            None => {
+                let result = self.fresh(true);
+                let arithmetic = Arithmetic {
+                    left: left_type,
+                    right: right_type,
+                    result: result.clone(),
+                };
+                self.arithmetic.push((arithmetic, span));
+                result
+            }
+        }
+    }
+
+    fn infer(&mut self, node: &'a Bound<'b>) -> Type {
+        let ty = self.infer_node(node);
+        self.nodes.push((node.span(), ty.clone()));
+        ty
+    }
+
+    fn infer_node(&mut self, node: &'a Bound<'b>) -> Type {
+        match node {
+            &Bound::Integer { .. } => Type::Int,
+            &Bound::Float { .. } => Type::Float,
+            &Bound::Identifier {
+                ref binding_kind, ..
+            } => self.lookup(binding_kind, node.span()),
+            &Bound::DebugCall { arg, .. } => {
+                self.infer(arg);
+                // `debug` produces no value to use.
+                self.fresh(false)
+            }
+            &Bound::FunctionCall {
+                target, ref args, ..
+            } => {
+                let args = args.iter()
+                    .map(|arg| (self.infer(arg), arg.span()))
+                    .collect();
+                self.infer_call(target, args, node.span())
+            }
+            &Bound::Add { left, right, .. }
+            | &Bound::Sub { left, right, .. }
+            | &Bound::Div { left, right, .. }
+            | &Bound::Mul { left, right, .. } => self.infer_arithmetic(left, right, node.span()),
+            &Bound::FunctionDecl {
+                name,
+                ref params,
+                body,
+                ref upvars,
+                ref location,
+                ast,
+                ..
+            } => {
+                let ty = self.infer_function(Some(name), ast, params, body, upvars, vec![]);
+                self.declare(name, location, ty.clone(), true);
+                self.nodes.push((name_span(ast), ty.clone()));
+                ty
+            }
+            &Bound::FunctionGroup { ref functions } => {
+                // The functions of a group refer to each other with the
+                // same type throughout, and are generalized together once
+                // all of them are known.
+                let locations = functions
+                    .iter()
+                    .filter_map(|function| match function {
+                        &Bound::FunctionDecl { ref location, .. } => Some(location.clone()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let types = locations.iter().map(|_| self.fresh(false)).collect::<Vec<_>>();
+                let slots = locations
+                    .iter()
+                    .filter_map(|location| match resolve(&self.frames, location) {
+                        Resolved::Slot(slot) => Some(slot),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                for (slot, ty) in slots.iter().zip(&types) {
+                    self.slots.insert(slot.clone(), Scheme::monomorphic(ty.clone()));
+                }
+                for (function, ty) in functions.iter().zip(&types) {
+                    if let &Bound::FunctionDecl {
+                        name,
+                        ref params,
+                        body,
+                        ref upvars,
+                        ast,
+                        ..
+                    } = function
+                    {
+                        let inferred = self.infer_function(
+                            Some(name),
+                            ast,
+                            params,
+                            body,
+                            upvars,
+                            locations.clone(),
+                        );
+                        self.expect(ty, &inferred, name_span(ast));
+                    }
+                }
+                // None of them may keep the others from being generalized.
+                for slot in &slots {
+                    self.slots.remove(slot);
+                }
+                for (function, ty) in functions.iter().zip(&types) {
+                    if let &Bound::FunctionDecl {
+                        name,
+                        ref location,
+                        ast,
+                        ..
+                    } = function
+                    {
+                        self.declare(name, location, ty.clone(), true);
+                        self.nodes.push((name_span(ast), ty.clone()));
+                    }
+                }
+                types.last().cloned().unwrap_or(Type::Map)
+            }
+            &Bound::AnonFunc {
+                ref params,
+                body,
+                ref upvars,
+                ast,
+                ..
+            } => self.infer_function(None, ast, params, body, upvars, vec![]),
+            &Bound::VariableDecl {
+                name,
+                expression,
+                ty: annotation,
+                ref location,
+                ast,
+                ..
+            } => {
+                let mut ty = self.infer(expression);
+                if let Some(annotation) = annotation {
+                    let annotated = annotation.into();
+                    self.expect(&annotated, &ty, expression.span());
+                    ty = annotated;
+                }
+                self.declare(name, location, ty.clone(), false);
+                self.nodes.push((name_span(ast), ty.clone()));
+                ty
+            }
+            &Bound::FieldAccess { target, .. } => {
+                let target_type = self.infer(target);
+                self.expect(&Type::Map, &target_type, target.span());
+                self.fresh(false)
+            }
+            &Bound::BlockExpr {
+                ref statements,
+                final_expression,
+                ..
+            } => {
+                for statement in statements {
+                    self.infer(statement);
+                }
+                self.infer(final_expression)
+            }
+            &Bound::Module { ref statements, .. } => {
+                for statement in statements {
+                    self.infer(statement);
+                }
+                Type::Map
+            }
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use super::*;
+use binder::bind_top;
+use lexer::{lex, remove_whitespace};
+use parser::parse_module;
+use typed_arena::Arena;
+
+fn with_inferred<F>(program: &'static str, env: &Env, f: F)
+where
+    F: FnOnce(Inferred),
+{
+    let mut parse_arena = copy_arena::Arena::new();
+    let mut alloc = parse_arena.allocator();
+    let bind_arena = Arena::new();
+
+    let lexed = lex(program, &mut alloc);
+    let lexed = remove_whitespace(lexed, &mut alloc);
+    let parsed = parse_module(lexed, "my_module", &mut alloc).unwrap().0;
+    let desugared = ast_lower::desugar(parsed, &mut alloc);
+    let bound = bind_top(&bind_arena, desugared).unwrap();
+    f(infer(&bound, env))
+}
+
+/// The type of each definition in `names`, checking there are no errors.
+fn definitions(program: &'static str, names: &[&str]) -> Vec<String> {
+    let mut types = vec![];
+    with_inferred(program, &Env::new(), |inferred| {
+        assert_eq!(inferred.errors, vec![]);
+        for name in names {
+            types.push(inferred.definitions.get(name).unwrap().to_string());
+        }
+    });
+    types
+}
+
+fn errors(program: &'static str) -> Vec<(String, &'static str)> {
+    let mut errors = vec![];
+    with_inferred(program, &Env::new(), |inferred| {
+        errors = inferred
+            .errors
+            .iter()
+            .map(|e| (e.to_string(), &program[e.span().start..e.span().end]))
+            .collect();
+    });
+    errors
+}
+
+#[test]
+fn arithmetic_follows_the_operands() {
+    assert_eq!(
+        definitions(
+            "let a = 1; let b = 2.5; let c = a + 1; let d = a * b; let e = b - 1;",
+            &["a", "b", "c", "d", "e"]
+        ),
+        vec!["Int", "Float", "Int", "Float", "Float"]
+    );
+}
+
+#[test]
+fn functions_over_numbers() {
+    assert_eq!(
+        definitions(
+            "let inc(x) = x + 1; let add(x, y) = x + y; let half(x) = x / 2.0;",
+            &["inc", "add", "half"]
+        ),
+        vec![
+            "(a) -> a where a: Num",
+            "(a, b) -> c where a: Num, b: Num, c: Num(a, b)",
+            "(a) -> Float where a: Num",
+        ]
+    );
+}
+
+#[test]
+fn generic_arithmetic_takes_mixed_numbers() {
+    assert_eq!(
+        definitions(
+            "let add(x, y) = x + y;
+             let sum(x, y, z) = x + y + z;
+             let a = add(1, 2.5);
+             let b = add(1, 2);
+             let c = add(2.5, 1);
+             let d = sum(1, 2, 3.5);
+             let e = sum(1, 2, 3);",
+            &["sum", "a", "b", "c", "d", "e"]
+        ),
+        vec![
+            "(a, b, c) -> d where a: Num, b: Num, c: Num, d: Num(a, e), e: Num(b, c)",
+            "Float",
+            "Int",
+            "Float",
+            "Float",
+            "Int",
+        ]
+    );
+    assert_eq!(
+        errors("let add(x, y) = x + y; let n: Int = add(1, 2.5);"),
+        vec![("expected Int, found Float".into(), "add(1, 2.5)")]
+    );
+}
+
+#[test]
+fn module_functions_are_polymorphic() {
+    assert_eq!(
+        definitions(
+            "let id(x) = x;
+             let apply(f, x) = f(x);
+             let i = id(1);
+             let f = apply(id, 2.5);
+             let g = id(id);
+             let h = 3 |> id;",
+            &["id", "apply", "i", "f", "g", "h"]
+        ),
+        vec!["(a) -> a", "((a) -> b, a) -> b", "Int", "Float", "(a) -> a", "Int"]
+    );
+}
+
+#[test]
+fn block_functions_are_not_polymorphic() {
+    assert_eq!(
+        errors("let f() = { let id(x) = x; let a = id(1); id(2.5) };"),
+        vec![("expected Int, found Float".into(), "2.5")]
+    );
+}
+
+#[test]
+fn reports_calls_of_non_functions() {
+    assert_eq!(
+        errors("let x = 1; let f(a) = a; x(2); f(1, 2); let g(n) = n + 1; g(1)(2);"),
+        vec![
+            ("Int is not a function".into(), "x"),
+            ("the function takes 1 argument but 2 were given".into(), "f(1, 2)"),
+            ("Int is not a function".into(), "g(1)"),
+        ]
+    );
+}
+
+#[test]
+fn reports_arithmetic_on_maps() {
+    assert_eq!(
+        errors("let f(m) = { let v = m.x; m + v };"),
+        vec![("arithmetic needs numbers, found Map".into(), "m")]
+    );
+}
+
+#[test]
+fn reports_mismatched_arguments() {
+    assert_eq!(
+        errors("let f(g) = g(1) + g(2.5); let h(x) = x(x);"),
+        vec![
+            ("expected Int, found Float".into(), "2.5"),
+            ("this would have a type that contains itself".into(), "x"),
+        ]
+    );
+}
+
+#[test]
+fn types_for_hovering() {
+    let program = "let scale(x) = { let y = x * 2.0; y };";
+    with_inferred(program, &Env::new(), |inferred| {
+        let hover = |text: &str| {
+            let offset = program.find(text).unwrap();
+            let (span, ty) = inferred.type_at(offset).unwrap();
+            (&program[span.start..span.end], ty.to_string())
+        };
+        assert_eq!(hover("scale"), ("scale", "(a) -> Float where a: Num".into()));
+        assert_eq!(hover("x)"), ("x", "a where a: Num".into()));
+        assert_eq!(hover("y ="), ("y", "Float".into()));
+        assert_eq!(hover("2.0"), ("2.0", "Float".into()));
+    });
+}
+
+#[test]
+fn definitions_carry_over_between_runs() {
+    use binder::{bind, BindingState, DeclarationKind, ModuleBinder};
+
+    with_inferred("let twice(f, x) = f(f(x));", &Env::new(), |first| {
+        // Binds the next statement the way the REPL does, in a module that
+        // already defines `twice`.
+        let mut parse_arena = copy_arena::Arena::new();
+        let mut alloc = parse_arena.allocator();
+        let bind_arena = Arena::new();
+        let program = "let n = twice((x) => x * 2, 1);";
+        let lexed = lex(program, &mut alloc);
+        let lexed = remove_whitespace(lexed, &mut alloc);
+        let statement = match parse_module(lexed, "my_module", &mut alloc).unwrap().0 {
+            &Ast::Module { statements, .. } => statements[0],
+            other => panic!("{:?} is not a module", other),
+        };
+        let mut module_binder = ModuleBinder {
+            module_id: "my_module",
+            definitions: vec![DeclarationKind::Named("twice")].into_iter().collect(),
+        };
+        let mut state = BindingState::new();
+        let bound = bind(&bind_arena, &mut module_binder, &mut state, statement).unwrap();
+
+        let second = infer(&bound, &first.definitions);
+        assert_eq!(second.errors, vec![]);
+        assert_eq!(second.definitions.get("n").unwrap().to_string(), "Int");
+    });
+}
+
+#[test]
+fn annotations_narrow_types() {
+    assert_eq!(
+        definitions(
+            "let add(x: Int, y): Float = x + y;
+             let apply(f: (Int) -> Int, x) = f(x);
+             let n: Float = add(1, 2.5);",
+            &["add", "apply", "n"]
+        ),
+        vec!["(Int, Float) -> Float", "((Int) -> Int, Int) -> Int", "Float"]
+    );
+}
+
+#[test]
+fn reports_values_that_do_not_match_annotations() {
+    assert_eq!(
+        errors(
+            "let f(x: Int): Int = x * 2.0; let y: Map = 1; let g = (m: Map) => m; g(3);"
+        ),
+        vec![
+            ("expected Int, found Float".into(), "x * 2.0"),
+            ("expected Map, found Int".into(), "1"),
+            ("expected Map, found Int".into(), "3"),
+        ]
+    );
+}
+
+#[test]
+fn function_groups_are_polymorphic_once_inferred() {
+    assert_eq!(
+        definitions(
+            "let ping(n) = { let p = pong; n + 1 };
+             let pong(n) = ping(n) * 2;
+             let even(n) = odd(n);
+             let odd(n) = even(n);
+             let a = even(1);
+             let b = odd(2.5);",
+            &["ping", "pong", "even", "odd"]
+        ),
+        vec![
+            "(a) -> a where a: Num",
+            "(a) -> a where a: Num",
+            "(a) -> b",
+            "(a) -> b",
+        ]
+    );
+    assert_eq!(
+        errors("let f() = g(1) + g(2.5); let g(x) = x;"),
+        vec![("expected Int, found Float".into(), "2.5")]
+    );
+}
@@ -0,0 +1,503 @@
+//! A textual assembly syntax for functions.
+//!
+//! ```text
+//! fn @main "main" args=0 upvars=0 locals=0
+//!     span 0..5
+//!     push @double        ; the function labelled @double below
+//!     build
+//!     push 21
+//!     call 1
+//!
+//! fn @double "double" args=1
+//!     get 1
+//!     get 1
+//!     add
+//!     resume
+//! ```
+//!
+//! Each `fn` line starts a function and gives it a label; the name string
+//! and the counts are optional (unnamed, and 0 respectively).  The first
+//! function is the one `assemble` returns, and the others are reached
+//! through `push @label`.  Constants are written inline: integers, floats
+//! (`1.5`), symbols (`'x`, or `'"with spaces"`) and function labels, and are
+//! collected into the constant pool in order of first use.  `native
+//! 'module.name` calls the native `name` of `module`, and `check integer`
+//! (or `float`, `map`, and so on) checks the kind of the value on top of the
+//! stack.  `span a..b` attributes the instructions after it to bytes `a..b`
+//! of the source.  `;` starts a comment.
+//!
+//! `disassemble` prints a function in this syntax.  Assembling the output
+//! gives back an equal function as long as its constant pool is in order
+//! of first use, which is how the compiler and the assembler build pools.
+
+use debug_info::{LineTable, Span};
+use value::{new_func, Code, Function, FunctionPtr, Symbol, Value, ValueKind};
+use vm::Instruction;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult, Write};
+use std::mem;
+use std::rc::Rc;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct AsmError {
+    /// The 1-based line the error was found on.
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error<T, S: Into<String>>(line: usize, message: S) -> Result<T, AsmError> {
+    Err(AsmError {
+        line,
+        message: message.into(),
+    })
+}
+
+const MNEMONICS: &[(&str, Instruction)] = &[
+    ("add", Instruction::Add),
+    ("sub", Instruction::Sub),
+    ("mul", Instruction::Mul),
+    ("div", Instruction::Div),
+    ("swap", Instruction::Swap),
+    ("pop", Instruction::Pop),
+    ("dup", Instruction::Dup),
+    ("print", Instruction::Print),
+    ("debug", Instruction::Debug),
+    ("build", Instruction::BuildFunction),
+    ("terminate", Instruction::Terminate),
+    ("current_continuation", Instruction::CurrentContinuation),
+    ("reset", Instruction::Reset),
+    ("shift", Instruction::Shift),
+    ("resume", Instruction::Resume),
+    ("module_add", Instruction::ModuleAdd),
+    ("module_get", Instruction::ModuleGet),
+    ("map_empty", Instruction::MapEmpty),
+    ("map_insert", Instruction::MapInsert),
+    ("map_get", Instruction::MapGet),
+];
+
+/// A constant as written in the source, before labels are resolved.
+enum Operand {
+    Value(Value),
+    Label(String),
+}
+
+struct Pending {
+    name: Option<String>,
+    args_count: u32,
+    upvars_count: u32,
+    locals_count: u32,
+    instructions: Vec<Instruction>,
+    constants: Vec<(Operand, usize)>,
+    line_table: LineTable,
+}
+
+/// Parses assembly into the unbuilt function defined first.
+pub fn assemble(source: &str) -> Result<FunctionPtr, AsmError> {
+    let mut functions: Vec<(String, Pending)> = vec![];
+
+    for (index, raw) in source.lines().enumerate() {
+        let line = index + 1;
+        let words = split_words(strip_comment(raw), line)?;
+        let (first, rest) = match words.split_first() {
+            Some((first, rest)) => (first.as_str(), rest),
+            None => continue,
+        };
+
+        if first == "fn" {
+            let (label, pending) = parse_header(rest, line)?;
+            if functions.iter().any(|&(ref l, _)| *l == label) {
+                return error(line, format!("label @{} is defined twice", label));
+            }
+            functions.push((label, pending));
+            continue;
+        }
+
+        let function = match functions.last_mut() {
+            Some(&mut (_, ref mut pending)) => pending,
+            None => return error(line, "expected `fn` before the first instruction"),
+        };
+        parse_instruction(first, rest, function, line)?;
+    }
+
+    if functions.is_empty() {
+        return error(1, "no functions defined");
+    }
+
+    let mut built: HashMap<String, FunctionPtr> = HashMap::new();
+    let mut in_progress = vec![];
+    let entry = functions[0].0.clone();
+    resolve(&entry, 0, &mut functions, &mut built, &mut in_progress)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits on whitespace, keeping quoted strings (and `'"..."` symbols)
+/// together with their escapes resolved.
+fn split_words(line: &str, line_no: usize) -> Result<Vec<String>, AsmError> {
+    let mut words = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                break;
+            }
+            chars.next();
+            if c != '"' {
+                word.push(c);
+                continue;
+            }
+            word.push('"');
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(escaped) => word.push(escaped),
+                        None => return error(line_no, "unterminated string"),
+                    },
+                    Some(c) => word.push(c),
+                    None => return error(line_no, "unterminated string"),
+                }
+            }
+            word.push('"');
+        }
+        words.push(word);
+    }
+    Ok(words)
+}
+
+fn parse_header(words: &[String], line: usize) -> Result<(String, Pending), AsmError> {
+    let label = match words.first() {
+        Some(word) if word.starts_with('@') && word.len() > 1 => word[1..].to_string(),
+        _ => return error(line, "expected a label like @main after `fn`"),
+    };
+    let mut pending = Pending {
+        name: None,
+        args_count: 0,
+        upvars_count: 0,
+        locals_count: 0,
+        instructions: vec![],
+        constants: vec![],
+        line_table: LineTable::new(),
+    };
+
+    for word in &words[1..] {
+        if word.starts_with('"') {
+            pending.name = Some(word[1..word.len() - 1].to_string());
+            continue;
+        }
+        let (key, value) = match word.find('=') {
+            Some(i) => (&word[..i], &word[i + 1..]),
+            None => return error(line, format!("unexpected `{}` in function header", word)),
+        };
+        let value = parse_u32(value, line)?;
+        match key {
+            "args" => pending.args_count = value,
+            "upvars" => pending.upvars_count = value,
+            "locals" => pending.locals_count = value,
+            _ => return error(line, format!("unknown function property `{}`", key)),
+        }
+    }
+    Ok((label, pending))
+}
+
+fn parse_u32(word: &str, line: usize) -> Result<u32, AsmError> {
+    word.parse()
+        .or_else(|_| error(line, format!("expected a number, found `{}`", word)))
+}
+
+fn parse_instruction(
+    mnemonic: &str,
+    operands: &[String],
+    function: &mut Pending,
+    line: usize,
+) -> Result<(), AsmError> {
+    let operand = match operands {
+        [] => None,
+        [ref operand] => Some(operand.as_str()),
+        _ => return error(line, format!("too many operands for `{}`", mnemonic)),
+    };
+
+    let instruction = match (mnemonic, operand) {
+        ("span", Some(range)) => {
+            let span = parse_span(range, line)?;
+            function.line_table.push(function.instructions.len(), span);
+            return Ok(());
+        }
+        ("push", Some(constant)) => {
+            Instruction::PushConst(add_constant(function, parse_constant(constant, line)?, line))
+        }
+        ("native", Some(name)) => match parse_constant(name, line)? {
+            Operand::Value(symbol @ Value::Symbol(_)) => {
+                Instruction::CallNative(add_constant(function, Operand::Value(symbol), line))
+            }
+            _ => return error(line, "`native` takes a symbol"),
+        },
+        ("get", Some(n)) => Instruction::GetFromStackPosition(parse_u32(n, line)?),
+        ("set", Some(n)) => Instruction::SetToStackPosition(parse_u32(n, line)?),
+        ("call", Some(n)) => Instruction::Call(parse_u32(n, line)?),
+        ("check", Some(kind)) => match ValueKind::ALL.iter().find(|k| k.name() == kind) {
+            Some(&kind) => Instruction::CheckType(kind),
+            None => return error(line, format!("unknown value kind `{}`", kind)),
+        },
+        (mnemonic, operand) => match MNEMONICS.iter().find(|&&(m, _)| m == mnemonic) {
+            Some(&(_, instruction)) if operand.is_none() => instruction,
+            Some(_) => return error(line, format!("`{}` does not take an operand", mnemonic)),
+            None if ["span", "push", "native", "get", "set", "call", "check"].contains(&mnemonic) => {
+                return error(line, format!("`{}` needs an operand", mnemonic))
+            }
+            None => return error(line, format!("unknown instruction `{}`", mnemonic)),
+        },
+    };
+    function.instructions.push(instruction);
+    Ok(())
+}
+
+fn parse_span(range: &str, line: usize) -> Result<Span, AsmError> {
+    match range.find("..") {
+        Some(i) => Ok(Span {
+            start: parse_u32(&range[..i], line)? as usize,
+            end: parse_u32(&range[i + 2..], line)? as usize,
+        }),
+        None => error(line, format!("expected a span like 3..7, found `{}`", range)),
+    }
+}
+
+fn parse_constant(word: &str, line: usize) -> Result<Operand, AsmError> {
+    if word.starts_with('@') {
+        return Ok(Operand::Label(word[1..].into()));
+    }
+    if word.starts_with('\'') {
+        let name = &word[1..];
+        let name = if name.starts_with('"') {
+            &name[1..name.len() - 1]
+        } else {
+            name
+        };
+        return Ok(Operand::Value(Value::Symbol(Symbol(name.into()))));
+    }
+    if let Ok(i) = word.parse::<i64>() {
+        return Ok(Operand::Value(Value::Integer(i)));
+    }
+    if let Ok(f) = word.parse::<f64>() {
+        return Ok(Operand::Value(Value::Float(f)));
+    }
+    error(line, format!("`{}` is not a constant", word))
+}
+
+fn add_constant(function: &mut Pending, operand: Operand, line: usize) -> u32 {
+    let existing = function.constants.iter().position(|&(ref c, _)| match (c, &operand) {
+        (&Operand::Label(ref a), &Operand::Label(ref b)) => a == b,
+        // Compare floats bitwise so that 0.0 and -0.0 stay apart.
+        (&Operand::Value(Value::Float(a)), &Operand::Value(Value::Float(b))) => {
+            a.to_bits() == b.to_bits()
+        }
+        (&Operand::Value(ref a), &Operand::Value(ref b)) => a == b,
+        _ => false,
+    });
+    let index = existing.unwrap_or_else(|| {
+        function.constants.push((operand, line));
+        function.constants.len() - 1
+    });
+    index as u32
+}
+
+fn resolve(
+    label: &str,
+    line: usize,
+    functions: &mut Vec<(String, Pending)>,
+    built: &mut HashMap<String, FunctionPtr>,
+    in_progress: &mut Vec<String>,
+) -> Result<FunctionPtr, AsmError> {
+    if let Some(function) = built.get(label) {
+        return Ok(function.clone());
+    }
+    if in_progress.iter().any(|l| l == label) {
+        return error(line, format!("@{} refers to itself through its constants", label));
+    }
+    let position = match functions.iter().position(|&(ref l, _)| l == label) {
+        Some(position) => position,
+        None => return error(line, format!("undefined label @{}", label)),
+    };
+
+    in_progress.push(label.into());
+    let constants = functions[position]
+        .1
+        .constants
+        .drain(..)
+        .collect::<Vec<_>>();
+    let mut values = vec![];
+    for (constant, line) in constants {
+        values.push(match constant {
+            Operand::Value(value) => value,
+            Operand::Label(ref nested) => {
+                Value::Function(resolve(nested, line, functions, built, in_progress)?)
+            }
+        });
+    }
+    in_progress.pop();
+
+    let pending = &mut functions[position].1;
+    let function = new_func(Function::new(Code {
+        name: pending.name.take(),
+        instructions: pending.instructions.drain(..).collect(),
+        constants: values,
+        args_count: pending.args_count,
+        upvars_count: pending.upvars_count,
+        locals_count: pending.locals_count,
+        line_table: mem::replace(&mut pending.line_table, LineTable::new()),
+    }));
+    built.insert(label.into(), function.clone());
+    Ok(function)
+}
+
+/// Prints `function` and every function reachable from its constants.
+pub fn disassemble(function: &FunctionPtr) -> String {
+    let mut labels: HashMap<*const Code, String> = HashMap::new();
+    let mut order = vec![];
+    assign_labels(function, &mut labels, &mut order);
+
+    let mut out = String::new();
+    for (i, code) in order.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        write_function(code, &labels, &mut out);
+    }
+    out
+}
+
+pub(crate) fn assign_labels(
+    function: &FunctionPtr,
+    labels: &mut HashMap<*const Code, String>,
+    order: &mut Vec<Rc<Code>>,
+) {
+    let key = &*function.code as *const Code;
+    if labels.contains_key(&key) {
+        return;
+    }
+    let base = function
+        .name
+        .as_ref()
+        .map(|name| {
+            name.chars()
+                .map(|c| if c.is_whitespace() || c == '"' || c == ';' { '_' } else { c })
+                .collect::<String>()
+        })
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "anon".into());
+    let mut label = base.clone();
+    let mut suffix = 1;
+    while labels.values().any(|l| *l == label) {
+        label = format!("{}.{}", base, suffix);
+        suffix += 1;
+    }
+    labels.insert(key, label);
+    order.push(function.code.clone());
+
+    for constant in &function.constants {
+        if let &Value::Function(ref nested) = constant {
+            assign_labels(nested, labels, order);
+        }
+    }
+}
+
+fn write_function(code: &Code, labels: &HashMap<*const Code, String>, out: &mut String) {
+    write!(out, "fn @{}", labels[&(code as *const Code)]).unwrap();
+    if let Some(ref name) = code.name {
+        write!(out, " {}", quote(name)).unwrap();
+    }
+    writeln!(
+        out,
+        " args={} upvars={} locals={}",
+        code.args_count, code.upvars_count, code.locals_count
+    ).unwrap();
+
+    let spans = code.line_table.entries();
+    let mut next_span = 0;
+    for (i, &instruction) in code.instructions.iter().enumerate() {
+        while next_span < spans.len() && spans[next_span].0 <= i {
+            let span = spans[next_span].1;
+            writeln!(out, "    span {}..{}", span.start, span.end).unwrap();
+            next_span += 1;
+        }
+        writeln!(out, "    {}", show_instruction(instruction, code, labels)).unwrap();
+    }
+}
+
+pub(crate) fn show_instruction(
+    instruction: Instruction,
+    code: &Code,
+    labels: &HashMap<*const Code, String>,
+) -> String {
+    let constant = |index: u32| match code.constants.get(index as usize) {
+        Some(value) => show_constant(value, labels),
+        None => format!("!missing-constant-{}", index),
+    };
+    match instruction {
+        Instruction::PushConst(index) => format!("push {}", constant(index)),
+        Instruction::CallNative(index) => format!("native {}", constant(index)),
+        Instruction::GetFromStackPosition(n) => format!("get {}", n),
+        Instruction::SetToStackPosition(n) => format!("set {}", n),
+        Instruction::Call(n) => format!("call {}", n),
+        Instruction::CheckType(kind) => format!("check {}", kind.name()),
+        other => MNEMONICS
+            .iter()
+            .find(|&&(_, i)| i == other)
+            .map(|&(m, _)| m.to_string())
+            .unwrap(),
+    }
+}
+
+fn show_constant(value: &Value, labels: &HashMap<*const Code, String>) -> String {
+    match value {
+        &Value::Integer(i) => format!("{}", i),
+        &Value::Float(f) => format!("{:?}", f),
+        &Value::Symbol(Symbol(ref s)) => {
+            let plain = !s.is_empty() && !s.chars().any(|c| c.is_whitespace() || c == '"' || c == ';');
+            if plain {
+                format!("'{}", s)
+            } else {
+                format!("'{}", quote(s))
+            }
+        }
+        &Value::Function(ref f) => format!("@{}", labels[&(&*f.code as *const Code)]),
+        // Maps and lists never appear in compiled code and have no syntax.
+        other => format!("!{:?}", other).replace(char::is_whitespace, ""),
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
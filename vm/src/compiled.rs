@@ -0,0 +1,476 @@
+//! The `.aresc` compiled-module file format.
+//!
+//! All integers are little-endian.  A file is laid out as:
+//!
+//! ```text
+//! magic        b"\0ARESC\r\n"
+//! version      u32                 FORMAT_VERSION when written
+//! flags        u8                  bit 0: functions carry line tables
+//! name         string              the module id
+//! exports      u32 count, strings  names the module defines
+//! functions    u32 count, records  see below
+//! checksum     u32                 FNV-1a of every byte before it
+//! ```
+//!
+//! A string is a u32 byte length followed by UTF-8.  A function record is
+//! an optional name (u8 0/1 then a string), the argument, upvar and local
+//! counts as u32s, the instructions (u32 count, then a u8 opcode with a u32
+//! operand where the instruction has one), the constant pool (u32 count of
+//! tagged values) and, when the debug flag is set, the line table (u32
+//! count of u32 instruction, u64 start, u64 end).
+//!
+//! Function constants refer to earlier records by index, so records are
+//! written callees first and the last one is the module body.
+
+use debug_info::{LineTable, Span};
+use value::{new_func, Code, Function, FunctionPtr, Symbol, Value, ValueKind};
+use vm::Instruction;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 8] = b"\0ARESC\r\n";
+pub const FORMAT_VERSION: u32 = 1;
+
+const FLAG_DEBUG_INFO: u8 = 1;
+
+const TAG_INTEGER: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_SYMBOL: u8 = 2;
+const TAG_FUNCTION: u8 = 3;
+
+/// A module compiled ahead of time, ready to be written to or read from an
+/// `.aresc` file.
+#[derive(Clone, PartialEq, Debug)]
+pub struct CompiledModule {
+    pub name: String,
+    /// The definitions the module adds to `Vm::modules` when it is loaded.
+    pub exports: Vec<String>,
+    /// The unbuilt function that runs the module body.
+    pub program: FunctionPtr,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum FormatError {
+    NotCompiledModule,
+    UnsupportedVersion { found: u32, supported: u32 },
+    ChecksumMismatch,
+    Truncated,
+    TrailingBytes,
+    InvalidOpcode(u8),
+    InvalidValueTag(u8),
+    InvalidUtf8,
+    BadFunctionIndex(u32),
+    BadLineTable,
+    /// Only the values the compiler puts into constant pools can be
+    /// written; this is raised for anything else.
+    Unencodable(ValueKind),
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            &FormatError::NotCompiledModule => write!(f, "not a compiled ares module"),
+            &FormatError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "compiled module uses format version {}, but this ares only reads up to version {}; \
+                 rebuild it or upgrade ares",
+                found, supported
+            ),
+            &FormatError::ChecksumMismatch => {
+                write!(f, "compiled module is corrupted (checksum mismatch)")
+            }
+            &FormatError::Truncated => write!(f, "compiled module is corrupted (unexpected end of file)"),
+            &FormatError::TrailingBytes => {
+                write!(f, "compiled module is corrupted (unexpected data after the functions)")
+            }
+            &FormatError::InvalidOpcode(op) => {
+                write!(f, "compiled module is corrupted (invalid opcode {})", op)
+            }
+            &FormatError::InvalidValueTag(tag) => {
+                write!(f, "compiled module is corrupted (invalid constant tag {})", tag)
+            }
+            &FormatError::InvalidUtf8 => {
+                write!(f, "compiled module is corrupted (string is not valid UTF-8)")
+            }
+            &FormatError::BadFunctionIndex(index) => write!(
+                f,
+                "compiled module is corrupted (reference to unknown function #{})",
+                index
+            ),
+            &FormatError::BadLineTable => {
+                write!(f, "compiled module is corrupted (line table is out of order)")
+            }
+            &FormatError::Unencodable(kind) => {
+                write!(f, "a {:?} constant cannot be written to a compiled module", kind)
+            }
+        }
+    }
+}
+
+impl CompiledModule {
+    /// Encodes the module, including line tables when `debug_info` is set.
+    pub fn to_bytes(&self, debug_info: bool) -> Result<Vec<u8>, FormatError> {
+        let mut functions = vec![];
+        let mut indices = HashMap::new();
+        collect(&self.program, &mut functions, &mut indices)?;
+
+        let mut w = Writer { out: vec![] };
+        w.out.extend_from_slice(MAGIC);
+        w.u32(FORMAT_VERSION);
+        w.u8(if debug_info { FLAG_DEBUG_INFO } else { 0 });
+        w.string(&self.name);
+        w.u32(self.exports.len() as u32);
+        for export in &self.exports {
+            w.string(export);
+        }
+        w.u32(functions.len() as u32);
+        for code in &functions {
+            w.function(code, &indices, debug_info)?;
+        }
+
+        let checksum = fnv1a(&w.out);
+        w.u32(checksum);
+        Ok(w.out)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<CompiledModule, FormatError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(FormatError::NotCompiledModule);
+        }
+        let mut r = Reader {
+            bytes,
+            pos: MAGIC.len(),
+        };
+        // The version comes before the checksum so that files from a newer
+        // ares are reported as such even if their layout has changed.
+        let version = r.u32()?;
+        if version > FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion {
+                found: version,
+                supported: FORMAT_VERSION,
+            });
+        }
+        if bytes.len() < r.pos + 4 {
+            return Err(FormatError::Truncated);
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        if fnv1a(body) != Reader::new(checksum).u32()? {
+            return Err(FormatError::ChecksumMismatch);
+        }
+        r.bytes = body;
+
+        let debug_info = r.u8()? & FLAG_DEBUG_INFO != 0;
+        let name = r.string()?;
+        let exports = (0..r.u32()?)
+            .map(|_| r.string())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let count = r.u32()?;
+        let mut functions: Vec<Rc<Code>> = vec![];
+        for _ in 0..count {
+            let code = r.function(&functions, debug_info)?;
+            functions.push(Rc::new(code));
+        }
+        if r.pos != body.len() {
+            return Err(FormatError::TrailingBytes);
+        }
+        let program = match functions.pop() {
+            Some(code) => new_func(Function::new(code)),
+            None => return Err(FormatError::Truncated),
+        };
+
+        Ok(CompiledModule {
+            name,
+            exports,
+            program,
+        })
+    }
+}
+
+/// Lists every function reachable through constant pools, callees before
+/// the functions that refer to them.
+fn collect(
+    function: &FunctionPtr,
+    out: &mut Vec<Rc<Code>>,
+    indices: &mut HashMap<*const Code, u32>,
+) -> Result<(), FormatError> {
+    if function.is_built {
+        return Err(FormatError::Unencodable(ValueKind::Function));
+    }
+    if indices.contains_key(&(&*function.code as *const Code)) {
+        return Ok(());
+    }
+    for constant in &function.constants {
+        if let &Value::Function(ref nested) = constant {
+            collect(nested, out, indices)?;
+        }
+    }
+    indices.insert(&*function.code as *const _, out.len() as u32);
+    out.push(function.code.clone());
+    Ok(())
+}
+
+fn fnv1a(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for &byte in bytes {
+        hash ^= u32::from(byte);
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+fn opcode(instruction: Instruction) -> (u8, Option<u32>) {
+    use vm::Instruction::*;
+    match instruction {
+        Add => (0, None),
+        Sub => (1, None),
+        Mul => (2, None),
+        Div => (3, None),
+        PushConst(i) => (4, Some(i)),
+        GetFromStackPosition(p) => (5, Some(p)),
+        SetToStackPosition(p) => (6, Some(p)),
+        Swap => (7, None),
+        Pop => (8, None),
+        Dup => (9, None),
+        Print => (10, None),
+        Debug => (11, None),
+        BuildFunction => (12, None),
+        Call(n) => (13, Some(n)),
+        Terminate => (14, None),
+        CurrentContinuation => (15, None),
+        Reset => (16, None),
+        Shift => (17, None),
+        Resume => (18, None),
+        ModuleAdd => (19, None),
+        ModuleGet => (20, None),
+        CallNative(i) => (21, Some(i)),
+        MapEmpty => (22, None),
+        MapInsert => (23, None),
+        MapGet => (24, None),
+        CheckType(kind) => (25, Some(ValueKind::ALL.iter().position(|&k| k == kind).unwrap() as u32)),
+    }
+}
+
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.out.push(v);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.out.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn string(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn function(
+        &mut self,
+        code: &Code,
+        indices: &HashMap<*const Code, u32>,
+        debug_info: bool,
+    ) -> Result<(), FormatError> {
+        match code.name {
+            Some(ref name) => {
+                self.u8(1);
+                self.string(name);
+            }
+            None => self.u8(0),
+        }
+        self.u32(code.args_count);
+        self.u32(code.upvars_count);
+        self.u32(code.locals_count);
+
+        self.u32(code.instructions.len() as u32);
+        for &instruction in &code.instructions {
+            let (op, operand) = opcode(instruction);
+            self.u8(op);
+            if let Some(operand) = operand {
+                self.u32(operand);
+            }
+        }
+
+        self.u32(code.constants.len() as u32);
+        for constant in &code.constants {
+            match constant {
+                &Value::Integer(i) => {
+                    self.u8(TAG_INTEGER);
+                    self.u64(i as u64);
+                }
+                &Value::Float(f) => {
+                    self.u8(TAG_FLOAT);
+                    self.u64(f.to_bits());
+                }
+                &Value::Symbol(Symbol(ref s)) => {
+                    self.u8(TAG_SYMBOL);
+                    self.string(s);
+                }
+                &Value::Function(ref f) => {
+                    self.u8(TAG_FUNCTION);
+                    self.u32(indices[&(&*f.code as *const Code)]);
+                }
+                other => return Err(FormatError::Unencodable(other.kind())),
+            }
+        }
+
+        if debug_info {
+            let entries = code.line_table.entries();
+            self.u32(entries.len() as u32);
+            for &(instruction, span) in entries {
+                self.u32(instruction as u32);
+                self.u64(span.start as u64);
+                self.u64(span.end as u64);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FormatError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(FormatError::Truncated);
+        }
+        let taken = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, FormatError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn string(&mut self) -> Result<String, FormatError> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| FormatError::InvalidUtf8)
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, FormatError> {
+        use vm::Instruction::*;
+        let op = self.u8()?;
+        Ok(match op {
+            0 => Add,
+            1 => Sub,
+            2 => Mul,
+            3 => Div,
+            4 => PushConst(self.u32()?),
+            5 => GetFromStackPosition(self.u32()?),
+            6 => SetToStackPosition(self.u32()?),
+            7 => Swap,
+            8 => Pop,
+            9 => Dup,
+            10 => Print,
+            11 => Debug,
+            12 => BuildFunction,
+            13 => Call(self.u32()?),
+            14 => Terminate,
+            15 => CurrentContinuation,
+            16 => Reset,
+            17 => Shift,
+            18 => Resume,
+            19 => ModuleAdd,
+            20 => ModuleGet,
+            21 => CallNative(self.u32()?),
+            22 => MapEmpty,
+            23 => MapInsert,
+            24 => MapGet,
+            25 => {
+                let kind = self.u32()?;
+                match ValueKind::ALL.get(kind as usize) {
+                    Some(&kind) => CheckType(kind),
+                    None => return Err(FormatError::InvalidOpcode(op)),
+                }
+            }
+            _ => return Err(FormatError::InvalidOpcode(op)),
+        })
+    }
+
+    fn function(&mut self, earlier: &[Rc<Code>], debug_info: bool) -> Result<Code, FormatError> {
+        let name = match self.u8()? {
+            0 => None,
+            _ => Some(self.string()?),
+        };
+        let args_count = self.u32()?;
+        let upvars_count = self.u32()?;
+        let locals_count = self.u32()?;
+
+        let instructions = (0..self.u32()?)
+            .map(|_| self.instruction())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut constants = vec![];
+        for _ in 0..self.u32()? {
+            let tag = self.u8()?;
+            constants.push(match tag {
+                TAG_INTEGER => Value::Integer(self.u64()? as i64),
+                TAG_FLOAT => Value::Float(f64::from_bits(self.u64()?)),
+                TAG_SYMBOL => Value::Symbol(Symbol(self.string()?)),
+                TAG_FUNCTION => {
+                    let index = self.u32()?;
+                    match earlier.get(index as usize) {
+                        Some(code) => Value::Function(new_func(Function::new(code.clone()))),
+                        None => return Err(FormatError::BadFunctionIndex(index)),
+                    }
+                }
+                _ => return Err(FormatError::InvalidValueTag(tag)),
+            });
+        }
+
+        let mut line_table = LineTable::new();
+        if debug_info {
+            let mut previous = 0;
+            for _ in 0..self.u32()? {
+                let instruction = self.u32()? as usize;
+                let start = self.u64()? as usize;
+                let end = self.u64()? as usize;
+                if instruction < previous {
+                    return Err(FormatError::BadLineTable);
+                }
+                previous = instruction;
+                line_table.push(instruction, Span { start, end });
+            }
+        }
+
+        Ok(Code {
+            name,
+            instructions,
+            constants,
+            args_count,
+            upvars_count,
+            locals_count,
+            line_table,
+        })
+    }
+}
@@ -0,0 +1,45 @@
+use value::{FunctionPtr, Value};
+use vm::{VmError, VmResult};
+
+/// A continuation captured by `Shift` that has been taken out of the VM.
+///
+/// Continuations are ordinary built functions of one argument whose
+/// continuation chain describes the rest of the computation up to the
+/// enclosing `Reset`.  Wrapping one in a `Continuation` lets the host
+/// serialize it and later resume it (possibly in a different `Vm`) with
+/// `Vm::resume_continuation`.  Sharing between the functions in the chain
+/// is preserved across a serialization round-trip.
+///
+/// Module definitions are not part of a continuation; the `Vm` that resumes
+/// it must have the same modules loaded as the one that captured it.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Continuation {
+    function: FunctionPtr,
+}
+
+impl Continuation {
+    pub fn from_value(value: Value) -> VmResult<Continuation> {
+        Continuation::from_function(value.into_function()?)
+    }
+
+    pub fn from_function(function: FunctionPtr) -> VmResult<Continuation> {
+        if !function.is_built {
+            return Err(VmError::CallOnUnbuiltFunction);
+        }
+        if function.args_count != 1 {
+            return Err(VmError::ArityMismatch {
+                actual: 1,
+                expected: function.args_count,
+            });
+        }
+        Ok(Continuation { function })
+    }
+
+    pub fn function(&self) -> &FunctionPtr {
+        &self.function
+    }
+
+    pub fn into_value(self) -> Value {
+        Value::Function(self.function)
+    }
+}
@@ -0,0 +1,89 @@
+use debug_info::{SourceLines, Span};
+use value::{FunctionPtr, Value};
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+/// One instruction of one function.  Continuation segments share the name
+/// of the function they were split from, so the span tells them apart.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct CoveredInstruction {
+    pub function: Option<String>,
+    pub instruction: usize,
+    pub span: Option<Span>,
+}
+
+/// How often each instruction ran, collected by `Vm::enable_coverage`.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Coverage {
+    hits: HashMap<CoveredInstruction, u64>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub(crate) fn record(&mut self, function: &FunctionPtr, instruction: usize) {
+        let key = CoveredInstruction {
+            function: function.name.clone(),
+            instruction,
+            span: function.line_table.lookup(instruction),
+        };
+        *self.hits.entry(key).or_insert(0) += 1;
+    }
+
+    pub fn hits(&self) -> &HashMap<CoveredInstruction, u64> {
+        &self.hits
+    }
+
+    /// Hit counts per 1-based source line of `source`.  A line's count is
+    /// that of its most executed instruction, so it counts how many times
+    /// the line ran rather than how many instructions it spans.  Every line
+    /// that `program` (or a function nested in it) has code on is present,
+    /// so lines that never ran show up with a count of zero.
+    pub fn line_hits(&self, source: &str, program: &FunctionPtr) -> BTreeMap<usize, u64> {
+        let lines = SourceLines::new(source);
+        let mut out = BTreeMap::new();
+
+        let mut functions = vec![];
+        collect_functions(program, &mut functions);
+        for function in functions {
+            for &(_, span) in function.line_table.entries() {
+                out.entry(lines.line_of(span.start)).or_insert(0);
+            }
+        }
+
+        for (instruction, &count) in &self.hits {
+            if let Some(span) = instruction.span {
+                let hits = out.entry(lines.line_of(span.start)).or_insert(0);
+                *hits = max(*hits, count);
+            }
+        }
+        out
+    }
+
+    /// An LCOV tracefile record for one source file.
+    pub fn lcov(&self, test_name: &str, path: &str, source: &str, program: &FunctionPtr) -> String {
+        let line_hits = self.line_hits(source, program);
+        let mut out = String::new();
+        writeln!(out, "TN:{}", test_name).unwrap();
+        writeln!(out, "SF:{}", path).unwrap();
+        for (line, count) in &line_hits {
+            writeln!(out, "DA:{},{}", line, count).unwrap();
+        }
+        writeln!(out, "LF:{}", line_hits.len()).unwrap();
+        writeln!(out, "LH:{}", line_hits.values().filter(|&&c| c > 0).count()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+}
+
+fn collect_functions(function: &FunctionPtr, out: &mut Vec<FunctionPtr>) {
+    out.push(function.clone());
+    for constant in &function.constants {
+        if let &Value::Function(ref nested) = constant {
+            collect_functions(nested, out);
+        }
+    }
+}
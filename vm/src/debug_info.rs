@@ -0,0 +1,84 @@
+/// A range of bytes in the source a function was compiled from.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Hash, Debug, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Turns byte offsets into 1-based line and column numbers.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourceLines {
+    starts: Vec<usize>,
+}
+
+impl SourceLines {
+    pub fn new(source: &str) -> SourceLines {
+        let mut starts = vec![0];
+        starts.extend(source.match_indices('\n').map(|(i, _)| i + 1));
+        SourceLines { starts }
+    }
+
+    pub fn line_of(&self, offset: usize) -> usize {
+        match self.starts.binary_search(&offset) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        }
+    }
+
+    /// The line and column of byte `offset`.  Columns count bytes from the
+    /// start of the line.
+    pub fn position(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_of(offset);
+        (line, offset - self.starts[line - 1] + 1)
+    }
+}
+
+/// Maps instruction indices back to source spans.
+///
+/// Entries are sorted by instruction index and each one covers every
+/// instruction up to the next entry, so runs of instructions emitted for the
+/// same node take a single entry.
+#[derive(Clone, PartialEq, PartialOrd, Debug, Default, Serialize, Deserialize)]
+pub struct LineTable {
+    entries: Vec<(usize, Span)>,
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        LineTable { entries: vec![] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Records that the instructions starting at `instruction` came from
+    /// `span`.  Instructions must be recorded in increasing order.
+    pub fn push(&mut self, instruction: usize, span: Span) {
+        if let Some(&mut (last_instruction, ref mut last_span)) = self.entries.last_mut() {
+            assert!(last_instruction <= instruction);
+            if *last_span == span {
+                return;
+            }
+            if last_instruction == instruction {
+                *last_span = span;
+                return;
+            }
+        }
+        self.entries.push((instruction, span));
+    }
+
+    pub fn lookup(&self, instruction: usize) -> Option<Span> {
+        match self.entries
+            .binary_search_by_key(&instruction, |&(i, _)| i)
+        {
+            Ok(index) => Some(self.entries[index].1),
+            Err(0) => None,
+            Err(index) => Some(self.entries[index - 1].1),
+        }
+    }
+
+    pub fn entries(&self) -> &[(usize, Span)] {
+        &self.entries
+    }
+}
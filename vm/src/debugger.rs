@@ -0,0 +1,226 @@
+use debug_info::{SourceLines, Span};
+use resultvec::ResultVec;
+use trace::{RunResult, StackTrace};
+use value::{FunctionPtr, Value};
+use vm::{Completion, FuncExecData, Instruction, Vm};
+
+/// A run of the VM that is paused between two instructions.
+///
+/// Created by `Vm::begin_function` and advanced by `Vm::step_execution`, or
+/// more conveniently through a `Debugger`.
+#[derive(Debug)]
+pub struct Execution {
+    pub(crate) exec: FuncExecData,
+    pub(crate) stack: ResultVec<Value>,
+}
+
+impl Execution {
+    pub fn function(&self) -> &FunctionPtr {
+        &self.exec.function
+    }
+
+    /// The index of the instruction that will run next.
+    pub fn ip(&self) -> usize {
+        self.exec.ip
+    }
+
+    pub fn next_instruction(&self) -> Option<Instruction> {
+        self.exec.function.instructions.get(self.exec.ip).cloned()
+    }
+
+    /// The source span of the instruction that will run next, if the
+    /// function was compiled with debug info.
+    pub fn span(&self) -> Option<Span> {
+        self.exec.function.line_table.lookup(self.exec.ip)
+    }
+
+    /// The whole stack of the current function, including its frame.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack.inner
+    }
+
+    pub fn args(&self) -> &[Value] {
+        let start = 1;
+        self.frame_slice(start, self.exec.function.args_count)
+    }
+
+    pub fn upvars(&self) -> &[Value] {
+        let start = 1 + self.exec.function.args_count;
+        self.frame_slice(start, self.exec.function.upvars_count)
+    }
+
+    pub fn locals(&self) -> &[Value] {
+        let function = &self.exec.function;
+        let start = 1 + function.args_count + function.upvars_count;
+        self.frame_slice(start, function.locals_count)
+    }
+
+    /// The current frame followed by every pending continuation.
+    pub fn backtrace(&self) -> StackTrace {
+        StackTrace::at(&self.exec.function, self.exec.ip)
+    }
+
+    fn frame_slice(&self, start: u32, len: u32) -> &[Value] {
+        let stack = &self.stack.inner;
+        let start = (start as usize).min(stack.len());
+        let end = (start + len as usize).min(stack.len());
+        &stack[start..end]
+    }
+
+    fn chain_depth(&self) -> usize {
+        let mut depth = 0;
+        let mut next = self.exec.function.continuation();
+        while let Some((function, _)) = next {
+            depth += 1;
+            next = function.continuation();
+        }
+        depth
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Breakpoint {
+    /// Stops whenever a function with this name is called.
+    Function(String),
+    /// Stops when execution arrives at this (1-based) source line.
+    Line(usize),
+}
+
+/// Why the debugger handed control back.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Stop {
+    /// A step finished without anything else happening.
+    Stepped,
+    /// The breakpoint with this index was hit.
+    Breakpoint(usize),
+    /// The run is over; the execution must not be used any more.
+    Finished(Completion),
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Option<Breakpoint>>,
+    lines: SourceLines,
+}
+
+impl Debugger {
+    /// Creates a debugger for code compiled from `source`, which is needed to
+    /// turn spans into line numbers.
+    pub fn new(source: &str) -> Debugger {
+        Debugger {
+            breakpoints: vec![],
+            lines: SourceLines::new(source),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.push(Some(breakpoint));
+        self.breakpoints.len() - 1
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        match self.breakpoints.get_mut(id) {
+            Some(slot) => slot.take().is_some(),
+            None => false,
+        }
+    }
+
+    pub fn breakpoints(&self) -> Vec<(usize, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(id, bp)| bp.as_ref().map(|bp| (id, bp)))
+            .collect()
+    }
+
+    /// The lines of the source, for showing spans as positions in it.
+    pub fn lines(&self) -> &SourceLines {
+        &self.lines
+    }
+
+    /// The 1-based line containing byte `offset` of the source.
+    pub fn line_of(&self, offset: usize) -> usize {
+        self.lines.line_of(offset)
+    }
+
+    /// The line the next instruction of `execution` was compiled from.
+    pub fn current_line(&self, execution: &Execution) -> Option<usize> {
+        execution.span().map(|span| self.line_of(span.start))
+    }
+
+    /// Runs exactly one instruction.
+    pub fn step_instruction(&mut self, vm: &mut Vm, execution: &mut Execution) -> RunResult<Stop> {
+        match vm.step_execution(execution)? {
+            Some(completion) => Ok(Stop::Finished(completion)),
+            None => Ok(Stop::Stepped),
+        }
+    }
+
+    /// Runs one instruction, or if it is a call, runs until the call has
+    /// returned to the code after it.
+    pub fn step_over(&mut self, vm: &mut Vm, execution: &mut Execution) -> RunResult<Stop> {
+        if !is_transfer(execution.next_instruction()) {
+            return self.step_instruction(vm, execution);
+        }
+
+        // The callee runs with our continuation pushed onto the chain, so the
+        // call has returned once the chain is back to its current length.
+        let depth = execution.chain_depth();
+        self.run_while(vm, execution, |execution| execution.chain_depth() > depth)
+    }
+
+    /// Runs until a breakpoint is hit or the run is over.
+    pub fn resume(&mut self, vm: &mut Vm, execution: &mut Execution) -> RunResult<Stop> {
+        self.run_while(vm, execution, |_| true)
+    }
+
+    fn run_while<F>(&mut self, vm: &mut Vm, execution: &mut Execution, keep_going: F) -> RunResult<Stop>
+    where
+        F: Fn(&Execution) -> bool,
+    {
+        loop {
+            let was_call = is_transfer(execution.next_instruction());
+            let previous_line = self.current_line(execution);
+
+            if let Stop::Finished(completion) = self.step_instruction(vm, execution)? {
+                return Ok(Stop::Finished(completion));
+            }
+            if let Some(id) = self.hit_breakpoint(execution, was_call, previous_line) {
+                return Ok(Stop::Breakpoint(id));
+            }
+            if !keep_going(execution) {
+                return Ok(Stop::Stepped);
+            }
+        }
+    }
+
+    fn hit_breakpoint(
+        &self,
+        execution: &Execution,
+        entered_by_call: bool,
+        previous_line: Option<usize>,
+    ) -> Option<usize> {
+        let line = self.current_line(execution);
+        for (id, breakpoint) in self.breakpoints() {
+            let hit = match breakpoint {
+                &Breakpoint::Function(ref name) => {
+                    entered_by_call && execution.ip() == 0
+                        && execution.function().name.as_ref() == Some(name)
+                }
+                &Breakpoint::Line(l) => line == Some(l) && previous_line != Some(l),
+            };
+            if hit {
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+fn is_transfer(instruction: Option<Instruction>) -> bool {
+    match instruction {
+        Some(Instruction::Call(_)) | Some(Instruction::Reset) | Some(Instruction::Shift) => {
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,126 @@
+//! Graphviz DOT output for compiled functions.
+//!
+//! Every function reachable through constants becomes a node listing its
+//! instructions, labelled like `asm::disassemble` labels them.  An edge
+//! goes from a function to each function in its constant pool.  Constants
+//! that a call in the function receives as its continuation are drawn
+//! dashed, so the segments that the emitter split a function into can be
+//! told apart from the functions that the program defines.
+
+use asm::{assign_labels, show_instruction};
+use graphviz::{EdgeStyle, Graph};
+use std::collections::{HashMap, HashSet};
+use value::{Code, FunctionPtr, Value};
+use vm::Instruction;
+
+pub fn to_dot(function: &FunctionPtr) -> String {
+    let mut labels: HashMap<*const Code, String> = HashMap::new();
+    let mut order = vec![];
+    assign_labels(function, &mut labels, &mut order);
+
+    let mut graph = Graph::new("functions");
+    let mut nodes = HashMap::new();
+    for code in &order {
+        let mut label = format!(
+            "fn @{} args={} upvars={} locals={}",
+            labels[&(&**code as *const Code)],
+            code.args_count,
+            code.upvars_count,
+            code.locals_count
+        );
+        for &instruction in &code.instructions {
+            label.push('\n');
+            label.push_str(&show_instruction(instruction, code, &labels));
+        }
+        nodes.insert(&**code as *const Code, graph.node(&label));
+    }
+
+    for code in &order {
+        let from = nodes[&(&**code as *const Code)];
+        let continuations = continuation_constants(code);
+        for (index, constant) in code.constants.iter().enumerate() {
+            if let &Value::Function(ref nested) = constant {
+                let to = nodes[&(&*nested.code as *const Code)];
+                if continuations.contains(&index) {
+                    graph.styled_edge(from, to, "continuation", EdgeStyle::Dashed);
+                } else {
+                    graph.edge(from, to, "");
+                }
+            }
+        }
+    }
+    graph.finish()
+}
+
+/// The indices of the constants that end up as the continuation of a
+/// `Call` in `code`.
+///
+/// This follows the stack through the instructions whose effect is known
+/// from the instruction alone.  Any other instruction forgets everything
+/// on the stack, so a constant is only reported when it certainly reaches
+/// the continuation slot.
+fn continuation_constants(code: &Code) -> HashSet<usize> {
+    let mut found = HashSet::new();
+    // The constant each stack slot was pushed from, with the top last.
+    let mut stack: Vec<Option<usize>> = vec![];
+    for &instruction in &code.instructions {
+        match instruction {
+            Instruction::PushConst(index) => stack.push(Some(index as usize)),
+            Instruction::GetFromStackPosition(_) => stack.push(None),
+            Instruction::SetToStackPosition(_) | Instruction::Pop | Instruction::Debug => {
+                stack.pop();
+            }
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Div
+            | Instruction::ModuleGet
+            | Instruction::MapGet => {
+                stack.pop();
+                stack.pop();
+                stack.push(None);
+            }
+            Instruction::MapInsert => {
+                stack.pop();
+                stack.pop();
+                stack.pop();
+                stack.push(None);
+            }
+            Instruction::ModuleAdd => {
+                stack.pop();
+                stack.pop();
+                stack.pop();
+            }
+            Instruction::MapEmpty => stack.push(None),
+            Instruction::Print | Instruction::CheckType(_) => {}
+            Instruction::Swap if stack.len() >= 2 => {
+                let len = stack.len();
+                stack.swap(len - 1, len - 2);
+            }
+            Instruction::BuildFunction => {
+                let function = stack.pop().and_then(|constant| constant);
+                let upvars = match function.and_then(|index| code.constants.get(index)) {
+                    Some(&Value::Function(ref f)) => f.upvars_count as usize,
+                    _ => {
+                        stack.clear();
+                        continue;
+                    }
+                };
+                let len = stack.len();
+                stack.truncate(len.saturating_sub(upvars));
+                stack.push(function);
+            }
+            Instruction::Call(arg_count) => {
+                let len = stack.len();
+                if len >= arg_count as usize + 2 {
+                    if let Some(index) = stack[len - arg_count as usize - 2] {
+                        found.insert(index);
+                    }
+                }
+                stack.clear();
+            }
+            _ => stack.clear(),
+        }
+    }
+    found
+}
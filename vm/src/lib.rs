@@ -1,10 +1,23 @@
+extern crate graphviz;
 extern crate rpds;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[cfg(test)]
+extern crate serde_json;
 
 pub mod value;
 pub mod vm;
+pub mod asm;
+pub mod compiled;
+pub mod continuation;
+pub mod coverage;
+pub mod debug_info;
+pub mod debugger;
+pub mod dot;
+pub mod native;
+pub mod profiler;
+pub mod trace;
 #[cfg(test)]
 pub mod vm_tests;
 pub mod resultvec;
@@ -0,0 +1,108 @@
+use debug_info::LineTable;
+use value::{BuiltFunction, Code, Function, FunctionPtr, new_func, Symbol, Value};
+use vm::{Instruction, VmResult};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::rc::Rc;
+
+/// What a host-provided function wants the VM to do after it has been called.
+#[derive(Clone, PartialEq, Debug)]
+pub enum NativeResult {
+    /// Continue the caller immediately with this value.
+    Return(Value),
+    /// Pause the VM.  The run loop hands a `PendingToken` carrying this
+    /// request back to the embedder, who answers it with `Vm::resume`.
+    Suspend(Value),
+}
+
+pub type NativeFn = dyn Fn(Vec<Value>) -> VmResult<NativeResult>;
+
+/// Host functions that can be reached from `Instruction::CallNative`.
+///
+/// Natives are looked up by module and name when they are called, so
+/// functions that refer to them (and continuations captured across them)
+/// stay serializable.  `CallNative` names one with a single symbol of the
+/// form `module.name`.
+#[derive(Clone, Default)]
+pub struct NativeRegistry {
+    functions: HashMap<(Symbol, Symbol), Rc<NativeFn>>,
+}
+
+/// Handed to the embedder when a native function suspends the VM.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PendingToken {
+    pub(crate) id: u64,
+    pub(crate) request: Value,
+}
+
+impl PendingToken {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// The value the native function suspended with, describing what the
+    /// host should do before resuming.
+    pub fn request(&self) -> &Value {
+        &self.request
+    }
+}
+
+impl NativeRegistry {
+    pub fn insert(&mut self, module: Symbol, name: Symbol, f: Rc<NativeFn>) {
+        self.functions.insert((module, name), f);
+    }
+
+    /// The native named by `qualified`, a symbol such as `io.read`.
+    pub fn get(&self, qualified: &Symbol) -> Option<Rc<NativeFn>> {
+        let dot = qualified.0.find('.')?;
+        let module = Symbol(qualified.0[..dot].into());
+        let name = Symbol(qualified.0[dot + 1..].into());
+        self.functions.get(&(module, name)).cloned()
+    }
+}
+
+/// The symbol that `CallNative` uses for the native `name` of `module`.
+pub fn qualified_name(module: &Symbol, name: &Symbol) -> Symbol {
+    Symbol(format!("{}.{}", module.0, name.0))
+}
+
+impl Debug for NativeRegistry {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+impl PartialEq for NativeRegistry {
+    fn eq(&self, other: &NativeRegistry) -> bool {
+        self.functions.len() == other.functions.len()
+            && self.functions.iter().all(|(k, v)| {
+                other
+                    .functions
+                    .get(k)
+                    .map(|o| Rc::ptr_eq(v, o))
+                    .unwrap_or(false)
+            })
+    }
+}
+
+/// Builds the Ares-side function value that forwards its arguments to the
+/// native registered as `name` in `module`.
+pub fn native_function(module: &Symbol, name: &Symbol, args_count: u32) -> FunctionPtr {
+    let qualified = qualified_name(module, name);
+    new_func(Function {
+        code: Rc::new(Code {
+            name: Some(qualified.0.clone()),
+            instructions: vec![Instruction::CallNative(0)],
+            constants: vec![Value::Symbol(qualified)],
+            args_count,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: true,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    })
+}
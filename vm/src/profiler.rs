@@ -0,0 +1,170 @@
+use value::FunctionPtr;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// What one function, or one call chain, cost while profiling.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Cost {
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+impl Cost {
+    fn add(&mut self, time: Duration) {
+        self.instructions += 1;
+        self.time += time;
+    }
+}
+
+/// Which cost the folded-stack output uses as its sample count.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FoldedWeight {
+    Instructions,
+    Microseconds,
+}
+
+/// Instruction counts and wall time collected by `Vm::enable_profiling`.
+///
+/// Costs are attributed both to the function that executed each
+/// instruction and to its call chain, which is read off the continuation
+/// chain with the outermost frame first.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Profile {
+    functions: HashMap<String, Cost>,
+    stacks: HashMap<String, Cost>,
+    // The chain of the function that ran last, which only changes on calls
+    // and returns.
+    last_function: Option<FunctionPtr>,
+    last_stack: String,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    pub(crate) fn record(&mut self, function: &FunctionPtr, time: Duration) {
+        let same_function = match self.last_function {
+            Some(ref last) => Rc::ptr_eq(&last.function, &function.function),
+            None => false,
+        };
+        if !same_function {
+            self.last_stack = folded_stack(function);
+            self.last_function = Some(function.clone());
+        }
+
+        self.functions
+            .entry(frame_name(function).into())
+            .or_insert_with(Cost::default)
+            .add(time);
+        self.stacks
+            .entry(self.last_stack.clone())
+            .or_insert_with(Cost::default)
+            .add(time);
+    }
+
+    pub fn functions(&self) -> &HashMap<String, Cost> {
+        &self.functions
+    }
+
+    pub fn stacks(&self) -> &HashMap<String, Cost> {
+        &self.stacks
+    }
+
+    pub fn total(&self) -> Cost {
+        let mut total = Cost::default();
+        for cost in self.functions.values() {
+            total.instructions += cost.instructions;
+            total.time += cost.time;
+        }
+        total
+    }
+
+    /// A table of functions, most executed instructions first.
+    pub fn report(&self) -> String {
+        let total = self.total();
+        let mut functions = self.functions.iter().collect::<Vec<_>>();
+        functions.sort_by(|&(a_name, a), &(b_name, b)| {
+            b.instructions
+                .cmp(&a.instructions)
+                .then_with(|| a_name.cmp(b_name))
+        });
+
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{:>12} {:>7} {:>12}  {}",
+            "instructions", "%", "time (us)", "function"
+        ).unwrap();
+        for (name, cost) in functions {
+            let percent = if total.instructions == 0 {
+                0.0
+            } else {
+                100.0 * cost.instructions as f64 / total.instructions as f64
+            };
+            writeln!(
+                out,
+                "{:>12} {:>6.2}% {:>12}  {}",
+                cost.instructions,
+                percent,
+                micros(cost.time),
+                name
+            ).unwrap();
+        }
+        writeln!(
+            out,
+            "{:>12} {:>7} {:>12}  total",
+            total.instructions,
+            "",
+            micros(total.time)
+        ).unwrap();
+        out
+    }
+
+    /// One `outer;inner;innermost count` line per call chain, the input
+    /// format of flamegraph tools.
+    pub fn folded(&self, weight: FoldedWeight) -> String {
+        let mut stacks = self.stacks.iter().collect::<Vec<_>>();
+        stacks.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut out = String::new();
+        for (stack, cost) in stacks {
+            let count = match weight {
+                FoldedWeight::Instructions => cost.instructions,
+                FoldedWeight::Microseconds => micros(cost.time),
+            };
+            writeln!(out, "{} {}", stack, count).unwrap();
+        }
+        out
+    }
+}
+
+fn frame_name(function: &FunctionPtr) -> &str {
+    function
+        .name
+        .as_ref()
+        .map(AsRef::as_ref)
+        .unwrap_or("<unnamed>")
+}
+
+fn folded_stack(function: &FunctionPtr) -> String {
+    let mut frames = vec![frame_name(function).to_string()];
+    let mut next = function.continuation();
+    while let Some((function, _)) = next {
+        frames.push(frame_name(&function).into());
+        next = function.continuation();
+    }
+    frames.reverse();
+    // Folded stacks use ';' as the frame separator.
+    frames
+        .iter()
+        .map(|f| f.replace(';', ":"))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_nanos() / 1_000)
+}
@@ -41,7 +41,7 @@ impl<T> ResultVec<T> {
     }
 
     pub fn peek(&self) -> VmResult<&T> {
-        self.inner.first().ok_or(VmError::StackUnderflow)
+        self.inner.last().ok_or(VmError::StackUnderflow)
     }
 
     #[allow(unused)]
@@ -82,3 +82,10 @@ fn pop_n_test() {
     assert_eq!(r.inner, vec![3, 4]);
     assert_eq!(v.inner, vec![1, 2]);
 }
+
+#[test]
+fn peek_sees_the_top() {
+    let v = ResultVec::new_with(vec![1, 2, 3]);
+    assert_eq!(v.peek(), Ok(&3));
+    assert_eq!(ResultVec::<i32>::new().peek(), Err(VmError::StackUnderflow));
+}
@@ -0,0 +1,181 @@
+use debug_info::{SourceLines, Span};
+use value::{FunctionPtr, Symbol};
+use vm::{FuncExecData, VmError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// One active frame at the time of a runtime error.
+///
+/// The innermost frame is the function that was executing; every frame
+/// after it is a pending continuation that would have been resumed next.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Frame {
+    pub function: Option<String>,
+    pub instruction: usize,
+    pub tag: Option<Symbol>,
+    pub span: Option<Span>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct StackTrace {
+    pub frames: Vec<Frame>,
+}
+
+/// A `VmError` together with the frames that were active when it happened.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct RuntimeError {
+    pub error: VmError,
+    pub trace: StackTrace,
+}
+
+pub type RunResult<T> = Result<T, RuntimeError>;
+
+impl StackTrace {
+    pub fn empty() -> StackTrace {
+        StackTrace { frames: vec![] }
+    }
+
+    pub(crate) fn capture(exec: &FuncExecData) -> StackTrace {
+        StackTrace::at(&exec.function, exec.ip.saturating_sub(1))
+    }
+
+    /// The trace of `function` stopped at `instruction`, followed by its
+    /// continuation chain.
+    pub fn at(function: &FunctionPtr, instruction: usize) -> StackTrace {
+        let mut frames = vec![
+            Frame {
+                function: function.name.clone(),
+                instruction,
+                tag: None,
+                span: function.line_table.lookup(instruction),
+            },
+        ];
+
+        let mut next = function.continuation();
+        while let Some((function, tag)) = next {
+            frames.push(Frame {
+                function: function.name.clone(),
+                instruction: 0,
+                tag,
+                span: function.line_table.lookup(0),
+            });
+            next = function.continuation();
+        }
+
+        StackTrace { frames }
+    }
+}
+
+impl RuntimeError {
+    pub(crate) fn new(error: VmError, exec: &FuncExecData) -> RuntimeError {
+        RuntimeError {
+            error,
+            trace: StackTrace::capture(exec),
+        }
+    }
+}
+
+impl From<VmError> for RuntimeError {
+    fn from(error: VmError) -> RuntimeError {
+        RuntimeError {
+            error,
+            trace: StackTrace::empty(),
+        }
+    }
+}
+
+/// A frame, trace or error shown with line and column numbers in place of
+/// byte offsets.
+pub struct WithLines<'a, T: 'a> {
+    item: &'a T,
+    lines: &'a SourceLines,
+}
+
+impl Frame {
+    /// Shows spans as positions in the source that `lines` was built from.
+    pub fn with_lines<'a>(&'a self, lines: &'a SourceLines) -> WithLines<'a, Frame> {
+        WithLines { item: self, lines }
+    }
+
+    fn write(&self, f: &mut Formatter, lines: Option<&SourceLines>) -> FmtResult {
+        write!(
+            f,
+            "at {}",
+            self.function.as_ref().map(AsRef::as_ref).unwrap_or("<unnamed>")
+        )?;
+        if let Some(ref tag) = self.tag {
+            write!(f, " {:?}", tag)?;
+        }
+        write!(f, " (instruction {}", self.instruction)?;
+        match (self.span, lines) {
+            (Some(span), Some(lines)) => {
+                let (line, column) = lines.position(span.start);
+                write!(f, ", line {}:{}", line, column)?;
+            }
+            (Some(span), None) => write!(f, ", bytes {}..{}", span.start, span.end)?,
+            (None, _) => {}
+        }
+        write!(f, ")")
+    }
+}
+
+impl StackTrace {
+    pub fn with_lines<'a>(&'a self, lines: &'a SourceLines) -> WithLines<'a, StackTrace> {
+        WithLines { item: self, lines }
+    }
+
+    fn write(&self, f: &mut Formatter, lines: Option<&SourceLines>) -> FmtResult {
+        for frame in &self.frames {
+            write!(f, "    ")?;
+            frame.write(f, lines)?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl RuntimeError {
+    pub fn with_lines<'a>(&'a self, lines: &'a SourceLines) -> WithLines<'a, RuntimeError> {
+        WithLines { item: self, lines }
+    }
+
+    fn write(&self, f: &mut Formatter, lines: Option<&SourceLines>) -> FmtResult {
+        writeln!(f, "runtime error: {}", self.error)?;
+        self.trace.write(f, lines)
+    }
+}
+
+impl Display for Frame {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.write(f, None)
+    }
+}
+
+impl Display for StackTrace {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.write(f, None)
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.write(f, None)
+    }
+}
+
+impl<'a> Display for WithLines<'a, Frame> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.item.write(f, Some(self.lines))
+    }
+}
+
+impl<'a> Display for WithLines<'a, StackTrace> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.item.write(f, Some(self.lines))
+    }
+}
+
+impl<'a> Display for WithLines<'a, RuntimeError> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        self.item.write(f, Some(self.lines))
+    }
+}
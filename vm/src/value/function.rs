@@ -1,10 +1,16 @@
 use vm::Instruction;
+use debug_info::LineTable;
 use super::Value;
 use super::Symbol;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as DeError;
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 use std::ops::Deref;
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct FunctionPtr {
     pub function: Rc<Function>,
 }
@@ -22,16 +28,37 @@ pub fn new_func(f: Function) -> FunctionPtr {
     }
 }
 
+/// The compiled, immutable part of a function.  Building a closure or
+/// attaching a continuation copies the `Function` but shares its `Code`.
 #[derive(PartialEq, Debug, PartialOrd, Serialize, Deserialize, Clone)]
-pub struct Function {
+pub struct Code {
     pub name: Option<String>,
     pub instructions: Vec<Instruction>,
-    pub is_built: bool,
-    pub built: BuiltFunction,
+    /// Values referenced by `Instruction::PushConst` and the other
+    /// instructions that take a constant index.
+    pub constants: Vec<Value>,
 
     pub args_count: u32,
     pub upvars_count: u32,
     pub locals_count: u32,
+
+    #[serde(default)]
+    pub line_table: LineTable,
+}
+
+#[derive(PartialEq, Debug, PartialOrd, Serialize, Deserialize, Clone)]
+pub struct Function {
+    #[serde(serialize_with = "serialize_shared", deserialize_with = "deserialize_shared")]
+    pub code: Rc<Code>,
+    pub is_built: bool,
+    pub built: BuiltFunction,
+}
+
+impl Deref for Function {
+    type Target = Code;
+    fn deref(&self) -> &Code {
+        &self.code
+    }
 }
 
 #[derive(PartialEq, Debug, PartialOrd, Serialize, Deserialize, Clone)]
@@ -41,6 +68,18 @@ pub struct BuiltFunction {
 }
 
 impl Function {
+    /// An unbuilt function running `code`.
+    pub fn new<C: Into<Rc<Code>>>(code: C) -> Function {
+        Function {
+            code: code.into(),
+            is_built: false,
+            built: BuiltFunction {
+                upvars: vec![],
+                continuation: None,
+            },
+        }
+    }
+
     pub fn tag(&self) -> Option<Symbol> {
         self.built
             .continuation.as_ref()
@@ -72,3 +111,125 @@ impl FunctionPtr {
         self.with_opt_continuation(Some(c))
     }
 }
+
+// Function graphs share structure through `Rc` (continuation chains, upvars
+// and constant pools all point at the same functions, and every frame of a
+// function points at the same code), so a `FunctionPtr` or `Rc<Code>`
+// serializes as either a full definition the first time it is seen or as a
+// reference to an earlier definition.  Deserializing rebuilds the same
+// sharing, so a persisted continuation comes back with identical topology.
+#[derive(Serialize)]
+#[serde(rename = "Shared")]
+enum SerializedRc<'a, T: 'a> {
+    Def(u32, &'a T),
+    Ref(u32),
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Shared")]
+enum DeserializedRc<T> {
+    Def(u32, T),
+    Ref(u32),
+}
+
+thread_local! {
+    static SERIALIZED_IDS: RefCell<Option<HashMap<*const (), u32>>> = const { RefCell::new(None) };
+    static DESERIALIZED_IDS: RefCell<Option<HashMap<u32, Rc<dyn Any>>>> = const { RefCell::new(None) };
+}
+
+/// Clears a thread-local id table when the outermost shared value finishes
+/// (de)serializing, even if it bailed out with an error.
+struct ScopeGuard<T: 'static> {
+    key: &'static ::std::thread::LocalKey<RefCell<Option<T>>>,
+    owner: bool,
+}
+
+impl<T: 'static> ScopeGuard<T> {
+    fn enter(key: &'static ::std::thread::LocalKey<RefCell<Option<T>>>, init: fn() -> T) -> Self {
+        let owner = key.with(|table| {
+            let mut table = table.borrow_mut();
+            if table.is_none() {
+                *table = Some(init());
+                true
+            } else {
+                false
+            }
+        });
+        ScopeGuard { key, owner }
+    }
+}
+
+impl<T: 'static> Drop for ScopeGuard<T> {
+    fn drop(&mut self) {
+        if self.owner {
+            self.key.with(|table| *table.borrow_mut() = None);
+        }
+    }
+}
+
+fn serialize_shared<T, S>(value: &Rc<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize,
+    S: Serializer,
+{
+    let _guard = ScopeGuard::enter(&SERIALIZED_IDS, HashMap::new);
+    let key = &**value as *const T as *const ();
+    let (id, seen) = SERIALIZED_IDS.with(|table| {
+        let mut table = table.borrow_mut();
+        let table = table.as_mut().unwrap();
+        if let Some(&id) = table.get(&key) {
+            (id, true)
+        } else {
+            let id = table.len() as u32;
+            table.insert(key, id);
+            (id, false)
+        }
+    });
+
+    if seen {
+        SerializedRc::Ref::<T>(id).serialize(serializer)
+    } else {
+        SerializedRc::Def(id, &**value).serialize(serializer)
+    }
+}
+
+fn deserialize_shared<'de, T, D>(deserializer: D) -> Result<Rc<T>, D::Error>
+where
+    T: Deserialize<'de> + 'static,
+    D: Deserializer<'de>,
+{
+    let _guard = ScopeGuard::enter(&DESERIALIZED_IDS, HashMap::new);
+    match DeserializedRc::<T>::deserialize(deserializer)? {
+        DeserializedRc::Def(id, value) => {
+            let value = Rc::new(value);
+            DESERIALIZED_IDS.with(|table| {
+                table
+                    .borrow_mut()
+                    .as_mut()
+                    .unwrap()
+                    .insert(id, value.clone() as Rc<dyn Any>)
+            });
+            Ok(value)
+        }
+        DeserializedRc::Ref(id) => {
+            let found = DESERIALIZED_IDS
+                .with(|table| table.borrow().as_ref().unwrap().get(&id).cloned());
+            found
+                .and_then(|value| value.downcast().ok())
+                .ok_or_else(|| D::Error::custom(format!("reference to undefined value #{}", id)))
+        }
+    }
+}
+
+impl Serialize for FunctionPtr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_shared(&self.function, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FunctionPtr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FunctionPtr, D::Error> {
+        let function = deserialize_shared(deserializer)?;
+        Ok(FunctionPtr { function })
+    }
+}
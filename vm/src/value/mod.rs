@@ -9,7 +9,7 @@ use std::fmt::{Debug, Formatter, Result as FmtResult};
 use std::hash::{Hash, Hasher};
 use std::cmp::Ordering;
 
-pub use self::function::{new_func, Function, BuiltFunction, FunctionPtr};
+pub use self::function::{new_func, BuiltFunction, Code, Function, FunctionPtr};
 pub use self::map::AresMap;
 pub use self::list::AresList;
 pub use self::symbol::Symbol;
@@ -65,7 +65,7 @@ impl Hash for Value {
 }
 impl Eq for Value {}
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Serialize, Deserialize)]
 pub enum ValueKind {
     Integer,
     Float,
@@ -77,6 +77,33 @@ pub enum ValueKind {
     List,
 }
 
+impl ValueKind {
+    pub const ALL: &'static [ValueKind] = &[
+        ValueKind::Integer,
+        ValueKind::Float,
+        ValueKind::Symbol,
+        ValueKind::Function,
+        ValueKind::Continuation,
+        ValueKind::Map,
+        ValueKind::Obj,
+        ValueKind::List,
+    ];
+
+    /// The lowercase name the assembler writes this kind as.
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueKind::Integer => "integer",
+            ValueKind::Float => "float",
+            ValueKind::Symbol => "symbol",
+            ValueKind::Function => "function",
+            ValueKind::Continuation => "continuation",
+            ValueKind::Map => "map",
+            ValueKind::Obj => "obj",
+            ValueKind::List => "list",
+        }
+    }
+}
+
 
 impl Debug for Value {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
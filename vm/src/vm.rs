@@ -1,7 +1,17 @@
-use value::{new_func, AresMap, BuiltFunction, Function, FunctionPtr, Symbol, Value, ValueKind};
+use debug_info::LineTable;
+use compiled::CompiledModule;
+use continuation::Continuation;
+use debugger::Execution;
+use native::{native_function, NativeFn, NativeRegistry, NativeResult, PendingToken};
+use coverage::Coverage;
+use profiler::Profile;
+use trace::{RunResult, RuntimeError};
+use value::{new_func, AresMap, BuiltFunction, Code, Function, FunctionPtr, Symbol, Value, ValueKind};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::ops::Deref;
 use std::rc::Rc;
+use std::time::Instant;
 use super::resultvec::ResultVec;
 
 pub type VmResult<T> = Result<T, VmError>;
@@ -20,16 +30,78 @@ pub enum VmError {
     NoModuleDefinition { module: Symbol, definition: Symbol },
     ContinueWithoutContinuation,
     CallOnUnbuiltFunction,
+    NoNativeFunction(Symbol),
+    UnknownPendingToken(u64),
+    UnhandledSuspension(Value),
+    NoSuchConstant(u32),
+    IntegerOverflow,
+    DivisionByZero,
 }
 
-#[derive(Clone, PartialEq, Debug, PartialOrd, Serialize, Deserialize)]
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            VmError::StackUnderflow => write!(f, "stack underflow"),
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::CrossBoundary => write!(f, "stack access crossed a frame boundary"),
+            VmError::KeyNotFound(ref key) => write!(f, "key {:?} not found", key),
+            VmError::FieldNotFound(ref field) => write!(f, "field {:?} not found", field),
+            VmError::ArityMismatch { actual, expected } => write!(
+                f,
+                "expected {} argument{}, got {}",
+                expected,
+                if expected == 1 { "" } else { "s" },
+                actual
+            ),
+            VmError::TagNotFound(ref tag) => write!(f, "no handler for tag {:?}", tag),
+            // A function already shows its kind, as in "function add".
+            VmError::UnexpectedType {
+                ref expected,
+                found: ref found @ Value::Function(_),
+            } => write!(f, "expected {}, found {:?}", expected.name(), found),
+            VmError::UnexpectedType {
+                ref expected,
+                ref found,
+            } => write!(
+                f,
+                "expected {}, found {} {:?}",
+                expected.name(),
+                found.kind().name(),
+                found
+            ),
+            VmError::RanOutOfInstructions => write!(f, "ran out of instructions"),
+            VmError::NoModuleDefinition {
+                ref module,
+                ref definition,
+            } => write!(f, "module {:?} has no definition {:?}", module, definition),
+            VmError::ContinueWithoutContinuation => {
+                write!(f, "continue used outside of a continuation")
+            }
+            VmError::CallOnUnbuiltFunction => write!(f, "called a function that was never built"),
+            VmError::NoNativeFunction(ref name) => write!(f, "no native function {:?}", name),
+            VmError::UnknownPendingToken(token) => write!(f, "unknown pending token {}", token),
+            VmError::UnhandledSuspension(ref value) => {
+                write!(f, "suspended with {:?} but nothing resumed it", value)
+            }
+            VmError::NoSuchConstant(index) => write!(f, "no constant at index {}", index),
+            VmError::IntegerOverflow => write!(f, "integer overflow"),
+            VmError::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+/// A single VM operation.  Operands are small integers; values that an
+/// instruction needs (`PushConst`, `CallNative`) live in the executing
+/// function's constant pool and are referred to by index, so instructions
+/// are `Copy` and dispatch never clones a `Value`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, PartialOrd, Serialize, Deserialize)]
 pub enum Instruction {
     Add,
     Sub,
     Mul,
     Div,
 
-    Push(Value),
+    PushConst(u32),
     GetFromStackPosition(u32),
     SetToStackPosition(u32),
     Swap,
@@ -51,74 +123,286 @@ pub enum Instruction {
     ModuleAdd,
     ModuleGet,
 
+    /// Calls the native whose name is the symbol at this constant index.
+    CallNative(u32),
+
     MapEmpty,
     MapInsert,
     MapGet,
+
+    /// Raises `UnexpectedType` unless the value on top of the stack is of
+    /// this kind, leaving the stack as it was.
+    CheckType(ValueKind),
+}
+
+impl Instruction {
+    /// The operation of `Add`, `Sub`, `Mul` and `Div`.
+    pub fn arithmetic(self) -> Option<Arithmetic> {
+        match self {
+            Instruction::Add => Some(Arithmetic::Add),
+            Instruction::Sub => Some(Arithmetic::Sub),
+            Instruction::Mul => Some(Arithmetic::Mul),
+            Instruction::Div => Some(Arithmetic::Div),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Arithmetic {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl Arithmetic {
+    /// Applies the operation to two numbers the way the VM does.  Integers
+    /// stay integers, and mixing one with a float gives a float.  Constant
+    /// folding goes through here as well, so that it never changes what a
+    /// program computes.
+    pub fn apply(self, left: &Value, right: &Value) -> VmResult<Value> {
+        let float = |l: f64, r: f64| {
+            Value::Float(match self {
+                Arithmetic::Add => l + r,
+                Arithmetic::Sub => l - r,
+                Arithmetic::Mul => l * r,
+                Arithmetic::Div => l / r,
+            })
+        };
+
+        match (left, right) {
+            (&Value::Integer(l), &Value::Integer(r)) => {
+                let result = match self {
+                    Arithmetic::Add => l.checked_add(r),
+                    Arithmetic::Sub => l.checked_sub(r),
+                    Arithmetic::Mul => l.checked_mul(r),
+                    Arithmetic::Div if r == 0 => return Err(VmError::DivisionByZero),
+                    Arithmetic::Div => l.checked_div(r),
+                };
+                result.map(Value::Integer).ok_or(VmError::IntegerOverflow)
+            }
+            (&Value::Float(l), &Value::Float(r)) => Ok(float(l, r)),
+            (&Value::Integer(l), &Value::Float(r)) => Ok(float(l as f64, r)),
+            (&Value::Float(l), &Value::Integer(r)) => Ok(float(l, r as f64)),
+            (l, r) => {
+                assert_numeric(l)?;
+                assert_numeric(r)?;
+                unreachable!()
+            }
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Debug, PartialOrd, Serialize, Deserialize)]
 pub struct FuncExecData {
-    function: FunctionPtr,
-    ip: usize,
+    pub(crate) function: FunctionPtr,
+    pub(crate) ip: usize,
 }
 
 #[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct Vm {
     pub debug_values: Vec<Value>,
     pub(crate) modules: HashMap<(Symbol, Symbol), Value>,
+    #[serde(skip)]
+    natives: NativeRegistry,
+    pending: HashMap<u64, Continuation>,
+    next_pending_id: u64,
+    #[serde(skip)]
+    profile: Option<Profile>,
+    #[serde(skip)]
+    coverage: Option<Coverage>,
 }
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum StepResult {
     Done(Value),
+    Pending(PendingToken),
     Continue,
 }
 
+/// How a run of the VM ended: either the program finished, or a native
+/// function suspended it and the host has to call `Vm::resume`.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Completion {
+    Done(Value),
+    Pending(PendingToken),
+}
+
 impl Vm {
     pub fn new() -> Vm {
         Vm {
             debug_values: vec![],
             modules: HashMap::new(),
+            natives: NativeRegistry::default(),
+            pending: HashMap::new(),
+            next_pending_id: 0,
+            profile: None,
+            coverage: None,
         }
     }
 
-    pub fn run_function(&mut self, fp: FunctionPtr) -> VmResult<Value> {
-        let terminate_function = new_func(Function {
-            name: Some("<terminate>".into()),
-            built: BuiltFunction {
-                upvars: vec![],
-                continuation: None,
-            },
-            is_built: true,
-            instructions: vec![Instruction::Terminate],
-            args_count: 1,
-            upvars_count: 0,
-            locals_count: 0,
-        });
+    /// Starts recording which instructions run.
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            self.coverage = Some(Coverage::new());
+        }
+    }
+
+    /// Stops recording coverage and returns the hits collected so far.
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Starts counting executed instructions and their wall time.  Profiling
+    /// slows every instruction down, so it is off unless asked for.
+    pub fn enable_profiling(&mut self) {
+        if self.profile.is_none() {
+            self.profile = Some(Profile::new());
+        }
+    }
+
+    /// Stops profiling and returns everything collected so far.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        self.profile.take()
+    }
+
+    /// Registers a host function and binds it as `module.name` so Ares code
+    /// can fetch it with `ModuleGet` and call it like any other function.
+    pub fn register_native<F>(&mut self, module: Symbol, name: Symbol, args_count: u32, f: F)
+    where
+        F: Fn(Vec<Value>) -> VmResult<NativeResult> + 'static,
+    {
+        let native: Rc<NativeFn> = Rc::new(f);
+        self.natives.insert(module.clone(), name.clone(), native);
+        let function = native_function(&module, &name, args_count);
+        self.modules.insert((module, name), Value::Function(function));
+    }
+
+    /// Runs the body of a compiled module, which registers each of its
+    /// definitions in `modules` under the module's name.
+    pub fn load_module(&mut self, module: &CompiledModule) -> RunResult<Value> {
+        self.run_function(module.program.clone())
+    }
+
+    /// Runs a function that must not suspend.
+    pub fn run_function(&mut self, fp: FunctionPtr) -> RunResult<Value> {
+        match self.start_function(fp)? {
+            Completion::Done(v) => Ok(v),
+            Completion::Pending(token) => {
+                self.pending.remove(&token.id);
+                Err(VmError::UnhandledSuspension(token.request).into())
+            }
+        }
+    }
+
+    pub fn start_function(&mut self, fp: FunctionPtr) -> RunResult<Completion> {
+        let execution = self.begin_function(fp)?;
+        self.run_loop(execution)
+    }
 
-        let mut fp = rc_get(fp.function).clone();
+    /// Sets up a run of `fp` without executing any of it, so that it can be
+    /// driven one instruction at a time with `step_execution`.
+    pub fn begin_function(&mut self, fp: FunctionPtr) -> RunResult<Execution> {
+        let mut fp = rc_get(fp.function);
         assert_eq!(fp.args_count, 0);
         assert_eq!(fp.upvars_count, 0);
 
         fp.built = BuiltFunction {
             upvars: vec![],
-            continuation: Some((terminate_function, None)),
+            continuation: Some((terminate_function(), None)),
         };
         fp.is_built = true;
 
-        let fp = new_func(fp);
+        self.prepare(new_func(fp), vec![])
+    }
+
+    /// Executes the next instruction of a paused run.  Returns the outcome
+    /// once the run is over; the execution must not be stepped after that.
+    pub fn step_execution(&mut self, execution: &mut Execution) -> RunResult<Option<Completion>> {
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record(&execution.exec.function, execution.exec.ip);
+        }
+        let result = if self.profile.is_some() {
+            let function = execution.exec.function.clone();
+            let started = Instant::now();
+            let result = self.step(&mut execution.exec, &mut execution.stack);
+            if let Some(ref mut profile) = self.profile {
+                profile.record(&function, started.elapsed());
+            }
+            result
+        } else {
+            self.step(&mut execution.exec, &mut execution.stack)
+        };
+
+        match result {
+            Ok(StepResult::Done(v)) => Ok(Some(Completion::Done(v))),
+            Ok(StepResult::Pending(token)) => Ok(Some(Completion::Pending(token))),
+            Ok(StepResult::Continue) => Ok(None),
+            Err(e) => Err(RuntimeError::new(e, &execution.exec)),
+        }
+    }
+
+    /// Answers a suspension with `value` and keeps running.  Each token can
+    /// only be resumed once.
+    pub fn resume(&mut self, token: PendingToken, value: Value) -> RunResult<Completion> {
+        let continuation = self.detach_pending(&token)?;
+        self.resume_continuation(continuation, value)
+    }
+
+    /// Takes the continuation of a suspended native call out of the VM, e.g.
+    /// to persist it and resume it later with `resume_continuation`.
+    pub fn detach_pending(&mut self, token: &PendingToken) -> VmResult<Continuation> {
+        self.pending
+            .remove(&token.id)
+            .ok_or(VmError::UnknownPendingToken(token.id))
+    }
+
+    /// Continues a captured continuation with `value`, running until the
+    /// continuation chain is exhausted or a native function suspends.
+    pub fn resume_continuation(
+        &mut self,
+        continuation: Continuation,
+        value: Value,
+    ) -> RunResult<Completion> {
+        let (function, _) = join_cont_chain(
+            Some((terminate_function(), None)),
+            (continuation.function().clone(), None),
+        );
+
+        self.enter_function(function, vec![value])
+    }
 
+    fn enter_function(&mut self, function: FunctionPtr, args: Vec<Value>) -> RunResult<Completion> {
+        let execution = self.prepare(function, args)?;
+        self.run_loop(execution)
+    }
+
+    fn prepare(&mut self, function: FunctionPtr, args: Vec<Value>) -> RunResult<Execution> {
         let mut exec_data = FuncExecData {
-            function: fp,
+            function: function.clone(),
             ip: 0,
         };
+        let mut stack = ResultVec::new();
+        if let Err(e) = setup_new_function(
+            function,
+            ResultVec::new_with(args),
+            &mut stack,
+            &mut exec_data,
+        ) {
+            return Err(RuntimeError::new(e, &exec_data));
+        }
 
-        let mut function_stack = ResultVec::new();
+        Ok(Execution {
+            exec: exec_data,
+            stack,
+        })
+    }
 
+    fn run_loop(&mut self, mut execution: Execution) -> RunResult<Completion> {
         loop {
-            match self.step(&mut exec_data, &mut function_stack)? {
-                StepResult::Done(v) => return Ok(v),
-                StepResult::Continue => continue,
+            if let Some(completion) = self.step_execution(&mut execution)? {
+                return Ok(completion);
             }
         }
     }
@@ -129,94 +413,18 @@ impl Vm {
         stack: &mut ResultVec<Value>,
     ) -> VmResult<StepResult> {
         use self::Instruction::*;
-        let instruction = {
-            let &FuncExecData {
-                ref function,
-                ref ip,
-            } = func_exec as &_;
-            if *ip >= function.instructions.len() {
-                return Err(VmError::RanOutOfInstructions);
-            }
-            function.instructions[*ip].clone()
+        let instruction = match func_exec.function.instructions.get(func_exec.ip) {
+            Some(&instruction) => instruction,
+            None => return Err(VmError::RanOutOfInstructions),
         };
         func_exec.ip += 1;
 
-        println!(
-            "{}: {:?}",
-            func_exec
-                .function
-                .function
-                .name
-                .as_ref()
-                .map(AsRef::as_ref)
-                .unwrap_or("<unnamed>"),
-            instruction
-        );
-
         match instruction {
-            Add => {
+            Add | Sub | Mul | Div => {
                 let r = stack.pop()?;
                 let l = stack.pop()?;
-                assert_numeric(&l)?;
-                assert_numeric(&r)?;
-
-                let result = match (l, r) {
-                    (Value::Integer(l), Value::Integer(r)) => Value::Integer(l + r),
-                    (Value::Float(l), Value::Float(r)) => Value::Float(l + r),
-                    (Value::Integer(l), Value::Float(r)) => Value::Float(l as f64 + r),
-                    (Value::Float(l), Value::Integer(r)) => Value::Float(l + r as f64),
-                    _ => unreachable!(),
-                };
-
-                stack.push(result)?;
-            }
-            Sub => {
-                let r = stack.pop()?;
-                let l = stack.pop()?;
-                assert_numeric(&l)?;
-                assert_numeric(&r)?;
-
-                let result = match (l, r) {
-                    (Value::Integer(l), Value::Integer(r)) => Value::Integer(l - r),
-                    (Value::Float(l), Value::Float(r)) => Value::Float(l - r),
-                    (Value::Integer(l), Value::Float(r)) => Value::Float(l as f64 - r),
-                    (Value::Float(l), Value::Integer(r)) => Value::Float(l - r as f64),
-                    _ => unreachable!(),
-                };
-
-                stack.push(result)?;
-            }
-            Mul => {
-                let r = stack.pop()?;
-                let l = stack.pop()?;
-                assert_numeric(&l)?;
-                assert_numeric(&r)?;
-
-                let result = match (l, r) {
-                    (Value::Integer(l), Value::Integer(r)) => Value::Integer(l * r),
-                    (Value::Float(l), Value::Float(r)) => Value::Float(l * r),
-                    (Value::Integer(l), Value::Float(r)) => Value::Float(l as f64 * r),
-                    (Value::Float(l), Value::Integer(r)) => Value::Float(l * r as f64),
-                    _ => unreachable!(),
-                };
-
-                stack.push(result)?;
-            }
-            Div => {
-                let r = stack.pop()?;
-                let l = stack.pop()?;
-                assert_numeric(&l)?;
-                assert_numeric(&r)?;
-
-                let result = match (l, r) {
-                    (Value::Integer(l), Value::Integer(r)) => Value::Integer(l / r),
-                    (Value::Float(l), Value::Float(r)) => Value::Float(l / r),
-                    (Value::Integer(l), Value::Float(r)) => Value::Float(l as f64 / r),
-                    (Value::Float(l), Value::Integer(r)) => Value::Float(l / r as f64),
-                    _ => unreachable!(),
-                };
-
-                stack.push(result)?;
+                let op = instruction.arithmetic().unwrap();
+                stack.push(op.apply(&l, &r)?)?;
             }
 
             BuildFunction => {
@@ -240,8 +448,9 @@ impl Vm {
                 let value = stack.pop()?;
                 stack.set(pos, value)?;
             }
-            Push(v) => {
-                stack.push(v)?;
+            PushConst(index) => {
+                let value = constant(&func_exec.function, index)?.clone();
+                stack.push(value)?;
             }
             Pop => {
                 stack.pop()?;
@@ -276,6 +485,40 @@ impl Vm {
                 stack.push(value.clone())?;
             }
 
+            CallNative(index) => {
+                let name = constant(&func_exec.function, index)?.clone().into_symbol()?;
+                let native = self.natives
+                    .get(&name)
+                    .ok_or_else(|| VmError::NoNativeFunction(name.clone()))?;
+                let args_count = func_exec.function.args_count;
+                let args = (1..args_count + 1)
+                    .map(|i| stack.get(i).map(Clone::clone))
+                    .collect::<VmResult<Vec<_>>>()?;
+
+                let continuation = match func_exec.function.continuation() {
+                    Some(f) => f.0,
+                    None => return Err(VmError::ContinueWithoutContinuation),
+                };
+
+                match native(args)? {
+                    NativeResult::Return(value) => {
+                        setup_new_function(
+                            continuation,
+                            ResultVec::new_with(vec![value]),
+                            stack,
+                            func_exec,
+                        )?;
+                    }
+                    NativeResult::Suspend(request) => {
+                        let id = self.next_pending_id;
+                        self.next_pending_id += 1;
+                        let continuation = Continuation::from_function(continuation)?;
+                        self.pending.insert(id, continuation);
+                        return Ok(StepResult::Pending(PendingToken { id, request }));
+                    }
+                }
+            }
+
             MapEmpty => {
                 stack.push(Value::Map(AresMap::new()))?;
             }
@@ -295,6 +538,15 @@ impl Vm {
                     return Err(VmError::KeyNotFound(k));
                 }
             }
+            CheckType(expected) => {
+                let found = stack.peek()?;
+                if found.kind() != expected {
+                    return Err(VmError::UnexpectedType {
+                        expected,
+                        found: found.clone(),
+                    });
+                }
+            }
             Dup => {
                 let v = stack.peek()?.clone();
                 stack.push(v)?;
@@ -321,13 +573,14 @@ impl Vm {
                 assert!(function.is_built);
                 assert!(continuation.is_built);
 
+                let callee = function.clone();
                 let (function, _) = join_cont_chain(
                     func_exec.function.continuation(),
                     join_cont_chain(Some((continuation, None)), (function, None)),
                 );
                 assert!(function.continuation().is_some());
 
-                setup_new_function(function, args, stack, func_exec)?;
+                setup_new_closure(callee, function, args, stack, func_exec)?;
             }
             Reset => {
                 let tag = stack.pop()?.into_symbol()?;
@@ -337,6 +590,7 @@ impl Vm {
                 assert!(function.args_count == 0);
                 assert!(after_reset.is_built);
 
+                let callee = function.clone();
                 // current continuation <- after_reset <- continue_up('s) <- function
                 let function = join_cont_chain(
                     Some(join_cont_chain(
@@ -346,7 +600,7 @@ impl Vm {
                     (function, None),
                 );
 
-                setup_new_function(function.0, ResultVec::new(), stack, func_exec)?;
+                setup_new_closure(callee, function.0, ResultVec::new(), stack, func_exec)?;
             }
             Shift => {
                 let tag = stack.pop()?.into_symbol()?;
@@ -363,10 +617,12 @@ impl Vm {
                     (Some(h), Some(l)) => (h, l),
                 };
 
+                let callee = function.clone();
                 let function = function.with_continuation(high);
                 let continuation_parameter = join_cont_chain(Some(low), (after_shift, None));
 
-                setup_new_function(
+                setup_new_closure(
+                    callee,
                     function,
                     ResultVec::new_with(vec![Value::Function(continuation_parameter.0)]),
                     stack,
@@ -395,6 +651,32 @@ impl Vm {
     }
 }
 
+fn terminate_function() -> FunctionPtr {
+    new_func(Function {
+        code: Rc::new(Code {
+            name: Some("<terminate>".into()),
+            instructions: vec![Instruction::Terminate],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: true,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    })
+}
+
+fn constant(function: &FunctionPtr, index: u32) -> VmResult<&Value> {
+    function
+        .constants
+        .get(index as usize)
+        .ok_or(VmError::NoSuchConstant(index))
+}
+
 fn rc_get<T: Clone>(rc: Rc<T>) -> T {
     match Rc::try_unwrap(rc) {
         Ok(t) => t,
@@ -459,6 +741,19 @@ fn setup_new_function(
     args: ResultVec<Value>,
     stack: &mut ResultVec<Value>,
     func_exec: &mut FuncExecData,
+) -> VmResult<()> {
+    setup_new_closure(f.clone(), f, args, stack, func_exec)
+}
+
+/// Enters `f`, which is `this` with a continuation chain attached.  The
+/// frame's first slot holds `this` so that a function referring to itself
+/// gets the closure back rather than the continuation it was called with.
+fn setup_new_closure(
+    this: FunctionPtr,
+    f: FunctionPtr,
+    args: ResultVec<Value>,
+    stack: &mut ResultVec<Value>,
+    func_exec: &mut FuncExecData,
 ) -> VmResult<()> {
     if f.args_count != args.inner.len() as u32 {
         return Err(VmError::ArityMismatch {
@@ -478,7 +773,7 @@ fn setup_new_function(
     };
 
     *stack = ResultVec::new();
-    stack.push(Value::Function(f))?;
+    stack.push(Value::Function(this))?;
     *func_exec = exec_data;
 
     for arg in args.inner {
@@ -508,16 +803,19 @@ fn assert_numeric(v: &Value) -> VmResult<()> {
 
 fn continue_up(cp: ContPair) -> FunctionPtr {
     new_func(Function {
-        name: Some("<continue-shim>".into()),
+        code: Rc::new(Code {
+            name: Some("<continue-shim>".into()),
+            instructions: vec![Instruction::Resume],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: true,
         built: BuiltFunction {
             upvars: vec![],
             continuation: Some(cp),
         },
-        is_built: true,
-        //upvars: vec![],
-        instructions: vec![Instruction::Resume],
-        args_count: 1,
-        upvars_count: 0,
-        locals_count: 0,
     })
 }
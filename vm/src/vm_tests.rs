@@ -1,8 +1,19 @@
 use vm::*;
 use self::Instruction::*;
 use value::Value::*;
-use value::{new_func, AresMap, Function, Symbol, Value};
-use value::BuiltFunction;
+use value::{new_func, AresMap, Code, Function, Symbol, Value, ValueKind};
+use value::{BuiltFunction, FunctionPtr};
+use compiled::{CompiledModule, FormatError, FORMAT_VERSION, MAGIC};
+use asm::{assemble, disassemble};
+use continuation::Continuation;
+use debugger::{Breakpoint, Debugger, Stop};
+use debug_info::{LineTable, SourceLines, Span};
+use native::NativeResult;
+use profiler::FoldedWeight;
+use trace::{Frame, RuntimeError, StackTrace};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
 
 fn symval(v: &str) -> Value {
     Value::Symbol(Symbol(v.into()))
@@ -11,16 +22,20 @@ fn symval(v: &str) -> Value {
 #[test]
 fn basic_return_value() {
     let function = new_func(Function {
-        name: Some("adder".into()),
+        code: Rc::new(Code {
+            name: Some("adder".into()),
+            instructions: vec![PushConst(0), Terminate],
+            constants: vec![Integer(1)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Push(Integer(1)), Terminate],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let mut vm = Vm::new();
@@ -30,16 +45,20 @@ fn basic_return_value() {
 #[test]
 fn basic_return_value_float() {
     let function = new_func(Function {
-        name: Some("adder".into()),
+        code: Rc::new(Code {
+            name: Some("adder".into()),
+            instructions: vec![PushConst(0), Terminate],
+            constants: vec![Float(1.234)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Push(Float(1.234)), Terminate],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let mut vm = Vm::new();
@@ -50,16 +69,20 @@ fn basic_return_value_float() {
 #[test]
 fn empty_map() {
     let function = new_func(Function {
-        name: Some("empty_map".into()),
+        code: Rc::new(Code {
+            name: Some("empty_map".into()),
+            instructions: vec![MapEmpty, Terminate],
+            constants: vec![],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![MapEmpty, Terminate],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let mut vm = Vm::new();
@@ -69,22 +92,26 @@ fn empty_map() {
 #[test]
 fn map_with_some_adds() {
     let function = new_func(Function {
-        name: Some("empty_map".into()),
+        code: Rc::new(Code {
+            name: Some("empty_map".into()),
+            instructions: vec![
+                PushConst(0),
+                PushConst(1),
+                MapEmpty,
+                MapInsert,
+                Terminate,
+            ],
+            constants: vec![Value::Integer(20), Value::Integer(5)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            Push(Value::Integer(20)),
-            Push(Value::Integer(5)),
-            MapEmpty,
-            MapInsert,
-            Terminate,
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
     let mut vm = Vm::new();
 
@@ -95,24 +122,28 @@ fn map_with_some_adds() {
 #[test]
 fn map_get() {
     let function = new_func(Function {
-        name: Some("empty_map".into()),
+        code: Rc::new(Code {
+            name: Some("empty_map".into()),
+            instructions: vec![
+                PushConst(0),
+                PushConst(1),
+                MapEmpty,
+                MapInsert,
+                PushConst(0),
+                MapGet,
+                Terminate,
+            ],
+            constants: vec![Value::Integer(20), Value::Integer(5)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            Push(Value::Integer(20)),
-            Push(Value::Integer(5)),
-            MapEmpty,
-            MapInsert,
-            Push(Value::Integer(20)),
-            MapGet,
-            Terminate,
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
     let mut vm = Vm::new();
 
@@ -122,39 +153,65 @@ fn map_get() {
 #[test]
 fn bad_map_get() {
     let function = new_func(Function {
-        name: Some("empty_map".into()),
+        code: Rc::new(Code {
+            name: Some("empty_map".into()),
+            instructions: vec![MapEmpty, PushConst(0), MapGet, Terminate],
+            constants: vec![Value::Integer(20)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![MapEmpty, Push(Value::Integer(20)), MapGet, Terminate],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let mut vm = Vm::new();
 
     assert_eq!(
         vm.run_function(function),
-        Err(VmError::KeyNotFound(Value::Integer(20)))
+        Err(RuntimeError {
+            error: VmError::KeyNotFound(Value::Integer(20)),
+            trace: StackTrace {
+                frames: vec![
+                    Frame {
+                        function: Some("empty_map".into()),
+                        instruction: 2,
+                        tag: None,
+                        span: None,
+                    },
+                    Frame {
+                        function: Some("<terminate>".into()),
+                        instruction: 0,
+                        tag: None,
+                        span: None,
+                    },
+                ],
+            },
+        })
     );
 }
 
 #[test]
 fn test_addition() {
     let function = new_func(Function {
-        name: Some("adder".into()),
+        code: Rc::new(Code {
+            name: Some("adder".into()),
+            instructions: vec![PushConst(0), PushConst(1), Add, Terminate],
+            constants: vec![Integer(5), Integer(10)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Push(Integer(5)), Push(Integer(10)), Add, Terminate],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
     let mut vm = Vm::new();
     assert_eq!(vm.run_function(function), Ok(Integer(15)));
@@ -163,70 +220,86 @@ fn test_addition() {
 #[test]
 fn test_tail_call() {
     let last = new_func(Function {
-        name: Some("last".into()),
+        code: Rc::new(Code {
+            name: Some("last".into()),
+            instructions: vec![PushConst(0), Resume],
+            constants: vec![Value::Integer(10)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Push(Value::Integer(10)), Resume],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let g = new_func(Function {
-        name: Some("g".into()),
+        code: Rc::new(Code {
+            name: Some("g".into()),
+            instructions: vec![
+                CurrentContinuation,
+                PushConst(0),
+                BuildFunction,
+                Call(0),
+            ],
+            constants: vec![Value::Function(last)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            CurrentContinuation,
-            Push(Value::Function(last)),
-            BuildFunction,
-            Call(0),
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let f = new_func(Function {
-        name: Some("f".into()),
+        code: Rc::new(Code {
+            name: Some("f".into()),
+            instructions: vec![
+                CurrentContinuation,
+                PushConst(0),
+                BuildFunction,
+                Call(0),
+            ],
+            constants: vec![Value::Function(g)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            CurrentContinuation,
-            Push(Value::Function(g)),
-            BuildFunction,
-            Call(0),
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let main = new_func(Function {
-        name: Some("main".into()),
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                CurrentContinuation,
+                PushConst(0),
+                BuildFunction,
+                Call(0),
+            ],
+            constants: vec![Function(f)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            CurrentContinuation,
-            Push(Function(f)),
-            BuildFunction,
-            Call(0),
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let mut vm = Vm::new();
@@ -236,70 +309,86 @@ fn test_tail_call() {
 #[test]
 fn fake_test_tail_call() {
     let last = new_func(Function {
-        name: Some("last".into()),
+        code: Rc::new(Code {
+            name: Some("last".into()),
+            instructions: vec![PushConst(0), Terminate],
+            constants: vec![Value::Integer(10)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Push(Value::Integer(10)), Terminate],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let g = new_func(Function {
-        name: Some("g".into()),
+        code: Rc::new(Code {
+            name: Some("g".into()),
+            instructions: vec![
+                CurrentContinuation,
+                PushConst(0),
+                BuildFunction,
+                Call(0),
+            ],
+            constants: vec![Value::Function(last)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            CurrentContinuation,
-            Push(Value::Function(last)),
-            BuildFunction,
-            Call(0),
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let f = new_func(Function {
-        name: Some("f".into()),
+        code: Rc::new(Code {
+            name: Some("f".into()),
+            instructions: vec![
+                CurrentContinuation,
+                PushConst(0),
+                BuildFunction,
+                Call(0),
+            ],
+            constants: vec![Value::Function(g)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            CurrentContinuation,
-            Push(Value::Function(g)),
-            BuildFunction,
-            Call(0),
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let main = new_func(Function {
-        name: Some("main".into()),
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                CurrentContinuation,
+                PushConst(0),
+                BuildFunction,
+                Call(0),
+            ],
+            constants: vec![Function(f)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            CurrentContinuation,
-            Push(Function(f)),
-            BuildFunction,
-            Call(0),
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let mut vm = Vm::new();
@@ -309,80 +398,100 @@ fn fake_test_tail_call() {
 #[test]
 fn test_function_call() {
     let get_x = new_func(Function {
-        name: Some("getX".into()),
+        code: Rc::new(Code {
+            name: Some("getX".into()),
+            instructions: vec![PushConst(0), Resume],
+            constants: vec![Integer(11)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Push(Integer(11)), Resume],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let printer = new_func(Function {
-        name: Some("printer".into()),
+        code: Rc::new(Code {
+            name: Some("printer".into()),
+            instructions: vec![Debug, PushConst(0), Resume],
+            constants: vec![Integer(9)],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Debug, Push(Integer(9)), Resume],
-        args_count: 1,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let after_get_x = new_func(Function {
-        name: Some("after_get_x".into()),
+        code: Rc::new(Code {
+            name: Some("after_get_x".into()),
+            instructions: vec![PushConst(0), Add, Resume],
+            constants: vec![Value::Integer(1)],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Push(Value::Integer(1)), Add, Resume],
-        args_count: 1,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let inside_print = new_func(Function {
-        name: Some("inside_print".into()),
+        code: Rc::new(Code {
+            name: Some("inside_print".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                Call(0),
+            ],
+            constants: vec![Value::Function(after_get_x), Value::Function(get_x)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            Push(Value::Function(after_get_x)),
-            BuildFunction,
-            Push(Value::Function(get_x)),
-            BuildFunction,
-            Call(0),
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let main = new_func(Function {
-        name: Some("main".into()),
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                Call(0),
+            ],
+            constants: vec![Function(printer), Function(inside_print)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            Push(Function(printer)),
-            BuildFunction,
-            Push(Function(inside_print)),
-            BuildFunction,
-            Call(0),
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let mut vm = Vm::new();
@@ -392,38 +501,158 @@ fn test_function_call() {
 }
 
 #[test]
-fn reset_without_a_shift() {
-    let inside_reset = new_func(Function {
-        name: Some("inside reset".into()),
+fn function_sees_itself_without_its_continuation() {
+    let itself = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("itself".into()),
+            instructions: vec![GetFromStackPosition(0), Resume],
+            constants: vec![],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
+    });
+
+    let after = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("after".into()),
+            instructions: vec![GetFromStackPosition(1), Terminate],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
         is_built: false,
-        instructions: vec![Push(Integer(1)), Terminate],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
     });
 
     let main = new_func(Function {
-        name: Some("main".into()),
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                Call(0),
+            ],
+            constants: vec![Function(after), Function(itself)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
+    });
+
+    let mut vm = Vm::new();
+    let result = vm.run_function(main).unwrap().into_function().unwrap();
+    assert_eq!(result.name, Some("itself".into()));
+    assert_eq!(result.continuation(), None);
+}
+
+#[test]
+fn entry_function_sees_itself() {
+    let main = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![GetFromStackPosition(0), Terminate],
+            constants: vec![],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
         is_built: false,
-        instructions: vec![
-            CurrentContinuation,
-            Push(Function(inside_reset)),
-            BuildFunction,
-            Push(symval("hi")),
-            Reset,
-            Terminate,
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let mut vm = Vm::new();
+    let result = vm.run_function(main).unwrap().into_function().unwrap();
+    assert_eq!(result.name, Some("main".into()));
+}
+
+#[test]
+fn dup_copies_the_top_of_the_stack() {
+    let function = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("dup".into()),
+            instructions: vec![PushConst(0), PushConst(1), Dup, Sub, Terminate],
+            constants: vec![Integer(1), Integer(2)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let mut vm = Vm::new();
+    assert_eq!(vm.run_function(function), Ok(Integer(0)));
+}
+
+#[test]
+fn reset_without_a_shift() {
+    let inside_reset = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("inside reset".into()),
+            instructions: vec![PushConst(0), Terminate],
+            constants: vec![Integer(1)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let main = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                CurrentContinuation,
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                Reset,
+                Terminate,
+            ],
+            constants: vec![Function(inside_reset), symval("hi")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
     });
 
     let mut vm = Vm::new();
@@ -434,99 +663,119 @@ fn reset_without_a_shift() {
 #[test]
 fn reset_and_shift_with_called_cont() {
     let shifter = new_func(Function {
-        name: Some("shifter".into()),
+        code: Rc::new(Code {
+            name: Some("shifter".into()),
+            instructions: vec![
+                CurrentContinuation,
+                PushConst(0),
+                Print,
+                GetFromStackPosition(2),
+                GetFromStackPosition(1),
+                GetFromStackPosition(3),
+                Call(1),
+            ],
+            constants: vec![Integer(10)],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            CurrentContinuation,
-            Push(Integer(10)),
-            Print,
-            GetFromStackPosition(2),
-            GetFromStackPosition(1),
-            GetFromStackPosition(3),
-            Call(1),
-        ],
-        args_count: 1,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let after_shift = new_func(Function {
-        name: Some("after_shift".into()),
+        code: Rc::new(Code {
+            name: Some("after_shift".into()),
+            instructions: vec![
+                Debug,
+                PushConst(0),
+                Debug,
+                PushConst(1),
+                Debug,
+                PushConst(2),
+                Resume,
+            ],
+            constants: vec![Integer(100), Integer(200), Integer(999)],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            Debug,
-            Push(Integer(100)),
-            Debug,
-            Push(Integer(200)),
-            Debug,
-            Push(Integer(999)),
-            Resume,
-        ],
-        args_count: 1,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
 
     let reseter = new_func(Function {
-        name: Some("resetter".into()),
+        code: Rc::new(Code {
+            name: Some("resetter".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                PushConst(2),
+                Shift,
+            ],
+            constants: vec![Function(after_shift), Function(shifter), symval("io")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            Push(Function(after_shift)),
-            BuildFunction,
-            Push(Function(shifter)),
-            BuildFunction,
-            Push(symval("io")),
-            Shift,
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let after_reset = new_func(Function {
-        name: Some("after_reset".into()),
+        code: Rc::new(Code {
+            name: Some("after_reset".into()),
+            instructions: vec![Resume],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Resume],
-        args_count: 1,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let main = new_func(Function {
-        name: Some("main".into()),
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                PushConst(2),
+                Reset,
+            ],
+            constants: vec![Function(after_reset), Function(reseter), symval("io")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            Push(Function(after_reset)),
-            BuildFunction,
-            Push(Function(reseter)),
-            BuildFunction,
-            Push(symval("io")),
-            Reset,
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
     let mut vm = Vm::new();
     let res = vm.run_function(main);
@@ -541,121 +790,1521 @@ fn reset_and_shift_with_called_cont() {
 #[test]
 fn reset_and_shift_with_ignored_cont() {
     let shifter = new_func(Function {
-        name: Some("shifter".into()),
+        code: Rc::new(Code {
+            name: Some("shifter".into()),
+            instructions: vec![PushConst(0), Resume],
+            constants: vec![Integer(10)],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![Push(Integer(10)), Resume],
-        args_count: 1,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
     let after_shift = new_func(Function {
-        name: Some("after_shift".into()),
+        code: Rc::new(Code {
+            name: Some("after_shift".into()),
+            instructions: vec![
+                PushConst(0),
+                Debug,
+                PushConst(0),
+                Debug,
+                PushConst(1),
+                Resume,
+            ],
+            constants: vec![Integer(100), Integer(999)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
-        instructions: vec![
-            Push(Integer(100)),
-            Debug,
-            Push(Integer(100)),
-            Debug,
-            Push(Integer(999)),
-            Resume,
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
     });
 
 
     let reseter = new_func(Function {
-        name: Some("resetter".into()),
+        code: Rc::new(Code {
+            name: Some("resetter".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                PushConst(2),
+                Shift,
+            ],
+            constants: vec![Function(after_shift), Function(shifter), symval("io")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
+    });
+
+    let after_reset = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("after_reset".into()),
+            instructions: vec![Resume],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
         is_built: false,
-        instructions: vec![
-            Push(Function(after_shift)),
-            BuildFunction,
-            Push(Function(shifter)),
-            BuildFunction,
-            Push(symval("io")),
-            Shift,
-        ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let main = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                PushConst(2),
+                Reset,
+            ],
+            constants: vec![Function(after_reset), Function(reseter), symval("io")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+    let mut vm = Vm::new();
+    let res = vm.run_function(main);
+
+    assert_eq!(res, Ok(Integer(10)));
+    assert_eq!(vm.debug_values, vec![]);
+}
+
+#[test]
+fn setting_module_variables() {
+    let main = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main2".into()),
+            instructions: vec![
+                PushConst(0),
+                PushConst(1),
+                PushConst(2),
+                ModuleAdd,
+                PushConst(1),
+                PushConst(2),
+                ModuleGet,
+                Terminate,
+            ],
+            constants: vec![Integer(5), symval("variable_name"), symval("module_name")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+    let mut vm = Vm::new();
+    assert_eq!(vm.run_function(main), Ok(Integer(5)));
+}
+
+fn capturing_program() -> FunctionPtr {
+    // reset('io) { let x = shift('io, (k) => k); debug(x); x + 1 }
+    let shifter = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("shifter".into()),
+            instructions: vec![GetFromStackPosition(1), Resume],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let after_shift = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("after_shift".into()),
+            instructions: vec![
+                GetFromStackPosition(1),
+                Debug,
+                GetFromStackPosition(1),
+                PushConst(0),
+                Add,
+                Resume,
+            ],
+            constants: vec![Integer(1)],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let reseter = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("resetter".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                PushConst(2),
+                Shift,
+            ],
+            constants: vec![Function(after_shift), Function(shifter), symval("io")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
     });
 
     let after_reset = new_func(Function {
-        name: Some("after_reset".into()),
+        code: Rc::new(Code {
+            name: Some("after_reset".into()),
+            instructions: vec![Resume],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
+    });
+
+    new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                PushConst(2),
+                Reset,
+            ],
+            constants: vec![Function(after_reset), Function(reseter), symval("io")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    })
+}
+
+#[test]
+fn resume_captured_continuation() {
+    let mut vm = Vm::new();
+    let captured = vm.run_function(capturing_program()).unwrap();
+    assert_eq!(vm.debug_values, vec![]);
+
+    let k = Continuation::from_value(captured).unwrap();
+    let mut vm = Vm::new();
+    assert_eq!(
+        vm.resume_continuation(k.clone(), Integer(41)),
+        Ok(Completion::Done(Integer(42)))
+    );
+    assert_eq!(vm.debug_values, vec![Integer(41)]);
+
+    // Resuming does not consume the continuation.
+    assert_eq!(
+        vm.resume_continuation(k, Integer(1)),
+        Ok(Completion::Done(Integer(2)))
+    );
+}
+
+#[test]
+fn resume_persisted_continuation() {
+    let mut vm = Vm::new();
+    let captured = vm.run_function(capturing_program()).unwrap();
+    let k = Continuation::from_value(captured).unwrap();
+
+    let persisted = ::serde_json::to_string(&k).unwrap();
+    let restored: Continuation = ::serde_json::from_str(&persisted).unwrap();
+    assert_eq!(restored, k);
+
+    let mut other_vm = Vm::new();
+    assert_eq!(
+        other_vm.resume_continuation(restored, Integer(9)),
+        Ok(Completion::Done(Integer(10)))
+    );
+    assert_eq!(other_vm.debug_values, vec![Integer(9)]);
+}
+
+#[test]
+fn persisted_functions_keep_sharing() {
+    let shared = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("shared".into()),
+            instructions: vec![PushConst(0), Resume],
+            constants: vec![Integer(1)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
         is_built: false,
-        instructions: vec![Resume],
-        args_count: 1,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let holder = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("holder".into()),
+            instructions: vec![PushConst(0), Resume],
+            constants: vec![Function(shared.clone())],
+            args_count: 1,
+            upvars_count: 2,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: true,
+        built: BuiltFunction {
+            upvars: vec![Function(shared.clone()), Function(shared.clone())],
+            continuation: None,
+        },
+    });
+
+    let persisted = ::serde_json::to_string(&holder).unwrap();
+    let restored: FunctionPtr = ::serde_json::from_str(&persisted).unwrap();
+
+    let upvars = &restored.built.upvars;
+    let (a, b) = match (&upvars[0], &upvars[1]) {
+        (&Function(ref a), &Function(ref b)) => (a, b),
+        _ => panic!(),
+    };
+    assert!(Rc::ptr_eq(&a.function, &b.function));
+    match restored.constants[0] {
+        Function(ref c) => assert!(Rc::ptr_eq(&a.function, &c.function)),
+        _ => panic!(),
+    }
+    assert_eq!(*a, shared);
+}
+
+#[test]
+fn persisted_frames_keep_sharing_code() {
+    let code = Rc::new(Code {
+        name: Some("frame".into()),
+        instructions: vec![GetFromStackPosition(1), Resume],
+        constants: vec![],
+        args_count: 0,
+        upvars_count: 1,
+        locals_count: 0,
+        line_table: LineTable::new(),
+    });
+    let frame = |upvar, continuation| {
+        new_func(Function {
+            code: code.clone(),
+            is_built: true,
+            built: BuiltFunction {
+                upvars: vec![upvar],
+                continuation,
+            },
+        })
+    };
+    let outer = frame(Integer(1), None);
+    let inner = frame(Integer(2), Some((outer, None)));
+
+    let persisted = ::serde_json::to_string(&inner).unwrap();
+    let restored: FunctionPtr = ::serde_json::from_str(&persisted).unwrap();
+    assert_eq!(restored, inner);
+
+    let (outer, _) = restored.continuation().unwrap();
+    assert!(!Rc::ptr_eq(&restored.function, &outer.function));
+    assert!(Rc::ptr_eq(&restored.code, &outer.code));
+}
+
+#[test]
+fn push_const_out_of_range() {
+    let function = new_func(Function::new(Code {
+        name: Some("bad".into()),
+        instructions: vec![PushConst(0), PushConst(1), Terminate],
+        constants: vec![Integer(1)],
+        args_count: 0,
         upvars_count: 0,
         locals_count: 0,
+        line_table: LineTable::new(),
+    }));
+
+    let mut vm = Vm::new();
+    assert_eq!(
+        vm.run_function(function).map_err(|e| e.error),
+        Err(VmError::NoSuchConstant(1))
+    );
+}
+
+#[test]
+fn integer_arithmetic_errors() {
+    let run = |constants, op| {
+        let function = new_func(Function::new(Code {
+            name: Some("bad".into()),
+            instructions: vec![PushConst(0), PushConst(1), op, Terminate],
+            constants,
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }));
+        Vm::new().run_function(function).map_err(|e| e.error)
+    };
+
+    assert_eq!(run(vec![Integer(1), Integer(0)], Div), Err(VmError::DivisionByZero));
+    assert_eq!(run(vec![Integer(i64::MAX), Integer(1)], Add), Err(VmError::IntegerOverflow));
+    assert_eq!(run(vec![Integer(i64::MIN), Integer(-1)], Div), Err(VmError::IntegerOverflow));
+    assert_eq!(run(vec![Float(1.0), Integer(0)], Div), Ok(Float(::std::f64::INFINITY)));
+    assert_eq!(
+        run(vec![Integer(1), symval("a")], Sub),
+        Err(VmError::UnexpectedType {
+            expected: ValueKind::Integer,
+            found: symval("a"),
+        })
+    );
+}
+
+#[test]
+fn continuation_requires_single_argument() {
+    let function = new_func(Function {
+        code: Rc::new(Code {
+            name: None,
+            instructions: vec![Resume],
+            constants: vec![],
+            args_count: 2,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: true,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    assert_eq!(
+        Continuation::from_function(function),
+        Err(VmError::ArityMismatch {
+            actual: 1,
+            expected: 2,
+        })
+    );
+}
+
+fn reading_program() -> FunctionPtr {
+    // let x = io.read(); debug(x); let y = io.read(); x + y
+    let after_second_read = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("after_second_read".into()),
+            instructions: vec![
+                GetFromStackPosition(2),
+                GetFromStackPosition(1),
+                Add,
+                Resume,
+            ],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 1,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let after_first_read = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("after_first_read".into()),
+            instructions: vec![
+                GetFromStackPosition(1),
+                Debug,
+                GetFromStackPosition(1),
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                PushConst(2),
+                ModuleGet,
+                Call(0),
+            ],
+            constants: vec![Function(after_second_read), symval("read"), symval("io")],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                PushConst(2),
+                ModuleGet,
+                Call(0),
+            ],
+            constants: vec![Function(after_first_read), symval("read"), symval("io")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    })
+}
+
+fn vm_with_suspending_read() -> Vm {
+    let mut vm = Vm::new();
+    vm.register_native(Symbol("io".into()), Symbol("read".into()), 0, |_| {
+        Ok(NativeResult::Suspend(symval("stdin")))
+    });
+    vm
+}
+
+#[test]
+fn native_function_returning_immediately() {
+    let calls = Rc::new(RefCell::new(0));
+    let calls_in_native = calls.clone();
+
+    let mut vm = Vm::new();
+    vm.register_native(Symbol("io".into()), Symbol("read".into()), 0, move |args| {
+        assert!(args.is_empty());
+        *calls_in_native.borrow_mut() += 1;
+        Ok(NativeResult::Return(Integer(5)))
+    });
+
+    assert_eq!(vm.run_function(reading_program()), Ok(Integer(10)));
+    assert_eq!(vm.debug_values, vec![Integer(5)]);
+    assert_eq!(*calls.borrow(), 2);
+}
+
+#[test]
+fn native_function_receives_arguments() {
+    let mut vm = Vm::new();
+    vm.register_native(Symbol("math".into()), Symbol("double".into()), 1, |args| {
+        Ok(NativeResult::Return(Integer(args[0].as_int()? * 2)))
+    });
+
+    let after = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("after".into()),
+            instructions: vec![Resume],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
     });
 
     let main = new_func(Function {
-        name: Some("main".into()),
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                PushConst(2),
+                ModuleGet,
+                PushConst(3),
+                Call(1),
+            ],
+            constants: vec![
+                Function(after),
+                symval("double"),
+                symval("math"),
+                Integer(21),
+            ],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
+    });
+
+    assert_eq!(vm.run_function(main), Ok(Integer(42)));
+}
+
+#[test]
+fn suspended_native_is_resumed_by_host() {
+    // Stand-in for an I/O source that the host polls between suspensions.
+    let mut input = VecDeque::new();
+    input.push_back(Integer(3));
+    input.push_back(Integer(4));
+
+    let mut vm = vm_with_suspending_read();
+    let mut completion = vm.start_function(reading_program()).unwrap();
+    let mut suspensions = 0;
+    let result = loop {
+        match completion {
+            Completion::Done(v) => break v,
+            Completion::Pending(token) => {
+                suspensions += 1;
+                assert_eq!(token.request(), &symval("stdin"));
+                let value = input.pop_front().unwrap();
+                completion = vm.resume(token, value).unwrap();
+            }
+        }
+    };
+
+    assert_eq!(result, Integer(7));
+    assert_eq!(suspensions, 2);
+    assert_eq!(vm.debug_values, vec![Integer(3)]);
+}
+
+#[test]
+fn pending_token_can_only_be_resumed_once() {
+    let mut vm = vm_with_suspending_read();
+    let token = match vm.start_function(reading_program()) {
+        Ok(Completion::Pending(token)) => token,
+        other => panic!("expected suspension, got {:?}", other),
+    };
+
+    assert!(vm.resume(token.clone(), Integer(1)).is_ok());
+    assert_eq!(
+        vm.resume(token.clone(), Integer(1)).map_err(|e| e.error),
+        Err(VmError::UnknownPendingToken(token.id()))
+    );
+}
+
+#[test]
+fn detached_pending_call_resumes_elsewhere() {
+    let mut vm = vm_with_suspending_read();
+    let token = match vm.start_function(reading_program()) {
+        Ok(Completion::Pending(token)) => token,
+        other => panic!("expected suspension, got {:?}", other),
+    };
+    let k = vm.detach_pending(&token).unwrap();
+    let persisted = ::serde_json::to_string(&k).unwrap();
+
+    let mut other_vm = vm_with_suspending_read();
+    let restored: Continuation = ::serde_json::from_str(&persisted).unwrap();
+    let token = match other_vm.resume_continuation(restored, Integer(10)) {
+        Ok(Completion::Pending(token)) => token,
+        other => panic!("expected suspension, got {:?}", other),
+    };
+    assert_eq!(other_vm.resume(token, Integer(5)), Ok(Completion::Done(Integer(15))));
+    assert_eq!(other_vm.debug_values, vec![Integer(10)]);
+}
+
+#[test]
+fn run_function_rejects_suspension() {
+    let mut vm = vm_with_suspending_read();
+    assert_eq!(
+        vm.run_function(reading_program()).map_err(|e| e.error),
+        Err(VmError::UnhandledSuspension(symval("stdin")))
+    );
+}
+
+#[test]
+fn missing_native_function() {
+    let mut vm = Vm::new();
+    vm.modules.insert(
+        (Symbol("io".into()), Symbol("read".into())),
+        Function(::native::native_function(&Symbol("io".into()), &Symbol("read".into()), 0)),
+    );
+    assert_eq!(
+        vm.run_function(reading_program()).map_err(|e| e.error),
+        Err(VmError::NoNativeFunction(Symbol("io.read".into())))
+    );
+}
+
+#[test]
+fn natives_with_the_same_name_in_different_modules() {
+    let mut vm = Vm::new();
+    vm.register_native(Symbol("io".into()), Symbol("read".into()), 0, |_| {
+        Ok(NativeResult::Return(Integer(5)))
+    });
+    vm.register_native(Symbol("net".into()), Symbol("read".into()), 0, |_| {
+        Ok(NativeResult::Return(Integer(7)))
+    });
+
+    // `reading_program` calls `io.read` twice and adds the results.
+    assert_eq!(vm.run_function(reading_program()), Ok(Integer(10)));
+}
+
+#[test]
+fn trace_walks_continuation_chain() {
+    // The error happens in the shift handler, whose continuation is
+    // everything above the reset.
+    let shifter = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("shifter".into()),
+            instructions: vec![
+                GetFromStackPosition(1),
+                PushConst(0),
+                Call(0),
+            ],
+            constants: vec![Integer(1)],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
         is_built: false,
-        instructions: vec![
-            Push(Function(after_reset)),
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let after_shift = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("after_shift".into()),
+            instructions: vec![MapEmpty, PushConst(0), MapGet, Resume],
+            constants: vec![Integer(3)],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let reseter = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("resetter".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                PushConst(2),
+                Shift,
+            ],
+            constants: vec![Function(after_shift), Function(shifter), symval("io")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let main = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                CurrentContinuation,
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                Reset,
+            ],
+            constants: vec![Function(reseter), symval("io")],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let mut vm = Vm::new();
+    let error = vm.run_function(main).unwrap_err();
+    assert_eq!(error.error, VmError::UnexpectedType {
+        expected: ValueKind::Function,
+        found: Integer(1),
+    });
+    let names = error
+        .trace
+        .frames
+        .iter()
+        .map(|f| f.function.clone().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["shifter", "<terminate>"]);
+    assert_eq!(error.trace.frames[0].instruction, 2);
+}
+
+#[test]
+fn trace_display() {
+    let trace = StackTrace {
+        frames: vec![
+            Frame {
+                function: Some("after_shift".into()),
+                instruction: 2,
+                tag: None,
+                span: Some(Span { start: 4, end: 9 }),
+            },
+            Frame {
+                function: Some("<continue-shim>".into()),
+                instruction: 0,
+                tag: Some(Symbol("io".into())),
+                span: None,
+            },
+            Frame {
+                function: None,
+                instruction: 0,
+                tag: None,
+                span: None,
+            },
+        ],
+    };
+    let error = RuntimeError {
+        error: VmError::KeyNotFound(Integer(3)),
+        trace,
+    };
+
+    assert_eq!(
+        format!("{}", error),
+        "runtime error: key 3 not found\n    \
+         at after_shift (instruction 2, bytes 4..9)\n    \
+         at <continue-shim> 'io (instruction 0)\n    \
+         at <unnamed> (instruction 0)\n"
+    );
+}
+
+#[test]
+fn runtime_error_display_with_lines() {
+    let error = RuntimeError {
+        error: VmError::KeyNotFound(Integer(3)),
+        trace: StackTrace {
+            frames: vec![
+                Frame {
+                    function: Some("lookup".into()),
+                    instruction: 2,
+                    tag: None,
+                    span: Some(Span { start: 14, end: 18 }),
+                },
+                Frame {
+                    function: None,
+                    instruction: 0,
+                    tag: None,
+                    span: None,
+                },
+            ],
+        },
+    };
+    let lines = SourceLines::new("let x = 1;\nx + {}.a;\n");
+
+    assert_eq!(
+        format!("{}", error.with_lines(&lines)),
+        "runtime error: key 3 not found\n    \
+         at lookup (instruction 2, line 2:4)\n    \
+         at <unnamed> (instruction 0)\n"
+    );
+    assert_eq!(lines.position(0), (1, 1));
+    assert_eq!(lines.position(11), (2, 1));
+}
+
+#[test]
+fn vm_error_display() {
+    let error = VmError::UnexpectedType {
+        expected: ValueKind::Integer,
+        found: Float(2.5),
+    };
+    assert_eq!(format!("{}", error), "expected integer, found float 2.5");
+    let error = VmError::UnexpectedType {
+        expected: ValueKind::Integer,
+        found: Function(new_func(Function::new(Code {
+            name: Some("add".into()),
+            instructions: vec![],
+            constants: vec![],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: LineTable::new(),
+        }))),
+    };
+    assert_eq!(format!("{}", error), "expected integer, found function add");
+    let error = VmError::ArityMismatch {
+        actual: 2,
+        expected: 1,
+    };
+    assert_eq!(format!("{}", error), "expected 1 argument, got 2");
+}
+
+#[test]
+fn line_table_covers_runs_of_instructions() {
+    let mut table = LineTable::new();
+    table.push(0, Span { start: 0, end: 5 });
+    table.push(1, Span { start: 0, end: 5 });
+    table.push(3, Span { start: 6, end: 8 });
+
+    assert_eq!(table.entries().len(), 2);
+    assert_eq!(table.lookup(0), Some(Span { start: 0, end: 5 }));
+    assert_eq!(table.lookup(2), Some(Span { start: 0, end: 5 }));
+    assert_eq!(table.lookup(3), Some(Span { start: 6, end: 8 }));
+    assert_eq!(table.lookup(40), Some(Span { start: 6, end: 8 }));
+}
+
+#[test]
+fn trace_reports_source_spans() {
+    let mut line_table = LineTable::new();
+    line_table.push(0, Span { start: 0, end: 2 });
+    line_table.push(2, Span { start: 3, end: 7 });
+    let function = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("bad_get".into()),
+            instructions: vec![MapEmpty, PushConst(0), MapGet, Terminate],
+            constants: vec![Integer(1)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table,
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    let serialized = serde_json::to_string(&function).unwrap();
+    let function: FunctionPtr = serde_json::from_str(&serialized).unwrap();
+
+    let mut vm = Vm::new();
+    let error = vm.run_function(function).unwrap_err();
+    assert_eq!(error.trace.frames[0].span, Some(Span { start: 3, end: 7 }));
+}
+
+fn doubler() -> FunctionPtr {
+    let mut double_lines = LineTable::new();
+    double_lines.push(0, Span { start: 6, end: 11 });
+    new_func(Function {
+        code: Rc::new(Code {
+            name: Some("double".into()),
+            instructions: vec![
+                GetFromStackPosition(1),
+                GetFromStackPosition(1),
+                Add,
+                Resume,
+            ],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: double_lines,
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    })
+}
+
+fn debuggee() -> FunctionPtr {
+    // main (line 1) calls double (line 2) with 21 and returns the result.
+    let double = doubler();
+
+    let mut main_lines = LineTable::new();
+    main_lines.push(0, Span { start: 0, end: 5 });
+    let mut after_lines = LineTable::new();
+    after_lines.push(0, Span { start: 1, end: 5 });
+    let after = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![GetFromStackPosition(1), Resume],
+            constants: vec![],
+            args_count: 1,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: after_lines,
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    });
+
+    new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![
+                PushConst(0),
+                BuildFunction,
+                PushConst(1),
+                BuildFunction,
+                PushConst(2),
+                Call(1),
+            ],
+            constants: vec![Function(after), Function(double), Integer(21)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: main_lines,
+        }),
+        is_built: false,
+        built: BuiltFunction {
+            upvars: vec![],
+            continuation: None,
+        },
+    })
+}
+
+const DEBUGGEE_SOURCE: &str = "main.\ndoubl\n";
+
+#[test]
+fn debugger_steps_single_instructions() {
+    let mut vm = Vm::new();
+    let mut debugger = Debugger::new(DEBUGGEE_SOURCE);
+    let mut execution = vm.begin_function(debuggee()).unwrap();
+
+    assert_eq!(execution.ip(), 0);
+    assert_eq!(debugger.current_line(&execution), Some(1));
+    for _ in 0..5 {
+        assert_eq!(
+            debugger.step_instruction(&mut vm, &mut execution),
+            Ok(Stop::Stepped)
+        );
+    }
+    assert_eq!(execution.next_instruction(), Some(Call(1)));
+    assert_eq!(execution.stack().last(), Some(&Integer(21)));
+}
+
+#[test]
+fn debugger_stops_at_function_breakpoint() {
+    let mut vm = Vm::new();
+    let mut debugger = Debugger::new(DEBUGGEE_SOURCE);
+    let id = debugger.add_breakpoint(Breakpoint::Function("double".into()));
+    let mut execution = vm.begin_function(debuggee()).unwrap();
+
+    assert_eq!(
+        debugger.resume(&mut vm, &mut execution),
+        Ok(Stop::Breakpoint(id))
+    );
+    assert_eq!(execution.function().name, Some("double".into()));
+    assert_eq!(execution.args(), &[Integer(21)]);
+    assert_eq!(debugger.current_line(&execution), Some(2));
+    let names = execution
+        .backtrace()
+        .frames
+        .into_iter()
+        .map(|f| f.function.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["double", "main", "<terminate>"]);
+
+    assert_eq!(
+        debugger.resume(&mut vm, &mut execution),
+        Ok(Stop::Finished(Completion::Done(Integer(42))))
+    );
+}
+
+#[test]
+fn debugger_stops_at_line_breakpoint() {
+    let mut vm = Vm::new();
+    let mut debugger = Debugger::new(DEBUGGEE_SOURCE);
+    let id = debugger.add_breakpoint(Breakpoint::Line(2));
+    let mut execution = vm.begin_function(debuggee()).unwrap();
+
+    assert_eq!(
+        debugger.resume(&mut vm, &mut execution),
+        Ok(Stop::Breakpoint(id))
+    );
+    assert_eq!(execution.function().name, Some("double".into()));
+    assert_eq!(execution.ip(), 0);
+
+    assert!(debugger.remove_breakpoint(id));
+    assert!(debugger.breakpoints().is_empty());
+}
+
+#[test]
+fn debugger_steps_over_calls() {
+    let mut vm = Vm::new();
+    let mut debugger = Debugger::new(DEBUGGEE_SOURCE);
+    let mut execution = vm.begin_function(debuggee()).unwrap();
+    while execution.next_instruction() != Some(Call(1)) {
+        debugger.step_instruction(&mut vm, &mut execution).unwrap();
+    }
+
+    assert_eq!(
+        debugger.step_over(&mut vm, &mut execution),
+        Ok(Stop::Stepped)
+    );
+    assert_eq!(execution.function().name, Some("main".into()));
+    assert_eq!(execution.ip(), 0);
+    assert_eq!(execution.args(), &[Integer(42)]);
+}
+
+#[test]
+fn profiler_counts_instructions_per_function_and_stack() {
+    let mut vm = Vm::new();
+    vm.enable_profiling();
+    assert_eq!(vm.run_function(debuggee()), Ok(Integer(42)));
+    let profile = vm.take_profile().unwrap();
+
+    let functions = profile.functions();
+    assert_eq!(functions["main"].instructions, 8);
+    assert_eq!(functions["double"].instructions, 4);
+    assert_eq!(functions["<terminate>"].instructions, 1);
+    assert_eq!(profile.total().instructions, 13);
+
+    assert_eq!(
+        profile.folded(FoldedWeight::Instructions),
+        "<terminate> 1\n<terminate>;main 8\n<terminate>;main;double 4\n"
+    );
+    assert!(profile.report().lines().nth(1).unwrap().ends_with("main"));
+    assert_eq!(vm.take_profile(), None);
+}
+
+#[test]
+fn coverage_maps_hits_to_lines() {
+    let mut vm = Vm::new();
+    vm.enable_coverage();
+    let program = debuggee();
+    assert_eq!(vm.run_function(program.clone()), Ok(Integer(42)));
+    let coverage = vm.take_coverage().unwrap();
+
+    let lines = coverage.line_hits(DEBUGGEE_SOURCE, &program);
+    assert_eq!(lines.into_iter().collect::<Vec<_>>(), vec![(1, 1), (2, 1)]);
+}
+
+#[test]
+fn coverage_counts_lines_once_per_run() {
+    // main (line 1) calls double (line 2) twice, passing the first result
+    // on to the second call.
+    let code = |instructions, constants, args_count, start| {
+        let mut main_lines = LineTable::new();
+        main_lines.push(0, Span { start, end: 5 });
+        new_func(Function {
+            code: Rc::new(Code {
+                name: Some("main".into()),
+                instructions,
+                constants,
+                args_count,
+                upvars_count: 0,
+                locals_count: 0,
+                line_table: main_lines,
+            }),
+            is_built: false,
+            built: BuiltFunction {
+                upvars: vec![],
+                continuation: None,
+            },
+        })
+    };
+    let last = code(vec![GetFromStackPosition(1), Resume], vec![], 1, 2);
+    let again = code(
+        vec![
+            PushConst(0),
             BuildFunction,
-            Push(Function(reseter)),
+            PushConst(1),
             BuildFunction,
-            Push(symval("io")),
-            Reset,
+            GetFromStackPosition(1),
+            Call(1),
         ],
-        args_count: 0,
-        upvars_count: 0,
-        locals_count: 0,
-    });
+        vec![Function(last), Function(doubler())],
+        1,
+        1,
+    );
+    let program = code(
+        vec![
+            PushConst(0),
+            BuildFunction,
+            PushConst(1),
+            BuildFunction,
+            PushConst(2),
+            Call(1),
+        ],
+        vec![Function(again), Function(doubler()), Integer(21)],
+        0,
+        0,
+    );
+
     let mut vm = Vm::new();
-    let res = vm.run_function(main);
+    vm.enable_coverage();
+    assert_eq!(vm.run_function(program.clone()), Ok(Integer(84)));
+    let coverage = vm.take_coverage().unwrap();
 
-    assert_eq!(res, Ok(Integer(10)));
-    assert_eq!(vm.debug_values, vec![]);
+    let lines = coverage.line_hits(DEBUGGEE_SOURCE, &program);
+    assert_eq!(lines.into_iter().collect::<Vec<_>>(), vec![(1, 1), (2, 2)]);
 }
 
 #[test]
-fn setting_module_variables() {
-    let main = new_func(Function {
-        name: Some("main2".into()),
+fn coverage_reports_lines_that_never_ran() {
+    let mut main_lines = LineTable::new();
+    main_lines.push(0, Span { start: 0, end: 5 });
+    let program = new_func(Function {
+        code: Rc::new(Code {
+            name: Some("main".into()),
+            instructions: vec![PushConst(0), Pop, PushConst(1), Terminate],
+            constants: vec![Function(doubler()), Integer(1)],
+            args_count: 0,
+            upvars_count: 0,
+            locals_count: 0,
+            line_table: main_lines,
+        }),
+        is_built: false,
         built: BuiltFunction {
             upvars: vec![],
             continuation: None,
         },
-        is_built: false,
+    });
+
+    let mut vm = Vm::new();
+    vm.enable_coverage();
+    assert_eq!(vm.run_function(program.clone()), Ok(Integer(1)));
+    let coverage = vm.take_coverage().unwrap();
+
+    assert_eq!(
+        coverage.lcov("unused", "main.ares", DEBUGGEE_SOURCE, &program),
+        "TN:unused\nSF:main.ares\nDA:1,1\nDA:2,0\nLF:2\nLH:1\nend_of_record\n"
+    );
+}
+
+fn compiled_module() -> CompiledModule {
+    // Defines `main.answer` as the result of calling double with 21.
+    let double = doubler();
+    let mut lines = LineTable::new();
+    lines.push(0, Span { start: 0, end: 5 });
+    let program = new_func(Function::new(Code {
+        name: Some("main".into()),
         instructions: vec![
-            Push(Integer(5)),
-            Push(symval("variable_name")),
-            Push(symval("module_name")),
+            PushConst(0),
+            PushConst(1),
+            PushConst(2),
+            ModuleAdd,
+            PushConst(3),
+            PushConst(4),
+            PushConst(2),
             ModuleAdd,
-            Push(symval("variable_name")),
-            Push(symval("module_name")),
-            ModuleGet,
+            MapEmpty,
             Terminate,
         ],
+        constants: vec![
+            Function(double.clone()),
+            symval("double"),
+            symval("main"),
+            Float(0.5),
+            symval("half"),
+        ],
         args_count: 0,
         upvars_count: 0,
         locals_count: 0,
-    });
+        line_table: lines,
+    }));
+
+    CompiledModule {
+        name: "main".into(),
+        exports: vec!["double".into(), "half".into()],
+        program,
+    }
+}
+
+#[test]
+fn compiled_module_round_trips() {
+    let module = compiled_module();
+    let bytes = module.to_bytes(true).unwrap();
+    assert_eq!(&bytes[..MAGIC.len()], MAGIC);
+    assert_eq!(CompiledModule::from_bytes(&bytes), Ok(module.clone()));
+
+    let stripped = CompiledModule::from_bytes(&module.to_bytes(false).unwrap()).unwrap();
+    assert!(stripped.program.line_table.is_empty());
+    assert_eq!(stripped.program.instructions, module.program.instructions);
+    assert_eq!(stripped.exports, module.exports);
+}
+
+#[test]
+fn compiled_module_shares_repeated_functions() {
+    let double = doubler();
+    let program = new_func(Function::new(Code {
+        name: None,
+        instructions: vec![PushConst(0), PushConst(1), Pop, Terminate],
+        constants: vec![Function(double.clone()), Function(double)],
+        args_count: 0,
+        upvars_count: 0,
+        locals_count: 0,
+        line_table: LineTable::new(),
+    }));
+    let module = CompiledModule {
+        name: "m".into(),
+        exports: vec![],
+        program,
+    };
+
+    let restored = CompiledModule::from_bytes(&module.to_bytes(false).unwrap()).unwrap();
+    match (&restored.program.constants[0], &restored.program.constants[1]) {
+        (&Function(ref a), &Function(ref b)) => assert!(Rc::ptr_eq(&a.code, &b.code)),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn loading_compiled_module_registers_definitions() {
+    let module = CompiledModule::from_bytes(&compiled_module().to_bytes(true).unwrap()).unwrap();
     let mut vm = Vm::new();
-    assert_eq!(vm.run_function(main), Ok(Integer(5)));
+    assert_eq!(vm.load_module(&module), Ok(Map(AresMap::new())));
+    assert_eq!(
+        vm.modules.get(&(Symbol("main".into()), Symbol("half".into()))),
+        Some(&Float(0.5))
+    );
+    assert!(vm.modules.contains_key(&(Symbol("main".into()), Symbol("double".into()))));
+}
+
+#[test]
+fn compiled_module_from_newer_version_is_rejected() {
+    let mut bytes = compiled_module().to_bytes(true).unwrap();
+    bytes[MAGIC.len()] = (FORMAT_VERSION + 1) as u8;
+    assert_eq!(
+        CompiledModule::from_bytes(&bytes),
+        Err(FormatError::UnsupportedVersion {
+            found: FORMAT_VERSION + 1,
+            supported: FORMAT_VERSION,
+        })
+    );
+}
+
+#[test]
+fn corrupted_compiled_module_is_rejected() {
+    let bytes = compiled_module().to_bytes(true).unwrap();
+
+    let mut flipped = bytes.clone();
+    let middle = flipped.len() / 2;
+    flipped[middle] ^= 0xff;
+    assert_eq!(
+        CompiledModule::from_bytes(&flipped),
+        Err(FormatError::ChecksumMismatch)
+    );
+
+    assert_eq!(
+        CompiledModule::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(FormatError::ChecksumMismatch)
+    );
+    assert_eq!(
+        CompiledModule::from_bytes(b"let x = 1;"),
+        Err(FormatError::NotCompiledModule)
+    );
+}
+
+const ASSEMBLED_PROGRAM: &str = "\
+fn @main \"main\" args=0 upvars=0 locals=0
+    span 0..5
+    push @after
+    build
+    push @double
+    build
+    push 21
+    call 1
+
+fn @after \"main\" args=1 upvars=0 locals=0
+    get 1
+    push 'done
+    pop
+    terminate
+
+fn @double \"double\" args=1 upvars=0 locals=0
+    span 6..11
+    get 1
+    get 1
+    add
+    resume
+";
+
+#[test]
+fn assembled_program_runs() {
+    let program = assemble(ASSEMBLED_PROGRAM).unwrap();
+    assert_eq!(program.constants.len(), 3);
+    assert_eq!(program.line_table.lookup(4), Some(Span { start: 0, end: 5 }));
+
+    let mut vm = Vm::new();
+    assert_eq!(vm.run_function(program), Ok(Integer(42)));
+}
+
+#[test]
+fn disassembly_round_trips() {
+    let program = assemble(ASSEMBLED_PROGRAM).unwrap();
+    let text = disassemble(&program);
+    assert_eq!(text.replace("after", "main.1"), ASSEMBLED_PROGRAM.replace("after", "main.1"));
+    assert_eq!(assemble(&text), Ok(program));
+
+    let debuggee = debuggee();
+    assert_eq!(assemble(&disassemble(&debuggee)), Ok(debuggee));
+}
+
+#[test]
+fn disassembly_quotes_unusual_constants() {
+    let program = new_func(Function::new(Code {
+        name: None,
+        instructions: vec![PushConst(0), PushConst(1), PushConst(2), CallNative(3)],
+        constants: vec![
+            symval("two words"),
+            Float(-0.0),
+            Integer(-3),
+            symval("say \"hi\""),
+        ],
+        args_count: 0,
+        upvars_count: 0,
+        locals_count: 0,
+        line_table: LineTable::new(),
+    }));
+
+    let text = disassemble(&program);
+    assert_eq!(
+        text,
+        "fn @anon args=0 upvars=0 locals=0
+    push '\"two words\"
+    push -0.0
+    push -3
+    native '\"say \\\"hi\\\"\"
+"
+    );
+    assert_eq!(assemble(&text), Ok(program));
+}
+
+#[test]
+fn assembler_reports_the_offending_line() {
+    assert_eq!(
+        assemble("fn @main\n    push 1\n    jump 3\n").map_err(|e| e.to_string()),
+        Err("line 3: unknown instruction `jump`".into())
+    );
+    assert_eq!(
+        assemble("fn @main\n    push @missing\n").map_err(|e| e.to_string()),
+        Err("line 2: undefined label @missing".into())
+    );
+    assert_eq!(
+        assemble("    add\n").map_err(|e| e.to_string()),
+        Err("line 1: expected `fn` before the first instruction".into())
+    );
+}
+
+#[test]
+fn check_type_stops_values_of_other_kinds() {
+    let program = assemble("fn @main\n    push 1.5\n    check float\n    check integer\n    terminate\n")
+        .unwrap();
+    assert_eq!(program.instructions[1], CheckType(ValueKind::Float));
+    assert_eq!(assemble(&disassemble(&program)), Ok(program.clone()));
+
+    let module = CompiledModule {
+        name: "m".into(),
+        exports: vec![],
+        program: program.clone(),
+    };
+    let restored = CompiledModule::from_bytes(&module.to_bytes(false).unwrap()).unwrap();
+    assert_eq!(restored.program.instructions, program.instructions);
+
+    let mut vm = Vm::new();
+    assert_eq!(
+        vm.run_function(program).map_err(|e| e.error),
+        Err(VmError::UnexpectedType {
+            expected: ValueKind::Integer,
+            found: Float(1.5),
+        })
+    );
+    assert_eq!(
+        assemble("fn @main\n    check number\n").map_err(|e| e.to_string()),
+        Err("line 2: unknown value kind `number`".into())
+    );
+}
+
+#[test]
+fn dot_marks_continuation_constants() {
+    let program = assemble(
+        "fn @main \"main\" args=0
+            push @after
+            build
+            push @double
+            build
+            push 21
+            call 1
+
+        fn @after \"after\" args=1
+            get 1
+            terminate
+
+        fn @double \"double\" args=1
+            get 1
+            get 1
+            add
+            resume",
+    ).unwrap();
+    assert_eq!(
+        ::dot::to_dot(&program),
+        r#"digraph functions {
+    node [shape=box, fontname="monospace"];
+    n0 [label="fn @main args=0 upvars=0 locals=0\lpush @after\lbuild\lpush @double\lbuild\lpush 21\lcall 1\l"];
+    n1 [label="fn @after args=1 upvars=0 locals=0\lget 1\lterminate\l"];
+    n2 [label="fn @double args=1 upvars=0 locals=0\lget 1\lget 1\ladd\lresume\l"];
+    n0 -> n1 [label="continuation", style=dashed];
+    n0 -> n2;
+}
+"#
+    );
 }